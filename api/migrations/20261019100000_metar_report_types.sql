@@ -0,0 +1,33 @@
+ALTER TABLE metars ADD COLUMN IF NOT EXISTS report_type TEXT NOT NULL DEFAULT 'METAR';
+ALTER TABLE metars ADD COLUMN IF NOT EXISTS corrected BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE metars ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+UPDATE metars SET report_type = 'SPECI' WHERE raw_text LIKE 'SPECI %';
+UPDATE metars SET corrected = true WHERE raw_text ~ '\mCOR\M';
+
+-- Remove repeated fetches of the same observation, preferring corrected reports
+DELETE FROM metars WHERE ctid IN (
+    SELECT ctid FROM (
+        SELECT ctid, ROW_NUMBER() OVER (
+            PARTITION BY icao, observation_time, report_type
+            ORDER BY corrected DESC, ctid DESC
+        ) AS position
+        FROM metars
+    ) ranked
+    WHERE ranked.position > 1
+);
+
+ALTER TABLE metars ADD CONSTRAINT metars_observation_key UNIQUE (icao, observation_time, report_type);
+
+CREATE TABLE IF NOT EXISTS metar_corrections (
+    id UUID PRIMARY KEY NOT NULL,
+    icao TEXT NOT NULL,
+    observation_time TIMESTAMPTZ NOT NULL,
+    report_type TEXT NOT NULL,
+    previous_raw_text TEXT NOT NULL,
+    previous_data JSONB NOT NULL,
+    raw_text TEXT NOT NULL,
+    corrected_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON metar_corrections (icao, observation_time);
@@ -0,0 +1,36 @@
+CREATE TABLE IF NOT EXISTS roles (
+    name TEXT PRIMARY KEY NOT NULL,
+    parent TEXT REFERENCES roles (name) ON UPDATE CASCADE,
+    description TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CHECK (parent IS NULL OR parent <> name)
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+    role TEXT NOT NULL REFERENCES roles (name) ON UPDATE CASCADE ON DELETE CASCADE,
+    permission TEXT NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+INSERT INTO roles (name, parent, description) VALUES
+    ('USER', NULL, 'Registered user'),
+    ('ADMIN', 'USER', 'Full administrative access')
+ON CONFLICT (name) DO NOTHING;
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('USER', 'weather:read'),
+    ('ADMIN', 'weather:refresh'),
+    ('ADMIN', 'airports:write'),
+    ('ADMIN', 'users:manage'),
+    ('ADMIN', 'roles:manage'),
+    ('ADMIN', 'system:read')
+ON CONFLICT DO NOTHING;
+
+-- Keep any role already assigned to a user so the foreign key can be added
+INSERT INTO roles (name)
+SELECT DISTINCT role FROM users
+ON CONFLICT (name) DO NOTHING;
+
+ALTER TABLE users
+    ADD CONSTRAINT users_role_fkey FOREIGN KEY (role) REFERENCES roles (name) ON UPDATE CASCADE;
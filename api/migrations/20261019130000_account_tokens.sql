@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS account_tokens (
+    id TEXT PRIMARY KEY NOT NULL,
+    email TEXT NOT NULL REFERENCES users (email) ON UPDATE CASCADE ON DELETE CASCADE,
+    purpose TEXT NOT NULL,
+    token_hash TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON account_tokens (email, purpose);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS user_favorites (
+    email TEXT NOT NULL REFERENCES users (email) ON UPDATE CASCADE ON DELETE CASCADE,
+    icao TEXT NOT NULL REFERENCES airports (icao) ON UPDATE CASCADE ON DELETE CASCADE,
+    label TEXT,
+    position INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (email, icao)
+);
+
+CREATE INDEX ON user_favorites (email, position);
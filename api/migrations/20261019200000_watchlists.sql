@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS watchlists (
+    id UUID PRIMARY KEY NOT NULL,
+    owner TEXT NOT NULL REFERENCES users (email) ON UPDATE CASCADE ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    description TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (owner, name)
+);
+
+CREATE TABLE IF NOT EXISTS watchlist_airports (
+    watchlist_id UUID NOT NULL REFERENCES watchlists (id) ON DELETE CASCADE,
+    icao TEXT NOT NULL REFERENCES airports (icao) ON UPDATE CASCADE ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    PRIMARY KEY (watchlist_id, icao)
+);
+
+CREATE TABLE IF NOT EXISTS watchlist_members (
+    watchlist_id UUID NOT NULL REFERENCES watchlists (id) ON DELETE CASCADE,
+    email TEXT NOT NULL REFERENCES users (email) ON UPDATE CASCADE ON DELETE CASCADE,
+    access TEXT NOT NULL CHECK (access IN ('read', 'edit')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (watchlist_id, email)
+);
+
+CREATE INDEX ON watchlist_members (email);
@@ -0,0 +1,79 @@
+-- Personal airports belong to the user who created them, organization airports to the organization
+ALTER TABLE airports
+    ADD COLUMN IF NOT EXISTS owner TEXT REFERENCES users (email) ON UPDATE CASCADE ON DELETE CASCADE,
+    ADD COLUMN IF NOT EXISTS notes TEXT,
+    ADD COLUMN IF NOT EXISTS user_defined BOOLEAN NOT NULL
+        GENERATED ALWAYS AS (owner IS NOT NULL OR organization_id IS NOT NULL) STORED,
+    ADD CONSTRAINT airports_single_owner CHECK (owner IS NULL OR organization_id IS NULL);
+
+CREATE INDEX ON airports (owner);
+
+-- User-defined airports are keyed within their owner or organization rather than the global
+-- ICAO space, so they never block imported airports and owners may reuse identifiers
+ALTER TABLE airports
+    ADD COLUMN IF NOT EXISTS namespace TEXT NOT NULL GENERATED ALWAYS AS (
+        CASE
+            WHEN organization_id IS NOT NULL THEN 'organization:' || organization_id::TEXT
+            WHEN owner IS NOT NULL THEN 'user:' || owner
+            ELSE ''
+        END
+    ) STORED;
+
+-- Identifiers in use by at least one airport, favorites and watchlists name airports by these
+CREATE TABLE IF NOT EXISTS airport_identifiers (
+    icao TEXT PRIMARY KEY NOT NULL
+);
+
+INSERT INTO airport_identifiers (icao) SELECT icao FROM airports;
+
+ALTER TABLE user_favorites
+    DROP CONSTRAINT user_favorites_icao_fkey,
+    ADD FOREIGN KEY (icao) REFERENCES airport_identifiers (icao) ON DELETE CASCADE;
+ALTER TABLE watchlist_airports
+    DROP CONSTRAINT watchlist_airports_icao_fkey,
+    ADD FOREIGN KEY (icao) REFERENCES airport_identifiers (icao) ON DELETE CASCADE;
+
+ALTER TABLE airports DROP CONSTRAINT airports_pkey;
+ALTER TABLE airports ADD PRIMARY KEY (namespace, icao);
+CREATE INDEX ON airports (icao);
+
+-- An identifier goes away with the last airport that has it, taking its references along
+CREATE OR REPLACE FUNCTION insert_airport_identifier() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO airport_identifiers (icao) VALUES (NEW.icao) ON CONFLICT (icao) DO NOTHING;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION delete_airport_identifier() RETURNS TRIGGER AS $$
+BEGIN
+    DELETE FROM airport_identifiers
+        WHERE icao = OLD.icao AND NOT EXISTS (SELECT 1 FROM airports WHERE icao = OLD.icao);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER airports_insert_identifier
+    AFTER INSERT ON airports
+    FOR EACH ROW EXECUTE FUNCTION insert_airport_identifier();
+
+CREATE TRIGGER airports_delete_identifier
+    AFTER DELETE ON airports
+    FOR EACH ROW EXECUTE FUNCTION delete_airport_identifier();
+
+-- Runways and frequencies belong to the airport in their namespace. Rows left behind by an
+-- interrupted import have no airport and are dropped so the foreign keys hold for every row
+ALTER TABLE runways ADD COLUMN IF NOT EXISTS namespace TEXT NOT NULL DEFAULT '';
+ALTER TABLE frequencies ADD COLUMN IF NOT EXISTS namespace TEXT NOT NULL DEFAULT '';
+
+DELETE FROM runways
+    WHERE NOT EXISTS (SELECT 1 FROM airports WHERE airports.icao = runways.icao);
+DELETE FROM frequencies
+    WHERE NOT EXISTS (SELECT 1 FROM airports WHERE airports.icao = frequencies.icao);
+
+ALTER TABLE runways ADD FOREIGN KEY (namespace, icao)
+    REFERENCES airports (namespace, icao) ON UPDATE CASCADE ON DELETE CASCADE;
+ALTER TABLE frequencies ADD FOREIGN KEY (namespace, icao)
+    REFERENCES airports (namespace, icao) ON UPDATE CASCADE ON DELETE CASCADE;
+CREATE INDEX ON runways (namespace, icao);
+CREATE INDEX ON frequencies (namespace, icao);
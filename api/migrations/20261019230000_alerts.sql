@@ -0,0 +1,43 @@
+CREATE TABLE IF NOT EXISTS alert_rules (
+    id UUID PRIMARY KEY NOT NULL,
+    owner TEXT NOT NULL REFERENCES users (email) ON UPDATE CASCADE ON DELETE CASCADE,
+    organization_id UUID REFERENCES organizations (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    icao TEXT REFERENCES airport_identifiers (icao) ON DELETE CASCADE,
+    watchlist_id UUID REFERENCES watchlists (id) ON DELETE CASCADE,
+    condition JSONB NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT true,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- A rule watches either a single airport or every airport in a watchlist
+    CHECK ((icao IS NULL) <> (watchlist_id IS NULL))
+);
+
+CREATE INDEX ON alert_rules (owner);
+CREATE INDEX ON alert_rules (organization_id);
+CREATE INDEX ON alert_rules (icao);
+CREATE INDEX ON alert_rules (watchlist_id);
+
+-- Last evaluation per rule and station, so alerts only fire when the outcome changes
+CREATE TABLE IF NOT EXISTS alert_states (
+    rule_id UUID NOT NULL REFERENCES alert_rules (id) ON DELETE CASCADE,
+    icao TEXT NOT NULL,
+    triggered BOOLEAN NOT NULL,
+    observation_time TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (rule_id, icao)
+);
+
+CREATE TABLE IF NOT EXISTS alert_events (
+    id UUID PRIMARY KEY NOT NULL,
+    rule_id UUID NOT NULL REFERENCES alert_rules (id) ON DELETE CASCADE,
+    icao TEXT NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('triggered', 'cleared')),
+    detail TEXT,
+    raw_text TEXT NOT NULL,
+    observation_time TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON alert_events (rule_id, created_at DESC);
+CREATE INDEX ON alert_events (icao);
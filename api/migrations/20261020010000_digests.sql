@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS digest_subscriptions (
+    email TEXT PRIMARY KEY NOT NULL REFERENCES users (email) ON UPDATE CASCADE ON DELETE CASCADE,
+    enabled BOOLEAN NOT NULL DEFAULT true,
+    -- Local time of day the digest is sent, such as the start of a shift
+    send_at TIME NOT NULL DEFAULT '06:00',
+    -- IANA time zone name, validated against pg_timezone_names
+    timezone TEXT NOT NULL DEFAULT 'UTC',
+    next_send_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_sent_at TIMESTAMPTZ,
+    -- Conditions per station when the last digest was sent, to report what changed since
+    last_snapshot JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON digest_subscriptions (next_send_at) WHERE enabled;
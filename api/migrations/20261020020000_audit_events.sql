@@ -0,0 +1,23 @@
+CREATE TABLE IF NOT EXISTS audit_events (
+    id UUID PRIMARY KEY NOT NULL,
+    -- Not a foreign key so that events outlive the accounts involved, NULL for anonymous requests
+    actor TEXT,
+    ip_address TEXT,
+    action TEXT NOT NULL,
+    outcome TEXT NOT NULL CHECK (outcome IN ('success', 'failure')),
+    target TEXT,
+    -- Only the fields that changed, sensitive values are redacted before they are stored
+    before JSONB,
+    after JSONB,
+    detail TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON audit_events (created_at DESC);
+CREATE INDEX ON audit_events (actor, created_at DESC);
+CREATE INDEX ON audit_events (action, created_at DESC);
+CREATE INDEX ON audit_events (target, created_at DESC);
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('ADMIN', 'audit:read')
+ON CONFLICT DO NOTHING;
@@ -1,20 +1,33 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 use futures_util::try_join;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use sqlx::{Postgres, QueryBuilder};
+use uuid::Uuid;
 use crate::airports::{
   AirportCategory, Frequency, FrequencyRow, Runway, RunwayRow, UpdateFrequency, UpdateRunway,
 };
-use crate::db;
+use crate::auth::Auth;
+use crate::db::{self, compass_direction, Coordinate};
 use crate::error::{ApiResult, Error};
 use crate::metars::Metar;
+use crate::organizations::Organization;
 
 const TABLE_NAME: &str = "airports";
+/// Namespace of the imported airports, user-defined ones are keyed by owner or organization
+pub const GLOBAL_NAMESPACE: &str = "";
+const MAX_IDENT_LENGTH: usize = 8;
+const MAX_NOTES_LENGTH: usize = 2000;
+const DEFAULT_NEAREST_METAR_RADIUS_NM: f64 = 25.0;
+const MAX_NEAREST_METAR_RADIUS_NM: f64 = 100.0;
+// Number of closest candidate stations queried for a METAR
+const NEAREST_METAR_CANDIDATES: i64 = 10;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Airport {
+  #[serde(skip)]
+  pub namespace: String,
   pub icao: String,
   #[serde(skip_serializing_if = "Option::is_none")]
   pub iata: Option<String>,
@@ -35,8 +48,35 @@ pub struct Airport {
   pub runways: Vec<Runway>,
   pub frequencies: Vec<Frequency>,
   pub public: bool,
+  /// Owning organization of a private airport, global airports have none
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub organization_id: Option<Uuid>,
+  /// Creator of a personal airport, visible to them alone
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub owner: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub notes: Option<String>,
+  /// Added by a user or organization rather than imported, bulk imports never replace these
+  #[serde(default)]
+  pub user_defined: bool,
   #[serde(skip_serializing_if = "Option::is_none")]
   pub latest_metar: Option<Metar>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub nearest_metar: Option<NearestMetar>,
+}
+
+/// METAR borrowed from the closest reporting station when an airport has none of its own
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NearestMetar {
+  pub station_id: String,
+  pub station_name: String,
+  pub distance_nm: f64,
+  /// True bearing from the airport to the reporting station
+  pub bearing_deg: f64,
+  pub direction: String,
+  /// Reporting station elevation minus the airport elevation
+  pub elevation_diff_ft: f32,
+  pub metar: Metar,
 }
 
 #[derive(Debug, Deserialize)]
@@ -53,6 +93,14 @@ pub struct AirportQuery {
   pub municipalities: Option<String>,
   pub bounds: Option<String>,
   pub metars: Option<bool>,
+  /// Fall back to the nearest reporting station's METAR (single airport lookup only)
+  pub nearest: Option<bool>,
+  /// Search radius in nautical miles for the nearest reporting station
+  pub radius: Option<f64>,
+  /// Only airports in this watchlist
+  pub list: Option<Uuid>,
+  /// Only user-defined airports, or only imported ones when false
+  pub user_defined: Option<bool>,
 }
 
 impl Default for AirportQuery {
@@ -70,10 +118,37 @@ impl Default for AirportQuery {
       municipalities: None,
       bounds: None,
       metars: None,
+      nearest: None,
+      radius: None,
+      list: None,
+      user_defined: None,
     }
   }
 }
 
+/// The requesting user and their organizations, deciding which user-defined airports are visible
+#[derive(Debug, Default)]
+pub struct AirportVisibility {
+  pub email: Option<String>,
+  pub organizations: Vec<Uuid>,
+}
+
+impl AirportVisibility {
+  pub async fn for_auth(auth: Option<&Auth>) -> ApiResult<Self> {
+    Ok(Self {
+      email: auth.map(|auth| auth.user.email.clone()),
+      organizations: Organization::visible_ids(auth).await?,
+    })
+  }
+
+  pub async fn for_email(email: &str) -> ApiResult<Self> {
+    Ok(Self {
+      email: Some(email.to_string()),
+      organizations: Organization::member_ids(email).await?,
+    })
+  }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Bounds {
   pub north_east_lat: f32,
@@ -107,6 +182,7 @@ impl Bounds {
 
 #[derive(Debug, Deserialize, sqlx::FromRow)]
 struct AirportRow {
+  pub namespace: String,
   pub icao: String,
   pub iata: Option<String>,
   pub local: Option<String>,
@@ -121,9 +197,13 @@ struct AirportRow {
   pub has_tower: Option<bool>,
   pub has_beacon: Option<bool>,
   pub public: bool,
+  pub organization_id: Option<Uuid>,
+  pub owner: Option<String>,
+  pub notes: Option<String>,
+  pub user_defined: bool,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct UpdateAirport {
   pub icao: Option<String>,
   pub iata: Option<String>,
@@ -141,11 +221,30 @@ pub struct UpdateAirport {
   pub runways: Option<Vec<UpdateRunway>>,
   pub frequencies: Option<Vec<UpdateFrequency>>,
   pub public: Option<bool>,
+  pub notes: Option<String>,
+}
+
+/// Which namespace an airport lives in and who may change it
+#[derive(Debug, sqlx::FromRow)]
+pub struct AirportOwnership {
+  pub namespace: String,
+  pub owner: Option<String>,
+  pub organization_id: Option<Uuid>,
+}
+
+/// Order of the airports sharing an identifier: the requester's own first, then their
+/// organizations' and the imported one last, so an import never shadows a custom airport
+fn precedence(
+  owner: &Option<String>,
+  organization_id: &Option<Uuid>,
+) -> (bool, bool, Option<Uuid>) {
+  (owner.is_none(), organization_id.is_none(), *organization_id)
 }
 
 impl Into<AirportRow> for Airport {
   fn into(self) -> AirportRow {
     AirportRow {
+      namespace: self.namespace.clone(),
       icao: self.icao.clone(),
       iata: self.iata.clone(),
       local: self.local.clone(),
@@ -160,6 +259,10 @@ impl Into<AirportRow> for Airport {
       has_tower: self.has_tower,
       has_beacon: self.has_beacon,
       public: self.public,
+      organization_id: self.organization_id,
+      owner: self.owner.clone(),
+      notes: self.notes.clone(),
+      user_defined: self.user_defined,
     }
   }
 }
@@ -167,6 +270,7 @@ impl Into<AirportRow> for Airport {
 impl From<AirportRow> for Airport {
   fn from(airport: AirportRow) -> Self {
     Self {
+      namespace: airport.namespace.clone(),
       icao: airport.icao.clone(),
       iata: airport.iata.clone(),
       local: airport.local.clone(),
@@ -189,26 +293,154 @@ impl From<AirportRow> for Airport {
       runways: vec![],
       frequencies: vec![],
       public: airport.public,
+      organization_id: airport.organization_id,
+      owner: airport.owner.clone(),
+      notes: airport.notes.clone(),
+      user_defined: airport.user_defined,
       latest_metar: None,
+      nearest_metar: None,
     }
   }
 }
 
+impl UpdateAirport {
+  /// Copy the given fields onto `airport`, the identifier itself cannot change
+  fn apply(&self, airport: &mut Airport) -> ApiResult<()> {
+    if let Some(icao) = &self.icao {
+      if !icao.trim().eq_ignore_ascii_case(&airport.icao) {
+        return Err(Error::new(
+          400,
+          "Airport identifiers cannot be changed".to_string(),
+        ));
+      }
+    }
+    if let Some(iata) = &self.iata {
+      airport.iata = Some(iata.clone());
+    }
+    if let Some(local) = &self.local {
+      airport.local = Some(local.clone());
+    }
+    if let Some(name) = &self.name {
+      airport.name = name.clone();
+    }
+    if let Some(category) = &self.category {
+      airport.category = category.clone();
+    }
+    if let Some(iso_country) = &self.iso_country {
+      airport.iso_country = iso_country.clone();
+    }
+    if let Some(iso_region) = &self.iso_region {
+      airport.iso_region = iso_region.clone();
+    }
+    if let Some(municipality) = &self.municipality {
+      airport.municipality = municipality.clone();
+    }
+    if let Some(elevation_ft) = self.elevation_ft {
+      airport.elevation_ft = elevation_ft;
+    }
+    if let Some(longitude) = self.longitude {
+      airport.longitude = longitude;
+    }
+    if let Some(latitude) = self.latitude {
+      airport.latitude = latitude;
+    }
+    if self.has_tower.is_some() {
+      airport.has_tower = self.has_tower;
+    }
+    if self.has_beacon.is_some() {
+      airport.has_beacon = self.has_beacon;
+    }
+    if let Some(public) = self.public {
+      airport.public = public;
+    }
+    if self.notes.is_some() {
+      airport.notes = self.notes.clone();
+    }
+    if let Some(runways) = &self.runways {
+      airport.runways = runways
+        .iter()
+        .map(|runway| match runway {
+          UpdateRunway {
+            frequency_id: Some(runway_id),
+            length_ft: Some(length_ft),
+            width_ft: Some(width_ft),
+            surface: Some(surface),
+            ..
+          } => Ok(Runway {
+            runway_id: runway_id.clone(),
+            length_ft: *length_ft,
+            width_ft: *width_ft,
+            surface: surface.clone(),
+          }),
+          _ => Err(Error::new(
+            400,
+            "Runways need an id, length, width and surface".to_string(),
+          )),
+        })
+        .collect::<ApiResult<Vec<Runway>>>()?;
+    }
+    if let Some(frequencies) = &self.frequencies {
+      airport.frequencies = frequencies
+        .iter()
+        .map(|frequency| match frequency {
+          UpdateFrequency {
+            frequency_id: Some(frequency_id),
+            frequency_mhz: Some(frequency_mhz),
+            ..
+          } => Ok(Frequency {
+            frequency_id: frequency_id.clone(),
+            frequency_mhz: *frequency_mhz,
+          }),
+          _ => Err(Error::new(
+            400,
+            "Frequencies need an id and a frequency".to_string(),
+          )),
+        })
+        .collect::<ApiResult<Vec<Frequency>>>()?;
+    }
+    Ok(())
+  }
+}
+
 impl Airport {
-  pub async fn select(client: &Client, icao: &str, metar: bool) -> Option<Self> {
+  /// Select a global airport or a user-defined one visible to the requester, preferring the
+  /// requester's own airport when several share the identifier
+  pub async fn select(
+    client: &Client,
+    icao: &str,
+    metar: bool,
+    visibility: &AirportVisibility,
+  ) -> Option<Self> {
     let pool = db::pool();
 
-    let airport_fut = async {
-      sqlx::query_as(&format!("SELECT * FROM {} WHERE icao = $1", TABLE_NAME))
-        .bind(icao)
-        .fetch_optional(pool)
-        .await
+    let airport_result: Result<Vec<AirportRow>, sqlx::Error> = sqlx::query_as(&format!(
+      r#"
+      SELECT * FROM {} WHERE icao = $1
+        AND ((organization_id IS NULL AND (owner IS NULL OR owner = $2))
+          OR organization_id = ANY($3))
+      "#,
+      TABLE_NAME
+    ))
+    .bind(icao)
+    .bind(&visibility.email)
+    .bind(&visibility.organizations)
+    .fetch_all(pool)
+    .await;
+
+    let airport_row: AirportRow = match airport_result {
+      Ok(rows) => rows
+        .into_iter()
+        .min_by_key(|row| precedence(&row.owner, &row.organization_id))?,
+      Err(err) => {
+        log::error!("Unable to find airport '{}': {}", icao, err);
+        return None;
+      }
     };
 
     let metar_fut = async {
       if metar {
-        match Metar::find_all(client, &vec![icao.to_string()], &false).await {
-          Ok(m) => Some(m.into_iter().nth(0)),
+        match Metar::find_all(client, &[icao.to_string()], &false).await {
+          Ok(m) => m.into_iter().nth(0),
           Err(err) => {
             log::error!("{}", err);
             None
@@ -219,19 +451,11 @@ impl Airport {
       }
     };
 
-    let runways_fut = Runway::select_all(icao);
-    let frequencies_fut = Frequency::select_all(icao);
+    let runways_fut = Runway::select_all(&airport_row.namespace, icao);
+    let frequencies_fut = Frequency::select_all(&airport_row.namespace, icao);
 
-    let (airport_result, runways_result, frequencies_result, metar_result) =
-      tokio::join!(airport_fut, runways_fut, frequencies_fut, metar_fut);
-
-    let airport_row: Option<AirportRow> = match airport_result {
-      Ok(opt) => opt,
-      Err(err) => {
-        log::error!("Unable to find airport '{}': {}", icao, err);
-        return None;
-      }
-    };
+    let (runways_result, frequencies_result, metar) =
+      tokio::join!(runways_fut, frequencies_fut, metar_fut);
 
     let runways: Vec<Runway> = match runways_result {
       Ok(r) => r,
@@ -253,30 +477,148 @@ impl Airport {
       }
     };
 
-    let metar: Option<Metar> = match metar_result {
-      Some(m_option) => match m_option {
-        Some(m) => Some(m),
-        None => None,
-      },
-      None => None,
+    let mut airport: Airport = airport_row.into();
+    airport.runways = runways;
+    airport.frequencies = frequencies;
+    airport.latest_metar = metar;
+    Some(airport)
+  }
+
+  /// Whether the airport exists and is global or visible to the requester
+  pub async fn is_visible(icao: &str, visibility: &AirportVisibility) -> ApiResult<bool> {
+    let pool = db::pool();
+    let visible: bool = sqlx::query_scalar(&format!(
+      r#"
+      SELECT EXISTS (
+        SELECT 1 FROM {} WHERE icao = $1
+          AND ((organization_id IS NULL AND (owner IS NULL OR owner = $2))
+            OR organization_id = ANY($3))
+      )
+      "#,
+      TABLE_NAME
+    ))
+    .bind(icao)
+    .bind(&visibility.email)
+    .bind(&visibility.organizations)
+    .fetch_one(pool)
+    .await?;
+    Ok(visible)
+  }
+
+  /// Default search radius for `select_nearest_metar`, configurable with `NEAREST_METAR_RADIUS_NM`
+  pub fn nearest_metar_radius(radius: Option<f64>) -> f64 {
+    let radius = radius.unwrap_or_else(|| {
+      std::env::var("NEAREST_METAR_RADIUS_NM")
+        .ok()
+        .and_then(|r| r.parse::<f64>().ok())
+        .unwrap_or(DEFAULT_NEAREST_METAR_RADIUS_NM)
+    });
+    radius.clamp(0.0, MAX_NEAREST_METAR_RADIUS_NM)
+  }
+
+  pub async fn select_nearest_metar(
+    &self,
+    client: &Client,
+    radius_nm: f64,
+  ) -> ApiResult<Option<NearestMetar>> {
+    let pool = db::pool();
+    let origin = Coordinate {
+      lon: self.longitude as f64,
+      lat: self.latitude as f64,
     };
 
-    airport_row.map(|row| {
-      let mut airport: Airport = row.into();
-      airport.runways = runways;
-      airport.frequencies = frequencies;
-      airport.latest_metar = metar;
-      airport
-    })
+    // Bounding box pre-filter, ordered by an equirectangular approximation of the distance
+    let lat_delta = radius_nm / 60.0;
+    let lon_scale = origin.lat.to_radians().cos().max(0.01);
+    let lon_delta = lat_delta / lon_scale;
+    let candidate_rows: Vec<AirportRow> = sqlx::query_as(&format!(
+      r#"
+      SELECT * FROM {}
+      WHERE icao <> $1
+        AND category <> 'closed'
+        AND NOT user_defined
+        AND latitude BETWEEN $2 AND $3
+        AND longitude BETWEEN $4 AND $5
+      ORDER BY POWER(latitude - $6, 2) + POWER((longitude - $7) * $8, 2)
+      LIMIT $9
+      "#,
+      TABLE_NAME
+    ))
+    .bind(&self.icao)
+    .bind(origin.lat - lat_delta)
+    .bind(origin.lat + lat_delta)
+    .bind(origin.lon - lon_delta)
+    .bind(origin.lon + lon_delta)
+    .bind(origin.lat)
+    .bind(origin.lon)
+    .bind(lon_scale)
+    .bind(NEAREST_METAR_CANDIDATES)
+    .fetch_all(pool)
+    .await?;
+
+    let mut candidates: Vec<(f64, AirportRow)> = candidate_rows
+      .into_iter()
+      .map(|row| {
+        let station = Coordinate {
+          lon: row.longitude as f64,
+          lat: row.latitude as f64,
+        };
+        (origin.distance_nm(&station), row)
+      })
+      .filter(|(distance, _)| *distance <= radius_nm)
+      .collect();
+    if candidates.is_empty() {
+      return Ok(None);
+    }
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let icaos: Vec<String> = candidates.iter().map(|(_, row)| row.icao.clone()).collect();
+    let mut metar_map: HashMap<String, Metar> = Metar::find_all(client, &icaos, &false)
+      .await?
+      .into_iter()
+      .map(|m| (m.station_id.clone(), m))
+      .collect();
+
+    for (distance_nm, row) in candidates {
+      if let Some(metar) = metar_map.remove(&row.icao) {
+        let station = Coordinate {
+          lon: row.longitude as f64,
+          lat: row.latitude as f64,
+        };
+        let bearing_deg = origin.bearing_deg(&station);
+        return Ok(Some(NearestMetar {
+          station_id: row.icao,
+          station_name: row.name,
+          distance_nm,
+          bearing_deg,
+          direction: compass_direction(bearing_deg).to_string(),
+          elevation_diff_ft: row.elevation_ft - self.elevation_ft,
+          metar,
+        }));
+      }
+    }
+    Ok(None)
   }
 
-  pub async fn select_all(client: &Client, query: &AirportQuery) -> ApiResult<Vec<Self>> {
+  /// Global airports plus the user-defined airports visible to the requester
+  pub async fn select_all(
+    client: &Client,
+    query: &AirportQuery,
+    visibility: &AirportVisibility,
+  ) -> ApiResult<Vec<Self>> {
     let pool = db::pool();
 
     let mut builder = QueryBuilder::<Postgres>::new("SELECT * FROM ");
     builder.push(TABLE_NAME);
 
     let mut has_where = false;
+    Self::push_condition_visible(&mut builder, &mut has_where, visibility);
+    Self::push_condition_bool(
+      &mut builder,
+      &mut has_where,
+      "user_defined",
+      query.user_defined,
+    );
     Self::push_condition_array(&mut builder, &mut has_where, "icao", &query.icaos);
     Self::push_condition_array(&mut builder, &mut has_where, "iata", &query.iatas);
     Self::push_condition_array(
@@ -333,10 +675,13 @@ impl Airport {
     }
 
     // Bulk update airport sub-fields
-    let icaos: Vec<String> = airports.iter().map(|a| a.icao.clone()).collect();
+    let (namespaces, icaos): (Vec<String>, Vec<String>) = airports
+      .iter()
+      .map(|a| (a.namespace.clone(), a.icao.clone()))
+      .unzip();
 
-    let runway_future = Runway::select_all_map(icaos.clone());
-    let frequency_future = Frequency::select_all_map(icaos.clone());
+    let runway_future = Runway::select_all_map(namespaces.clone(), icaos.clone());
+    let frequency_future = Frequency::select_all_map(namespaces, icaos.clone());
     let metar_future = if query.metars.unwrap_or(false) {
       Some(Metar::find_all(client, &icaos, &false))
     } else {
@@ -365,11 +710,9 @@ impl Airport {
     };
 
     for airport in airports.iter_mut() {
-      airport.runways = runway_map.get(&airport.icao).cloned().unwrap_or_default();
-      airport.frequencies = frequency_map
-        .get(&airport.icao)
-        .cloned()
-        .unwrap_or_default();
+      let key = (airport.namespace.clone(), airport.icao.clone());
+      airport.runways = runway_map.get(&key).cloned().unwrap_or_default();
+      airport.frequencies = frequency_map.get(&key).cloned().unwrap_or_default();
       if let Some(ref mut metar_map) = metars_opt {
         airport.latest_metar = metar_map.remove(&airport.icao);
       }
@@ -378,13 +721,41 @@ impl Airport {
     Ok(airports)
   }
 
-  pub async fn count(query: &AirportQuery) -> i64 {
+  /// ICAOs of the visible airports inside `bounds`, at most `limit` of them
+  pub async fn select_icaos_in_bounds(
+    bounds: &str,
+    visibility: &AirportVisibility,
+    limit: i64,
+  ) -> ApiResult<Vec<String>> {
+    let pool = db::pool();
+    let bounds = Some(bounds.to_string());
+
+    let mut builder = QueryBuilder::<Postgres>::new("SELECT DISTINCT icao FROM ");
+    builder.push(TABLE_NAME);
+
+    let mut has_where = false;
+    Self::push_condition_visible(&mut builder, &mut has_where, visibility);
+    Self::push_condition_bounds(&mut builder, &mut has_where, &bounds)?;
+    builder.push(" LIMIT ").push_bind(limit);
+
+    let icaos: Vec<String> = builder.build_query_scalar().fetch_all(pool).await?;
+    Ok(icaos)
+  }
+
+  pub async fn count(query: &AirportQuery, visibility: &AirportVisibility) -> i64 {
     let pool = db::pool();
 
     let mut builder = QueryBuilder::<Postgres>::new("SELECT COUNT(*) FROM ");
     builder.push(TABLE_NAME);
 
     let mut has_where = false;
+    Self::push_condition_visible(&mut builder, &mut has_where, visibility);
+    Self::push_condition_bool(
+      &mut builder,
+      &mut has_where,
+      "user_defined",
+      query.user_defined,
+    );
     Self::push_condition_array(&mut builder, &mut has_where, "icao", &query.icaos);
     Self::push_condition_array(&mut builder, &mut has_where, "iata", &query.iatas);
     Self::push_condition_array(
@@ -420,26 +791,17 @@ impl Airport {
   pub async fn insert(&self) -> ApiResult<Self> {
     let pool = db::pool();
 
-    let mut all_runway_rows: Vec<RunwayRow> = Vec::new();
-    let mut all_frequency_rows: Vec<FrequencyRow> = Vec::new();
-    for runway in &self.runways {
-      all_runway_rows.push(Runway::into(runway, &self.icao));
-    }
-    for frequency in &self.frequencies {
-      all_frequency_rows.push(Frequency::into(frequency, &self.icao));
-    }
-    Runway::insert_all(&all_runway_rows).await?;
-    Frequency::insert_all(&all_frequency_rows).await?;
-
     let airport: AirportRow = sqlx::query_as(&format!(
       r#"
       INSERT INTO {} (
         icao, iata, local, name, category, iso_country, iso_region, municipality,
-        elevation_ft, longitude, latitude, has_tower, has_beacon, public
+        elevation_ft, longitude, latitude, has_tower, has_beacon, public, organization_id,
+        owner, notes
       )
       VALUES (
         $1, $2, $3, $4, $5, $6, $7,
-        $8, $9, $10, $11, $12, $13, $14
+        $8, $9, $10, $11, $12, $13, $14, $15,
+        $16, $17
       )
       RETURNING *
       "#,
@@ -459,31 +821,84 @@ impl Airport {
     .bind(self.has_tower)
     .bind(self.has_beacon)
     .bind(self.public)
+    .bind(self.organization_id)
+    .bind(&self.owner)
+    .bind(&self.notes)
     .fetch_one(pool)
     .await?;
 
-    Ok(airport.into())
+    // Only once the airport exists, a conflicting ICAO must not leave stray runways behind
+    let mut all_runway_rows: Vec<RunwayRow> = Vec::new();
+    let mut all_frequency_rows: Vec<FrequencyRow> = Vec::new();
+    for runway in &self.runways {
+      all_runway_rows.push(Runway::into(runway, &airport.namespace, &self.icao));
+    }
+    for frequency in &self.frequencies {
+      all_frequency_rows.push(Frequency::into(frequency, &airport.namespace, &self.icao));
+    }
+    Runway::insert_all(&all_runway_rows).await?;
+    Frequency::insert_all(&all_frequency_rows).await?;
+
+    let mut airport: Airport = airport.into();
+    airport.runways = self.runways.clone();
+    airport.frequencies = self.frequencies.clone();
+    Ok(airport)
+  }
+
+  /// Normalize and check an airport submitted by a user
+  pub fn validate_user_defined(&mut self) -> ApiResult<()> {
+    self.icao = self.icao.trim().to_uppercase();
+    if self.icao.is_empty()
+      || self.icao.len() > MAX_IDENT_LENGTH
+      || !self.icao.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+      return Err(Error::new(
+        400,
+        format!(
+          "Identifiers must be 1 to {} letters or digits",
+          MAX_IDENT_LENGTH
+        ),
+      ));
+    }
+    if !(-90.0..=90.0).contains(&self.latitude) || !(-180.0..=180.0).contains(&self.longitude) {
+      return Err(Error::new(400, "Invalid coordinates".to_string()));
+    }
+    if let Some(notes) = &self.notes {
+      if notes.chars().count() > MAX_NOTES_LENGTH {
+        return Err(Error::new(
+          400,
+          format!("Notes are limited to {} characters", MAX_NOTES_LENGTH),
+        ));
+      }
+    }
+    Ok(())
   }
 
-  pub async fn insert_all(airports: Vec<Self>) -> ApiResult<()> {
+  /// Bulk import into the global dataset, replacing airports imported before along with their
+  /// runways and frequencies. User-defined airports live in their own namespaces and are never
+  /// touched. Returns the number of airports imported.
+  pub async fn insert_all(airports: Vec<Self>) -> ApiResult<usize> {
     let pool = db::pool();
     let chunk_size = 1000;
+
+    // The last occurrence of a repeated ICAO wins, an upsert cannot touch a row twice
+    let mut seen: HashSet<String> = HashSet::new();
     let mut all_runway_rows: Vec<RunwayRow> = Vec::new();
     let mut all_frequency_rows: Vec<FrequencyRow> = Vec::new();
     let airport_rows: Vec<AirportRow> = airports
       .into_iter()
+      .rev()
+      .filter(|airport| seen.insert(airport.icao.clone()))
       .map(|airport| {
         for runway in &airport.runways {
-          all_runway_rows.push(Runway::into(runway, &airport.icao));
+          all_runway_rows.push(Runway::into(runway, GLOBAL_NAMESPACE, &airport.icao));
         }
         for frequency in &airport.frequencies {
-          all_frequency_rows.push(Frequency::into(frequency, &airport.icao));
+          all_frequency_rows.push(Frequency::into(frequency, GLOBAL_NAMESPACE, &airport.icao));
         }
         airport.into()
       })
       .collect();
-    Runway::insert_all(&all_runway_rows).await?;
-    Frequency::insert_all(&all_frequency_rows).await?;
 
     for chunk in airport_rows.chunks(chunk_size) {
       let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(
@@ -507,48 +922,175 @@ impl Airport {
           .push_bind(row.has_beacon)
           .push_bind(row.public);
       });
+      query_builder.push(
+        " ON CONFLICT (namespace, icao) DO UPDATE SET \
+        iata = EXCLUDED.iata, local = EXCLUDED.local, name = EXCLUDED.name, \
+        category = EXCLUDED.category, iso_country = EXCLUDED.iso_country, \
+        iso_region = EXCLUDED.iso_region, municipality = EXCLUDED.municipality, \
+        elevation_ft = EXCLUDED.elevation_ft, longitude = EXCLUDED.longitude, \
+        latitude = EXCLUDED.latitude, has_tower = EXCLUDED.has_tower, \
+        has_beacon = EXCLUDED.has_beacon, public = EXCLUDED.public",
+      );
 
       let query = query_builder.build();
       query.execute(pool).await?;
     }
 
-    Ok(())
+    // Runways and frequencies need their airport, and re-imported ones replace the old
+    let icaos: Vec<String> = airport_rows.iter().map(|row| row.icao.clone()).collect();
+    Runway::delete_all(GLOBAL_NAMESPACE, &icaos).await?;
+    Frequency::delete_all(GLOBAL_NAMESPACE, &icaos).await?;
+    Runway::insert_all(&all_runway_rows).await?;
+    Frequency::insert_all(&all_frequency_rows).await?;
+
+    Ok(airport_rows.len())
   }
 
-  // TODO
-  pub async fn update(_icao: &str, _airport: &UpdateAirport) -> ApiResult<()> {
-    Ok(())
+  /// Apply the changed fields of `update`, runways and frequencies are replaced when given
+  pub async fn update(namespace: &str, icao: &str, update: &UpdateAirport) -> ApiResult<Self> {
+    let pool = db::pool();
+
+    let row: Option<AirportRow> = sqlx::query_as(&format!(
+      r#"
+      SELECT * FROM {} WHERE namespace = $1 AND icao = $2
+      "#,
+      TABLE_NAME
+    ))
+    .bind(namespace)
+    .bind(icao)
+    .fetch_optional(pool)
+    .await?;
+    let mut airport: Airport = row
+      .ok_or_else(|| Error::new(404, format!("Airport {} not found", icao)))?
+      .into();
+    update.apply(&mut airport)?;
+    if airport.user_defined {
+      airport.validate_user_defined()?;
+    }
+
+    let row: AirportRow = sqlx::query_as(&format!(
+      r#"
+      UPDATE {} SET
+        iata = $3, local = $4, name = $5, category = $6, iso_country = $7, iso_region = $8,
+        municipality = $9, elevation_ft = $10, longitude = $11, latitude = $12,
+        has_tower = $13, has_beacon = $14, public = $15, notes = $16
+      WHERE namespace = $1 AND icao = $2
+      RETURNING *
+      "#,
+      TABLE_NAME
+    ))
+    .bind(namespace)
+    .bind(icao)
+    .bind(&airport.iata)
+    .bind(&airport.local)
+    .bind(&airport.name)
+    .bind(airport.category.to_string())
+    .bind(&airport.iso_country)
+    .bind(&airport.iso_region)
+    .bind(&airport.municipality)
+    .bind(airport.elevation_ft)
+    .bind(airport.longitude)
+    .bind(airport.latitude)
+    .bind(airport.has_tower)
+    .bind(airport.has_beacon)
+    .bind(airport.public)
+    .bind(&airport.notes)
+    .fetch_one(pool)
+    .await?;
+
+    let icaos = [icao.to_string()];
+    if update.runways.is_some() {
+      let runway_rows: Vec<RunwayRow> = airport
+        .runways
+        .iter()
+        .map(|runway| Runway::into(runway, namespace, icao))
+        .collect();
+      Runway::delete_all(namespace, &icaos).await?;
+      Runway::insert_all(&runway_rows).await?;
+    } else {
+      airport.runways = Runway::select_all(namespace, icao).await?;
+    }
+    if update.frequencies.is_some() {
+      let frequency_rows: Vec<FrequencyRow> = airport
+        .frequencies
+        .iter()
+        .map(|frequency| Frequency::into(frequency, namespace, icao))
+        .collect();
+      Frequency::delete_all(namespace, &icaos).await?;
+      Frequency::insert_all(&frequency_rows).await?;
+    } else {
+      airport.frequencies = Frequency::select_all(namespace, icao).await?;
+    }
+
+    let mut updated: Airport = row.into();
+    updated.runways = airport.runways;
+    updated.frequencies = airport.frequencies;
+    Ok(updated)
   }
 
-  pub async fn delete(icao: &str) -> ApiResult<()> {
+  /// Namespace and owners of the airport `select` resolves for the requester, both owners are
+  /// `None` for a global airport
+  pub async fn select_ownership(
+    icao: &str,
+    visibility: &AirportVisibility,
+  ) -> ApiResult<AirportOwnership> {
+    let pool = db::pool();
+    let ownerships: Vec<AirportOwnership> = sqlx::query_as(&format!(
+      r#"
+      SELECT namespace, owner, organization_id FROM {} WHERE icao = $1
+        AND ((organization_id IS NULL AND (owner IS NULL OR owner = $2))
+          OR organization_id = ANY($3))
+      "#,
+      TABLE_NAME
+    ))
+    .bind(icao)
+    .bind(&visibility.email)
+    .bind(&visibility.organizations)
+    .fetch_all(pool)
+    .await?;
+    Self::resolve(ownerships).ok_or_else(|| Error::new(404, format!("Airport {} not found", icao)))
+  }
+
+  /// The airport a requester means by an identifier several of their visible airports share
+  fn resolve(ownerships: Vec<AirportOwnership>) -> Option<AirportOwnership> {
+    ownerships
+      .into_iter()
+      .min_by_key(|ownership| precedence(&ownership.owner, &ownership.organization_id))
+  }
+
+  /// Runways and frequencies are removed with the airport
+  pub async fn delete(namespace: &str, icao: &str) -> ApiResult<()> {
     let pool = db::pool();
 
     sqlx::query(&format!(
       r#"
-      DELETE FROM {} WHERE icao = $1
+      DELETE FROM {} WHERE namespace = $1 AND icao = $2
       "#,
       TABLE_NAME
     ))
-    .bind(icao.to_string())
+    .bind(namespace)
+    .bind(icao)
     .execute(pool)
     .await?;
 
     Ok(())
   }
 
-  pub async fn delete_all() -> ApiResult<()> {
+  /// Delete the imported dataset, user-defined airports are left to their owners.
+  /// Returns the number of airports deleted.
+  pub async fn delete_all() -> ApiResult<u64> {
     let pool = db::pool();
 
-    sqlx::query(&format!(
+    let result = sqlx::query(&format!(
       r#"
-      DELETE FROM {} WHERE true
+      DELETE FROM {} WHERE NOT user_defined
       "#,
       TABLE_NAME
     ))
     .execute(pool)
     .await?;
 
-    Ok(())
+    Ok(result.rows_affected())
   }
 
   fn push_condition_array<'a>(
@@ -579,6 +1121,42 @@ impl Airport {
     }
   }
 
+  fn push_condition_visible(
+    builder: &mut QueryBuilder<'_, Postgres>,
+    has_where: &mut bool,
+    visibility: &AirportVisibility,
+  ) {
+    if !*has_where {
+      builder.push(" WHERE ");
+      *has_where = true;
+    } else {
+      builder.push(" AND ");
+    }
+    builder
+      .push("((organization_id IS NULL AND (owner IS NULL OR owner = ")
+      .push_bind(visibility.email.clone())
+      .push(")) OR organization_id = ANY(")
+      .push_bind(visibility.organizations.clone())
+      .push("))");
+  }
+
+  fn push_condition_bool(
+    builder: &mut QueryBuilder<'_, Postgres>,
+    has_where: &mut bool,
+    column: &str,
+    field: Option<bool>,
+  ) {
+    if let Some(value) = field {
+      if !*has_where {
+        builder.push(" WHERE ");
+        *has_where = true;
+      } else {
+        builder.push(" AND ");
+      }
+      builder.push(column).push(" = ").push_bind(value);
+    }
+  }
+
   fn push_condition_like<'a>(
     builder: &mut QueryBuilder<'a, Postgres>,
     has_where: &mut bool,
@@ -631,3 +1209,116 @@ impl Airport {
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn airport(icao: &str) -> Airport {
+    AirportRow {
+      namespace: GLOBAL_NAMESPACE.to_string(),
+      icao: icao.to_string(),
+      iata: None,
+      local: None,
+      name: "Private Strip".to_string(),
+      category: "small_airport".to_string(),
+      iso_country: "US".to_string(),
+      iso_region: "US-VA".to_string(),
+      municipality: "Luray".to_string(),
+      elevation_ft: 902.0,
+      longitude: -78.5,
+      latitude: 38.7,
+      has_tower: None,
+      has_beacon: None,
+      public: false,
+      organization_id: None,
+      owner: None,
+      notes: None,
+      user_defined: false,
+    }
+    .into()
+  }
+
+  #[test]
+  fn test_validate_user_defined() {
+    let mut strip = airport(" xva1 ");
+    strip.validate_user_defined().unwrap();
+    assert_eq!(strip.icao, "XVA1");
+
+    assert_eq!(
+      airport("XVA-1").validate_user_defined().unwrap_err().status,
+      400
+    );
+    assert_eq!(
+      airport("XVALONGID")
+        .validate_user_defined()
+        .unwrap_err()
+        .status,
+      400
+    );
+
+    let mut strip = airport("XVA1");
+    strip.latitude = 91.0;
+    assert!(strip.validate_user_defined().is_err());
+
+    let mut strip = airport("XVA1");
+    strip.notes = Some("a".repeat(MAX_NOTES_LENGTH + 1));
+    assert!(strip.validate_user_defined().is_err());
+  }
+
+  #[test]
+  fn test_update_apply() {
+    let update: UpdateAirport = serde_json::from_value(serde_json::json!({
+      "icao": "xva1",
+      "name": "Luray Strip",
+      "has_tower": true,
+      "runways": [{ "id": "04/22", "length_ft": 2400.0, "width_ft": 50.0, "surface": "turf" }]
+    }))
+    .unwrap();
+    let mut strip = airport("XVA1");
+    update.apply(&mut strip).unwrap();
+    assert_eq!(strip.name, "Luray Strip");
+    assert_eq!(strip.has_tower, Some(true));
+    assert_eq!(strip.municipality, "Luray");
+    assert_eq!(strip.runways[0].runway_id, "04/22");
+
+    let rename: UpdateAirport =
+      serde_json::from_value(serde_json::json!({ "icao": "XVA2" })).unwrap();
+    assert_eq!(rename.apply(&mut strip).unwrap_err().status, 400);
+
+    let partial: UpdateAirport =
+      serde_json::from_value(serde_json::json!({ "runways": [{ "id": "04/22" }] })).unwrap();
+    assert_eq!(partial.apply(&mut strip).unwrap_err().status, 400);
+  }
+
+  #[test]
+  fn test_resolve_prefers_own_airport() {
+    let organization = Uuid::new_v4();
+    let imported = || AirportOwnership {
+      namespace: GLOBAL_NAMESPACE.to_string(),
+      owner: None,
+      organization_id: None,
+    };
+    let personal = || AirportOwnership {
+      namespace: "user:pilot@example.com".to_string(),
+      owner: Some("pilot@example.com".to_string()),
+      organization_id: None,
+    };
+    let shared = || AirportOwnership {
+      namespace: format!("organization:{}", organization),
+      owner: None,
+      organization_id: Some(organization),
+    };
+
+    // An import colliding with a custom airport leaves the custom one in reach of its owner
+    let resolved = Airport::resolve(vec![imported(), personal()]).unwrap();
+    assert_eq!(resolved.owner.as_deref(), Some("pilot@example.com"));
+    let resolved = Airport::resolve(vec![imported(), shared(), personal()]).unwrap();
+    assert_eq!(resolved.namespace, "user:pilot@example.com");
+    let resolved = Airport::resolve(vec![imported(), shared()]).unwrap();
+    assert_eq!(resolved.organization_id, Some(organization));
+    let resolved = Airport::resolve(vec![imported()]).unwrap();
+    assert_eq!(resolved.namespace, GLOBAL_NAMESPACE);
+    assert!(Airport::resolve(vec![]).is_none());
+  }
+}
@@ -17,6 +17,7 @@ pub struct Frequency {
 #[derive(Debug, Deserialize, sqlx::FromRow)]
 pub struct FrequencyRow {
   pub id: Uuid,
+  pub namespace: String,
   pub icao: String,
   pub frequency_id: String,
   pub frequency_mhz: f32,
@@ -42,48 +43,57 @@ impl From<FrequencyRow> for Frequency {
 }
 
 impl Frequency {
-  pub fn into(frequency: &Frequency, icao: &str) -> FrequencyRow {
+  pub fn into(frequency: &Frequency, namespace: &str, icao: &str) -> FrequencyRow {
     FrequencyRow {
       id: Uuid::new_v4(),
+      namespace: namespace.to_string(),
       icao: icao.to_string(),
       frequency_id: frequency.frequency_id.clone(),
       frequency_mhz: frequency.frequency_mhz.clone(),
     }
   }
 
-  pub async fn select_all_map(icaos: Vec<String>) -> ApiResult<HashMap<String, Vec<Self>>> {
+  /// Children of each airport, keyed by the airport's namespace and ICAO identifier
+  pub async fn select_all_map(
+    namespaces: Vec<String>,
+    icaos: Vec<String>,
+  ) -> ApiResult<HashMap<(String, String), Vec<Self>>> {
     let pool = db::pool();
 
     let frequency_rows: Vec<FrequencyRow> = sqlx::query_as(&format!(
-      r#"SELECT * FROM {} WHERE icao = ANY($1)"#,
+      r#"
+      SELECT * FROM {}
+      WHERE (namespace, icao) IN (SELECT * FROM UNNEST($1::TEXT[], $2::TEXT[]))
+      "#,
       TABLE_NAME
     ))
+    .bind(&namespaces)
     .bind(&icaos)
     .fetch_all(pool)
     .await?;
 
-    let mut frequency_map: HashMap<String, Vec<Self>> = HashMap::new();
+    let mut frequency_map: HashMap<(String, String), Vec<Self>> = HashMap::new();
     for frequency_row in frequency_rows {
-      let icao = frequency_row.icao.clone();
-      let frequency = frequency_row.into();
+      let key = (frequency_row.namespace.clone(), frequency_row.icao.clone());
       frequency_map
-        .entry(icao.to_string())
+        .entry(key)
         .or_default()
-        .push(frequency);
+        .push(frequency_row.into());
     }
 
     Ok(frequency_map)
   }
 
-  pub async fn select_all(icao: &str) -> ApiResult<Vec<Self>> {
+  pub async fn select_all(namespace: &str, icao: &str) -> ApiResult<Vec<Self>> {
     let pool = db::pool();
 
     let frequency_row: Vec<FrequencyRow> = sqlx::query_as(&format!(
       r#"
-      SELECT * FROM {} WHERE icao = $1
+      SELECT * FROM {} WHERE namespace = $1 AND icao = $2
       "#,
       TABLE_NAME
     ))
+    .bind(namespace)
     .bind(icao)
     .fetch_all(pool)
     .await?;
@@ -96,11 +106,12 @@ impl Frequency {
 
     for chunk in frequencies.chunks(chunk_size) {
       let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(&format!(
-        "INSERT INTO {} (id, icao, frequency_id, frequency_mhz) ",
+        "INSERT INTO {} (id, namespace, icao, frequency_id, frequency_mhz) ",
         TABLE_NAME
       ));
       query_builder.push_values(chunk, |mut b, row| {
         b.push_bind(&row.id)
+          .push_bind(&row.namespace)
           .push_bind(&row.icao)
           .push_bind(&row.frequency_id)
           .push_bind(&row.frequency_mhz);
@@ -112,4 +123,18 @@ impl Frequency {
 
     Ok(())
   }
+
+  pub async fn delete_all(namespace: &str, icaos: &[String]) -> ApiResult<()> {
+    let pool = db::pool();
+
+    sqlx::query(&format!(
+      r#"DELETE FROM {} WHERE namespace = $1 AND icao = ANY($2)"#,
+      TABLE_NAME
+    ))
+    .bind(namespace)
+    .bind(icaos)
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
 }
@@ -19,6 +19,7 @@ pub struct Runway {
 #[derive(Debug, Deserialize, sqlx::FromRow)]
 pub struct RunwayRow {
   pub id: Uuid,
+  pub namespace: String,
   pub icao: String,
   pub runway_id: String,
   pub length_ft: f32,
@@ -52,9 +53,10 @@ impl From<RunwayRow> for Runway {
 }
 
 impl Runway {
-  pub fn into(runway: &Runway, icao: &str) -> RunwayRow {
+  pub fn into(runway: &Runway, namespace: &str, icao: &str) -> RunwayRow {
     RunwayRow {
       id: Uuid::new_v4(),
+      namespace: namespace.to_string(),
       icao: icao.to_string(),
       runway_id: runway.runway_id.clone(),
       length_ft: runway.length_ft.clone(),
@@ -63,36 +65,44 @@ impl Runway {
     }
   }
 
-  pub async fn select_all_map(icaos: Vec<String>) -> ApiResult<HashMap<String, Vec<Self>>> {
+  /// Children of each airport, keyed by the airport's namespace and ICAO identifier
+  pub async fn select_all_map(
+    namespaces: Vec<String>,
+    icaos: Vec<String>,
+  ) -> ApiResult<HashMap<(String, String), Vec<Self>>> {
     let pool = db::pool();
 
     let runway_rows: Vec<RunwayRow> = sqlx::query_as(&format!(
-      r#"SELECT * FROM {} WHERE icao = ANY($1)"#,
+      r#"
+      SELECT * FROM {}
+      WHERE (namespace, icao) IN (SELECT * FROM UNNEST($1::TEXT[], $2::TEXT[]))
+      "#,
       TABLE_NAME
     ))
+    .bind(&namespaces)
     .bind(&icaos)
     .fetch_all(pool)
     .await?;
 
-    let mut runway_map: HashMap<String, Vec<Self>> = HashMap::new();
+    let mut runway_map: HashMap<(String, String), Vec<Self>> = HashMap::new();
     for runway_row in runway_rows {
-      let icao = runway_row.icao.clone();
-      let runway = runway_row.into();
-      runway_map.entry(icao.to_string()).or_default().push(runway);
+      let key = (runway_row.namespace.clone(), runway_row.icao.clone());
+      runway_map.entry(key).or_default().push(runway_row.into());
     }
 
     Ok(runway_map)
   }
 
-  pub async fn select_all(icao: &str) -> ApiResult<Vec<Self>> {
+  pub async fn select_all(namespace: &str, icao: &str) -> ApiResult<Vec<Self>> {
     let pool = db::pool();
 
     let runway_rows: Vec<RunwayRow> = sqlx::query_as(&format!(
       r#"
-      SELECT * FROM {} WHERE icao = $1
+      SELECT * FROM {} WHERE namespace = $1 AND icao = $2
       "#,
       TABLE_NAME
     ))
+    .bind(namespace)
     .bind(icao)
     .fetch_all(pool)
     .await?;
@@ -105,11 +115,12 @@ impl Runway {
 
     for chunk in runways.chunks(chunk_size) {
       let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(&format!(
-        "INSERT INTO {} (id, icao, runway_id, length_ft, width_ft, surface) ",
+        "INSERT INTO {} (id, namespace, icao, runway_id, length_ft, width_ft, surface) ",
         TABLE_NAME
       ));
       query_builder.push_values(chunk, |mut b, row| {
         b.push_bind(&row.id)
+          .push_bind(&row.namespace)
           .push_bind(&row.icao)
           .push_bind(&row.runway_id)
           .push_bind(&row.length_ft)
@@ -123,4 +134,18 @@ impl Runway {
 
     Ok(())
   }
+
+  pub async fn delete_all(namespace: &str, icaos: &[String]) -> ApiResult<()> {
+    let pool = db::pool();
+
+    sqlx::query(&format!(
+      r#"DELETE FROM {} WHERE namespace = $1 AND icao = ANY($2)"#,
+      TABLE_NAME
+    ))
+    .bind(namespace)
+    .bind(icaos)
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
 }
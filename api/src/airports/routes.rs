@@ -2,21 +2,27 @@ use futures_util::stream::StreamExt as _;
 
 use crate::{
   airports::Airport,
+  audit::{self, AuditAction, AuditEvent},
   db::Paged,
-  auth::{Auth, verify_role},
+  auth::{client_ip, require, verify_permission, Auth, Permission, Permitted},
+  organizations::{Organization, OrganizationRole},
+  watchlists::Watchlist,
   AppState,
 };
 use actix_multipart::Multipart;
 use actix_web::{delete, get, post, put, web, HttpResponse, HttpRequest, ResponseError};
-use crate::airports::{AirportQuery, UpdateAirport};
-use crate::users::ADMIN_ROLE;
+use uuid::Uuid;
+use crate::airports::{AirportQuery, AirportVisibility, UpdateAirport};
+use crate::error::{ApiResult, Error};
 
 #[post("/import")]
-async fn import_airports(mut payload: Multipart, auth: Auth) -> HttpResponse {
-  if let Err(err) = verify_role(&auth, ADMIN_ROLE) {
-    return ResponseError::error_response(&err);
-  };
-
+async fn import_airports(
+  mut payload: Multipart,
+  req: HttpRequest,
+  auth: Permitted<require::AirportsWrite>,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  let mut imported = 0;
   while let Some(item) = payload.next().await {
     let mut bytes = web::BytesMut::new();
     let mut field = match item {
@@ -46,15 +52,44 @@ async fn import_airports(mut payload: Multipart, auth: Auth) -> HttpResponse {
     };
 
     match Airport::insert_all(airports).await {
-      Ok(_) => {}
-      Err(err) => return ResponseError::error_response(&err),
+      Ok(count) => imported += count,
+      Err(err) => {
+        AuditEvent::new(
+          AuditAction::AirportsImported,
+          Some(&auth.user.email),
+          &ip_address,
+        )
+        .detail(format!("Failed after {} airports: {}", imported, err))
+        .failure()
+        .record()
+        .await;
+        return ResponseError::error_response(&err);
+      }
     };
   }
+  log::info!(
+    "Imported {} airports [Email: {}] [IP Address: {}]",
+    imported,
+    auth.user.email,
+    ip_address
+  );
+  AuditEvent::new(
+    AuditAction::AirportsImported,
+    Some(&auth.user.email),
+    &ip_address,
+  )
+  .detail(format!("Imported {} airports", imported))
+  .record()
+  .await;
   HttpResponse::Ok().finish()
 }
 
 #[get("")]
-async fn get_airports(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+async fn get_airports(
+  data: web::Data<AppState>,
+  req: HttpRequest,
+  auth: Option<Auth>,
+) -> HttpResponse {
   let mut query = match web::Query::<AirportQuery>::from_query(req.query_string()) {
     Ok(q) => q.into_inner(),
     Err(err) => {
@@ -63,7 +98,26 @@ async fn get_airports(data: web::Data<AppState>, req: HttpRequest) -> HttpRespon
     }
   };
 
-  let total = Airport::count(&query).await;
+  if let Some(list) = &query.list {
+    match Watchlist::filter_icaos(list, query.icaos.as_deref(), auth.as_ref()).await {
+      Ok(icaos) if icaos.is_empty() => {
+        return HttpResponse::Ok().json(Paged {
+          data: Vec::<Airport>::new(),
+          page: 1,
+          limit: 0,
+          total: 0,
+        })
+      }
+      Ok(icaos) => query.icaos = Some(icaos.join(",")),
+      Err(err) => return ResponseError::error_response(&err),
+    }
+  }
+
+  let visibility = match AirportVisibility::for_auth(auth.as_ref()).await {
+    Ok(visibility) => visibility,
+    Err(err) => return ResponseError::error_response(&err),
+  };
+  let total = Airport::count(&query, &visibility).await;
   let page = query.page.unwrap_or(1);
   let mut limit = query.limit.unwrap_or(total as u32);
   if limit > 1000 {
@@ -73,7 +127,7 @@ async fn get_airports(data: web::Data<AppState>, req: HttpRequest) -> HttpRespon
   query.page = Some(page);
 
   let client = &data.client;
-  match Airport::select_all(client, &query).await {
+  match Airport::select_all(client, &query, &visibility).await {
     Ok(airports) => HttpResponse::Ok().json(Paged {
       data: airports,
       page,
@@ -92,30 +146,135 @@ async fn get_airport(
   data: web::Data<AppState>,
   icao: web::Path<String>,
   req: HttpRequest,
+  auth: Option<Auth>,
 ) -> HttpResponse {
-  let metar = match web::Query::<AirportQuery>::from_query(req.query_string()) {
-    Ok(q) => q.metars.unwrap_or_else(|| false),
+  let query = match web::Query::<AirportQuery>::from_query(req.query_string()) {
+    Ok(q) => q.into_inner(),
     Err(err) => {
       log::error!("{}", err);
-      false
+      AirportQuery::default()
     }
   };
+  let metar = query.metars.unwrap_or(false);
+  let nearest = query.nearest.unwrap_or(false);
+
+  let visibility = match AirportVisibility::for_auth(auth.as_ref()).await {
+    Ok(visibility) => visibility,
+    Err(err) => return ResponseError::error_response(&err),
+  };
 
   let client = &data.client;
-  match Airport::select(client, &icao.into_inner(), metar).await {
-    Some(airport) => HttpResponse::Ok().json(airport),
+  match Airport::select(client, &icao.into_inner(), metar || nearest, &visibility).await {
+    Some(mut airport) => {
+      if nearest && airport.latest_metar.is_none() {
+        let radius = Airport::nearest_metar_radius(query.radius);
+        match airport.select_nearest_metar(client, radius).await {
+          Ok(nearest_metar) => airport.nearest_metar = nearest_metar,
+          Err(err) => log::error!(
+            "Unable to find nearest METAR for airport '{}': {}",
+            airport.icao,
+            err
+          ),
+        }
+      }
+      HttpResponse::Ok().json(airport)
+    }
     None => HttpResponse::NotFound().finish(),
   }
 }
 
+/// Personal airports are managed by their owner, organization airports by the organization's
+/// admins and global ones need `airports:write`
+async fn verify_airport_access(
+  auth: &Auth,
+  owner: Option<&str>,
+  organization: Option<&Uuid>,
+) -> ApiResult<()> {
+  match (owner, organization) {
+    (_, Some(id)) => Organization::authorize(id, &auth.user.email, OrganizationRole::Admin)
+      .await
+      .map(|_| ()),
+    (Some(owner), None) if owner == auth.user.email => Ok(()),
+    (Some(_), None) => Err(Error::new(404, "Airport not found".to_string())),
+    (None, None) => verify_permission(auth, Permission::AirportsWrite),
+  }
+}
+
+/// Add a global airport, user-defined airports are created with `insert_custom_airport`
 #[post("")]
-async fn insert_airport(airport: web::Json<Airport>, auth: Auth) -> HttpResponse {
-  let _ = match verify_role(&auth, ADMIN_ROLE) {
-    Ok(_) => {}
-    Err(err) => return ResponseError::error_response(&err),
+async fn insert_airport(
+  airport: web::Json<Airport>,
+  req: HttpRequest,
+  auth: Permitted<require::AirportsWrite>,
+) -> HttpResponse {
+  let mut airport = airport.into_inner();
+  airport.owner = None;
+  airport.organization_id = None;
+  match airport.insert().await {
+    Ok(a) => {
+      AuditEvent::new(
+        AuditAction::AirportCreated,
+        Some(&auth.user.email),
+        &client_ip(&req),
+      )
+      .target(&a.icao)
+      .changes((None, audit::fields(&a)))
+      .record()
+      .await;
+      HttpResponse::Ok().json(a)
+    }
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+/// Create a user-defined airport, personal unless an organization the user belongs to is given
+#[post("/custom")]
+async fn insert_custom_airport(
+  airport: web::Json<Airport>,
+  req: HttpRequest,
+  auth: Auth,
+) -> HttpResponse {
+  let mut airport = airport.into_inner();
+  if let Err(err) = airport.validate_user_defined() {
+    return ResponseError::error_response(&err);
+  }
+  airport.owner = match &airport.organization_id {
+    Some(id) => {
+      if let Err(err) =
+        Organization::authorize(id, &auth.user.email, OrganizationRole::Member).await
+      {
+        return ResponseError::error_response(&err);
+      }
+      None
+    }
+    None => Some(auth.user.email.clone()),
   };
   match airport.insert().await {
-    Ok(a) => HttpResponse::Ok().json(a),
+    Ok(a) => {
+      log::info!(
+        "Created user-defined airport {} [Email: {}] [IP Address: {}]",
+        a.icao,
+        auth.user.email,
+        client_ip(&req)
+      );
+      AuditEvent::new(
+        AuditAction::AirportCreated,
+        Some(&auth.user.email),
+        &client_ip(&req),
+      )
+      .target(&a.icao)
+      .changes((None, audit::fields(&a)))
+      .record()
+      .await;
+      HttpResponse::Created().json(a)
+    }
+    Err(err) if err.status == 409 => ResponseError::error_response(&Error::new(
+      409,
+      format!("Airport {} already exists", airport.icao),
+    )),
     Err(err) => {
       log::error!("{}", err);
       ResponseError::error_response(&err)
@@ -125,16 +284,45 @@ async fn insert_airport(airport: web::Json<Airport>, auth: Auth) -> HttpResponse
 
 #[put("/{icao}")]
 async fn update_airport(
+  data: web::Data<AppState>,
   icao: web::Path<String>,
   airport: web::Json<UpdateAirport>,
+  req: HttpRequest,
   auth: Auth,
 ) -> HttpResponse {
-  let _ = match verify_role(&auth, ADMIN_ROLE) {
-    Ok(_) => {}
+  let icao = icao.into_inner();
+  let airport = airport.into_inner();
+  let visibility = match AirportVisibility::for_auth(Some(&auth)).await {
+    Ok(visibility) => visibility,
+    Err(err) => return ResponseError::error_response(&err),
+  };
+  let ownership = match Airport::select_ownership(&icao, &visibility).await {
+    Ok(ownership) => ownership,
     Err(err) => return ResponseError::error_response(&err),
   };
-  match Airport::update(&icao.into_inner(), &airport.into_inner()).await {
-    Ok(a) => HttpResponse::Ok().json(a),
+  if let Err(err) = verify_airport_access(
+    &auth,
+    ownership.owner.as_deref(),
+    ownership.organization_id.as_ref(),
+  )
+  .await
+  {
+    return ResponseError::error_response(&err);
+  }
+  let before = Airport::select(&data.client, &icao, false, &visibility).await;
+  match Airport::update(&ownership.namespace, &icao, &airport).await {
+    Ok(a) => {
+      AuditEvent::new(
+        AuditAction::AirportUpdated,
+        Some(&auth.user.email),
+        &client_ip(&req),
+      )
+      .target(&icao)
+      .changes(audit::diff(&before, &a))
+      .record()
+      .await;
+      HttpResponse::Ok().json(a)
+    }
     Err(err) => {
       log::error!("{}", err);
       ResponseError::error_response(&err)
@@ -143,13 +331,29 @@ async fn update_airport(
 }
 
 #[delete("")]
-async fn delete_airports(auth: Auth) -> HttpResponse {
-  let _ = match verify_role(&auth, ADMIN_ROLE) {
-    Ok(_) => {}
-    Err(err) => return ResponseError::error_response(&err),
-  };
+async fn delete_airports(
+  req: HttpRequest,
+  auth: Permitted<require::AirportsWrite>,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
   match Airport::delete_all().await {
-    Ok(_) => HttpResponse::NoContent().finish(),
+    Ok(deleted) => {
+      log::warn!(
+        "Deleted {} imported airports [Email: {}] [IP Address: {}]",
+        deleted,
+        auth.user.email,
+        ip_address
+      );
+      AuditEvent::new(
+        AuditAction::AirportsDeleted,
+        Some(&auth.user.email),
+        &ip_address,
+      )
+      .detail(format!("Deleted {} airports", deleted))
+      .record()
+      .await;
+      HttpResponse::NoContent().finish()
+    }
     Err(err) => {
       log::error!("{}", err);
       ResponseError::error_response(&err)
@@ -158,13 +362,44 @@ async fn delete_airports(auth: Auth) -> HttpResponse {
 }
 
 #[delete("/{icao}")]
-async fn delete_airport(icao: web::Path<String>, auth: Auth) -> HttpResponse {
-  let _ = match verify_role(&auth, ADMIN_ROLE) {
-    Ok(_) => {}
+async fn delete_airport(
+  data: web::Data<AppState>,
+  icao: web::Path<String>,
+  req: HttpRequest,
+  auth: Auth,
+) -> HttpResponse {
+  let icao = icao.into_inner();
+  let visibility = match AirportVisibility::for_auth(Some(&auth)).await {
+    Ok(visibility) => visibility,
     Err(err) => return ResponseError::error_response(&err),
   };
-  match Airport::delete(&icao.into_inner()).await {
-    Ok(_) => HttpResponse::NoContent().finish(),
+  let ownership = match Airport::select_ownership(&icao, &visibility).await {
+    Ok(ownership) => ownership,
+    Err(err) => return ResponseError::error_response(&err),
+  };
+  if let Err(err) = verify_airport_access(
+    &auth,
+    ownership.owner.as_deref(),
+    ownership.organization_id.as_ref(),
+  )
+  .await
+  {
+    return ResponseError::error_response(&err);
+  }
+  let before = Airport::select(&data.client, &icao, false, &visibility).await;
+  match Airport::delete(&ownership.namespace, &icao).await {
+    Ok(_) => {
+      AuditEvent::new(
+        AuditAction::AirportDeleted,
+        Some(&auth.user.email),
+        &client_ip(&req),
+      )
+      .target(&icao)
+      .changes((audit::fields(&before), None))
+      .record()
+      .await;
+      HttpResponse::NoContent().finish()
+    }
     Err(err) => {
       log::error!("{}", err);
       ResponseError::error_response(&err)
@@ -179,6 +414,7 @@ pub fn init_routes(config: &mut web::ServiceConfig) {
       .service(get_airports)
       .service(get_airport)
       .service(insert_airport)
+      .service(insert_custom_airport)
       .service(update_airport)
       .service(delete_airports)
       .service(delete_airport),
@@ -0,0 +1,252 @@
+use serde::{Deserialize, Serialize};
+use crate::airports::Runway;
+use crate::error::{ApiResult, Error};
+use crate::metars::{FlightCategory, Metar};
+
+const MAX_WIND_KT: f64 = 200.0;
+
+/// Threshold an alert rule watches for, tagged by `type` in JSON
+///
+/// ```json
+/// { "type": "crosswind_above", "knots": 15 }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlertCondition {
+  /// Conditions worse than the given category, e.g. IFR or LIFR when below MVFR
+  FlightCategoryBelow {
+    category: FlightCategory,
+  },
+  /// Sustained wind speed
+  WindAbove {
+    knots: f64,
+  },
+  GustsAbove {
+    knots: f64,
+  },
+  /// Crosswind component on any runway, using the gust speed when one is reported
+  CrosswindAbove {
+    knots: f64,
+  },
+  VisibilityBelow {
+    statute_mi: f64,
+  },
+  /// Lowest broken, overcast or obscured layer
+  CeilingBelow {
+    feet: i32,
+  },
+}
+
+/// Lower is worse, unknown categories never trigger
+fn category_rank(category: FlightCategory) -> Option<u8> {
+  match category {
+    FlightCategory::LIFR => Some(0),
+    FlightCategory::IFR => Some(1),
+    FlightCategory::MVFR => Some(2),
+    FlightCategory::VFR => Some(3),
+    FlightCategory::UNKN => None,
+  }
+}
+
+/// Magnetic heading of a runway from the number of either end, helipads have none
+fn runway_heading(runway_id: &str) -> Option<f64> {
+  let end = runway_id.split('/').next()?;
+  let digits: String = end.chars().take_while(|c| c.is_ascii_digit()).collect();
+  match digits.parse::<u32>() {
+    Ok(number) if (1..=36).contains(&number) => Some(number as f64 * 10.0),
+    _ => None,
+  }
+}
+
+fn visibility(metar: &Metar) -> Option<f64> {
+  let visibility = metar.visibility_statute_mi.as_ref()?;
+  visibility
+    .trim_start_matches(['M', 'P'])
+    .parse::<f64>()
+    .ok()
+}
+
+fn ceiling(metar: &Metar) -> Option<i32> {
+  metar
+    .sky_condition
+    .iter()
+    .filter(|layer| matches!(layer.sky_cover.as_str(), "BKN" | "OVC" | "VV"))
+    .map(|layer| layer.cloud_base_ft_agl.unwrap_or(0))
+    .min()
+}
+
+/// Worst crosswind component across the runways as `(runway, knots)`. Variable or missing
+/// directions count the whole wind as crosswind.
+fn worst_crosswind(metar: &Metar, runways: &[Runway]) -> Option<(String, f64)> {
+  let speed = metar.wind_gust_kt.or(metar.wind_speed_kt)?;
+  let direction = metar
+    .wind_dir_degrees
+    .as_ref()
+    .and_then(|direction| direction.parse::<f64>().ok());
+  runways
+    .iter()
+    .filter_map(|runway| {
+      let heading = runway_heading(&runway.runway_id)?;
+      let crosswind = match direction {
+        Some(direction) => (speed * (direction - heading).to_radians().sin()).abs(),
+        None => speed,
+      };
+      Some((runway.runway_id.clone(), crosswind))
+    })
+    .max_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+impl AlertCondition {
+  pub fn validate(&self) -> ApiResult<()> {
+    let valid = match self {
+      AlertCondition::FlightCategoryBelow { category } => category_rank(*category).is_some(),
+      AlertCondition::WindAbove { knots }
+      | AlertCondition::GustsAbove { knots }
+      | AlertCondition::CrosswindAbove { knots } => *knots > 0.0 && *knots <= MAX_WIND_KT,
+      AlertCondition::VisibilityBelow { statute_mi } => *statute_mi > 0.0,
+      AlertCondition::CeilingBelow { feet } => *feet > 0,
+    };
+    if !valid {
+      return Err(Error::new(400, "Invalid alert threshold".to_string()));
+    }
+    Ok(())
+  }
+
+  /// Only crosswind rules need the airport's runways
+  pub fn needs_runways(&self) -> bool {
+    matches!(self, AlertCondition::CrosswindAbove { .. })
+  }
+
+  /// Describe why the observation meets the condition, `None` when it does not
+  pub fn evaluate(&self, metar: &Metar, runways: &[Runway]) -> Option<String> {
+    match self {
+      AlertCondition::FlightCategoryBelow { category } => {
+        let observed = category_rank(metar.flight_category)?;
+        (observed < category_rank(*category)?)
+          .then(|| format!("Flight category {:?}", metar.flight_category))
+      }
+      AlertCondition::WindAbove { knots } => {
+        let speed = metar.wind_speed_kt?;
+        (speed > *knots).then(|| format!("Wind {:.0} kt", speed))
+      }
+      AlertCondition::GustsAbove { knots } => {
+        let gusts = metar.wind_gust_kt?;
+        (gusts > *knots).then(|| format!("Gusts {:.0} kt", gusts))
+      }
+      AlertCondition::CrosswindAbove { knots } => {
+        let (runway, crosswind) = worst_crosswind(metar, runways)?;
+        (crosswind > *knots).then(|| format!("Crosswind {:.0} kt on runway {}", crosswind, runway))
+      }
+      AlertCondition::VisibilityBelow { statute_mi } => {
+        let visibility = visibility(metar)?;
+        (visibility < *statute_mi).then(|| format!("Visibility {} SM", visibility))
+      }
+      AlertCondition::CeilingBelow { feet } => {
+        let ceiling = ceiling(metar)?;
+        (ceiling < *feet).then(|| format!("Ceiling {} ft", ceiling))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Utc;
+
+  fn metar(raw_text: &str) -> Metar {
+    Metar::parse_with_reference(raw_text, Utc::now()).unwrap()
+  }
+
+  fn runway(id: &str) -> Runway {
+    Runway {
+      runway_id: id.to_string(),
+      length_ft: 5000.0,
+      width_ft: 100.0,
+      surface: "ASP".to_string(),
+    }
+  }
+
+  #[test]
+  fn test_flight_category_below() {
+    let condition = AlertCondition::FlightCategoryBelow {
+      category: FlightCategory::MVFR,
+    };
+    let ifr = metar("KBOS 121754Z 09012KT 2SM BR OVC006 08/07 A2990");
+    assert_eq!(
+      condition.evaluate(&ifr, &[]),
+      Some("Flight category IFR".to_string())
+    );
+    let mvfr = metar("KBOS 121754Z 09012KT 4SM BR OVC020 08/07 A2990");
+    assert_eq!(condition.evaluate(&mvfr, &[]), None);
+  }
+
+  #[test]
+  fn test_wind_and_gusts() {
+    let metar = metar("KBOS 121754Z 27018G30KT 10SM CLR 08/M02 A2990");
+    assert!(AlertCondition::WindAbove { knots: 15.0 }
+      .evaluate(&metar, &[])
+      .is_some());
+    assert!(AlertCondition::WindAbove { knots: 20.0 }
+      .evaluate(&metar, &[])
+      .is_none());
+    assert_eq!(
+      AlertCondition::GustsAbove { knots: 25.0 }.evaluate(&metar, &[]),
+      Some("Gusts 30 kt".to_string())
+    );
+  }
+
+  #[test]
+  fn test_crosswind() {
+    assert_eq!(runway_heading("04L/22R"), Some(40.0));
+    assert_eq!(runway_heading("36"), Some(360.0));
+    assert_eq!(runway_heading("H1"), None);
+
+    let condition = AlertCondition::CrosswindAbove { knots: 15.0 };
+    // Straight down runway 27, across runway 18
+    let westerly = metar("KBOS 121754Z 27020KT 10SM CLR 08/M02 A2990");
+    assert_eq!(condition.evaluate(&westerly, &[runway("09/27")]), None);
+    assert_eq!(
+      condition.evaluate(&westerly, &[runway("09/27"), runway("18/36")]),
+      Some("Crosswind 20 kt on runway 18/36".to_string())
+    );
+    assert_eq!(condition.evaluate(&westerly, &[runway("H1")]), None);
+
+    let variable = metar("KBOS 121754Z VRB18KT 10SM CLR 08/M02 A2990");
+    assert!(condition.evaluate(&variable, &[runway("09/27")]).is_some());
+  }
+
+  #[test]
+  fn test_visibility_and_ceiling() {
+    let metar = metar("KBOS 121754Z 09012KT 1 1/2SM BR BKN008 OVC015 08/07 A2990");
+    assert_eq!(
+      AlertCondition::VisibilityBelow { statute_mi: 3.0 }.evaluate(&metar, &[]),
+      Some("Visibility 1.5 SM".to_string())
+    );
+    assert_eq!(
+      AlertCondition::CeilingBelow { feet: 1000 }.evaluate(&metar, &[]),
+      Some("Ceiling 800 ft".to_string())
+    );
+    assert!(AlertCondition::CeilingBelow { feet: 500 }
+      .evaluate(&metar, &[])
+      .is_none());
+  }
+
+  #[test]
+  fn test_validate() {
+    assert!(AlertCondition::GustsAbove { knots: 25.0 }
+      .validate()
+      .is_ok());
+    assert!(AlertCondition::GustsAbove { knots: 0.0 }
+      .validate()
+      .is_err());
+    assert!(AlertCondition::FlightCategoryBelow {
+      category: FlightCategory::UNKN
+    }
+    .validate()
+    .is_err());
+    let condition: AlertCondition =
+      serde_json::from_str(r#"{ "type": "crosswind_above", "knots": 15 }"#).unwrap();
+    assert_eq!(condition, AlertCondition::CrosswindAbove { knots: 15.0 });
+  }
+}
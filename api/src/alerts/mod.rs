@@ -0,0 +1,7 @@
+mod condition;
+mod model;
+mod routes;
+
+pub use condition::*;
+pub use model::*;
+pub use routes::init_routes;
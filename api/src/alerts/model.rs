@@ -0,0 +1,610 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, QueryBuilder};
+use uuid::Uuid;
+use crate::airports::{Airport, AirportVisibility, Runway};
+use crate::db;
+use crate::error::{ApiResult, Error};
+use crate::metars::Metar;
+use crate::organizations::{Organization, OrganizationRole};
+use crate::watchlists::{Watchlist, WatchlistAccess};
+use crate::webhooks::Webhook;
+use super::AlertCondition;
+
+const TABLE_NAME: &str = "alert_rules";
+const STATES_TABLE_NAME: &str = "alert_states";
+const EVENTS_TABLE_NAME: &str = "alert_events";
+const ORGANIZATION_MEMBERS_TABLE_NAME: &str = "organization_members";
+const WATCHLIST_AIRPORTS_TABLE_NAME: &str = "watchlist_airports";
+const WATCHLISTS_TABLE_NAME: &str = "watchlists";
+const WATCHLIST_MEMBERS_TABLE_NAME: &str = "watchlist_members";
+const AIRPORTS_TABLE_NAME: &str = "airports";
+const USERS_TABLE_NAME: &str = "users";
+const MAX_RULES: i64 = 100;
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AlertRule {
+  pub id: Uuid,
+  pub owner: String,
+  /// Organization whose members can see the rule and its history
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub organization_id: Option<Uuid>,
+  pub name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub icao: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub watchlist_id: Option<Uuid>,
+  #[sqlx(json)]
+  pub condition: AlertCondition,
+  pub enabled: bool,
+  /// Whether the requesting user may change the rule
+  pub editable: bool,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateAlertRule {
+  pub name: String,
+  /// Watch a single airport, or every airport of `watchlist_id`
+  pub icao: Option<String>,
+  pub watchlist_id: Option<Uuid>,
+  pub organization_id: Option<Uuid>,
+  pub condition: AlertCondition,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateAlertRule {
+  pub name: Option<String>,
+  pub condition: Option<AlertCondition>,
+  pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertEventKind {
+  Triggered,
+  Cleared,
+}
+
+impl AlertEventKind {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      AlertEventKind::Triggered => "triggered",
+      AlertEventKind::Cleared => "cleared",
+    }
+  }
+
+  /// Event for a change in outcome, `None` while the outcome holds. A station seen for the
+  /// first time only fires when it is already triggered.
+  pub fn transition(previous: Option<bool>, triggered: bool) -> Option<Self> {
+    match (previous.unwrap_or(false), triggered) {
+      (false, true) => Some(AlertEventKind::Triggered),
+      (true, false) => Some(AlertEventKind::Cleared),
+      _ => None,
+    }
+  }
+}
+
+impl TryFrom<String> for AlertEventKind {
+  type Error = String;
+
+  fn try_from(kind: String) -> Result<Self, Self::Error> {
+    match kind.as_str() {
+      "triggered" => Ok(AlertEventKind::Triggered),
+      "cleared" => Ok(AlertEventKind::Cleared),
+      _ => Err(format!("Invalid alert event kind '{}'", kind)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AlertEvent {
+  pub id: Uuid,
+  pub rule_id: Uuid,
+  pub rule_name: String,
+  pub icao: String,
+  #[sqlx(try_from = "String")]
+  pub kind: AlertEventKind,
+  /// What met the condition, absent once cleared
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub detail: Option<String>,
+  pub raw_text: String,
+  pub observation_time: DateTime<Utc>,
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AlertEventQuery {
+  pub page: Option<u32>,
+  pub limit: Option<u32>,
+  pub rule_id: Option<Uuid>,
+  pub icao: Option<String>,
+  pub kind: Option<AlertEventKind>,
+}
+
+impl Default for AlertEventQuery {
+  fn default() -> Self {
+    Self {
+      page: Some(1),
+      limit: Some(100),
+      rule_id: None,
+      icao: None,
+      kind: None,
+    }
+  }
+}
+
+#[derive(sqlx::FromRow)]
+struct AlertState {
+  triggered: bool,
+  observation_time: DateTime<Utc>,
+}
+
+fn validate_name(name: &str) -> ApiResult<String> {
+  let name = name.trim();
+  if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+    return Err(Error::new(
+      400,
+      format!("Names must be 1 to {} characters", MAX_NAME_LENGTH),
+    ));
+  }
+  Ok(name.to_string())
+}
+
+impl AlertRule {
+  /// Columns of a rule visible to `$1`, owned or through an organization whose admins may
+  /// edit it
+  fn select_sql() -> String {
+    format!(
+      r#"
+      SELECT r.id, r.owner, r.organization_id, r.name, r.icao, r.watchlist_id, r.condition,
+        r.enabled, r.created_at, r.updated_at,
+        (r.owner = $1 OR COALESCE(o.role IN ('admin', 'owner'), false)) AS editable
+      FROM {} r
+      LEFT JOIN {} o ON o.organization_id = r.organization_id AND o.email = $1
+      WHERE (r.owner = $1 OR o.email IS NOT NULL)
+      "#,
+      TABLE_NAME, ORGANIZATION_MEMBERS_TABLE_NAME
+    )
+  }
+
+  pub async fn select_all(email: &str) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let rules: Vec<Self> = sqlx::query_as(&format!("{} ORDER BY r.name", Self::select_sql()))
+      .bind(email)
+      .fetch_all(pool)
+      .await?;
+    Ok(rules)
+  }
+
+  pub async fn select(id: &Uuid, email: &str) -> ApiResult<Self> {
+    let pool = db::pool();
+    let rule: Option<Self> = sqlx::query_as(&format!("{} AND r.id = $2", Self::select_sql()))
+      .bind(email)
+      .bind(id)
+      .fetch_optional(pool)
+      .await?;
+    rule.ok_or_else(|| Error::new(404, format!("Alert rule {} not found", id)))
+  }
+
+  async fn select_editable(id: &Uuid, email: &str) -> ApiResult<Self> {
+    let rule = Self::select(id, email).await?;
+    if !rule.editable {
+      return Err(Error::new(
+        403,
+        format!("Alert rule {} can only be changed by its owner", id),
+      ));
+    }
+    Ok(rule)
+  }
+
+  pub async fn insert(email: &str, request: &CreateAlertRule) -> ApiResult<Self> {
+    let pool = db::pool();
+    let name = validate_name(&request.name)?;
+    request.condition.validate()?;
+    if let Some(organization) = &request.organization_id {
+      Organization::authorize(organization, email, OrganizationRole::Member).await?;
+    }
+    let icao = match (&request.icao, &request.watchlist_id) {
+      (Some(icao), None) => {
+        let icao = icao.trim().to_uppercase();
+        let visibility = AirportVisibility::for_email(email).await?;
+        if !Airport::is_visible(&icao, &visibility).await? {
+          return Err(Error::new(404, format!("Airport {} not found", icao)));
+        }
+        Some(icao)
+      }
+      (None, Some(watchlist)) => {
+        Watchlist::authorize(watchlist, email, WatchlistAccess::Read).await?;
+        None
+      }
+      _ => {
+        return Err(Error::new(
+          400,
+          "Alert rules watch either an icao or a watchlist_id".to_string(),
+        ))
+      }
+    };
+
+    // Lock the owner so concurrent creates count each other's rules
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+      r#"
+      SELECT email FROM {} WHERE email = $1 FOR NO KEY UPDATE
+      "#,
+      USERS_TABLE_NAME
+    ))
+    .bind(email)
+    .execute(&mut *tx)
+    .await?;
+    let count: i64 = sqlx::query_scalar(&format!(
+      r#"
+      SELECT COUNT(*) FROM {} WHERE owner = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_one(&mut *tx)
+    .await?;
+    if count >= MAX_RULES {
+      return Err(Error::new(
+        400,
+        format!("Users are limited to {} alert rules", MAX_RULES),
+      ));
+    }
+
+    let id = Uuid::new_v4();
+    sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (id, owner, organization_id, name, icao, watchlist_id, condition)
+      VALUES ($1, $2, $3, $4, $5, $6, $7)
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(email)
+    .bind(request.organization_id)
+    .bind(&name)
+    .bind(&icao)
+    .bind(request.watchlist_id)
+    .bind(sqlx::types::Json(&request.condition))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Self::select(&id, email).await
+  }
+
+  /// Changing the condition starts its stations over, so the next observation fires afresh
+  pub async fn update(id: &Uuid, email: &str, request: &UpdateAlertRule) -> ApiResult<Self> {
+    let pool = db::pool();
+    let rule = Self::select_editable(id, email).await?;
+    let name = match &request.name {
+      Some(name) => Some(validate_name(name)?),
+      None => None,
+    };
+    if let Some(condition) = &request.condition {
+      condition.validate()?;
+    }
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET
+        name = COALESCE($2, name),
+        condition = COALESCE($3, condition),
+        enabled = COALESCE($4, enabled),
+        updated_at = NOW()
+      WHERE id = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(name)
+    .bind(request.condition.as_ref().map(sqlx::types::Json))
+    .bind(request.enabled)
+    .execute(&mut *tx)
+    .await?;
+    let condition_changed = request
+      .condition
+      .as_ref()
+      .is_some_and(|condition| condition != &rule.condition);
+    if condition_changed {
+      sqlx::query(&format!(
+        r#"
+        DELETE FROM {} WHERE rule_id = $1
+        "#,
+        STATES_TABLE_NAME
+      ))
+      .bind(id)
+      .execute(&mut *tx)
+      .await?;
+    }
+    tx.commit().await?;
+    Self::select(id, email).await
+  }
+
+  pub async fn delete(id: &Uuid, email: &str) -> ApiResult<()> {
+    let pool = db::pool();
+    Self::select_editable(id, email).await?;
+    sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE id = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+
+  /// Enabled rules watching the station directly or through a watchlist, skipping rules whose
+  /// owner has since lost access to the organization, the watchlist or the airport
+  async fn select_for_station(icao: &str) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let rules: Vec<Self> = sqlx::query_as(&format!(
+      r#"
+      SELECT r.id, r.owner, r.organization_id, r.name, r.icao, r.watchlist_id, r.condition,
+        r.enabled, r.created_at, r.updated_at, true AS editable
+      FROM {0} r
+      WHERE r.enabled
+        AND (r.icao = $1
+          OR r.watchlist_id IN (SELECT watchlist_id FROM {1} WHERE icao = $1))
+        AND (r.organization_id IS NULL OR EXISTS (
+          SELECT 1 FROM {2} o WHERE o.organization_id = r.organization_id AND o.email = r.owner
+        ))
+        AND (r.watchlist_id IS NULL OR EXISTS (
+          SELECT 1 FROM {3} w WHERE w.id = r.watchlist_id
+            AND (w.owner = r.owner
+              OR EXISTS (SELECT 1 FROM {4} m WHERE m.watchlist_id = w.id AND m.email = r.owner)
+              OR EXISTS (
+                SELECT 1 FROM {2} o WHERE o.organization_id = w.organization_id AND o.email = r.owner
+              ))
+        ))
+        AND EXISTS (
+          SELECT 1 FROM {5} a WHERE a.icao = $1
+            AND ((a.organization_id IS NULL AND (a.owner IS NULL OR a.owner = r.owner))
+              OR a.organization_id IN (SELECT organization_id FROM {2} WHERE email = r.owner))
+        )
+      "#,
+      TABLE_NAME,
+      WATCHLIST_AIRPORTS_TABLE_NAME,
+      ORGANIZATION_MEMBERS_TABLE_NAME,
+      WATCHLISTS_TABLE_NAME,
+      WATCHLIST_MEMBERS_TABLE_NAME,
+      AIRPORTS_TABLE_NAME
+    ))
+    .bind(icao)
+    .fetch_all(pool)
+    .await?;
+    Ok(rules)
+  }
+
+  /// Evaluate a newly stored observation against every rule watching its station, recording
+  /// an event for each rule whose outcome changed
+  pub async fn evaluate(metar: &Metar) -> ApiResult<Vec<AlertEvent>> {
+    let rules = Self::select_for_station(&metar.station_id).await?;
+    if rules.is_empty() {
+      return Ok(vec![]);
+    }
+    // Runways per rule owner, the airport they see may be their own or their organization's
+    let mut runways: HashMap<String, Vec<Runway>> = HashMap::new();
+
+    let mut events = vec![];
+    for rule in rules {
+      if rule.condition.needs_runways() && !runways.contains_key(&rule.owner) {
+        let visibility = AirportVisibility::for_email(&rule.owner).await?;
+        let ownership = Airport::select_ownership(&metar.station_id, &visibility).await?;
+        let station_runways = Runway::select_all(&ownership.namespace, &metar.station_id).await?;
+        runways.insert(rule.owner.clone(), station_runways);
+      }
+      let station_runways = runways
+        .get(&rule.owner)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+      let detail = rule.condition.evaluate(metar, station_runways);
+      if let Some(event) = rule.record(metar, detail).await? {
+        if event.kind == AlertEventKind::Triggered {
+          if let Err(err) = Webhook::dispatch_alert(&rule, &event).await {
+            log::error!(
+              "Unable to queue webhooks for alert rule {}: {}",
+              rule.id,
+              err
+            );
+          }
+        }
+        events.push(event);
+      }
+    }
+    Ok(events)
+  }
+
+  async fn record(&self, metar: &Metar, detail: Option<String>) -> ApiResult<Option<AlertEvent>> {
+    let pool = db::pool();
+    let mut tx = pool.begin().await?;
+    // A station seen for the first time gets an untriggered state older than any observation,
+    // so concurrent evaluations always have a row to lock
+    sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (rule_id, icao, triggered, observation_time)
+      VALUES ($1, $2, false, TO_TIMESTAMP(0))
+      ON CONFLICT (rule_id, icao) DO NOTHING
+      "#,
+      STATES_TABLE_NAME
+    ))
+    .bind(self.id)
+    .bind(&metar.station_id)
+    .execute(&mut *tx)
+    .await?;
+    let previous: AlertState = sqlx::query_as(&format!(
+      r#"
+      SELECT triggered, observation_time FROM {} WHERE rule_id = $1 AND icao = $2 FOR UPDATE
+      "#,
+      STATES_TABLE_NAME
+    ))
+    .bind(self.id)
+    .bind(&metar.station_id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // Late arrivals must not undo a newer observation, corrections replace their own
+    if previous.observation_time > metar.observation_time
+      || (previous.observation_time == metar.observation_time && !metar.is_correction())
+    {
+      return Ok(None);
+    }
+
+    let triggered = detail.is_some();
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET triggered = $3, observation_time = $4, updated_at = NOW()
+      WHERE rule_id = $1 AND icao = $2
+      "#,
+      STATES_TABLE_NAME
+    ))
+    .bind(self.id)
+    .bind(&metar.station_id)
+    .bind(triggered)
+    .bind(metar.observation_time)
+    .execute(&mut *tx)
+    .await?;
+
+    let kind = AlertEventKind::transition(Some(previous.triggered), triggered);
+    let Some(kind) = kind else {
+      tx.commit().await?;
+      return Ok(None);
+    };
+    let event = AlertEvent {
+      id: Uuid::new_v4(),
+      rule_id: self.id,
+      rule_name: self.name.clone(),
+      icao: metar.station_id.clone(),
+      kind,
+      detail,
+      raw_text: metar.raw_text.clone(),
+      observation_time: metar.observation_time,
+      created_at: Utc::now(),
+    };
+    sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (id, rule_id, icao, kind, detail, raw_text, observation_time, created_at)
+      VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+      "#,
+      EVENTS_TABLE_NAME
+    ))
+    .bind(event.id)
+    .bind(event.rule_id)
+    .bind(&event.icao)
+    .bind(event.kind.as_str())
+    .bind(&event.detail)
+    .bind(&event.raw_text)
+    .bind(event.observation_time)
+    .bind(event.created_at)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    log::info!(
+      "Alert rule {} {} for {}",
+      self.id,
+      event.kind.as_str(),
+      event.icao
+    );
+    Ok(Some(event))
+  }
+
+  /// Evaluate in the background, alerts must never hold up storing an observation
+  pub fn spawn_evaluation(metar: Metar) {
+    tokio::spawn(async move {
+      if let Err(err) = Self::evaluate(&metar).await {
+        log::error!(
+          "Unable to evaluate alert rules for {}: {}",
+          metar.station_id,
+          err
+        );
+      }
+    });
+  }
+}
+
+impl AlertEvent {
+  fn push_conditions(builder: &mut QueryBuilder<Postgres>, email: &str, query: &AlertEventQuery) {
+    builder
+      .push(" FROM ")
+      .push(EVENTS_TABLE_NAME)
+      .push(" e JOIN ")
+      .push(TABLE_NAME)
+      .push(" r ON r.id = e.rule_id WHERE (r.owner = ")
+      .push_bind(email.to_string())
+      .push(" OR r.organization_id IN (SELECT organization_id FROM ")
+      .push(ORGANIZATION_MEMBERS_TABLE_NAME)
+      .push(" WHERE email = ")
+      .push_bind(email.to_string())
+      .push("))");
+    if let Some(rule_id) = query.rule_id {
+      builder.push(" AND e.rule_id = ").push_bind(rule_id);
+    }
+    if let Some(icao) = &query.icao {
+      builder
+        .push(" AND e.icao = ")
+        .push_bind(icao.trim().to_uppercase());
+    }
+    if let Some(kind) = query.kind {
+      builder.push(" AND e.kind = ").push_bind(kind.as_str());
+    }
+  }
+
+  /// Alert history of the rules visible to the user, newest first
+  pub async fn select_all(email: &str, query: &AlertEventQuery) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let mut builder = QueryBuilder::<Postgres>::new(
+      "SELECT e.id, e.rule_id, r.name AS rule_name, e.icao, e.kind, e.detail, e.raw_text, \
+      e.observation_time, e.created_at",
+    );
+    Self::push_conditions(&mut builder, email, query);
+    builder.push(" ORDER BY e.created_at DESC");
+    if let Some(limit) = query.limit {
+      builder.push(" LIMIT ").push_bind(limit as i64);
+      let offset = (query.page.unwrap_or(1).saturating_sub(1) * limit) as i64;
+      builder.push(" OFFSET ").push_bind(offset);
+    }
+    let events: Vec<Self> = builder.build_query_as().fetch_all(pool).await?;
+    Ok(events)
+  }
+
+  pub async fn count(email: &str, query: &AlertEventQuery) -> i64 {
+    let pool = db::pool();
+    let mut builder = QueryBuilder::<Postgres>::new("SELECT COUNT(*)");
+    Self::push_conditions(&mut builder, email, query);
+    builder
+      .build_query_scalar()
+      .fetch_one(pool)
+      .await
+      .unwrap_or(0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_transition() {
+    assert_eq!(
+      AlertEventKind::transition(None, true),
+      Some(AlertEventKind::Triggered)
+    );
+    assert_eq!(AlertEventKind::transition(None, false), None);
+    assert_eq!(AlertEventKind::transition(Some(true), true), None);
+    assert_eq!(
+      AlertEventKind::transition(Some(true), false),
+      Some(AlertEventKind::Cleared)
+    );
+    assert_eq!(
+      AlertEventKind::transition(Some(false), true),
+      Some(AlertEventKind::Triggered)
+    );
+  }
+}
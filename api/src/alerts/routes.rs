@@ -0,0 +1,118 @@
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, ResponseError};
+use uuid::Uuid;
+use crate::alerts::{AlertEvent, AlertEventQuery, AlertRule, CreateAlertRule, UpdateAlertRule};
+use crate::auth::{client_ip, Auth};
+use crate::db::Paged;
+
+const MAX_LIMIT: u32 = 500;
+
+#[get("/rules")]
+async fn get_rules(auth: Auth) -> HttpResponse {
+  match AlertRule::select_all(&auth.user.email).await {
+    Ok(rules) => HttpResponse::Ok().json(rules),
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[post("/rules")]
+async fn create_rule(
+  request: web::Json<CreateAlertRule>,
+  req: HttpRequest,
+  auth: Auth,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  match AlertRule::insert(&auth.user.email, &request).await {
+    Ok(rule) => {
+      log::info!(
+        "Created alert rule {} [Email: {}] [IP Address: {}]",
+        rule.id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::Created().json(rule)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[get("/rules/{id}")]
+async fn get_rule(id: web::Path<Uuid>, auth: Auth) -> HttpResponse {
+  match AlertRule::select(&id, &auth.user.email).await {
+    Ok(rule) => HttpResponse::Ok().json(rule),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[put("/rules/{id}")]
+async fn update_rule(
+  id: web::Path<Uuid>,
+  request: web::Json<UpdateAlertRule>,
+  auth: Auth,
+) -> HttpResponse {
+  match AlertRule::update(&id, &auth.user.email, &request).await {
+    Ok(rule) => HttpResponse::Ok().json(rule),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/rules/{id}")]
+async fn delete_rule(id: web::Path<Uuid>, req: HttpRequest, auth: Auth) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  match AlertRule::delete(&id, &auth.user.email).await {
+    Ok(_) => {
+      log::info!(
+        "Deleted alert rule {} [Email: {}] [IP Address: {}]",
+        id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[get("/events")]
+async fn get_events(req: HttpRequest, auth: Auth) -> HttpResponse {
+  let mut query = match web::Query::<AlertEventQuery>::from_query(req.query_string()) {
+    Ok(q) => q.into_inner(),
+    Err(err) => {
+      log::error!("{}", err);
+      AlertEventQuery::default()
+    }
+  };
+
+  let total = AlertEvent::count(&auth.user.email, &query).await;
+  let page = query.page.unwrap_or(1);
+  let limit = query.limit.unwrap_or(100).min(MAX_LIMIT);
+  query.limit = Some(limit);
+  query.page = Some(page);
+
+  match AlertEvent::select_all(&auth.user.email, &query).await {
+    Ok(events) => HttpResponse::Ok().json(Paged {
+      data: events,
+      page,
+      limit,
+      total,
+    }),
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+  config.service(
+    web::scope("alerts")
+      .service(get_rules)
+      .service(create_rule)
+      .service(get_rule)
+      .service(update_rule)
+      .service(delete_rule)
+      .service(get_events),
+  );
+}
@@ -0,0 +1,489 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{Postgres, QueryBuilder};
+use uuid::Uuid;
+use crate::db;
+use crate::error::ApiResult;
+
+const TABLE_NAME: &str = "audit_events";
+/// Fields whose values never reach the audit log, only the fact that they changed
+const REDACTED_FIELDS: [&str; 5] = ["password", "password_hash", "secret", "token", "key"];
+const REDACTED: &str = "[redacted]";
+const CSV_COLUMNS: [&str; 10] = [
+  "id",
+  "created_at",
+  "actor",
+  "ip_address",
+  "action",
+  "outcome",
+  "target",
+  "before",
+  "after",
+  "detail",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditAction {
+  #[serde(rename = "auth.login")]
+  Login,
+  #[serde(rename = "auth.password_changed")]
+  PasswordChanged,
+  #[serde(rename = "auth.password_reset")]
+  PasswordReset,
+  #[serde(rename = "auth.two_factor_enabled")]
+  TwoFactorEnabled,
+  #[serde(rename = "auth.two_factor_disabled")]
+  TwoFactorDisabled,
+  #[serde(rename = "auth.api_key_created")]
+  ApiKeyCreated,
+  #[serde(rename = "auth.api_key_revoked")]
+  ApiKeyRevoked,
+  #[serde(rename = "user.updated")]
+  UserUpdated,
+  #[serde(rename = "user.role_changed")]
+  UserRoleChanged,
+  #[serde(rename = "user.disabled")]
+  UserDisabled,
+  #[serde(rename = "user.enabled")]
+  UserEnabled,
+  #[serde(rename = "user.password_reset_forced")]
+  UserPasswordResetForced,
+  #[serde(rename = "user.sessions_revoked")]
+  UserSessionsRevoked,
+  #[serde(rename = "user.unlocked")]
+  UserUnlocked,
+  #[serde(rename = "user.deleted")]
+  UserDeleted,
+  #[serde(rename = "role.updated")]
+  RoleUpdated,
+  #[serde(rename = "role.deleted")]
+  RoleDeleted,
+  #[serde(rename = "airports.imported")]
+  AirportsImported,
+  #[serde(rename = "airport.created")]
+  AirportCreated,
+  #[serde(rename = "airport.updated")]
+  AirportUpdated,
+  #[serde(rename = "airport.deleted")]
+  AirportDeleted,
+  /// The whole imported dataset was deleted
+  #[serde(rename = "airports.deleted")]
+  AirportsDeleted,
+  #[serde(rename = "settings.updated")]
+  SettingsUpdated,
+}
+
+impl AuditAction {
+  pub const ALL: [AuditAction; 23] = [
+    AuditAction::Login,
+    AuditAction::PasswordChanged,
+    AuditAction::PasswordReset,
+    AuditAction::TwoFactorEnabled,
+    AuditAction::TwoFactorDisabled,
+    AuditAction::ApiKeyCreated,
+    AuditAction::ApiKeyRevoked,
+    AuditAction::UserUpdated,
+    AuditAction::UserRoleChanged,
+    AuditAction::UserDisabled,
+    AuditAction::UserEnabled,
+    AuditAction::UserPasswordResetForced,
+    AuditAction::UserSessionsRevoked,
+    AuditAction::UserUnlocked,
+    AuditAction::UserDeleted,
+    AuditAction::RoleUpdated,
+    AuditAction::RoleDeleted,
+    AuditAction::AirportsImported,
+    AuditAction::AirportCreated,
+    AuditAction::AirportUpdated,
+    AuditAction::AirportDeleted,
+    AuditAction::AirportsDeleted,
+    AuditAction::SettingsUpdated,
+  ];
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      AuditAction::Login => "auth.login",
+      AuditAction::PasswordChanged => "auth.password_changed",
+      AuditAction::PasswordReset => "auth.password_reset",
+      AuditAction::TwoFactorEnabled => "auth.two_factor_enabled",
+      AuditAction::TwoFactorDisabled => "auth.two_factor_disabled",
+      AuditAction::ApiKeyCreated => "auth.api_key_created",
+      AuditAction::ApiKeyRevoked => "auth.api_key_revoked",
+      AuditAction::UserUpdated => "user.updated",
+      AuditAction::UserRoleChanged => "user.role_changed",
+      AuditAction::UserDisabled => "user.disabled",
+      AuditAction::UserEnabled => "user.enabled",
+      AuditAction::UserPasswordResetForced => "user.password_reset_forced",
+      AuditAction::UserSessionsRevoked => "user.sessions_revoked",
+      AuditAction::UserUnlocked => "user.unlocked",
+      AuditAction::UserDeleted => "user.deleted",
+      AuditAction::RoleUpdated => "role.updated",
+      AuditAction::RoleDeleted => "role.deleted",
+      AuditAction::AirportsImported => "airports.imported",
+      AuditAction::AirportCreated => "airport.created",
+      AuditAction::AirportUpdated => "airport.updated",
+      AuditAction::AirportDeleted => "airport.deleted",
+      AuditAction::AirportsDeleted => "airports.deleted",
+      AuditAction::SettingsUpdated => "settings.updated",
+    }
+  }
+}
+
+impl TryFrom<String> for AuditAction {
+  type Error = String;
+
+  fn try_from(action: String) -> Result<Self, Self::Error> {
+    AuditAction::ALL
+      .into_iter()
+      .find(|a| a.as_str() == action)
+      .ok_or_else(|| format!("Invalid audit action '{}'", action))
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+  Success,
+  Failure,
+}
+
+impl AuditOutcome {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      AuditOutcome::Success => "success",
+      AuditOutcome::Failure => "failure",
+    }
+  }
+}
+
+impl TryFrom<String> for AuditOutcome {
+  type Error = String;
+
+  fn try_from(outcome: String) -> Result<Self, Self::Error> {
+    match outcome.as_str() {
+      "success" => Ok(AuditOutcome::Success),
+      "failure" => Ok(AuditOutcome::Failure),
+      _ => Err(format!("Invalid audit outcome '{}'", outcome)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AuditEvent {
+  pub id: Uuid,
+  /// Email of the user who acted, absent for anonymous requests such as failed logins
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub actor: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub ip_address: Option<String>,
+  #[sqlx(try_from = "String")]
+  pub action: AuditAction,
+  #[sqlx(try_from = "String")]
+  pub outcome: AuditOutcome,
+  /// What was acted on, e.g. the email of a user or the ICAO of an airport
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub target: Option<String>,
+  /// Previous values of the changed fields
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub before: Option<Value>,
+  /// New values of the changed fields
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub after: Option<Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub detail: Option<String>,
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+  pub page: Option<u32>,
+  pub limit: Option<u32>,
+  pub actor: Option<String>,
+  pub action: Option<AuditAction>,
+  pub outcome: Option<AuditOutcome>,
+  pub target: Option<String>,
+  /// Only events at or after this time
+  pub from: Option<DateTime<Utc>>,
+  /// Only events before this time
+  pub to: Option<DateTime<Utc>>,
+}
+
+impl Default for AuditQuery {
+  fn default() -> Self {
+    Self {
+      page: Some(1),
+      limit: Some(100),
+      actor: None,
+      action: None,
+      outcome: None,
+      target: None,
+      from: None,
+      to: None,
+    }
+  }
+}
+
+/// Serialize `value` as an object of its fields, leaving out unset ones and redacting secrets
+pub fn fields<T: Serialize>(value: &T) -> Option<Value> {
+  match serde_json::to_value(value) {
+    Ok(Value::Object(object)) => {
+      let object: Map<String, Value> = object
+        .into_iter()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(field, value)| redact(field, value))
+        .collect();
+      (!object.is_empty()).then_some(Value::Object(object))
+    }
+    Ok(Value::Null) => None,
+    Ok(value) => Some(value),
+    Err(err) => {
+      log::error!("Unable to serialize audit fields: {}", err);
+      None
+    }
+  }
+}
+
+/// The fields that differ between `before` and `after`, as their previous and new values
+pub fn diff<B: Serialize, A: Serialize>(before: &B, after: &A) -> (Option<Value>, Option<Value>) {
+  let (Ok(Value::Object(before)), Ok(Value::Object(after))) =
+    (serde_json::to_value(before), serde_json::to_value(after))
+  else {
+    return (fields(before), fields(after));
+  };
+  let mut previous = Map::new();
+  let mut current = Map::new();
+  for field in before
+    .keys()
+    .chain(after.keys().filter(|f| !before.contains_key(*f)))
+  {
+    let old = before.get(field).unwrap_or(&Value::Null);
+    let new = after.get(field).unwrap_or(&Value::Null);
+    if old != new {
+      let (field, old) = redact(field.clone(), old.clone());
+      previous.insert(field.clone(), old);
+      current.insert(field.clone(), redact(field, new.clone()).1);
+    }
+  }
+  if previous.is_empty() {
+    return (None, None);
+  }
+  (Some(Value::Object(previous)), Some(Value::Object(current)))
+}
+
+fn redact(field: String, value: Value) -> (String, Value) {
+  if !value.is_null() && REDACTED_FIELDS.contains(&field.as_str()) {
+    return (field, Value::String(REDACTED.to_string()));
+  }
+  (field, value)
+}
+
+/// Quote a CSV field when needed, prefixing values a spreadsheet would run as a formula
+fn csv_field(value: &str) -> String {
+  let value = match value.chars().next() {
+    Some('=' | '+' | '-' | '@' | '\t' | '\r') => format!("'{}", value),
+    _ => value.to_string(),
+  };
+  if value.contains([',', '"', '\n', '\r']) {
+    format!("\"{}\"", value.replace('"', "\"\""))
+  } else {
+    value
+  }
+}
+
+impl AuditEvent {
+  const COLUMNS: &'static str =
+    "id, actor, ip_address, action, outcome, target, before, after, detail, created_at";
+
+  pub fn new(action: AuditAction, actor: Option<&str>, ip_address: &str) -> Self {
+    Self {
+      id: Uuid::new_v4(),
+      actor: actor.map(|actor| actor.to_lowercase()),
+      ip_address: Some(ip_address.to_string()),
+      action,
+      outcome: AuditOutcome::Success,
+      target: None,
+      before: None,
+      after: None,
+      detail: None,
+      created_at: Utc::now(),
+    }
+  }
+
+  pub fn target(mut self, target: &str) -> Self {
+    self.target = Some(target.to_string());
+    self
+  }
+
+  pub fn changes(mut self, (before, after): (Option<Value>, Option<Value>)) -> Self {
+    self.before = before;
+    self.after = after;
+    self
+  }
+
+  pub fn detail(mut self, detail: impl ToString) -> Self {
+    self.detail = Some(detail.to_string());
+    self
+  }
+
+  pub fn failure(mut self) -> Self {
+    self.outcome = AuditOutcome::Failure;
+    self
+  }
+
+  /// Store the event; auditing never fails the audited request so errors are only logged
+  pub async fn record(self) {
+    if let Err(err) = self.insert().await {
+      log::error!(
+        "Unable to record audit event {} [Email: {}]: {}",
+        self.action.as_str(),
+        self.actor.as_deref().unwrap_or("-"),
+        err
+      );
+    }
+  }
+
+  async fn insert(&self) -> ApiResult<()> {
+    let pool = db::pool();
+    sqlx::query(&format!(
+      r#"
+      INSERT INTO {} ({})
+      VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+      "#,
+      TABLE_NAME,
+      Self::COLUMNS
+    ))
+    .bind(self.id)
+    .bind(&self.actor)
+    .bind(&self.ip_address)
+    .bind(self.action.as_str())
+    .bind(self.outcome.as_str())
+    .bind(&self.target)
+    .bind(&self.before)
+    .bind(&self.after)
+    .bind(&self.detail)
+    .bind(self.created_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+
+  fn push_conditions<'a>(builder: &mut QueryBuilder<'a, Postgres>, query: &'a AuditQuery) {
+    builder.push(" FROM ").push(TABLE_NAME).push(" WHERE TRUE");
+    if let Some(actor) = &query.actor {
+      builder
+        .push(" AND actor = ")
+        .push_bind(actor.to_lowercase());
+    }
+    if let Some(action) = query.action {
+      builder.push(" AND action = ").push_bind(action.as_str());
+    }
+    if let Some(outcome) = query.outcome {
+      builder.push(" AND outcome = ").push_bind(outcome.as_str());
+    }
+    if let Some(target) = &query.target {
+      builder.push(" AND target = ").push_bind(target);
+    }
+    if let Some(from) = query.from {
+      builder.push(" AND created_at >= ").push_bind(from);
+    }
+    if let Some(to) = query.to {
+      builder.push(" AND created_at < ").push_bind(to);
+    }
+  }
+
+  /// Matching events, newest first
+  pub async fn select_all(query: &AuditQuery) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let mut builder = QueryBuilder::<Postgres>::new(format!("SELECT {}", Self::COLUMNS));
+    Self::push_conditions(&mut builder, query);
+    builder.push(" ORDER BY created_at DESC, id");
+    if let Some(limit) = query.limit {
+      builder.push(" LIMIT ").push_bind(limit as i64);
+      let offset = (query.page.unwrap_or(1).saturating_sub(1) * limit) as i64;
+      builder.push(" OFFSET ").push_bind(offset);
+    }
+    let events: Vec<Self> = builder.build_query_as().fetch_all(pool).await?;
+    Ok(events)
+  }
+
+  pub async fn count(query: &AuditQuery) -> i64 {
+    let pool = db::pool();
+    let mut builder = QueryBuilder::<Postgres>::new("SELECT COUNT(*)");
+    Self::push_conditions(&mut builder, query);
+    builder
+      .build_query_scalar()
+      .fetch_one(pool)
+      .await
+      .unwrap_or(0)
+  }
+
+  /// Events as CSV with a header row, the before and after columns hold JSON
+  pub fn to_csv(events: &[Self]) -> String {
+    let mut csv = CSV_COLUMNS.join(",");
+    csv.push_str("\r\n");
+    let json = |value: &Option<Value>| value.as_ref().map(Value::to_string).unwrap_or_default();
+    for event in events {
+      let row = [
+        event.id.to_string(),
+        event.created_at.to_rfc3339(),
+        event.actor.clone().unwrap_or_default(),
+        event.ip_address.clone().unwrap_or_default(),
+        event.action.as_str().to_string(),
+        event.outcome.as_str().to_string(),
+        event.target.clone().unwrap_or_default(),
+        json(&event.before),
+        json(&event.after),
+        event.detail.clone().unwrap_or_default(),
+      ];
+      let row: Vec<String> = row.iter().map(|value| csv_field(value)).collect();
+      csv.push_str(&row.join(","));
+      csv.push_str("\r\n");
+    }
+    csv
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_diff() {
+    let before =
+      json!({"email": "a@b.c", "role": "USER", "first_name": "Ada", "password_hash": "x"});
+    let after =
+      json!({"email": "a@b.c", "role": "ADMIN", "first_name": "Ada", "password_hash": "y"});
+    assert_eq!(
+      diff(&before, &after),
+      (
+        Some(json!({"role": "USER", "password_hash": "[redacted]"})),
+        Some(json!({"role": "ADMIN", "password_hash": "[redacted]"}))
+      )
+    );
+    assert_eq!(diff(&before, &before), (None, None));
+    assert_eq!(
+      diff(&json!({}), &json!({"public": true})),
+      (Some(json!({"public": null})), Some(json!({"public": true})))
+    );
+  }
+
+  #[test]
+  fn test_fields() {
+    assert_eq!(
+      fields(&json!({"name": "Logan", "iata": null, "secret": "s"})),
+      Some(json!({"name": "Logan", "secret": "[redacted]"}))
+    );
+    assert_eq!(fields(&json!({"iata": null})), None);
+  }
+
+  #[test]
+  fn test_csv_field() {
+    assert_eq!(csv_field("KBOS"), "KBOS");
+    assert_eq!(csv_field(r#"{"a":1,"b":2}"#), r#""{""a"":1,""b"":2}""#);
+    assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+    assert_eq!(csv_field("=HYPERLINK(1)"), "'=HYPERLINK(1)");
+    assert_eq!(csv_field("-1,2"), "\"'-1,2\"");
+  }
+}
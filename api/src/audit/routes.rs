@@ -0,0 +1,86 @@
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse, ResponseError};
+use crate::audit::{AuditEvent, AuditQuery};
+use crate::auth::{client_ip, require, Permitted};
+use crate::db::Paged;
+
+const MAX_LIMIT: u32 = 500;
+/// Events in a single export, narrow the filters to export more
+const MAX_EXPORT_ROWS: u32 = 50_000;
+
+/// Bad filters are rejected, a search or export must never silently widen to every event
+fn parse_query(req: &HttpRequest) -> Result<AuditQuery, HttpResponse> {
+  match web::Query::<AuditQuery>::from_query(req.query_string()) {
+    Ok(q) => Ok(q.into_inner()),
+    Err(err) => Err(ResponseError::error_response(&err)),
+  }
+}
+
+#[get("")]
+async fn get_events(req: HttpRequest, _: Permitted<require::AuditRead>) -> HttpResponse {
+  let mut query = match parse_query(&req) {
+    Ok(query) => query,
+    Err(response) => return response,
+  };
+  let total = AuditEvent::count(&query).await;
+  let page = query.page.unwrap_or(1);
+  let limit = query.limit.unwrap_or(100).min(MAX_LIMIT);
+  query.limit = Some(limit);
+  query.page = Some(page);
+
+  match AuditEvent::select_all(&query).await {
+    Ok(events) => HttpResponse::Ok().json(Paged {
+      data: events,
+      page,
+      limit,
+      total,
+    }),
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+/// Matching events as a CSV download, newest first. A truncated export carries the number of
+/// matching events in `X-Total-Count`
+#[get("/export")]
+async fn export_events(req: HttpRequest, auth: Permitted<require::AuditRead>) -> HttpResponse {
+  let mut query = match parse_query(&req) {
+    Ok(query) => query,
+    Err(response) => return response,
+  };
+  query.page = Some(1);
+  query.limit = Some(MAX_EXPORT_ROWS);
+  let events = match AuditEvent::select_all(&query).await {
+    Ok(events) => events,
+    Err(err) => {
+      log::error!("{}", err);
+      return ResponseError::error_response(&err);
+    }
+  };
+  log::info!(
+    "Exported {} audit events [Email: {}] [IP Address: {}]",
+    events.len(),
+    auth.user.email,
+    client_ip(&req)
+  );
+  let mut response = HttpResponse::Ok();
+  response
+    .content_type("text/csv; charset=utf-8")
+    .insert_header((
+      header::CONTENT_DISPOSITION,
+      "attachment; filename=\"audit-events.csv\"",
+    ));
+  if events.len() as u32 == MAX_EXPORT_ROWS {
+    response.insert_header(("X-Total-Count", AuditEvent::count(&query).await.to_string()));
+  }
+  response.body(AuditEvent::to_csv(&events))
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+  config.service(
+    web::scope("admin/audit")
+      .service(get_events)
+      .service(export_events),
+  );
+}
@@ -0,0 +1,336 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::db;
+use crate::error::{ApiResult, Error};
+use super::{csprng, Permission};
+
+const TABLE_NAME: &str = "api_keys";
+pub const API_KEY_PREFIX: &str = "avk_";
+const PREFIX_LENGTH: usize = 8;
+const SECRET_LENGTH: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ApiKeyScope {
+  #[serde(rename = "weather:read")]
+  ReadWeather,
+  #[serde(rename = "airports:manage")]
+  ManageAirports,
+  #[serde(rename = "admin")]
+  Admin,
+}
+
+impl ApiKeyScope {
+  /// Permissions a key with this scope may use, still limited by the owner's role
+  pub fn permissions(&self) -> &'static [Permission] {
+    match self {
+      ApiKeyScope::ReadWeather => &[Permission::WeatherRead],
+      ApiKeyScope::ManageAirports => &[Permission::WeatherRead, Permission::AirportsWrite],
+      ApiKeyScope::Admin => &Permission::ALL,
+    }
+  }
+}
+
+impl FromStr for ApiKeyScope {
+  type Err = ();
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "weather:read" => Ok(ApiKeyScope::ReadWeather),
+      "airports:manage" => Ok(ApiKeyScope::ManageAirports),
+      "admin" => Ok(ApiKeyScope::Admin),
+      _ => Err(()),
+    }
+  }
+}
+
+impl Display for ApiKeyScope {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ApiKeyScope::ReadWeather => write!(f, "weather:read"),
+      ApiKeyScope::ManageAirports => write!(f, "airports:manage"),
+      ApiKeyScope::Admin => write!(f, "admin"),
+    }
+  }
+}
+
+#[derive(Debug, Deserialize, sqlx::FromRow)]
+struct ApiKeyRow {
+  id: Uuid,
+  email: String,
+  name: String,
+  prefix: String,
+  key_hash: String,
+  scopes: Vec<String>,
+  expires_at: Option<DateTime<Utc>>,
+  last_used_at: Option<DateTime<Utc>>,
+  revoked_at: Option<DateTime<Utc>>,
+  created_at: DateTime<Utc>,
+}
+
+/// Long-lived credential for programmatic access; only a hash of the secret is stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+  pub id: Uuid,
+  #[serde(skip)]
+  pub email: String,
+  pub name: String,
+  pub prefix: String,
+  pub scopes: Vec<ApiKeyScope>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub expires_at: Option<DateTime<Utc>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_used_at: Option<DateTime<Utc>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub revoked_at: Option<DateTime<Utc>>,
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+  pub name: String,
+  pub scopes: Vec<ApiKeyScope>,
+  pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Returned once on creation, the full key cannot be retrieved afterwards
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+  pub key: String,
+  #[serde(flatten)]
+  pub api_key: ApiKey,
+}
+
+impl From<ApiKeyRow> for ApiKey {
+  fn from(row: ApiKeyRow) -> Self {
+    Self {
+      id: row.id,
+      email: row.email,
+      name: row.name,
+      prefix: row.prefix,
+      scopes: row
+        .scopes
+        .iter()
+        .filter_map(|scope| ApiKeyScope::from_str(scope).ok())
+        .collect(),
+      expires_at: row.expires_at,
+      last_used_at: row.last_used_at,
+      revoked_at: row.revoked_at,
+      created_at: row.created_at,
+    }
+  }
+}
+
+impl ApiKey {
+  pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(API_KEY_PREFIX)
+  }
+
+  /// Split `avk_<prefix>_<secret>` into its prefix and secret
+  fn split(token: &str) -> Option<(&str, &str)> {
+    let (prefix, secret) = token.strip_prefix(API_KEY_PREFIX)?.split_once('_')?;
+    if prefix.len() != PREFIX_LENGTH || secret.is_empty() {
+      return None;
+    }
+    Some((prefix, secret))
+  }
+
+  /// Hex SHA-256 of the secret. Secrets are long random strings, so unlike passwords they need
+  /// no slow hash and every request can afford to check one.
+  fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+  }
+
+  /// Compare the secret with the stored hash in constant time
+  fn verify_secret(secret: &str, key_hash: &str) -> bool {
+    let computed = Self::hash_secret(secret);
+    computed.len() == key_hash.len()
+      && computed
+        .bytes()
+        .zip(key_hash.bytes())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+  }
+
+  pub fn allows(&self, permission: Permission) -> bool {
+    self
+      .scopes
+      .iter()
+      .any(|scope| scope.permissions().contains(&permission))
+  }
+
+  pub async fn insert(email: &str, request: &CreateApiKeyRequest) -> ApiResult<CreatedApiKey> {
+    let pool = db::pool();
+    let name = request.name.trim();
+    if name.is_empty() {
+      return Err(Error::new(400, "API key name is required".to_string()));
+    }
+    if request.scopes.is_empty() {
+      return Err(Error::new(
+        400,
+        "API key requires at least one scope".to_string(),
+      ));
+    }
+    if let Some(expires_at) = request.expires_at {
+      if expires_at <= Utc::now() {
+        return Err(Error::new(
+          400,
+          "API key expiry must be in the future".to_string(),
+        ));
+      }
+    }
+
+    let prefix = csprng(PREFIX_LENGTH);
+    let secret = csprng(SECRET_LENGTH);
+    let mut scopes: Vec<String> = request.scopes.iter().map(|s| s.to_string()).collect();
+    scopes.sort();
+    scopes.dedup();
+
+    let row: ApiKeyRow = sqlx::query_as(&format!(
+      r#"
+      INSERT INTO {} (id, email, name, prefix, key_hash, scopes, expires_at)
+      VALUES ($1, $2, $3, $4, $5, $6, $7)
+      RETURNING *
+      "#,
+      TABLE_NAME
+    ))
+    .bind(Uuid::new_v4())
+    .bind(email)
+    .bind(name)
+    .bind(&prefix)
+    .bind(Self::hash_secret(&secret))
+    .bind(&scopes)
+    .bind(request.expires_at)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(CreatedApiKey {
+      key: format!("{}{}_{}", API_KEY_PREFIX, prefix, secret),
+      api_key: row.into(),
+    })
+  }
+
+  pub async fn select_all(email: &str) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let rows: Vec<ApiKeyRow> = sqlx::query_as(&format!(
+      r#"
+      SELECT * FROM {} WHERE email = $1 ORDER BY created_at DESC
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(From::from).collect())
+  }
+
+  pub async fn revoke(email: &str, id: &Uuid) -> ApiResult<()> {
+    let pool = db::pool();
+    let result = sqlx::query(&format!(
+      r#"
+      UPDATE {} SET revoked_at = NOW() WHERE id = $1 AND email = $2 AND revoked_at IS NULL
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(email)
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+      return Err(Error::new(404, format!("API key {} not found", id)));
+    }
+    Ok(())
+  }
+
+  /// Look up the key by its prefix and verify the secret against the stored hash
+  pub async fn authenticate(token: &str) -> ApiResult<Self> {
+    let pool = db::pool();
+    let unauthorized = || Error::new(401, "Invalid API key".to_string());
+    let (prefix, secret) = Self::split(token).ok_or_else(unauthorized)?;
+
+    let row: ApiKeyRow = sqlx::query_as(&format!(
+      r#"
+      SELECT * FROM {} WHERE prefix = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(prefix)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(unauthorized)?;
+
+    if row.revoked_at.is_some() || row.expires_at.is_some_and(|e| e <= Utc::now()) {
+      return Err(unauthorized());
+    }
+    if !Self::verify_secret(secret, &row.key_hash) {
+      return Err(unauthorized());
+    }
+
+    let id = row.id;
+    tokio::spawn(async move {
+      if let Err(err) = Self::touch(&id).await {
+        log::warn!("Unable to update API key last use [ID: {}]: {}", id, err);
+      }
+    });
+    Ok(row.into())
+  }
+
+  async fn touch(id: &Uuid) -> ApiResult<()> {
+    let pool = db::pool();
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET last_used_at = NOW() WHERE id = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_split() {
+    assert_eq!(
+      ApiKey::split("avk_abcd1234_secret"),
+      Some(("abcd1234", "secret"))
+    );
+    assert_eq!(ApiKey::split("avk_abcd1234_"), None);
+    assert_eq!(ApiKey::split("avk_short_secret"), None);
+    assert_eq!(ApiKey::split("abcd1234_secret"), None);
+  }
+
+  #[test]
+  fn test_verify_secret() {
+    let key_hash = ApiKey::hash_secret("secret");
+    assert_eq!(key_hash.len(), 64);
+    assert!(ApiKey::verify_secret("secret", &key_hash));
+    assert!(!ApiKey::verify_secret("secreT", &key_hash));
+    assert!(!ApiKey::verify_secret("secret", &key_hash[..63]));
+  }
+
+  #[test]
+  fn test_scope_permissions() {
+    let api_key = ApiKey {
+      id: Uuid::new_v4(),
+      email: "test@example.com".to_string(),
+      name: "test".to_string(),
+      prefix: "abcd1234".to_string(),
+      scopes: vec![ApiKeyScope::ManageAirports],
+      expires_at: None,
+      last_used_at: None,
+      revoked_at: None,
+      created_at: Utc::now(),
+    };
+    assert!(api_key.allows(Permission::AirportsWrite));
+    assert!(api_key.allows(Permission::WeatherRead));
+    assert!(!api_key.allows(Permission::UsersManage));
+  }
+}
@@ -0,0 +1,381 @@
+use std::sync::OnceLock;
+use chrono::{DateTime, Duration, Utc};
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+use crate::db::redis_async_connection;
+use crate::error::{ApiResult, Error};
+use super::{csprng, hash, verify_hash};
+
+const ACCOUNT_FAILURES_PREFIX: &str = "login:failures:account:";
+const ADDRESS_FAILURES_PREFIX: &str = "login:failures:account_address:";
+const IP_FAILURES_PREFIX: &str = "login:failures:ip:";
+const ACCOUNT_LOCK_PREFIX: &str = "login:lock:account:";
+const ADDRESS_LOCK_PREFIX: &str = "login:lock:account_address:";
+/// Addresses with failures against an account, so an administrator can see and clear them
+const ACCOUNT_ADDRESSES_PREFIX: &str = "login:addresses:account:";
+const IP_LOCK_PREFIX: &str = "login:lock:ip:";
+const RESET_ACCOUNT_PREFIX: &str = "password_reset:account:";
+const RESET_IP_PREFIX: &str = "password_reset:ip:";
+const MAX_ACCOUNT_RESETS: u64 = 3;
+const MAX_IP_RESETS: u64 = 20;
+const RESET_WINDOW: i64 = 3600; // (In seconds) 1 hour
+const DEFAULT_MAX_ACCOUNT_FAILURES: u64 = 20;
+const DEFAULT_MAX_ACCOUNT_ADDRESS_FAILURES: u64 = 5;
+const DEFAULT_MAX_IP_FAILURES: u64 = 50;
+const DEFAULT_FAILURE_WINDOW: i64 = 900; // (In seconds) 15 minutes
+const DEFAULT_LOCKOUT_DURATION: i64 = 900; // (In seconds) 15 minutes
+const FREE_ATTEMPTS: u64 = 2;
+const BASE_DELAY: u64 = 250; // (In milliseconds) Doubled for every further failure
+const MAX_DELAY: u64 = 4000; // (In milliseconds)
+
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+/// Limits read from the `LOGIN_*` environment variables
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+  /// Failed logins for one account, across addresses, before the account is locked
+  pub max_account_failures: u64,
+  /// Failed logins for one account from one address before that address is locked out of it
+  pub max_account_address_failures: u64,
+  /// Failed logins from one address, across accounts, before it is locked
+  pub max_ip_failures: u64,
+  /// Seconds a failure is counted for
+  pub failure_window: i64,
+  /// Seconds a lockout lasts
+  pub lockout_duration: i64,
+}
+
+impl LockoutPolicy {
+  pub fn from_env() -> Self {
+    fn var<T: std::str::FromStr>(key: &str, default: T) -> T {
+      std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse::<T>().ok())
+        .unwrap_or(default)
+    }
+    Self {
+      max_account_failures: var("LOGIN_MAX_ACCOUNT_FAILURES", DEFAULT_MAX_ACCOUNT_FAILURES),
+      max_account_address_failures: var(
+        "LOGIN_MAX_ACCOUNT_ADDRESS_FAILURES",
+        DEFAULT_MAX_ACCOUNT_ADDRESS_FAILURES,
+      ),
+      max_ip_failures: var("LOGIN_MAX_IP_FAILURES", DEFAULT_MAX_IP_FAILURES),
+      failure_window: var("LOGIN_FAILURE_WINDOW", DEFAULT_FAILURE_WINDOW),
+      lockout_duration: var("LOGIN_LOCKOUT_DURATION", DEFAULT_LOCKOUT_DURATION),
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LockoutStatus {
+  pub email: String,
+  /// Failures across every address
+  pub failures: u64,
+  /// Locked for every address, or for at least one
+  pub locked: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub locked_until: Option<DateTime<Utc>>,
+  /// Addresses currently locked out of the account
+  pub locked_addresses: Vec<String>,
+}
+
+/// Failed login tracking per account, per account and client address, and per client address
+/// alone. Password and two-factor failures count alike.
+pub struct LoginThrottle;
+
+impl LoginThrottle {
+  fn account(email: &str) -> String {
+    email.trim().to_lowercase()
+  }
+
+  /// Key for an account as seen from one address
+  fn address_key(prefix: &str, account: &str, ip_address: &str) -> String {
+    format!("{}{}:{}", prefix, account, ip_address)
+  }
+
+  /// Reject the attempt while the account, the account from this address or the address is
+  /// locked, otherwise wait out the delay earned by earlier failures against the account
+  pub async fn check(email: &str, ip_address: &str) -> ApiResult<()> {
+    let mut conn = redis_async_connection().await?;
+    let account = Self::account(email);
+    let (account_locked, address_locked, ip_locked, failures): (bool, bool, bool, Option<u64>) =
+      redis::pipe()
+        .exists(format!("{}{}", ACCOUNT_LOCK_PREFIX, account))
+        .exists(Self::address_key(ADDRESS_LOCK_PREFIX, &account, ip_address))
+        .exists(format!("{}{}", IP_LOCK_PREFIX, ip_address))
+        .get(format!("{}{}", ACCOUNT_FAILURES_PREFIX, account))
+        .query_async(&mut conn)
+        .await?;
+    if account_locked || address_locked || ip_locked {
+      return Err(Error::new(
+        429,
+        "Too many failed login attempts, try again later".to_string(),
+      ));
+    }
+
+    let delay = Self::delay(failures.unwrap_or(0));
+    if delay > 0 {
+      tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+    }
+    Ok(())
+  }
+
+  /// Milliseconds to wait before checking the password after the given number of failures
+  fn delay(failures: u64) -> u64 {
+    if failures <= FREE_ATTEMPTS {
+      return 0;
+    }
+    let exponent = (failures - FREE_ATTEMPTS - 1).min(16) as u32;
+    (BASE_DELAY << exponent).min(MAX_DELAY)
+  }
+
+  /// Lock `key` for the lockout duration and clear the counter that reached its limit
+  async fn lock(
+    conn: &mut MultiplexedConnection,
+    key: String,
+    counter: &str,
+    duration: u64,
+  ) -> ApiResult<()> {
+    let result: RedisResult<()> = redis::pipe()
+      .set_ex(key, 1, duration)
+      .ignore()
+      .del(counter)
+      .ignore()
+      .query_async(conn)
+      .await;
+    result?;
+    Ok(())
+  }
+
+  /// Count a failed attempt, returning true when it caused a lockout
+  pub async fn record_failure(email: &str, ip_address: &str) -> ApiResult<bool> {
+    let policy = LockoutPolicy::from_env();
+    let mut conn = redis_async_connection().await?;
+    let account = Self::account(email);
+    let account_key = format!("{}{}", ACCOUNT_FAILURES_PREFIX, account);
+    let address_key = Self::address_key(ADDRESS_FAILURES_PREFIX, &account, ip_address);
+    let ip_key = format!("{}{}", IP_FAILURES_PREFIX, ip_address);
+    let addresses_key = format!("{}{}", ACCOUNT_ADDRESSES_PREFIX, account);
+    let lockout_duration = policy.lockout_duration.max(1) as u64;
+
+    let (account_failures, address_failures, ip_failures): (u64, u64, u64) = redis::pipe()
+      .incr(&account_key, 1)
+      .incr(&address_key, 1)
+      .incr(&ip_key, 1)
+      .sadd(&addresses_key, ip_address)
+      .ignore()
+      .expire(
+        &addresses_key,
+        policy.failure_window + lockout_duration as i64,
+      )
+      .ignore()
+      .query_async(&mut conn)
+      .await?;
+
+    // The window starts with the first failure rather than sliding with every attempt
+    let started: Vec<&String> = [
+      (&account_key, account_failures),
+      (&address_key, address_failures),
+      (&ip_key, ip_failures),
+    ]
+    .into_iter()
+    .filter(|(_, failures)| *failures == 1)
+    .map(|(key, _)| key)
+    .collect();
+    if !started.is_empty() {
+      let mut pipe = redis::pipe();
+      for key in started {
+        pipe.expire(key, policy.failure_window).ignore();
+      }
+      let result: RedisResult<()> = pipe.query_async(&mut conn).await;
+      result?;
+    }
+
+    let mut locked = false;
+    if account_failures >= policy.max_account_failures {
+      Self::lock(
+        &mut conn,
+        format!("{}{}", ACCOUNT_LOCK_PREFIX, account),
+        &account_key,
+        lockout_duration,
+      )
+      .await?;
+      log::warn!(
+        "Account locked after {} failed logins [Email: {}] [IP Address: {}]",
+        account_failures,
+        email,
+        ip_address
+      );
+      locked = true;
+    }
+    if address_failures >= policy.max_account_address_failures {
+      Self::lock(
+        &mut conn,
+        Self::address_key(ADDRESS_LOCK_PREFIX, &account, ip_address),
+        &address_key,
+        lockout_duration,
+      )
+      .await?;
+      log::warn!(
+        "Account locked for address after {} failed logins [Email: {}] [IP Address: {}]",
+        address_failures,
+        email,
+        ip_address
+      );
+      locked = true;
+    }
+    if ip_failures >= policy.max_ip_failures {
+      Self::lock(
+        &mut conn,
+        format!("{}{}", IP_LOCK_PREFIX, ip_address),
+        &ip_key,
+        lockout_duration,
+      )
+      .await?;
+      log::warn!(
+        "Address locked after {} failed logins [IP Address: {}]",
+        ip_failures,
+        ip_address
+      );
+      locked = true;
+    }
+    Ok(locked)
+  }
+
+  /// Forget the address's failures against the account once a login is complete. Failures
+  /// across addresses run out with their window, so a login cannot reset them for an attacker
+  /// rotating addresses.
+  pub async fn record_success(email: &str, ip_address: &str) -> ApiResult<()> {
+    let mut conn = redis_async_connection().await?;
+    let key = Self::address_key(ADDRESS_FAILURES_PREFIX, &Self::account(email), ip_address);
+    let result: RedisResult<()> = conn.del(key).await;
+    result?;
+    Ok(())
+  }
+
+  pub async fn status(email: &str) -> ApiResult<LockoutStatus> {
+    let mut conn = redis_async_connection().await?;
+    let account = Self::account(email);
+    let (failures, account_ttl, addresses): (Option<u64>, i64, Vec<String>) = redis::pipe()
+      .get(format!("{}{}", ACCOUNT_FAILURES_PREFIX, account))
+      .ttl(format!("{}{}", ACCOUNT_LOCK_PREFIX, account))
+      .smembers(format!("{}{}", ACCOUNT_ADDRESSES_PREFIX, account))
+      .query_async(&mut conn)
+      .await?;
+    let mut pipe = redis::pipe();
+    for ip_address in &addresses {
+      pipe.ttl(Self::address_key(ADDRESS_LOCK_PREFIX, &account, ip_address));
+    }
+    let address_ttls: Vec<i64> = if addresses.is_empty() {
+      vec![]
+    } else {
+      pipe.query_async(&mut conn).await?
+    };
+
+    let ttl = address_ttls
+      .iter()
+      .copied()
+      .chain([account_ttl])
+      .max()
+      .unwrap_or(0);
+    let locked_addresses = addresses
+      .into_iter()
+      .zip(&address_ttls)
+      .filter(|(_, ttl)| **ttl > 0)
+      .map(|(ip_address, _)| ip_address)
+      .collect::<Vec<_>>();
+    Ok(LockoutStatus {
+      email: account,
+      failures: failures.unwrap_or(0),
+      locked: ttl > 0,
+      locked_until: (ttl > 0).then(|| Utc::now() + Duration::seconds(ttl)),
+      locked_addresses,
+    })
+  }
+
+  /// Clear the account's lockouts and failures, from every address, returning whether it was
+  /// locked
+  pub async fn unlock(email: &str) -> ApiResult<bool> {
+    let mut conn = redis_async_connection().await?;
+    let account = Self::account(email);
+    let addresses_key = format!("{}{}", ACCOUNT_ADDRESSES_PREFIX, account);
+    let addresses: Vec<String> = conn.smembers(&addresses_key).await?;
+    let mut pipe = redis::pipe();
+    pipe
+      .del(format!("{}{}", ACCOUNT_LOCK_PREFIX, account))
+      .del(format!("{}{}", ACCOUNT_FAILURES_PREFIX, account))
+      .ignore();
+    for ip_address in &addresses {
+      pipe
+        .del(Self::address_key(ADDRESS_LOCK_PREFIX, &account, ip_address))
+        .del(Self::address_key(
+          ADDRESS_FAILURES_PREFIX,
+          &account,
+          ip_address,
+        ))
+        .ignore();
+    }
+    pipe.del(&addresses_key).ignore();
+    let unlocked: Vec<u64> = pipe.query_async(&mut conn).await?;
+    Ok(unlocked.iter().any(|deleted| *deleted > 0))
+  }
+
+  /// Spend the same time as a real password check when the account does not exist
+  pub fn verify_dummy(password: &str) {
+    let dummy_hash = DUMMY_HASH.get_or_init(|| hash(&csprng(32)).unwrap_or_default());
+    let _ = verify_hash(password, dummy_hash);
+  }
+}
+
+/// Password reset requests per account and per client address, so the endpoint cannot flood
+/// an inbox or the mail server
+pub struct ResetThrottle;
+
+impl ResetThrottle {
+  /// Count a reset request. Fails with 429 once the address has made too many, and returns
+  /// false once the account has had too many mails so the request is silently dropped.
+  pub async fn check(email: &str, ip_address: &str) -> ApiResult<bool> {
+    let mut conn = redis_async_connection().await?;
+    let account_key = format!("{}{}", RESET_ACCOUNT_PREFIX, LoginThrottle::account(email));
+    let ip_key = format!("{}{}", RESET_IP_PREFIX, ip_address);
+    let (account_requests, ip_requests): (u64, u64) = redis::pipe()
+      .incr(&account_key, 1)
+      .incr(&ip_key, 1)
+      .query_async(&mut conn)
+      .await?;
+    if account_requests == 1 || ip_requests == 1 {
+      let mut pipe = redis::pipe();
+      if account_requests == 1 {
+        pipe.expire(&account_key, RESET_WINDOW).ignore();
+      }
+      if ip_requests == 1 {
+        pipe.expire(&ip_key, RESET_WINDOW).ignore();
+      }
+      let result: RedisResult<()> = pipe.query_async(&mut conn).await;
+      result?;
+    }
+
+    if ip_requests > MAX_IP_RESETS {
+      return Err(Error::new(
+        429,
+        "Too many password reset requests, try again later".to_string(),
+      ));
+    }
+    Ok(account_requests <= MAX_ACCOUNT_RESETS)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_delay() {
+    assert_eq!(LoginThrottle::delay(0), 0);
+    assert_eq!(LoginThrottle::delay(FREE_ATTEMPTS), 0);
+    assert_eq!(LoginThrottle::delay(FREE_ATTEMPTS + 1), BASE_DELAY);
+    assert_eq!(LoginThrottle::delay(FREE_ATTEMPTS + 2), BASE_DELAY * 2);
+    assert_eq!(LoginThrottle::delay(1000), MAX_DELAY);
+  }
+}
@@ -6,15 +6,29 @@ use rand::distr::Alphanumeric;
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
 
+mod api_key;
+mod lockout;
 mod model;
+mod network;
+mod oidc;
+mod permission;
 mod routes;
 mod session;
+mod token;
+mod totp;
 
+pub use api_key::*;
+pub use lockout::*;
 pub use model::*;
+pub use network::*;
+pub use oidc::*;
+pub use permission::*;
 pub use session::*;
+pub use token::*;
+pub use totp::*;
 pub use routes::init_routes;
 
-use crate::error::{Error, ApiResult};
+use crate::error::ApiResult;
 
 pub fn csprng(take: usize) -> String {
   // Generate a CSPRNG 128-bit (16 byte) ID using alphanumeric characters (a-z, A-Z, 0-9)
@@ -52,17 +66,6 @@ pub fn verify_hash(string: &str, hashed_string: &str) -> bool {
     .is_ok()
 }
 
-pub fn verify_role(auth: &Auth, role: &str) -> ApiResult<()> {
-  if auth.user.role == role {
-    Ok(())
-  } else {
-    Err(Error {
-      status: 403,
-      details: "User does not have permission to perform this action.".to_string(),
-    })
-  }
-}
-
 #[cfg(test)]
 mod tests {
   use super::*;
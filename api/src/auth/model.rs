@@ -1,63 +1,146 @@
 use std::future::Future;
+use std::ops::Deref;
 use std::pin::Pin;
 
 use actix_web::{FromRequest, Error as ActixError, HttpRequest, dev::Payload, http};
 use serde::{Serialize, Deserialize};
-use crate::{error::Error, users::User};
-use super::{Session, SESSION_COOKIE_NAME};
+use crate::{error::Error, roles::Role, users::User};
+use super::{client_ip, ApiKey, Permission, Session, SESSION_COOKIE_NAME};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Auth {
   pub session_id: Option<String>,
-  pub api_key: Option<String>,
+  pub api_key: Option<ApiKey>,
   pub user: User,
+  /// Permissions of the user's role, narrowed to the API key scopes when one is used
+  pub permissions: Vec<Permission>,
 }
 
-impl FromRequest for Auth {
+impl Auth {
+  async fn new(
+    session_id: Option<String>,
+    api_key: Option<ApiKey>,
+    user: User,
+  ) -> Result<Self, Error> {
+    if user.is_disabled() {
+      return Err(Error::new(403, "Account is disabled".to_string()));
+    }
+    let mut permissions = Role::permissions(&user.role).await?;
+    // Nothing is granted to the enrolment endpoints until the required 2FA is enabled
+    if user.is_pending_two_factor() {
+      permissions.clear();
+    }
+    if let Some(api_key) = &api_key {
+      permissions.retain(|permission| api_key.allows(*permission));
+    }
+    Ok(Self {
+      session_id,
+      api_key,
+      user,
+      permissions,
+    })
+  }
+
+  pub fn has_permission(&self, permission: Permission) -> bool {
+    self.permissions.contains(&permission)
+  }
+}
+
+/// Authenticated browser session, account and session endpoints cannot be used with API keys
+/// whatever their scopes
+pub struct SessionAuth {
+  pub auth: Auth,
+}
+
+impl Deref for SessionAuth {
+  type Target = Auth;
+
+  fn deref(&self) -> &Self::Target {
+    &self.auth
+  }
+}
+
+impl FromRequest for SessionAuth {
   type Error = ActixError;
-  type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+  type Future = AuthFuture<Self>;
+
+  fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+    let auth = Auth::from_request(req, payload);
+    Box::pin(async move {
+      let auth = auth.await?;
+      reject_api_key(&auth)?;
+      Ok(SessionAuth { auth })
+    })
+  }
+}
+
+/// Browser session that may still have to enrol in a required 2FA, only for the enrolment
+/// endpoints and logout
+pub struct EnrollingAuth {
+  pub auth: Auth,
+}
+
+impl Deref for EnrollingAuth {
+  type Target = Auth;
+
+  fn deref(&self) -> &Self::Target {
+    &self.auth
+  }
+}
+
+impl FromRequest for EnrollingAuth {
+  type Error = ActixError;
+  type Future = AuthFuture<Self>;
 
   fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+    let auth = Auth::authenticate(req);
+    Box::pin(async move {
+      let auth = auth.await?;
+      reject_api_key(&auth)?;
+      Ok(EnrollingAuth { auth })
+    })
+  }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+  req
+    .headers()
+    .get(http::header::AUTHORIZATION)
+    .and_then(|h| h.to_str().ok())
+    .and_then(|h| h.strip_prefix("Bearer "))
+    .map(|token| token.trim().to_string())
+}
+
+type AuthFuture<T> = Pin<Box<dyn Future<Output = Result<T, ActixError>>>>;
+
+impl Auth {
+  /// Authenticate by API key or session, including users who still have to enrol in a
+  /// required 2FA
+  fn authenticate(req: &HttpRequest) -> AuthFuture<Self> {
     // Check for API key
-    match req
-      .headers()
-      .get(http::header::AUTHORIZATION)
-      .map(|h| h.to_str().unwrap().split_at(7).1.to_string())
-    {
-      Some(key_id) => {
-        let fut = async move {
-          // Check if the Session API key exists
-          let api_key = match Session::get(&key_id).await {
-            Ok(session) => session,
-            Err(err) => {
-              log::error!("Invalid session auth attempt: {}", err);
-              return Err(Error::new(401, "API Key does not exist".to_string()).into());
-            }
-          };
-          match User::select(&api_key.email).await {
-            Some(user) => Ok(Auth {
-              session_id: None,
-              api_key: Some(key_id),
-              user,
-            }),
-            None => Err(Error::new(404, format!("User {} not found", api_key.email)).into()),
+    if let Some(token) = bearer_token(req).filter(|token| ApiKey::is_api_key(token)) {
+      let fut = async move {
+        let api_key = match ApiKey::authenticate(&token).await {
+          Ok(api_key) => api_key,
+          Err(err) => {
+            log::error!("Invalid API key auth attempt: {}", err);
+            return Err(Error::new(401, "Invalid API key".to_string()).into());
           }
         };
-        return Box::pin(fut);
-      }
-      None => {}
-    };
+        match User::select(&api_key.email).await {
+          Some(user) => Ok(Auth::new(None, Some(api_key), user).await?),
+          None => Err(Error::new(404, format!("User {} not found", api_key.email)).into()),
+        }
+      };
+      return Box::pin(fut);
+    }
 
     // Check for session
     let session_id = match req
       .cookie(SESSION_COOKIE_NAME)
       .map(|c| c.value().to_string())
-      .or_else(|| {
-        req
-          .headers()
-          .get(http::header::AUTHORIZATION)
-          .map(|h| h.to_str().unwrap().split_at(7).1.to_string())
-      }) {
+      .or_else(|| bearer_token(req))
+    {
       Some(id) => id,
       None => {
         let fut = async {
@@ -74,22 +157,62 @@ impl FromRequest for Auth {
     };
 
     // Get IP address from request
-    let ip_address = req.peer_addr().unwrap().ip().to_string();
+    let ip_address = client_ip(req);
 
     // Verify the session
     let fut = async move {
       match Session::verify(&session_id, &ip_address).await {
-        Ok(session) => match User::select(&session.email).await {
-          Some(user) => Ok(Auth {
-            session_id: Some(session_id),
-            api_key: None,
-            user,
-          }),
-          None => Err(Error::new(404, format!("User {} not found", session.email)).into()),
-        },
+        Ok(mut session) => {
+          if let Err(err) = session.touch().await {
+            if err.status == 401 {
+              return Err(err.into());
+            }
+            log::error!("Unable to update session last used time: {}", err);
+          }
+          match User::select(&session.email).await {
+            Some(user) => Ok(Auth::new(Some(session_id), None, user).await?),
+            None => Err(Error::new(404, format!("User {} not found", session.email)).into()),
+          }
+        }
         Err(err) => Err(err.into()),
       }
     };
     Box::pin(fut)
   }
 }
+
+impl FromRequest for Auth {
+  type Error = ActixError;
+  type Future = AuthFuture<Self>;
+
+  /// Users with a required 2FA can only reach the enrolment endpoints until it is enabled
+  fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+    let auth = Auth::authenticate(req);
+    Box::pin(async move {
+      let auth = auth.await?;
+      if auth.user.is_pending_two_factor() {
+        return Err(
+          Error::new(
+            403,
+            "Two-factor authentication must be enabled first".to_string(),
+          )
+          .into(),
+        );
+      }
+      Ok(auth)
+    })
+  }
+}
+
+fn reject_api_key(auth: &Auth) -> Result<(), ActixError> {
+  match auth.api_key {
+    Some(_) => Err(
+      Error::new(
+        403,
+        "Account endpoints require a browser session".to_string(),
+      )
+      .into(),
+    ),
+    None => Ok(()),
+  }
+}
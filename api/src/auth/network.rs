@@ -0,0 +1,162 @@
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use actix_web::HttpRequest;
+
+const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+static TRUSTED_PROXIES: OnceLock<Vec<IpNetwork>> = OnceLock::new();
+
+/// An address range such as `10.0.0.0/8`, a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+  address: IpAddr,
+  prefix: u8,
+}
+
+impl IpNetwork {
+  pub fn parse(network: &str) -> Option<Self> {
+    let (address, prefix) = match network.trim().split_once('/') {
+      Some((address, prefix)) => (
+        address.parse::<IpAddr>().ok()?,
+        Some(prefix.parse::<u8>().ok()?),
+      ),
+      None => (network.trim().parse::<IpAddr>().ok()?, None),
+    };
+    let max_prefix = match address {
+      IpAddr::V4(_) => 32,
+      IpAddr::V6(_) => 128,
+    };
+    let prefix = prefix.unwrap_or(max_prefix);
+    if prefix > max_prefix {
+      return None;
+    }
+    Some(Self { address, prefix })
+  }
+
+  pub fn contains(&self, ip: &IpAddr) -> bool {
+    match (self.address, ip.to_canonical()) {
+      (IpAddr::V4(network), IpAddr::V4(ip)) => {
+        let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+        u32::from(network) & mask == u32::from(ip) & mask
+      }
+      (IpAddr::V6(network), IpAddr::V6(ip)) => {
+        let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+        u128::from(network) & mask == u128::from(ip) & mask
+      }
+      _ => false,
+    }
+  }
+}
+
+/// Proxies allowed to report the client address, from the comma separated `TRUSTED_PROXIES`
+fn trusted_proxies() -> &'static [IpNetwork] {
+  TRUSTED_PROXIES.get_or_init(|| {
+    let proxies = std::env::var("TRUSTED_PROXIES").unwrap_or_default();
+    proxies
+      .split(',')
+      .filter(|proxy| !proxy.trim().is_empty())
+      .filter_map(|proxy| match IpNetwork::parse(proxy) {
+        Some(network) => Some(network),
+        None => {
+          log::warn!("Ignoring invalid trusted proxy '{}'", proxy);
+          None
+        }
+      })
+      .collect()
+  })
+}
+
+fn is_trusted(proxies: &[IpNetwork], ip: &IpAddr) -> bool {
+  proxies.iter().any(|network| network.contains(ip))
+}
+
+/// Walk the forwarded chain from the nearest hop, the first untrusted address is the client
+fn resolve_client_ip(peer: IpAddr, forwarded_for: Option<&str>, proxies: &[IpNetwork]) -> IpAddr {
+  if !is_trusted(proxies, &peer) {
+    return peer;
+  }
+  let Some(forwarded_for) = forwarded_for else {
+    return peer;
+  };
+
+  let mut client = peer;
+  for hop in forwarded_for.rsplit(',') {
+    match hop.trim().parse::<IpAddr>() {
+      Ok(ip) => {
+        client = ip;
+        if !is_trusted(proxies, &ip) {
+          break;
+        }
+      }
+      // A malformed entry could have been written by anyone, stop trusting the chain
+      Err(_) => break,
+    }
+  }
+  client
+}
+
+/// Address of the client making the request, honouring `X-Forwarded-For` from trusted proxies
+pub fn client_ip(req: &HttpRequest) -> String {
+  let Some(peer) = req.peer_addr().map(|addr| addr.ip()) else {
+    return "unknown".to_string();
+  };
+  let forwarded_for = req
+    .headers()
+    .get(FORWARDED_FOR_HEADER)
+    .and_then(|header| header.to_str().ok());
+  resolve_client_ip(peer, forwarded_for, trusted_proxies()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_network_contains() {
+    let network = IpNetwork::parse("10.1.0.0/16").unwrap();
+    assert!(network.contains(&"10.1.200.3".parse().unwrap()));
+    assert!(!network.contains(&"10.2.0.1".parse().unwrap()));
+    assert!(network.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+
+    let host = IpNetwork::parse("192.168.1.10").unwrap();
+    assert!(host.contains(&"192.168.1.10".parse().unwrap()));
+    assert!(!host.contains(&"192.168.1.11".parse().unwrap()));
+
+    let any = IpNetwork::parse("0.0.0.0/0").unwrap();
+    assert!(any.contains(&"203.0.113.1".parse().unwrap()));
+
+    assert!(IpNetwork::parse("fd00::/8")
+      .unwrap()
+      .contains(&"fd12::1".parse().unwrap()));
+    assert_eq!(IpNetwork::parse("10.0.0.0/33"), None);
+    assert_eq!(IpNetwork::parse("proxy"), None);
+  }
+
+  #[test]
+  fn test_resolve_client_ip() {
+    let proxies = vec![IpNetwork::parse("172.16.0.0/12").unwrap()];
+    let proxy: IpAddr = "172.18.0.2".parse().unwrap();
+    let client: IpAddr = "203.0.113.7".parse().unwrap();
+
+    assert_eq!(
+      resolve_client_ip(proxy, Some("203.0.113.7"), &proxies),
+      client
+    );
+    // A client supplied entry left of the real address is ignored
+    assert_eq!(
+      resolve_client_ip(
+        proxy,
+        Some("198.51.100.1, 203.0.113.7, 172.18.0.5"),
+        &proxies
+      ),
+      client
+    );
+    assert_eq!(resolve_client_ip(proxy, None, &proxies), proxy);
+    // Untrusted peers cannot spoof their address
+    assert_eq!(
+      resolve_client_ip(client, Some("198.51.100.1"), &proxies),
+      client
+    );
+    assert_eq!(resolve_client_ip(proxy, Some("garbage"), &proxies), proxy);
+  }
+}
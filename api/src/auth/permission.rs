@@ -0,0 +1,176 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::str::FromStr;
+use actix_web::{dev::Payload, Error as ActixError, FromRequest, HttpRequest};
+use serde::{Deserialize, Serialize};
+use crate::error::{ApiResult, Error};
+use super::Auth;
+
+/// Actions a role can be granted; roles themselves are stored in the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Permission {
+  #[serde(rename = "weather:read")]
+  WeatherRead,
+  #[serde(rename = "weather:refresh")]
+  WeatherRefresh,
+  #[serde(rename = "airports:write")]
+  AirportsWrite,
+  #[serde(rename = "users:manage")]
+  UsersManage,
+  #[serde(rename = "roles:manage")]
+  RolesManage,
+  #[serde(rename = "system:read")]
+  SystemRead,
+  #[serde(rename = "settings:manage")]
+  SettingsManage,
+  #[serde(rename = "webhooks:manage")]
+  WebhooksManage,
+  #[serde(rename = "audit:read")]
+  AuditRead,
+}
+
+impl Permission {
+  pub const ALL: [Permission; 9] = [
+    Permission::WeatherRead,
+    Permission::WeatherRefresh,
+    Permission::AirportsWrite,
+    Permission::UsersManage,
+    Permission::RolesManage,
+    Permission::SystemRead,
+    Permission::SettingsManage,
+    Permission::WebhooksManage,
+    Permission::AuditRead,
+  ];
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Permission::WeatherRead => "weather:read",
+      Permission::WeatherRefresh => "weather:refresh",
+      Permission::AirportsWrite => "airports:write",
+      Permission::UsersManage => "users:manage",
+      Permission::RolesManage => "roles:manage",
+      Permission::SystemRead => "system:read",
+      Permission::SettingsManage => "settings:manage",
+      Permission::WebhooksManage => "webhooks:manage",
+      Permission::AuditRead => "audit:read",
+    }
+  }
+}
+
+impl FromStr for Permission {
+  type Err = ();
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Permission::ALL
+      .into_iter()
+      .find(|permission| permission.as_str() == s)
+      .ok_or(())
+  }
+}
+
+impl Display for Permission {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+/// Marker for a permission checked by the [`Permitted`] extractor
+pub trait RequiredPermission {
+  const PERMISSION: Permission;
+}
+
+macro_rules! required_permissions {
+  ($($name:ident),* $(,)?) => {
+    /// Marker types for [`Permitted`], one per [`Permission`]
+    #[allow(dead_code)]
+    pub mod require {
+      $(
+        pub struct $name;
+
+        impl super::RequiredPermission for $name {
+          const PERMISSION: super::Permission = super::Permission::$name;
+        }
+      )*
+    }
+  };
+}
+
+required_permissions!(
+  WeatherRead,
+  WeatherRefresh,
+  AirportsWrite,
+  UsersManage,
+  RolesManage,
+  SystemRead,
+  SettingsManage,
+  WebhooksManage,
+  AuditRead,
+);
+
+/// Authenticated request that holds the permission `P`, rejected with a 403 otherwise
+///
+/// ```ignore
+/// async fn delete_airports(_: Permitted<require::AirportsWrite>) -> HttpResponse
+/// ```
+pub struct Permitted<P: RequiredPermission> {
+  pub auth: Auth,
+  permission: PhantomData<P>,
+}
+
+impl<P: RequiredPermission> Deref for Permitted<P> {
+  type Target = Auth;
+
+  fn deref(&self) -> &Self::Target {
+    &self.auth
+  }
+}
+
+impl<P: RequiredPermission + 'static> FromRequest for Permitted<P> {
+  type Error = ActixError;
+  type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+  fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+    let auth = Auth::from_request(req, payload);
+    Box::pin(async move {
+      let auth = auth.await?;
+      verify_permission(&auth, P::PERMISSION)?;
+      Ok(Permitted {
+        auth,
+        permission: PhantomData,
+      })
+    })
+  }
+}
+
+pub fn verify_permission(auth: &Auth, permission: Permission) -> ApiResult<()> {
+  if auth.has_permission(permission) {
+    Ok(())
+  } else {
+    Err(Error::new(
+      403,
+      format!(
+        "Missing the '{}' permission to perform this action.",
+        permission
+      ),
+    ))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_permission_strings() {
+    for permission in Permission::ALL {
+      assert_eq!(Permission::from_str(permission.as_str()), Ok(permission));
+      assert_eq!(
+        serde_json::to_string(&permission).unwrap(),
+        format!("\"{}\"", permission)
+      );
+    }
+    assert!(Permission::from_str("weather:write").is_err());
+  }
+}
@@ -1,18 +1,80 @@
-use actix_web::{post, web, HttpResponse, ResponseError, HttpRequest, put, get};
+use actix_web::{post, web, http, HttpResponse, ResponseError, HttpRequest, put, get, delete};
+use uuid::Uuid;
 use crate::{
-  auth::{verify_hash, Session, SESSION_COOKIE_NAME},
-  error::Error,
-  users::{LoginRequest, RegisterRequest, User, UserResponse},
+  audit::{self, AuditAction, AuditEvent},
+  auth::{
+    client_ip, oidc, session, verify_hash, AccountToken, ApiKey, CreateApiKeyRequest,
+    LoginChallenge, LoginChallengeRequest, LoginChallengeResponse, LoginThrottle, Oidc,
+    OidcCallback, RecoveryCodes, ResetThrottle, Session, SessionResponse, TokenPurpose, Totp,
+    TotpCodeRequest, SESSION_COOKIE_NAME,
+  },
+  error::{ApiResult, Error},
+  mail::{self, Mail},
+  settings::Settings,
+  users::{
+    LoginRequest, PasswordResetConfirmRequest, PasswordResetRequest, RegisterRequest,
+    validate_password, ResendVerificationRequest, User, UserResponse, VerifyEmailRequest,
+  },
+  AppState,
 };
 
-use crate::auth::Auth;
+use crate::auth::{EnrollingAuth, SessionAuth};
 use crate::users::UpdateUser;
 
+const DEFAULT_EMAIL_VERIFICATION_TTL: i64 = 86400; // (In seconds) 24 hours
+
+fn email_verification_ttl() -> i64 {
+  std::env::var("EMAIL_VERIFICATION_TTL")
+    .ok()
+    .and_then(|ttl| ttl.parse::<i64>().ok())
+    .unwrap_or(DEFAULT_EMAIL_VERIFICATION_TTL)
+}
+
+async fn send_email_verification(email: &str) -> ApiResult<()> {
+  let ttl = email_verification_ttl();
+  let token = AccountToken::issue(
+    email,
+    TokenPurpose::EmailVerification,
+    chrono::Duration::seconds(ttl),
+  )
+  .await?;
+  mail::spawn_send(Mail::email_verification(email, &token, ttl));
+  Ok(())
+}
+
+/// Reject unverified users when the instance requires a verified email address
+async fn verify_email_requirement(user: &User) -> ApiResult<()> {
+  if user.is_email_verified() || !Settings::get().await?.require_email_verification {
+    return Ok(());
+  }
+  Err(Error::new(
+    403,
+    "Email address has not been verified".to_string(),
+  ))
+}
+
 #[post("/register")]
 async fn register(user: web::Json<RegisterRequest>, req: HttpRequest) -> HttpResponse {
   let register_user = user.into_inner();
   let email = register_user.email.clone();
-  let ip_address = req.peer_addr().unwrap().ip().to_string();
+  let ip_address = client_ip(&req);
+
+  match Settings::get().await {
+    Ok(settings) if !settings.open_registration => {
+      log::warn!(
+        "Registration attempt while registration is closed [Email: {}] [IP Address: {}]",
+        email,
+        ip_address
+      );
+      return ResponseError::error_response(&Error::new(
+        403,
+        "Registration is disabled".to_string(),
+      ));
+    }
+    Ok(_) => {}
+    Err(err) => return ResponseError::error_response(&err),
+  }
+
   let insert_user: User = match register_user.to_user() {
     Ok(user) => user,
     Err(err) => return ResponseError::error_response(&err),
@@ -20,6 +82,13 @@ async fn register(user: web::Json<RegisterRequest>, req: HttpRequest) -> HttpRes
 
   match insert_user.insert().await {
     Ok(user) => {
+      if let Err(err) = send_email_verification(&user.email).await {
+        log::error!(
+          "Unable to send email verification [Email: {}]: {}",
+          user.email,
+          err
+        );
+      }
       let user_response: UserResponse = user.into();
       log::info!(
         "Successful user registration [Email: {}] [IP Address: {}]",
@@ -45,53 +114,451 @@ async fn register(user: web::Json<RegisterRequest>, req: HttpRequest) -> HttpRes
   }
 }
 
+/// Create a session for a fully authenticated user and set its cookie
+async fn start_session(user: User, ip_address: &str, req: &HttpRequest) -> HttpResponse {
+  let email = user.email.clone();
+  let session = Session::default(&email, ip_address, session::user_agent(req));
+  let session_cookie = session.cookie();
+  // Save the session to the database
+  if let Err(err) = session.store().await {
+    log::error!(
+      "Login attempt failure [Email: {}] [IP Address: {}]: {}",
+      email,
+      ip_address,
+      err
+    );
+    return ResponseError::error_response(&Error::new(500, err.to_string()));
+  }
+  // Failures are only forgotten once every factor has been passed
+  if let Err(err) = LoginThrottle::record_success(&email, ip_address).await {
+    log::error!("Unable to reset failed logins: {}", err);
+  }
+  log::info!(
+    "Successful login attempt [Email: {}] [IP Address: {}]",
+    email,
+    ip_address
+  );
+  AuditEvent::new(AuditAction::Login, Some(&email), ip_address)
+    .record()
+    .await;
+  let user_response: UserResponse = user.into();
+  HttpResponse::Ok()
+    .cookie(session_cookie)
+    .json(user_response)
+}
+
 #[post("/login")]
 async fn login(request: web::Json<LoginRequest>, req: HttpRequest) -> HttpResponse {
   let email = &request.email;
-  let ip_address = req.peer_addr().unwrap().ip().to_string();
+  let ip_address = client_ip(&req);
 
-  let query_user = match User::select(&email).await {
-    Some(query_user) => query_user,
-    None => return HttpResponse::Unauthorized().finish(),
+  if let Err(err) = LoginThrottle::check(email, &ip_address).await {
+    log::warn!(
+      "Throttled login attempt [Email: {}] [IP Address: {}]: {}",
+      email,
+      ip_address,
+      err
+    );
+    AuditEvent::new(AuditAction::Login, None, &ip_address)
+      .target(email)
+      .detail("Throttled")
+      .failure()
+      .record()
+      .await;
+    return ResponseError::error_response(&err);
+  }
+
+  // Disabled accounts are rejected like a wrong password, so the response never confirms
+  // that the password was right
+  let query_user = match User::select(email).await {
+    Some(query_user)
+      if verify_hash(&request.password, &query_user.password_hash) && !query_user.is_disabled() =>
+    {
+      query_user
+    }
+    query_user => {
+      // Unknown accounts take as long to reject as wrong passwords
+      if query_user.is_none() {
+        LoginThrottle::verify_dummy(&request.password);
+      }
+      let disabled = query_user.is_some_and(|user| user.is_disabled());
+      log::error!(
+        "Invalid {}login attempt [Email: {}] [IP Address: {}]",
+        if disabled { "disabled account " } else { "" },
+        email,
+        ip_address
+      );
+      if let Err(err) = LoginThrottle::record_failure(email, &ip_address).await {
+        log::error!("Unable to record failed login: {}", err);
+      }
+      AuditEvent::new(AuditAction::Login, None, &ip_address)
+        .target(email)
+        .detail(if disabled {
+          "Account is disabled"
+        } else {
+          "Invalid credentials"
+        })
+        .failure()
+        .record()
+        .await;
+      return HttpResponse::Unauthorized().finish();
+    }
   };
 
-  if verify_hash(&request.password, &query_user.password_hash) {
-    // Create a session
-    let session = Session::default(&email, &ip_address);
-    let session_cookie = session.cookie();
-    // Save the session to the database
-    if let Err(err) = session.store().await {
+  if let Err(err) = verify_email_requirement(&query_user).await {
+    log::warn!(
+      "Unverified login attempt [Email: {}] [IP Address: {}]",
+      email,
+      ip_address
+    );
+    return ResponseError::error_response(&err);
+  }
+
+  // The session is only created once the second factor is verified
+  if query_user.totp_enabled_at.is_some() {
+    let challenge = match LoginChallenge::new(&query_user.email, &ip_address) {
+      Ok(challenge) => challenge,
+      Err(err) => return ResponseError::error_response(&err),
+    };
+    if let Err(err) = challenge.store().await {
       log::error!(
         "Login attempt failure [Email: {}] [IP Address: {}]: {}",
         email,
         ip_address,
         err
       );
-      return ResponseError::error_response(&Error::new(500, err.to_string()));
+      return ResponseError::error_response(&err);
     }
     log::info!(
-      "Successful login attempt [Email: {}] [IP Address: {}]",
+      "Two-factor challenge issued [Email: {}] [IP Address: {}]",
       email,
       ip_address
     );
-    let user_response: UserResponse = query_user.into();
-    HttpResponse::Ok()
-      .cookie(session_cookie)
-      .json(user_response)
-  } else {
+    return HttpResponse::Accepted().json(LoginChallengeResponse {
+      two_factor_required: true,
+      challenge: challenge.challenge_id,
+      expires_at: challenge.expires_at,
+    });
+  }
+  start_session(query_user, &ip_address, &req).await
+}
+
+#[get("/oidc/login")]
+async fn oidc_login(data: web::Data<AppState>) -> HttpResponse {
+  let oidc = match Oidc::new(&data.client).await {
+    Ok(oidc) => oidc,
+    Err(err) => return ResponseError::error_response(&err),
+  };
+  match oidc.authorization_url().await {
+    Ok((url, state_id)) => HttpResponse::Found()
+      .cookie(oidc::state_cookie(&state_id))
+      .insert_header((http::header::LOCATION, url))
+      .finish(),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[get("/oidc/callback")]
+async fn oidc_callback(
+  data: web::Data<AppState>,
+  callback: web::Query<OidcCallback>,
+  req: HttpRequest,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  if let Some(error) = &callback.error {
+    log::warn!(
+      "Single sign-on rejected by the identity provider [IP Address: {}]: {} {}",
+      ip_address,
+      error,
+      callback.error_description.as_deref().unwrap_or_default()
+    );
+    return ResponseError::error_response(&Error::new(401, error.to_string()));
+  }
+  let (code, state) = match (&callback.code, &callback.state) {
+    (Some(code), Some(state)) => (code, state),
+    _ => {
+      return ResponseError::error_response(&Error::new(
+        400,
+        "Missing code or state parameter".to_string(),
+      ))
+    }
+  };
+  // The state must come back to the browser that started the login
+  let bound = req
+    .cookie(oidc::STATE_COOKIE_NAME)
+    .is_some_and(|cookie| cookie.value() == state.as_str());
+  if !bound {
+    log::warn!(
+      "Single sign-on state does not match the browser [IP Address: {}]",
+      ip_address
+    );
+    return ResponseError::error_response(&Error::new(
+      400,
+      "Login state does not match this browser".to_string(),
+    ));
+  }
+
+  let oidc = match Oidc::new(&data.client).await {
+    Ok(oidc) => oidc,
+    Err(err) => return ResponseError::error_response(&err),
+  };
+  let user = match oidc.exchange(&data.client, code, state).await {
+    Ok(claims) => oidc.sign_in(&claims).await,
+    Err(err) => Err(err),
+  };
+  let user = match user {
+    Ok(user) => user,
+    Err(err) => {
+      log::error!(
+        "Invalid single sign-on attempt [IP Address: {}]: {}",
+        ip_address,
+        err
+      );
+      return ResponseError::error_response(&err);
+    }
+  };
+
+  // Same second factor as a password login, users who still have to enrol are held at
+  // enrolment by the session
+  if user.totp_enabled_at.is_some() {
+    let challenge = match LoginChallenge::new(&user.email, &ip_address) {
+      Ok(challenge) => challenge,
+      Err(err) => return ResponseError::error_response(&err),
+    };
+    if let Err(err) = challenge.store().await {
+      log::error!(
+        "Single sign-on failure [Email: {}] [IP Address: {}]: {}",
+        user.email,
+        ip_address,
+        err
+      );
+      return ResponseError::error_response(&err);
+    }
+    log::info!(
+      "Two-factor challenge issued [Email: {}] [IP Address: {}]",
+      user.email,
+      ip_address
+    );
+    let link = mail::app_link(&format!("/login/2fa?challenge={}", challenge.challenge_id));
+    return HttpResponse::Found()
+      .cookie(oidc::clear_state_cookie())
+      .insert_header((http::header::LOCATION, link))
+      .finish();
+  }
+
+  let session = Session::default(&user.email, &ip_address, session::user_agent(&req));
+  if let Err(err) = session.store().await {
     log::error!(
-      "Invalid login attempt [Email: {}] [IP Address: {}]",
+      "Single sign-on failure [Email: {}] [IP Address: {}]: {}",
+      user.email,
+      ip_address,
+      err
+    );
+    return ResponseError::error_response(&Error::new(500, err.to_string()));
+  }
+  log::info!(
+    "Successful single sign-on attempt [Email: {}] [IP Address: {}]",
+    user.email,
+    ip_address
+  );
+  AuditEvent::new(AuditAction::Login, Some(&user.email), &ip_address)
+    .detail("Single sign-on")
+    .record()
+    .await;
+  HttpResponse::Found()
+    .cookie(session.cookie())
+    .cookie(oidc::clear_state_cookie())
+    .insert_header((http::header::LOCATION, mail::app_link("/")))
+    .finish()
+}
+
+#[post("/login/2fa")]
+async fn login_two_factor(
+  request: web::Json<LoginChallengeRequest>,
+  req: HttpRequest,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+
+  let challenge = match LoginChallenge::verify(&request.challenge, &ip_address).await {
+    Ok(challenge) => challenge,
+    Err(err) => {
+      log::warn!(
+        "Invalid two-factor challenge [IP Address: {}]: {}",
+        ip_address,
+        err
+      );
+      return ResponseError::error_response(&err);
+    }
+  };
+  let email = challenge.email.clone();
+
+  // Codes are throttled together with passwords, a correct password does not reset the count
+  if let Err(err) = LoginThrottle::check(&email, &ip_address).await {
+    log::warn!(
+      "Throttled two-factor login attempt [Email: {}] [IP Address: {}]: {}",
+      email,
+      ip_address,
+      err
+    );
+    AuditEvent::new(AuditAction::Login, None, &ip_address)
+      .target(&email)
+      .detail("Throttled")
+      .failure()
+      .record()
+      .await;
+    return ResponseError::error_response(&err);
+  }
+
+  if let Err(err) = Totp::verify(&email, &request.code).await {
+    log::error!(
+      "Invalid two-factor login attempt [Email: {}] [IP Address: {}]",
+      email,
+      ip_address
+    );
+    if let Err(err) = LoginThrottle::record_failure(&email, &ip_address).await {
+      log::error!("Unable to record failed login: {}", err);
+    }
+    AuditEvent::new(AuditAction::Login, None, &ip_address)
+      .target(&email)
+      .detail("Invalid two-factor code")
+      .failure()
+      .record()
+      .await;
+    if let Err(err) = challenge.record_failure().await {
+      log::error!("Unable to record two-factor failure: {}", err);
+    }
+    return ResponseError::error_response(&err);
+  }
+  if let Err(err) = LoginChallenge::delete(&challenge.challenge_id).await {
+    log::error!("Unable to delete two-factor challenge: {}", err);
+  }
+
+  match User::select(&email).await {
+    Some(user) => start_session(user, &ip_address, &req).await,
+    None => HttpResponse::Unauthorized().finish(),
+  }
+}
+
+#[post("/2fa/enroll")]
+async fn enroll_two_factor(req: HttpRequest, auth: EnrollingAuth) -> HttpResponse {
+  let email = auth.user.email.clone();
+  let ip_address = client_ip(&req);
+  match Totp::enroll(&email).await {
+    Ok(enrollment) => {
+      log::info!(
+        "Two-factor enrolment started [Email: {}] [IP Address: {}]",
+        email,
+        ip_address
+      );
+      HttpResponse::Ok().json(enrollment)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[post("/2fa/confirm")]
+async fn confirm_two_factor(
+  request: web::Json<TotpCodeRequest>,
+  req: HttpRequest,
+  auth: EnrollingAuth,
+) -> HttpResponse {
+  let email = auth.user.email.clone();
+  let ip_address = client_ip(&req);
+  match Totp::confirm(&email, &request.code).await {
+    Ok(recovery_codes) => {
+      log::info!(
+        "Successful two-factor enrolment [Email: {}] [IP Address: {}]",
+        email,
+        ip_address
+      );
+      AuditEvent::new(AuditAction::TwoFactorEnabled, Some(&email), &ip_address)
+        .record()
+        .await;
+      HttpResponse::Ok().json(RecoveryCodes { recovery_codes })
+    }
+    Err(err) => {
+      log::warn!(
+        "Invalid two-factor enrolment attempt [Email: {}] [IP Address: {}]: {}",
+        email,
+        ip_address,
+        err
+      );
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[post("/2fa/disable")]
+async fn disable_two_factor(
+  request: web::Json<TotpCodeRequest>,
+  req: HttpRequest,
+  auth: SessionAuth,
+) -> HttpResponse {
+  let email = auth.user.email.clone();
+  let ip_address = client_ip(&req);
+  if auth.user.totp_required {
+    return ResponseError::error_response(&Error::new(
+      403,
+      "Two-factor authentication is required for this account".to_string(),
+    ));
+  }
+  if let Err(err) = Totp::verify(&email, &request.code).await {
+    log::warn!(
+      "Invalid two-factor disable attempt [Email: {}] [IP Address: {}]",
       email,
       ip_address
     );
-    HttpResponse::Unauthorized().finish()
+    AuditEvent::new(AuditAction::TwoFactorDisabled, Some(&email), &ip_address)
+      .detail("Invalid two-factor code")
+      .failure()
+      .record()
+      .await;
+    return ResponseError::error_response(&err);
+  }
+  match Totp::disable(&email).await {
+    Ok(_) => {
+      log::info!(
+        "Successful two-factor disable [Email: {}] [IP Address: {}]",
+        email,
+        ip_address
+      );
+      AuditEvent::new(AuditAction::TwoFactorDisabled, Some(&email), &ip_address)
+        .record()
+        .await;
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[post("/2fa/recovery-codes")]
+async fn regenerate_recovery_codes(
+  request: web::Json<TotpCodeRequest>,
+  req: HttpRequest,
+  auth: SessionAuth,
+) -> HttpResponse {
+  let email = auth.user.email.clone();
+  let ip_address = client_ip(&req);
+  if let Err(err) = Totp::verify(&email, &request.code).await {
+    return ResponseError::error_response(&err);
+  }
+  match Totp::regenerate_recovery_codes(&email).await {
+    Ok(recovery_codes) => {
+      log::info!(
+        "Successful recovery code regeneration [Email: {}] [IP Address: {}]",
+        email,
+        ip_address
+      );
+      HttpResponse::Ok().json(RecoveryCodes { recovery_codes })
+    }
+    Err(err) => ResponseError::error_response(&err),
   }
 }
 
 #[post("/logout")]
-async fn logout(req: HttpRequest, auth: Auth) -> HttpResponse {
-  let email = auth.user.email;
-  let ip_address = req.peer_addr().unwrap().ip().to_string();
+async fn logout(req: HttpRequest, auth: EnrollingAuth) -> HttpResponse {
+  let email = auth.user.email.clone();
+  let ip_address = client_ip(&req);
   // Delete the session from the store
   match req.cookie(SESSION_COOKIE_NAME) {
     Some(cookie) => {
@@ -126,7 +593,7 @@ async fn logout(req: HttpRequest, auth: Auth) -> HttpResponse {
 
 #[get("/session")]
 async fn validate_session(req: HttpRequest) -> HttpResponse {
-  let ip_address = req.peer_addr().unwrap().ip().to_string();
+  let ip_address = client_ip(&req);
   // Verify a session cookie exists
   match req.cookie(SESSION_COOKIE_NAME) {
     // Validate the session
@@ -145,8 +612,8 @@ async fn validate_session(req: HttpRequest) -> HttpResponse {
       };
       let email = &session.email;
       let query_user = match User::select(&email).await {
-        Some(query_user) => query_user,
-        None => {
+        Some(query_user) if !query_user.is_disabled() => query_user,
+        _ => {
           return HttpResponse::Unauthorized()
             .cookie(Session::empty_cookie())
             .finish()
@@ -175,10 +642,13 @@ async fn validate_session(req: HttpRequest) -> HttpResponse {
 async fn change_password(
   password: web::Json<String>,
   req: HttpRequest,
-  auth: Auth,
+  auth: SessionAuth,
 ) -> HttpResponse {
-  let ip_address = req.peer_addr().unwrap().ip().to_string();
-  let email = auth.user.email;
+  let ip_address = client_ip(&req);
+  let email = auth.user.email.clone();
+  if let Err(err) = validate_password(&password) {
+    return ResponseError::error_response(&err);
+  }
 
   if let None = User::select(&email).await {
     return HttpResponse::Unauthorized().finish();
@@ -194,12 +664,24 @@ async fn change_password(
 
   match update_user.update(&email).await {
     Ok(user) => {
+      // Anyone holding another session may have known the old password
+      if let Err(err) = Session::delete_others(&email, auth.session_id.as_deref()).await {
+        log::error!(
+          "Unable to revoke sessions after password change [Email: {}]: {}",
+          &email,
+          err
+        );
+        return ResponseError::error_response(&err);
+      }
       let response: UserResponse = user.into();
       log::info!(
         "Successful password change attempt [Email: {}] [IP Address: {}]",
         &email,
         ip_address
       );
+      AuditEvent::new(AuditAction::PasswordChanged, Some(&email), &ip_address)
+        .record()
+        .await;
       HttpResponse::Ok().json(response)
     }
     Err(err) => {
@@ -209,15 +691,332 @@ async fn change_password(
         ip_address,
         err
       );
+      AuditEvent::new(AuditAction::PasswordChanged, Some(&email), &ip_address)
+        .detail(&err.details)
+        .failure()
+        .record()
+        .await;
       ResponseError::error_response(&Error::new(500, err.to_string()))
     }
   }
 }
 
 #[post("/password-reset")]
-async fn password_reset(req: HttpRequest, _auth: Auth) -> HttpResponse {
-  let _ip_address = req.peer_addr().unwrap().ip().to_string();
-  HttpResponse::Ok().finish()
+async fn password_reset(
+  request: web::Json<PasswordResetRequest>,
+  req: HttpRequest,
+) -> HttpResponse {
+  let email = request.email.to_lowercase();
+  let ip_address = client_ip(&req);
+
+  match ResetThrottle::check(&email, &ip_address).await {
+    Ok(true) => {}
+    // Accepted as usual, telling the caller would reveal that the address was asked for before
+    Ok(false) => {
+      log::warn!(
+        "Throttled password reset request [Email: {}] [IP Address: {}]",
+        email,
+        ip_address
+      );
+      return HttpResponse::Accepted().finish();
+    }
+    Err(err) => {
+      log::warn!(
+        "Throttled password reset request [Email: {}] [IP Address: {}]: {}",
+        email,
+        ip_address,
+        err
+      );
+      return ResponseError::error_response(&err);
+    }
+  }
+
+  // Respond at once whether or not the account exists, so neither the response nor its timing
+  // can be used to discover accounts
+  tokio::spawn(async move {
+    if User::select(&email).await.is_none() {
+      log::warn!(
+        "Password reset requested for unknown user [Email: {}] [IP Address: {}]",
+        email,
+        ip_address
+      );
+      return;
+    }
+    match AccountToken::send_password_reset(&email).await {
+      Ok(_) => log::info!(
+        "Successful password reset request [Email: {}] [IP Address: {}]",
+        email,
+        ip_address
+      ),
+      Err(err) => log::error!(
+        "Password reset request failure [Email: {}] [IP Address: {}]: {}",
+        email,
+        ip_address,
+        err
+      ),
+    }
+  });
+  HttpResponse::Accepted().finish()
+}
+
+#[post("/password-reset/confirm")]
+async fn confirm_password_reset(
+  request: web::Json<PasswordResetConfirmRequest>,
+  req: HttpRequest,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  let request = request.into_inner();
+  // Checked first so that a rejected password does not use up the token
+  if let Err(err) = validate_password(&request.password) {
+    return ResponseError::error_response(&err);
+  }
+
+  let email = match AccountToken::consume(&request.token, TokenPurpose::PasswordReset).await {
+    Ok(email) => email,
+    Err(err) => {
+      log::warn!(
+        "Invalid password reset attempt [IP Address: {}]: {}",
+        ip_address,
+        err
+      );
+      return ResponseError::error_response(&err);
+    }
+  };
+
+  let update_user = UpdateUser {
+    email: None,
+    password: Some(request.password),
+    role: None,
+    first_name: None,
+    last_name: None,
+  };
+  if let Err(err) = update_user.update(&email).await {
+    log::error!(
+      "Password reset failure [Email: {}] [IP Address: {}]: {}",
+      email,
+      ip_address,
+      err
+    );
+    return ResponseError::error_response(&Error::new(500, err.to_string()));
+  }
+
+  // Sign out everywhere, the old password may have been compromised
+  if let Err(err) = Session::delete_all(&email).await {
+    log::error!(
+      "Unable to revoke sessions after password reset [Email: {}]: {}",
+      email,
+      err
+    );
+    return ResponseError::error_response(&err);
+  }
+  log::info!(
+    "Successful password reset [Email: {}] [IP Address: {}]",
+    email,
+    ip_address
+  );
+  AuditEvent::new(AuditAction::PasswordReset, None, &ip_address)
+    .target(&email)
+    .record()
+    .await;
+  HttpResponse::Ok().cookie(Session::empty_cookie()).finish()
+}
+
+#[post("/verify")]
+async fn verify_email(request: web::Json<VerifyEmailRequest>, req: HttpRequest) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  let email = match AccountToken::consume(&request.token, TokenPurpose::EmailVerification).await {
+    Ok(email) => email,
+    Err(err) => {
+      log::warn!(
+        "Invalid email verification attempt [IP Address: {}]: {}",
+        ip_address,
+        err
+      );
+      return ResponseError::error_response(&err);
+    }
+  };
+
+  match User::verify_email(&email).await {
+    Ok(user) => {
+      log::info!(
+        "Successful email verification [Email: {}] [IP Address: {}]",
+        email,
+        ip_address
+      );
+      let user_response: UserResponse = user.into();
+      HttpResponse::Ok().json(user_response)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[post("/verify/resend")]
+async fn resend_email_verification(
+  request: web::Json<ResendVerificationRequest>,
+  req: HttpRequest,
+) -> HttpResponse {
+  let email = request.email.to_lowercase();
+  let ip_address = client_ip(&req);
+
+  // Respond the same way for unknown or verified users so accounts cannot be discovered
+  match User::select(&email).await {
+    Some(user) if !user.is_email_verified() => match send_email_verification(&email).await {
+      Ok(_) => log::info!(
+        "Successful email verification resend [Email: {}] [IP Address: {}]",
+        email,
+        ip_address
+      ),
+      Err(err) => log::error!(
+        "Email verification resend failure [Email: {}] [IP Address: {}]: {}",
+        email,
+        ip_address,
+        err
+      ),
+    },
+    _ => log::warn!(
+      "Email verification resend ignored [Email: {}] [IP Address: {}]",
+      email,
+      ip_address
+    ),
+  }
+  HttpResponse::Accepted().finish()
+}
+
+#[get("/sessions")]
+async fn get_sessions(auth: SessionAuth) -> HttpResponse {
+  match Session::list(&auth.user.email).await {
+    Ok(sessions) => {
+      let sessions: Vec<SessionResponse> = sessions
+        .into_iter()
+        .map(|session| SessionResponse::new(session, auth.session_id.as_deref()))
+        .collect();
+      HttpResponse::Ok().json(sessions)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/sessions/{id}")]
+async fn revoke_session(
+  id: web::Path<String>,
+  req: HttpRequest,
+  auth: SessionAuth,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  match Session::revoke(&auth.user.email, &id).await {
+    Ok(_) => {
+      log::info!(
+        "Revoked session {} [Email: {}] [IP Address: {}]",
+        id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+/// Log out every session except the one making the request
+#[delete("/sessions")]
+async fn revoke_other_sessions(req: HttpRequest, auth: SessionAuth) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  match Session::delete_others(&auth.user.email, auth.session_id.as_deref()).await {
+    Ok(revoked) => {
+      log::info!(
+        "Revoked {} other sessions [Email: {}] [IP Address: {}]",
+        revoked,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[get("/keys")]
+async fn get_api_keys(auth: SessionAuth) -> HttpResponse {
+  match ApiKey::select_all(&auth.user.email).await {
+    Ok(api_keys) => HttpResponse::Ok().json(api_keys),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[post("/keys")]
+async fn create_api_key(
+  request: web::Json<CreateApiKeyRequest>,
+  req: HttpRequest,
+  auth: SessionAuth,
+) -> HttpResponse {
+  let email = auth.user.email.clone();
+  let ip_address = client_ip(&req);
+
+  if let Err(err) = verify_email_requirement(&auth.user).await {
+    return ResponseError::error_response(&err);
+  }
+  // A key may only be granted scopes whose permissions the user already holds
+  let privileged = request.scopes.iter().any(|scope| {
+    scope
+      .permissions()
+      .iter()
+      .any(|permission| !auth.has_permission(*permission))
+  });
+  if privileged {
+    return ResponseError::error_response(&Error::new(
+      403,
+      "User does not have permission to request these scopes.".to_string(),
+    ));
+  }
+
+  match ApiKey::insert(&email, &request).await {
+    Ok(created) => {
+      log::info!(
+        "Successful API key creation [Email: {}] [IP Address: {}] [Key: {}]",
+        email,
+        ip_address,
+        created.api_key.prefix
+      );
+      AuditEvent::new(AuditAction::ApiKeyCreated, Some(&email), &ip_address)
+        .target(&created.api_key.id.to_string())
+        .changes((None, audit::fields(&created.api_key)))
+        .record()
+        .await;
+      HttpResponse::Created().json(created)
+    }
+    Err(err) => {
+      log::error!(
+        "Invalid API key creation attempt [Email: {}] [IP Address: {}]: {}",
+        email,
+        ip_address,
+        err
+      );
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[delete("/keys/{id}")]
+async fn revoke_api_key(id: web::Path<Uuid>, req: HttpRequest, auth: SessionAuth) -> HttpResponse {
+  let email = auth.user.email.clone();
+  let ip_address = client_ip(&req);
+  let id = id.into_inner();
+  match ApiKey::revoke(&email, &id).await {
+    Ok(_) => {
+      log::info!(
+        "Successful API key revocation [Email: {}] [IP Address: {}] [ID: {}]",
+        email,
+        ip_address,
+        id
+      );
+      AuditEvent::new(AuditAction::ApiKeyRevoked, Some(&email), &ip_address)
+        .target(&id.to_string())
+        .record()
+        .await;
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
 }
 
 pub fn init_routes(config: &mut web::ServiceConfig) {
@@ -225,8 +1024,25 @@ pub fn init_routes(config: &mut web::ServiceConfig) {
     web::scope("account")
       .service(register)
       .service(login)
+      .service(login_two_factor)
+      .service(oidc_login)
+      .service(oidc_callback)
       .service(logout)
       .service(change_password)
-      .service(validate_session),
+      .service(password_reset)
+      .service(confirm_password_reset)
+      .service(verify_email)
+      .service(resend_email_verification)
+      .service(enroll_two_factor)
+      .service(confirm_two_factor)
+      .service(disable_two_factor)
+      .service(regenerate_recovery_codes)
+      .service(validate_session)
+      .service(get_sessions)
+      .service(revoke_session)
+      .service(revoke_other_sessions)
+      .service(get_api_keys)
+      .service(create_api_key)
+      .service(revoke_api_key),
   );
 }
@@ -1,4 +1,10 @@
-use actix_web::cookie::{time::Duration, Cookie};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use actix_web::{
+  cookie::{time::Duration, Cookie},
+  http, HttpRequest,
+};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use redis::{AsyncCommands, RedisResult};
@@ -9,34 +15,208 @@ use crate::{
 };
 use super::{csprng, hash, verify_hash};
 
-const DEFAULT_SESSION_TTL: i64 = 86400; // (In seconds) 24 hours
+const DEFAULT_IDLE_TIMEOUT: i64 = 86400; // (In seconds) 24 hours without a request
+const DEFAULT_MAX_LIFETIME: i64 = 604800; // (In seconds) 7 days after login
 pub const SESSION_COOKIE_NAME: &str = "session";
+const USER_SESSIONS_PREFIX: &str = "sessions:user:";
+const PUBLIC_ID_LENGTH: usize = 16;
+const MAX_USER_AGENT_LENGTH: usize = 256;
+const LAST_USED_RESOLUTION: i64 = 60; // (In seconds) Minimum interval between last used updates
+
+static POLICY: OnceLock<SessionPolicy> = OnceLock::new();
+
+/// How closely a session is tied to the address it was created from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionBinding {
+  /// The exact client address
+  Strict,
+  /// The client's /24 (IPv4) or /48 (IPv6) network
+  Subnet,
+  None,
+}
+
+impl FromStr for SessionBinding {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "strict" => Ok(SessionBinding::Strict),
+      "subnet" => Ok(SessionBinding::Subnet),
+      "none" => Ok(SessionBinding::None),
+      _ => Err(format!("Invalid session binding '{}'", s)),
+    }
+  }
+}
+
+/// Session settings read from the `SESSION_*` environment variables
+#[derive(Debug, Clone, Copy)]
+pub struct SessionPolicy {
+  pub binding: SessionBinding,
+  /// Seconds without a request before the session expires
+  pub idle_timeout: i64,
+  /// Seconds after login before the session expires, however active it is
+  pub max_lifetime: i64,
+}
+
+impl SessionPolicy {
+  /// Policy read from the environment on first use
+  pub fn current() -> &'static Self {
+    POLICY.get_or_init(Self::from_env)
+  }
+
+  pub fn from_env() -> Self {
+    let binding = std::env::var("SESSION_BINDING")
+      .ok()
+      .and_then(|binding| match binding.parse() {
+        Ok(binding) => Some(binding),
+        Err(err) => {
+          log::warn!("{}, using strict", err);
+          None
+        }
+      })
+      .unwrap_or(SessionBinding::Strict);
+    let seconds = |key: &str, default: i64| {
+      std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(default)
+    };
+    Self {
+      binding,
+      idle_timeout: seconds("SESSION_IDLE_TIMEOUT", DEFAULT_IDLE_TIMEOUT),
+      max_lifetime: seconds("SESSION_MAX_LIFETIME", DEFAULT_MAX_LIFETIME),
+    }
+  }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Session {
   pub session_id: String,
+  /// Identifier shown to the user, the session ID itself is never exposed
+  #[serde(default = "Session::public_id")]
+  pub id: String,
   pub email: String,
   pub ip_address: String,
+  /// Network of the client address, the full address is only kept hashed
+  #[serde(default)]
+  pub approximate_ip: String,
+  #[serde(default)]
+  pub user_agent: Option<String>,
+  #[serde(default = "Utc::now")]
+  pub created_at: DateTime<Utc>,
+  #[serde(default = "Utc::now")]
+  pub last_used_at: DateTime<Utc>,
   #[serde(skip_serializing_if = "Option::is_none")]
   pub expires_at: Option<DateTime<Utc>>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionResponse {
+  pub id: String,
+  pub ip_address: String,
+  pub user_agent: Option<String>,
+  pub created_at: DateTime<Utc>,
+  pub last_used_at: DateTime<Utc>,
+  pub expires_at: Option<DateTime<Utc>>,
+  /// The session making the request
+  pub current: bool,
+}
+
+impl SessionResponse {
+  pub fn new(session: Session, current_session_id: Option<&str>) -> Self {
+    Self {
+      current: current_session_id == Some(session.session_id.as_str()),
+      id: session.id,
+      ip_address: session.approximate_ip,
+      user_agent: session.user_agent,
+      created_at: session.created_at,
+      last_used_at: session.last_used_at,
+      expires_at: session.expires_at,
+    }
+  }
+}
+
+/// User agent of the request, truncated for storage
+pub fn user_agent(req: &HttpRequest) -> Option<&str> {
+  let user_agent = req.headers().get(http::header::USER_AGENT)?.to_str().ok()?;
+  match user_agent.char_indices().nth(MAX_USER_AGENT_LENGTH) {
+    Some((index, _)) => Some(&user_agent[..index]),
+    None => Some(user_agent),
+  }
+}
+
+/// Whether the client address satisfies the binding to a stored hash and network
+pub fn matches_address(
+  binding: SessionBinding,
+  ip_address: &str,
+  ip_hash: &str,
+  approximate: &str,
+) -> bool {
+  match binding {
+    SessionBinding::Strict => verify_hash(ip_address, ip_hash),
+    SessionBinding::Subnet => {
+      approximate_ip(ip_address) == approximate || verify_hash(ip_address, ip_hash)
+    }
+    SessionBinding::None => true,
+  }
+}
+
+/// Mask the host part of an address, keeping the /24 of IPv4 and the /48 of IPv6
+pub fn approximate_ip(ip_address: &str) -> String {
+  match ip_address.parse::<IpAddr>() {
+    Ok(IpAddr::V4(ip)) => {
+      let [a, b, c, _] = ip.octets();
+      format!("{}.{}.{}.0/24", a, b, c)
+    }
+    Ok(IpAddr::V6(ip)) => {
+      let segments = ip.segments();
+      format!("{:x}:{:x}:{:x}::/48", segments[0], segments[1], segments[2])
+    }
+    Err(_) => "unknown".to_string(),
+  }
+}
+
 impl Session {
-  pub fn default(email: &str, ip_address: &str) -> Self {
-    Self::new(64, email, ip_address, Some(DEFAULT_SESSION_TTL))
+  pub fn default(email: &str, ip_address: &str, user_agent: Option<&str>) -> Self {
+    Self::new(64, email, ip_address, user_agent, SessionPolicy::current())
   }
 
-  pub fn new(take: usize, email: &str, ip_address: &str, ttl: Option<i64>) -> Self {
+  pub fn new(
+    take: usize,
+    email: &str,
+    ip_address: &str,
+    user_agent: Option<&str>,
+    policy: &SessionPolicy,
+  ) -> Self {
     let now = Utc::now();
-    Self {
+    let mut session = Self {
       session_id: csprng(take),
+      id: Self::public_id(),
       email: email.to_string(),
       ip_address: hash(&ip_address).unwrap(),
-      expires_at: match ttl {
-        Some(ttl) => Some(now + chrono::Duration::seconds(ttl)),
-        None => None,
-      },
-    }
+      approximate_ip: approximate_ip(ip_address),
+      user_agent: user_agent.map(|user_agent| user_agent.to_string()),
+      created_at: now,
+      last_used_at: now,
+      expires_at: None,
+    };
+    session.expires_at = Some(session.expiry(policy, now));
+    session
+  }
+
+  /// Sliding expiry, capped by the maximum lifetime from login
+  fn expiry(&self, policy: &SessionPolicy, now: DateTime<Utc>) -> DateTime<Utc> {
+    let idle = now + chrono::Duration::seconds(policy.idle_timeout);
+    idle.min(self.absolute_expiry(policy))
+  }
+
+  fn absolute_expiry(&self, policy: &SessionPolicy) -> DateTime<Utc> {
+    self.created_at + chrono::Duration::seconds(policy.max_lifetime)
+  }
+
+  fn public_id() -> String {
+    csprng(PUBLIC_ID_LENGTH)
   }
 
   pub async fn store(&self) -> ApiResult<()> {
@@ -46,16 +226,111 @@ impl Session {
     let result: RedisResult<()> = match self.expires_at {
       Some(expires_at) => {
         let ttl = expires_at.timestamp() - Utc::now().timestamp();
+        if ttl <= 0 {
+          Self::delete(&self.session_id).await?;
+          return Err(Error::new(401, "Session has expired".to_string()));
+        }
         conn.set_ex(key, &value, ttl as u64).await
       }
       None => conn.set(key, value).await,
     };
+    result?;
+
+    // Index the session by user so every session can be revoked at once
+    let user_key = Self::user_key(&self.email);
+    let result: RedisResult<()> = redis::pipe()
+      .sadd(&user_key, &self.session_id)
+      .ignore()
+      .expire(&user_key, SessionPolicy::current().max_lifetime)
+      .ignore()
+      .query_async(&mut conn)
+      .await;
     match result {
       Ok(_) => Ok(()),
       Err(err) => Err(err.into()),
     }
   }
 
+  fn user_key(email: &str) -> String {
+    format!("{}{}", USER_SESSIONS_PREFIX, email)
+  }
+
+  /// Delete every session belonging to the user
+  pub async fn delete_all(email: &str) -> ApiResult<()> {
+    Self::delete_others(email, None).await.map(|_| ())
+  }
+
+  /// Delete every session of the user except the given one, returning how many were deleted
+  pub async fn delete_others(email: &str, keep_session_id: Option<&str>) -> ApiResult<usize> {
+    let mut conn = redis_async_connection().await?;
+    let user_key = Self::user_key(email);
+    let session_ids: Vec<String> = conn.smembers(&user_key).await?;
+    let mut pipe = redis::pipe();
+    let mut deleted = 0;
+    for session_id in &session_ids {
+      if Some(session_id.as_str()) == keep_session_id {
+        continue;
+      }
+      pipe.del(session_id).ignore();
+      pipe.srem(&user_key, session_id).ignore();
+      deleted += 1;
+    }
+    let result: RedisResult<()> = pipe.query_async(&mut conn).await;
+    match result {
+      Ok(_) => Ok(deleted),
+      Err(err) => Err(err.into()),
+    }
+  }
+
+  /// Active sessions of the user, most recently used first
+  pub async fn list(email: &str) -> ApiResult<Vec<Self>> {
+    let mut conn = redis_async_connection().await?;
+    let user_key = Self::user_key(email);
+    let session_ids: Vec<String> = conn.smembers(&user_key).await?;
+    if session_ids.is_empty() {
+      return Ok(Vec::new());
+    }
+    let values: Vec<Option<String>> = conn.mget(&session_ids).await?;
+
+    let mut sessions = Vec::new();
+    let mut expired = Vec::new();
+    for (session_id, value) in session_ids.iter().zip(values) {
+      match value.map(|value| serde_json::from_str::<Self>(&value)) {
+        Some(Ok(session)) => sessions.push(session),
+        Some(Err(err)) => log::warn!("Skipping unreadable session [Email: {}]: {}", email, err),
+        None => expired.push(session_id),
+      }
+    }
+    // Sessions expire on their own, drop them from the index as they are found
+    if !expired.is_empty() {
+      let result: RedisResult<()> = conn.srem(&user_key, expired).await;
+      result?;
+    }
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.last_used_at));
+    Ok(sessions)
+  }
+
+  /// Delete one of the user's sessions by its public ID
+  pub async fn revoke(email: &str, id: &str) -> ApiResult<()> {
+    let session = Self::list(email)
+      .await?
+      .into_iter()
+      .find(|session| session.id == id)
+      .ok_or_else(|| Error::new(404, format!("Session {} not found", id)))?;
+    Self::delete(&session.session_id).await
+  }
+
+  /// Record that the session was used and slide its expiry, at most once per minute
+  pub async fn touch(&mut self) -> ApiResult<()> {
+    let now = Utc::now();
+    if (now - self.last_used_at).num_seconds() < LAST_USED_RESOLUTION {
+      return Ok(());
+    }
+    self.last_used_at = now;
+    self.expires_at = Some(self.expiry(SessionPolicy::current(), now));
+    self.store().await
+  }
+
   pub async fn get(session_id: &str) -> ApiResult<Self> {
     let mut conn = redis_async_connection().await?;
     let result: RedisResult<Option<String>> = conn.get(session_id).await;
@@ -67,7 +342,7 @@ impl Session {
   }
 
   pub async fn replace(session_id: &str, ip_address: &str) -> ApiResult<Self> {
-    let mut session = Self::verify(session_id, ip_address).await?;
+    let previous = Self::verify(session_id, ip_address).await?;
     let session_id_owned = session_id.to_owned();
     task::spawn(async move {
       if let Err(err) = Self::delete(&session_id_owned).await {
@@ -77,14 +352,22 @@ impl Session {
         );
       };
     });
-    session = Session::default(&session.email, ip_address);
+    // Rotate the session ID but keep it the same session from the user's point of view
+    let mut session = Session::default(&previous.email, ip_address, previous.user_agent.as_deref());
+    session.id = previous.id;
+    session.created_at = previous.created_at;
+    session.expires_at = Some(session.expiry(SessionPolicy::current(), Utc::now()));
     session.store().await?;
     Ok(session)
   }
 
   pub async fn delete(session_id: &str) -> ApiResult<()> {
     let mut conn = redis_async_connection().await?;
-    let result: RedisResult<()> = conn.del(session_id).await;
+    let value: Option<String> = conn.get_del(session_id).await?;
+    let Some(session) = value.and_then(|value| serde_json::from_str::<Self>(&value).ok()) else {
+      return Ok(());
+    };
+    let result: RedisResult<()> = conn.srem(Self::user_key(&session.email), session_id).await;
     match result {
       Ok(_) => Ok(()),
       Err(err) => Err(err.into()),
@@ -93,20 +376,29 @@ impl Session {
 
   pub async fn verify(session_id: &str, ip_address: &str) -> ApiResult<Self> {
     let session = Self::get(session_id).await?;
+    let policy = SessionPolicy::current();
+
+    // The maximum lifetime may have been lowered since the session was created
+    if session.absolute_expiry(policy) <= Utc::now() {
+      Self::delete(session_id).await?;
+      return Err(Error::new(401, "Session has expired".to_string()));
+    }
 
     // Check if the IP Address matches the Session's IP Address
-    if verify_hash(ip_address, &session.ip_address) {
+    if session.matches_address(policy.binding, ip_address) {
       Ok(session)
     } else {
       Err(Error::new(401, "IP Address does not match".to_string()))
     }
   }
 
+  fn matches_address(&self, binding: SessionBinding, ip_address: &str) -> bool {
+    matches_address(binding, ip_address, &self.ip_address, &self.approximate_ip)
+  }
+
   pub fn cookie(&self) -> Cookie {
-    let expires_at = match self.expires_at {
-      Some(expires_at) => expires_at.timestamp(),
-      None => DEFAULT_SESSION_TTL,
-    };
+    // The cookie lasts for the maximum lifetime, the idle timeout is enforced by the store
+    let expires_at = self.absolute_expiry(SessionPolicy::current()).timestamp();
     let ttl = expires_at - Utc::now().timestamp();
     let mut cookie = Cookie::build(SESSION_COOKIE_NAME, self.session_id.clone())
       .path("/")
@@ -148,3 +440,62 @@ impl Session {
     cookie
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_session_binding() {
+    let session = Session::new(
+      16,
+      "user@example.com",
+      "203.0.113.42",
+      None,
+      SessionPolicy::current(),
+    );
+    assert!(session.matches_address(SessionBinding::Strict, "203.0.113.42"));
+    assert!(!session.matches_address(SessionBinding::Strict, "203.0.113.43"));
+    assert!(session.matches_address(SessionBinding::Subnet, "203.0.113.43"));
+    assert!(!session.matches_address(SessionBinding::Subnet, "198.51.100.42"));
+    assert!(session.matches_address(SessionBinding::None, "198.51.100.42"));
+    assert_eq!("SUBNET".parse(), Ok(SessionBinding::Subnet));
+    assert!("loose".parse::<SessionBinding>().is_err());
+  }
+
+  #[test]
+  fn test_session_expiry() {
+    let policy = SessionPolicy {
+      binding: SessionBinding::Strict,
+      idle_timeout: 3600,
+      max_lifetime: 7200,
+    };
+    let mut session = Session::new(16, "user@example.com", "203.0.113.42", None, &policy);
+    let created_at = session.created_at;
+    assert_eq!(
+      session.expires_at,
+      Some(created_at + chrono::Duration::seconds(3600))
+    );
+
+    // Activity slides the expiry until the maximum lifetime is reached
+    let later = created_at + chrono::Duration::seconds(1800);
+    assert_eq!(
+      session.expiry(&policy, later),
+      later + chrono::Duration::seconds(3600)
+    );
+    let much_later = created_at + chrono::Duration::seconds(6000);
+    assert_eq!(
+      session.expiry(&policy, much_later),
+      created_at + chrono::Duration::seconds(7200)
+    );
+    session.created_at = created_at - chrono::Duration::seconds(7200);
+    assert!(session.absolute_expiry(&policy) <= Utc::now());
+  }
+
+  #[test]
+  fn test_approximate_ip() {
+    assert_eq!(approximate_ip("203.0.113.42"), "203.0.113.0/24");
+    assert_eq!(approximate_ip("2001:db8:abcd:12::1"), "2001:db8:abcd::/48");
+    assert_eq!(approximate_ip("not an address"), "unknown");
+  }
+}
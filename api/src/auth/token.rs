@@ -0,0 +1,160 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use crate::db;
+use crate::error::{ApiResult, Error};
+use crate::mail::{self, Mail};
+use super::{csprng, hash, verify_hash};
+
+const TABLE_NAME: &str = "account_tokens";
+const SELECTOR_LENGTH: usize = 16;
+const SECRET_LENGTH: usize = 48;
+const DEFAULT_PASSWORD_RESET_TTL: i64 = 3600; // (In seconds) 1 hour
+
+fn password_reset_ttl() -> i64 {
+  std::env::var("PASSWORD_RESET_TTL")
+    .ok()
+    .and_then(|ttl| ttl.parse::<i64>().ok())
+    .unwrap_or(DEFAULT_PASSWORD_RESET_TTL)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenPurpose {
+  PasswordReset,
+  EmailVerification,
+}
+
+impl TokenPurpose {
+  fn as_str(&self) -> &'static str {
+    match self {
+      TokenPurpose::PasswordReset => "password_reset",
+      TokenPurpose::EmailVerification => "email_verification",
+    }
+  }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct AccountTokenRow {
+  email: String,
+  token_hash: String,
+  expires_at: DateTime<Utc>,
+  used_at: Option<DateTime<Utc>>,
+}
+
+/// Single-use token emailed to a user, formatted as `<selector>.<secret>`
+///
+/// The selector looks up the row and only an argon2 hash of the secret is stored.
+pub struct AccountToken;
+
+impl AccountToken {
+  fn split(token: &str) -> Option<(&str, &str)> {
+    let (selector, secret) = token.split_once('.')?;
+    if selector.len() != SELECTOR_LENGTH || secret.is_empty() {
+      return None;
+    }
+    Some((selector, secret))
+  }
+
+  /// Issue a new token, invalidating any earlier unused token for the same purpose
+  pub async fn issue(email: &str, purpose: TokenPurpose, ttl: Duration) -> ApiResult<String> {
+    let pool = db::pool();
+    let selector = csprng(SELECTOR_LENGTH);
+    let secret = csprng(SECRET_LENGTH);
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET used_at = NOW() WHERE email = $1 AND purpose = $2 AND used_at IS NULL
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(purpose.as_str())
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (id, email, purpose, token_hash, expires_at)
+      VALUES ($1, $2, $3, $4, $5)
+      "#,
+      TABLE_NAME
+    ))
+    .bind(&selector)
+    .bind(email)
+    .bind(purpose.as_str())
+    .bind(hash(&secret)?)
+    .bind(Utc::now() + ttl)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(format!("{}.{}", selector, secret))
+  }
+
+  /// Issue a password reset token and email it to the user
+  pub async fn send_password_reset(email: &str) -> ApiResult<()> {
+    let ttl = password_reset_ttl();
+    let token = Self::issue(email, TokenPurpose::PasswordReset, Duration::seconds(ttl)).await?;
+    mail::spawn_send(Mail::password_reset(email, &token, ttl));
+    Ok(())
+  }
+
+  /// Mark the token as used and return the email it was issued to
+  pub async fn consume(token: &str, purpose: TokenPurpose) -> ApiResult<String> {
+    let pool = db::pool();
+    let invalid = || Error::new(400, "Invalid or expired token".to_string());
+    let (selector, secret) = Self::split(token).ok_or_else(invalid)?;
+
+    let mut tx = pool.begin().await?;
+    let row: AccountTokenRow = sqlx::query_as(&format!(
+      r#"
+      SELECT email, token_hash, expires_at, used_at FROM {}
+      WHERE id = $1 AND purpose = $2
+      FOR UPDATE
+      "#,
+      TABLE_NAME
+    ))
+    .bind(selector)
+    .bind(purpose.as_str())
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(invalid)?;
+
+    if row.used_at.is_some() || row.expires_at <= Utc::now() {
+      return Err(invalid());
+    }
+    if !verify_hash(secret, &row.token_hash) {
+      return Err(invalid());
+    }
+
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET used_at = NOW() WHERE id = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(selector)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(row.email)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_split() {
+    let selector = "a".repeat(SELECTOR_LENGTH);
+    let token = format!("{}.secret", selector);
+    assert_eq!(
+      AccountToken::split(&token),
+      Some((selector.as_str(), "secret"))
+    );
+    assert_eq!(AccountToken::split(&format!("{}.", selector)), None);
+    assert_eq!(AccountToken::split("short.secret"), None);
+    assert_eq!(AccountToken::split(&selector), None);
+  }
+}
@@ -0,0 +1,473 @@
+use chrono::{DateTime, Utc};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use redis::{AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+use crate::db::{self, redis_async_connection};
+use crate::error::{ApiResult, Error};
+use super::{approximate_ip, csprng, hash, matches_address, verify_hash, SessionPolicy};
+
+const TABLE_NAME: &str = "users";
+const RECOVERY_CODES_TABLE_NAME: &str = "totp_recovery_codes";
+const SECRET_BYTES: usize = 20;
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+// Codes from the previous or next step are accepted to tolerate clock drift
+const ALLOWED_SKEW_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+const CHALLENGE_PREFIX: &str = "login:challenge:";
+const CHALLENGE_TTL: i64 = 300; // (In seconds) 5 minutes
+const MAX_CHALLENGE_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+  /// Base32 secret for authenticator apps that cannot scan the URI
+  pub secret: String,
+  pub otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+  pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpCodeRequest {
+  pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginChallengeRequest {
+  pub challenge: String,
+  pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginChallengeResponse {
+  pub two_factor_required: bool,
+  pub challenge: String,
+  pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct TotpRow {
+  totp_secret: Option<String>,
+  totp_enabled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct RecoveryCodeRow {
+  id: Uuid,
+  code_hash: String,
+}
+
+/// Time-based one-time passwords (RFC 6238) used as a second login factor
+pub struct Totp;
+
+impl Totp {
+  fn issuer() -> String {
+    std::env::var("TOTP_ISSUER")
+      .unwrap_or("Aviation Weather".to_string())
+      .replace(':', "")
+  }
+
+  fn build(secret: &str, email: &str) -> ApiResult<TOTP> {
+    let bytes = Secret::Encoded(secret.to_string())
+      .to_bytes()
+      .map_err(|err| Error::new(500, format!("Invalid TOTP secret: {:?}", err)))?;
+    TOTP::new(
+      Algorithm::SHA1,
+      DIGITS,
+      0,
+      STEP_SECONDS,
+      bytes,
+      Some(Self::issuer()),
+      email.to_string(),
+    )
+    .map_err(|err| Error::new(500, format!("Invalid TOTP configuration: {}", err)))
+  }
+
+  /// Time step matched by the code within the allowed clock skew
+  fn matching_step(totp: &TOTP, code: &str, now: u64) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    let current = (now / STEP_SECONDS) as i64;
+    (current - ALLOWED_SKEW_STEPS..=current + ALLOWED_SKEW_STEPS)
+      .find(|step| *step >= 0 && totp.check(&code, *step as u64 * STEP_SECONDS))
+  }
+
+  fn generate_recovery_code() -> String {
+    let code = csprng(RECOVERY_CODE_LENGTH).to_lowercase();
+    format!(
+      "{}-{}",
+      &code[..RECOVERY_CODE_LENGTH / 2],
+      &code[RECOVERY_CODE_LENGTH / 2..]
+    )
+  }
+
+  /// Selector of a recovery code, `None` for anything shaped differently such as a TOTP code
+  fn recovery_code_selector(code: &str) -> Option<&str> {
+    let (selector, rest) = code.split_once('-')?;
+    let valid = |part: &str| {
+      part.len() == RECOVERY_CODE_LENGTH / 2 && part.chars().all(|c| c.is_ascii_alphanumeric())
+    };
+    (valid(selector) && valid(rest)).then_some(selector)
+  }
+
+  async fn row(email: &str) -> ApiResult<TotpRow> {
+    let pool = db::pool();
+    let row: TotpRow = sqlx::query_as(&format!(
+      r#"
+      SELECT totp_secret, totp_enabled_at FROM {} WHERE email = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_one(pool)
+    .await?;
+    Ok(row)
+  }
+
+  /// Start enrolment with a new secret, 2FA is only enabled once a first code is confirmed
+  pub async fn enroll(email: &str) -> ApiResult<TotpEnrollment> {
+    let pool = db::pool();
+    if Self::row(email).await?.totp_enabled_at.is_some() {
+      return Err(Error::new(
+        409,
+        "Two-factor authentication is already enabled".to_string(),
+      ));
+    }
+
+    let mut bytes = vec![0u8; SECRET_BYTES];
+    ChaCha20Rng::from_os_rng().fill_bytes(&mut bytes);
+    let secret = match Secret::Raw(bytes).to_encoded() {
+      Secret::Encoded(secret) => secret,
+      Secret::Raw(_) => unreachable!(),
+    };
+    let totp = Self::build(&secret, email)?;
+
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET totp_secret = $2, totp_last_step = NULL WHERE email = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(&secret)
+    .execute(pool)
+    .await?;
+
+    Ok(TotpEnrollment {
+      secret,
+      otpauth_uri: totp.get_url(),
+    })
+  }
+
+  /// Enable 2FA with the first code from the authenticator and issue recovery codes
+  pub async fn confirm(email: &str, code: &str) -> ApiResult<Vec<String>> {
+    let pool = db::pool();
+    let row = Self::row(email).await?;
+    if row.totp_enabled_at.is_some() {
+      return Err(Error::new(
+        409,
+        "Two-factor authentication is already enabled".to_string(),
+      ));
+    }
+    if row.totp_secret.is_none() {
+      return Err(Error::new(
+        400,
+        "Two-factor enrolment has not been started".to_string(),
+      ));
+    }
+    if !Self::consume_code(email, &row, code).await? {
+      return Err(Error::new(400, "Invalid two-factor code".to_string()));
+    }
+
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET totp_enabled_at = NOW() WHERE email = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .execute(pool)
+    .await?;
+    Self::regenerate_recovery_codes(email).await
+  }
+
+  /// Verify a TOTP or recovery code for a user with 2FA enabled
+  pub async fn verify(email: &str, code: &str) -> ApiResult<()> {
+    let row = Self::row(email).await?;
+    if row.totp_enabled_at.is_none() {
+      return Err(Error::new(
+        400,
+        "Two-factor authentication is not enabled".to_string(),
+      ));
+    }
+    if Self::consume_code(email, &row, code).await?
+      || Self::consume_recovery_code(email, code).await?
+    {
+      Ok(())
+    } else {
+      Err(Error::new(401, "Invalid two-factor code".to_string()))
+    }
+  }
+
+  /// Check a TOTP code and record its step so the same code cannot be replayed
+  async fn consume_code(email: &str, row: &TotpRow, code: &str) -> ApiResult<bool> {
+    let pool = db::pool();
+    let secret = match &row.totp_secret {
+      Some(secret) => secret,
+      None => return Ok(false),
+    };
+    let totp = Self::build(secret, email)?;
+    let step = match Self::matching_step(&totp, code, Utc::now().timestamp() as u64) {
+      Some(step) => step,
+      None => return Ok(false),
+    };
+    let result = sqlx::query(&format!(
+      r#"
+      UPDATE {} SET totp_last_step = $2
+      WHERE email = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(step)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+  }
+
+  async fn consume_recovery_code(email: &str, code: &str) -> ApiResult<bool> {
+    let pool = db::pool();
+    let code = code.trim().to_lowercase();
+    let Some(selector) = Self::recovery_code_selector(&code) else {
+      return Ok(false);
+    };
+    let mut tx = pool.begin().await?;
+    let rows: Vec<RecoveryCodeRow> = sqlx::query_as(&format!(
+      r#"
+      SELECT id, code_hash FROM {} WHERE email = $1 AND selector = $2 AND used_at IS NULL
+      FOR UPDATE
+      "#,
+      RECOVERY_CODES_TABLE_NAME
+    ))
+    .bind(email)
+    .bind(selector)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let Some(row) = rows.iter().find(|row| verify_hash(&code, &row.code_hash)) else {
+      return Ok(false);
+    };
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET used_at = NOW() WHERE id = $1
+      "#,
+      RECOVERY_CODES_TABLE_NAME
+    ))
+    .bind(row.id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+  }
+
+  /// Replace every recovery code, only hashes are stored
+  pub async fn regenerate_recovery_codes(email: &str) -> ApiResult<Vec<String>> {
+    let pool = db::pool();
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+      .map(|_| Self::generate_recovery_code())
+      .collect();
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE email = $1
+      "#,
+      RECOVERY_CODES_TABLE_NAME
+    ))
+    .bind(email)
+    .execute(&mut *tx)
+    .await?;
+    for code in &codes {
+      sqlx::query(&format!(
+        r#"
+        INSERT INTO {} (id, email, selector, code_hash) VALUES ($1, $2, $3, $4)
+        "#,
+        RECOVERY_CODES_TABLE_NAME
+      ))
+      .bind(Uuid::new_v4())
+      .bind(email)
+      .bind(Self::recovery_code_selector(code))
+      .bind(hash(code)?)
+      .execute(&mut *tx)
+      .await?;
+    }
+    tx.commit().await?;
+    Ok(codes)
+  }
+
+  pub async fn disable(email: &str) -> ApiResult<()> {
+    let pool = db::pool();
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+      r#"
+      UPDATE {}
+      SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL
+      WHERE email = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE email = $1
+      "#,
+      RECOVERY_CODES_TABLE_NAME
+    ))
+    .bind(email)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+  }
+
+  /// Force a user to enrol in 2FA before any permission is granted
+  pub async fn set_required(email: &str, required: bool) -> ApiResult<()> {
+    let pool = db::pool();
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET totp_required = $2 WHERE email = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(required)
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+}
+
+/// Pending login that passed the password check and still needs a second factor
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginChallenge {
+  pub challenge_id: String,
+  pub email: String,
+  ip_address: String,
+  /// Network of the client address for subnet binding
+  #[serde(default)]
+  approximate_ip: String,
+  attempts: u32,
+  pub expires_at: DateTime<Utc>,
+}
+
+impl LoginChallenge {
+  pub fn new(email: &str, ip_address: &str) -> ApiResult<Self> {
+    Ok(Self {
+      challenge_id: csprng(64),
+      email: email.to_string(),
+      ip_address: hash(ip_address)?,
+      approximate_ip: approximate_ip(ip_address),
+      attempts: 0,
+      expires_at: Utc::now() + chrono::Duration::seconds(CHALLENGE_TTL),
+    })
+  }
+
+  fn key(challenge_id: &str) -> String {
+    format!("{}{}", CHALLENGE_PREFIX, challenge_id)
+  }
+
+  pub async fn store(&self) -> ApiResult<()> {
+    let mut conn = redis_async_connection().await?;
+    let ttl = (self.expires_at - Utc::now()).num_seconds().max(1);
+    let value = serde_json::to_string(self)?;
+    let result: RedisResult<()> = conn
+      .set_ex(Self::key(&self.challenge_id), value, ttl as u64)
+      .await;
+    Ok(result?)
+  }
+
+  /// Look up the challenge, which is bound to the login's address like a session
+  pub async fn verify(challenge_id: &str, ip_address: &str) -> ApiResult<Self> {
+    let mut conn = redis_async_connection().await?;
+    let value: Option<String> = conn.get(Self::key(challenge_id)).await?;
+    let challenge: Self = match value {
+      Some(value) => serde_json::from_str(&value)?,
+      None => return Err(Error::new(401, "Missing login challenge".to_string())),
+    };
+    let binding = SessionPolicy::current().binding;
+    if !matches_address(
+      binding,
+      ip_address,
+      &challenge.ip_address,
+      &challenge.approximate_ip,
+    ) {
+      return Err(Error::new(401, "IP Address does not match".to_string()));
+    }
+    Ok(challenge)
+  }
+
+  /// Count a wrong code, the challenge is discarded after too many attempts
+  pub async fn record_failure(mut self) -> ApiResult<()> {
+    self.attempts += 1;
+    if self.attempts >= MAX_CHALLENGE_ATTEMPTS {
+      Self::delete(&self.challenge_id).await
+    } else {
+      self.store().await
+    }
+  }
+
+  pub async fn delete(challenge_id: &str) -> ApiResult<()> {
+    let mut conn = redis_async_connection().await?;
+    let result: RedisResult<()> = conn.del(Self::key(challenge_id)).await;
+    Ok(result?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_matching_step() {
+    let secret = match Secret::Raw(b"12345678901234567890".to_vec()).to_encoded() {
+      Secret::Encoded(secret) => secret,
+      Secret::Raw(_) => unreachable!(),
+    };
+    let totp = Totp::build(&secret, "test@example.com").unwrap();
+    let now = 1_700_000_000;
+    let code = totp.generate(now);
+    let step = (now / STEP_SECONDS) as i64;
+
+    assert_eq!(Totp::matching_step(&totp, &code, now), Some(step));
+    // A code from the previous step is still accepted
+    assert_eq!(
+      Totp::matching_step(&totp, &code, now + STEP_SECONDS),
+      Some(step)
+    );
+    assert_eq!(
+      Totp::matching_step(&totp, &code, now + STEP_SECONDS * 3),
+      None
+    );
+    assert_eq!(Totp::matching_step(&totp, "000000x", now), None);
+  }
+
+  #[test]
+  fn test_recovery_code_format() {
+    let code = Totp::generate_recovery_code();
+    assert_eq!(code.len(), 11);
+    assert_eq!(code.chars().nth(5), Some('-'));
+    assert_eq!(code, code.to_lowercase());
+    assert_eq!(Totp::recovery_code_selector(&code), Some(&code[..5]));
+    assert_eq!(Totp::recovery_code_selector("123456"), None);
+    assert_eq!(Totp::recovery_code_selector("abcde-fgh"), None);
+    assert_eq!(Totp::recovery_code_selector("abc!e-fghij"), None);
+  }
+}
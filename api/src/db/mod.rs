@@ -139,6 +139,12 @@ pub async fn redis_async_connection() -> RedisResult<RedisConnection> {
   Ok(conn)
 }
 
+/// Dedicated connection for subscribing to channels, it cannot run other commands
+pub async fn redis_pubsub() -> RedisResult<redis::aio::PubSub> {
+  let pubsub = redis().get_async_pubsub().await?;
+  Ok(pubsub)
+}
+
 async fn run_migrations() -> ApiResult<()> {
   log::debug!("Running database migrations");
   let pool = pool();
@@ -170,8 +176,71 @@ pub struct Paged<T> {
   pub total: i64,
 }
 
+const EARTH_RADIUS_NM: f64 = 3440.065;
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Coordinate {
   pub lon: f64,
   pub lat: f64,
 }
+
+impl Coordinate {
+  /// Great-circle (haversine) distance to another coordinate in nautical miles
+  pub fn distance_nm(&self, other: &Coordinate) -> f64 {
+    let d_lat = (other.lat - self.lat).to_radians();
+    let d_lon = (other.lon - self.lon).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+      + self.lat.to_radians().cos() * other.lat.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_NM * a.sqrt().asin()
+  }
+
+  /// Initial true bearing to another coordinate in degrees, normalized to [0, 360)
+  pub fn bearing_deg(&self, other: &Coordinate) -> f64 {
+    let lat1 = self.lat.to_radians();
+    let lat2 = other.lat.to_radians();
+    let d_lon = (other.lon - self.lon).to_radians();
+    let y = d_lon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+    (y.atan2(x).to_degrees() + 360.0) % 360.0
+  }
+}
+
+/// Eight-point compass direction (N, NE, E, ...) for a bearing in degrees
+pub fn compass_direction(bearing_deg: f64) -> &'static str {
+  const DIRECTIONS: [&str; 8] = ["N", "NE", "E", "SE", "S", "SW", "W", "NW"];
+  let index = ((bearing_deg.rem_euclid(360.0) + 22.5) / 45.0) as usize % 8;
+  DIRECTIONS[index]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_distance_and_bearing() {
+    // KIAD -> KJYO is roughly 9 nm to the north-west
+    let iad = Coordinate {
+      lon: -77.4558,
+      lat: 38.9445,
+    };
+    let jyo = Coordinate {
+      lon: -77.5580,
+      lat: 39.0780,
+    };
+    let distance = iad.distance_nm(&jyo);
+    assert!((distance - 8.9).abs() < 0.5, "distance was {}", distance);
+    assert_eq!(compass_direction(iad.bearing_deg(&jyo)), "NW");
+    assert_eq!(iad.distance_nm(&iad), 0.0);
+  }
+
+  #[test]
+  fn test_compass_direction() {
+    assert_eq!(compass_direction(0.0), "N");
+    assert_eq!(compass_direction(22.4), "N");
+    assert_eq!(compass_direction(22.5), "NE");
+    assert_eq!(compass_direction(90.0), "E");
+    assert_eq!(compass_direction(225.0), "SW");
+    assert_eq!(compass_direction(350.0), "N");
+    assert_eq!(compass_direction(-45.0), "NW");
+  }
+}
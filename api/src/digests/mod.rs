@@ -0,0 +1,8 @@
+mod model;
+mod routes;
+mod schedule;
+mod template;
+
+pub use model::*;
+pub use routes::init_routes;
+pub use schedule::*;
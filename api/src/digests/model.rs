@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::db;
+use crate::error::{ApiResult, Error};
+use crate::mail::app_link;
+use crate::metars::{FlightCategory, Metar};
+use crate::users::Favorite;
+use super::template::{escape_html, render};
+
+const TABLE_NAME: &str = "digest_subscriptions";
+const DIGEST_TEXT: &str = include_str!("templates/digest.txt");
+const DIGEST_HTML: &str = include_str!("templates/digest.html");
+const AIRPORT_TEXT: &str = include_str!("templates/airport.txt");
+const AIRPORT_HTML: &str = include_str!("templates/airport.html");
+const NOT_REPORTED: &str = "Not reported";
+
+/// Next `send_at` in the subscriber's time zone that is still ahead of now
+pub(super) const NEXT_SEND_AT_SQL: &str = r#"
+  CASE
+    WHEN ((NOW() AT TIME ZONE timezone)::date + send_at) AT TIME ZONE timezone > NOW()
+      THEN ((NOW() AT TIME ZONE timezone)::date + send_at) AT TIME ZONE timezone
+    ELSE ((NOW() AT TIME ZONE timezone)::date + 1 + send_at) AT TIME ZONE timezone
+  END
+"#;
+
+/// Daily email summarising the weather at the user's favorite airports
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct DigestSubscription {
+  pub email: String,
+  pub enabled: bool,
+  /// Local time of day the digest is sent
+  pub send_at: NaiveTime,
+  /// IANA time zone of `send_at`, such as `America/New_York`
+  pub timezone: String,
+  pub next_send_at: DateTime<Utc>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_sent_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UpdateDigestSubscription {
+  pub enabled: Option<bool>,
+  /// `HH:MM`, 06:00 when first subscribing
+  pub send_at: Option<NaiveTime>,
+  /// UTC when first subscribing
+  pub timezone: Option<String>,
+}
+
+/// Conditions of a station when a digest was sent, to report what changed by the next one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StationSnapshot {
+  pub flight_category: FlightCategory,
+  pub weather_phenomena: Vec<String>,
+  pub observation_time: DateTime<Utc>,
+}
+
+pub type DigestSnapshot = HashMap<String, StationSnapshot>;
+
+#[derive(Debug, Serialize)]
+pub struct DigestAirport {
+  pub icao: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub label: Option<String>,
+  pub metar: Option<Metar>,
+  /// What changed since the last digest, empty for the first one
+  pub changes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Digest {
+  pub generated_at: DateTime<Utc>,
+  /// When the previous digest was sent
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub since: Option<DateTime<Utc>>,
+  pub airports: Vec<DigestAirport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RenderedDigest {
+  pub subject: String,
+  pub text: String,
+  pub html: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct SentSnapshot {
+  last_sent_at: Option<DateTime<Utc>>,
+  last_snapshot: Option<sqlx::types::Json<DigestSnapshot>>,
+}
+
+impl DigestSubscription {
+  pub async fn select(email: &str) -> ApiResult<Self> {
+    let pool = db::pool();
+    let subscription: Option<Self> = sqlx::query_as(&format!(
+      r#"
+      SELECT email, enabled, send_at, timezone, next_send_at, last_sent_at FROM {}
+      WHERE email = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_optional(pool)
+    .await?;
+    subscription.ok_or_else(|| Error::new(404, "No digest subscription".to_string()))
+  }
+
+  /// Subscribe or change the schedule, the next digest follows the new schedule
+  pub async fn upsert(email: &str, request: &UpdateDigestSubscription) -> ApiResult<Self> {
+    let pool = db::pool();
+    if let Some(timezone) = &request.timezone {
+      let valid: bool =
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM pg_timezone_names WHERE name = $1)")
+          .bind(timezone)
+          .fetch_one(pool)
+          .await?;
+      if !valid {
+        return Err(Error::new(400, format!("Unknown time zone '{}'", timezone)));
+      }
+    }
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (email, enabled, send_at, timezone)
+      VALUES ($1, COALESCE($2, true), COALESCE($3, '06:00'), COALESCE($4, 'UTC'))
+      ON CONFLICT (email) DO UPDATE SET
+        enabled = COALESCE($2, {0}.enabled),
+        send_at = COALESCE($3, {0}.send_at),
+        timezone = COALESCE($4, {0}.timezone),
+        updated_at = NOW()
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(request.enabled)
+    .bind(request.send_at)
+    .bind(&request.timezone)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET next_send_at = {} WHERE email = $1
+      "#,
+      TABLE_NAME, NEXT_SEND_AT_SQL
+    ))
+    .bind(email)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Self::select(email).await
+  }
+
+  /// When the last digest was sent and the conditions it reported
+  pub(super) async fn select_snapshot(
+    email: &str,
+  ) -> ApiResult<Option<(DateTime<Utc>, DigestSnapshot)>> {
+    let pool = db::pool();
+    let row: Option<SentSnapshot> = sqlx::query_as(&format!(
+      r#"
+      SELECT last_sent_at, last_snapshot FROM {} WHERE email = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_optional(pool)
+    .await?;
+    Ok(match row {
+      Some(SentSnapshot {
+        last_sent_at: Some(sent_at),
+        last_snapshot: Some(snapshot),
+      }) => Some((sent_at, snapshot.0)),
+      _ => None,
+    })
+  }
+
+  pub(super) async fn record_sent(email: &str, snapshot: &DigestSnapshot) -> ApiResult<()> {
+    let pool = db::pool();
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET last_sent_at = NOW(), last_snapshot = $2 WHERE email = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(sqlx::types::Json(snapshot))
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+}
+
+fn is_below_vfr(category: FlightCategory) -> bool {
+  matches!(
+    category,
+    FlightCategory::MVFR | FlightCategory::IFR | FlightCategory::LIFR
+  )
+}
+
+fn category_color(category: Option<FlightCategory>) -> &'static str {
+  match category {
+    Some(FlightCategory::VFR) => "#16a34a",
+    Some(FlightCategory::MVFR) => "#2563eb",
+    Some(FlightCategory::IFR) => "#dc2626",
+    Some(FlightCategory::LIFR) => "#c026d3",
+    _ => "#6b7280",
+  }
+}
+
+fn describe_wind(metar: &Metar) -> String {
+  let Some(speed) = metar.wind_speed_kt else {
+    return NOT_REPORTED.to_string();
+  };
+  if speed == 0.0 {
+    return "Calm".to_string();
+  }
+  let direction = match metar
+    .wind_dir_degrees
+    .as_ref()
+    .and_then(|direction| direction.parse::<f64>().ok())
+  {
+    Some(direction) => format!("{:03.0}°", direction),
+    None => "Variable".to_string(),
+  };
+  match metar.wind_gust_kt {
+    Some(gusts) => format!("{} at {:.0} kt, gusting {:.0} kt", direction, speed, gusts),
+    None => format!("{} at {:.0} kt", direction, speed),
+  }
+}
+
+fn describe_visibility(metar: &Metar) -> String {
+  match &metar.visibility_statute_mi {
+    Some(visibility) => format!("{} SM", visibility),
+    None => NOT_REPORTED.to_string(),
+  }
+}
+
+/// Lowest broken, overcast or obscured layer
+fn describe_ceiling(metar: &Metar) -> String {
+  metar
+    .sky_condition
+    .iter()
+    .filter(|layer| matches!(layer.sky_cover.as_str(), "BKN" | "OVC" | "VV"))
+    .min_by_key(|layer| layer.cloud_base_ft_agl.unwrap_or(0))
+    .map(|layer| {
+      format!(
+        "{} {} ft",
+        layer.sky_cover,
+        layer.cloud_base_ft_agl.unwrap_or(0)
+      )
+    })
+    .unwrap_or("None".to_string())
+}
+
+fn describe_weather(metar: &Metar) -> String {
+  if metar.weather_phenomena.is_empty() {
+    "None".to_string()
+  } else {
+    metar.weather_phenomena.join(", ")
+  }
+}
+
+/// Category and weather changes between the last digest and the current observation
+fn changes(previous: &StationSnapshot, metar: &Metar) -> Vec<String> {
+  let mut changes = vec![];
+  if previous.flight_category != metar.flight_category {
+    changes.push(format!(
+      "Flight category changed from {:?} to {:?}",
+      previous.flight_category, metar.flight_category
+    ));
+  }
+  let started: Vec<&str> = metar
+    .weather_phenomena
+    .iter()
+    .filter(|phenomenon| !previous.weather_phenomena.contains(phenomenon))
+    .map(String::as_str)
+    .collect();
+  if !started.is_empty() {
+    changes.push(format!("Now reporting {}", started.join(", ")));
+  }
+  let ended: Vec<&str> = previous
+    .weather_phenomena
+    .iter()
+    .filter(|phenomenon| !metar.weather_phenomena.contains(phenomenon))
+    .map(String::as_str)
+    .collect();
+  if !ended.is_empty() {
+    changes.push(format!("No longer reporting {}", ended.join(", ")));
+  }
+  changes
+}
+
+impl Digest {
+  /// Current conditions at the user's favorites, compared with the last digest when given
+  pub async fn build(
+    client: &reqwest::Client,
+    email: &str,
+    previous: Option<(DateTime<Utc>, DigestSnapshot)>,
+  ) -> ApiResult<Self> {
+    let favorites = Favorite::select_weather(client, email).await?;
+    let (since, snapshot) = match previous {
+      Some((since, snapshot)) => (Some(since), snapshot),
+      None => (None, DigestSnapshot::new()),
+    };
+    let airports = favorites
+      .into_iter()
+      .map(|favorite| {
+        let changes = match (&favorite.metar, snapshot.get(&favorite.favorite.icao)) {
+          (Some(metar), Some(previous)) => changes(previous, metar),
+          _ => vec![],
+        };
+        DigestAirport {
+          icao: favorite.favorite.icao,
+          label: favorite.favorite.label,
+          metar: favorite.metar,
+          changes,
+        }
+      })
+      .collect();
+    Ok(Self {
+      generated_at: Utc::now(),
+      since,
+      airports,
+    })
+  }
+
+  /// Preview the next digest without sending it or moving the comparison forward
+  pub async fn preview(client: &reqwest::Client, email: &str) -> ApiResult<Self> {
+    let previous = DigestSubscription::select_snapshot(email).await?;
+    Self::build(client, email, previous).await
+  }
+
+  pub fn snapshot(&self) -> DigestSnapshot {
+    self
+      .airports
+      .iter()
+      .filter_map(|airport| {
+        let metar = airport.metar.as_ref()?;
+        Some((
+          airport.icao.clone(),
+          StationSnapshot {
+            flight_category: metar.flight_category,
+            weather_phenomena: metar.weather_phenomena.clone(),
+            observation_time: metar.observation_time,
+          },
+        ))
+      })
+      .collect()
+  }
+
+  fn summary(&self) -> String {
+    let below_vfr = self
+      .airports
+      .iter()
+      .filter_map(|airport| airport.metar.as_ref())
+      .filter(|metar| is_below_vfr(metar.flight_category))
+      .count();
+    match below_vfr {
+      0 => "No airports are below VFR.".to_string(),
+      _ => format!(
+        "{} of {} airports are below VFR.",
+        below_vfr,
+        self.airports.len()
+      ),
+    }
+  }
+
+  fn render_airport(airport: &DigestAirport, html: bool) -> String {
+    let escape = |value: &str| {
+      if html {
+        escape_html(value)
+      } else {
+        value.to_string()
+      }
+    };
+    let label = match &airport.label {
+      Some(label) => format!(" ({})", label),
+      None => String::new(),
+    };
+    let (category, observed, wind, visibility, ceiling, weather, raw_text) = match &airport.metar {
+      Some(metar) => (
+        format!("{:?}", metar.flight_category),
+        metar.observation_time.format("%H%MZ, %d %b").to_string(),
+        describe_wind(metar),
+        describe_visibility(metar),
+        describe_ceiling(metar),
+        describe_weather(metar),
+        metar.raw_text.clone(),
+      ),
+      None => (
+        "No recent observation".to_string(),
+        NOT_REPORTED.to_string(),
+        NOT_REPORTED.to_string(),
+        NOT_REPORTED.to_string(),
+        NOT_REPORTED.to_string(),
+        NOT_REPORTED.to_string(),
+        String::new(),
+      ),
+    };
+    let changes = if html {
+      match airport.changes.is_empty() {
+        true => String::new(),
+        false => format!(
+          "<ul style=\"margin: 8px 0 0; padding-left: 20px;\">{}</ul>",
+          airport
+            .changes
+            .iter()
+            .map(|change| format!("<li>{}</li>", escape_html(change)))
+            .collect::<String>()
+        ),
+      }
+    } else {
+      airport
+        .changes
+        .iter()
+        .map(|change| format!("  Since last digest: {}\n", change))
+        .collect()
+    };
+    let color = category_color(airport.metar.as_ref().map(|metar| metar.flight_category));
+    let values = [
+      ("icao", escape(&airport.icao)),
+      ("label", escape(&label)),
+      ("flight_category", escape(&category)),
+      ("category_color", color.to_string()),
+      ("observed", escape(&observed)),
+      ("wind", escape(&wind)),
+      ("visibility", escape(&visibility)),
+      ("ceiling", escape(&ceiling)),
+      ("weather", escape(&weather)),
+      ("raw_text", escape(&raw_text)),
+      ("changes", changes),
+    ];
+    let values: Vec<(&str, &str)> = values
+      .iter()
+      .map(|(key, value)| (*key, value.as_str()))
+      .collect();
+    let template = if html { AIRPORT_HTML } else { AIRPORT_TEXT };
+    render(template, &values)
+  }
+
+  pub fn render(&self) -> RenderedDigest {
+    let date = self.generated_at.format("%a %d %b %Y").to_string();
+    let summary = self.summary();
+    let link = app_link("/");
+    let text_airports: String = self
+      .airports
+      .iter()
+      .map(|airport| Self::render_airport(airport, false))
+      .collect::<Vec<String>>()
+      .join("\n");
+    let html_airports: String = self
+      .airports
+      .iter()
+      .map(|airport| Self::render_airport(airport, true))
+      .collect();
+    let text = render(
+      DIGEST_TEXT,
+      &[
+        ("date", &date),
+        ("summary", &summary),
+        ("airports", &text_airports),
+        ("app_link", &link),
+      ],
+    );
+    let html = render(
+      DIGEST_HTML,
+      &[
+        ("date", &escape_html(&date)),
+        ("summary", &escape_html(&summary)),
+        ("airports", &html_airports),
+        ("app_link", &escape_html(&link)),
+      ],
+    );
+    RenderedDigest {
+      subject: format!("Weather digest for {}: {}", date, summary),
+      text,
+      html,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn metar(raw_text: &str) -> Metar {
+    Metar::parse_with_reference(raw_text, Utc::now()).unwrap()
+  }
+
+  #[test]
+  fn test_describe() {
+    let gusty = metar("KBOS 121754Z 09018G30KT 1 1/2SM -RA BR BKN008 OVC015 08/07 A2990");
+    assert_eq!(describe_wind(&gusty), "090° at 18 kt, gusting 30 kt");
+    assert_eq!(describe_visibility(&gusty), "1.5 SM");
+    assert_eq!(describe_ceiling(&gusty), "BKN 800 ft");
+    assert_eq!(describe_weather(&gusty), "-RA, BR");
+
+    let calm = metar("KBOS 121754Z 00000KT 10SM CLR 08/M02 A2990");
+    assert_eq!(describe_wind(&calm), "Calm");
+    assert_eq!(describe_ceiling(&calm), "None");
+    assert_eq!(describe_weather(&calm), "None");
+  }
+
+  #[test]
+  fn test_changes() {
+    let previous = StationSnapshot {
+      flight_category: FlightCategory::VFR,
+      weather_phenomena: vec!["BR".to_string()],
+      observation_time: Utc::now(),
+    };
+    let current = metar("KBOS 121754Z 09012KT 2SM TSRA OVC006 08/07 A2990");
+    assert_eq!(
+      changes(&previous, &current),
+      vec![
+        "Flight category changed from VFR to IFR".to_string(),
+        "Now reporting TSRA".to_string(),
+        "No longer reporting BR".to_string(),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_render() {
+    let digest = Digest {
+      generated_at: Utc::now(),
+      since: None,
+      airports: vec![
+        DigestAirport {
+          icao: "KBOS".to_string(),
+          label: Some("<Home>".to_string()),
+          metar: Some(metar("KBOS 121754Z 09012KT 2SM BR OVC006 08/07 A2990")),
+          changes: vec!["Now reporting BR".to_string()],
+        },
+        DigestAirport {
+          icao: "KXYZ".to_string(),
+          label: None,
+          metar: None,
+          changes: vec![],
+        },
+      ],
+    };
+    let rendered = digest.render();
+    assert!(rendered.subject.ends_with("1 of 2 airports are below VFR."));
+    assert!(rendered.text.contains("KBOS (<Home>): IFR"));
+    assert!(rendered
+      .text
+      .contains("Since last digest: Now reporting BR"));
+    assert!(rendered.text.contains("KXYZ: No recent observation"));
+    assert!(rendered.html.contains("(&lt;Home&gt;)"));
+    assert!(rendered.html.contains("<li>Now reporting BR</li>"));
+    assert!(!rendered.html.contains("{{"));
+    assert!(!rendered.text.contains("{{"));
+  }
+
+  #[test]
+  fn test_update_request() {
+    let request: UpdateDigestSubscription =
+      serde_json::from_str(r#"{ "send_at": "05:30", "timezone": "Europe/London" }"#).unwrap();
+    assert_eq!(request.send_at, NaiveTime::from_hms_opt(5, 30, 0));
+  }
+}
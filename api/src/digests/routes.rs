@@ -0,0 +1,96 @@
+use actix_web::{get, put, web, HttpRequest, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use crate::auth::{client_ip, Auth};
+use crate::digests::{Digest, DigestSubscription, RenderedDigest, UpdateDigestSubscription};
+use crate::error::Error;
+use crate::AppState;
+
+#[get("/subscription")]
+async fn get_subscription(auth: Auth) -> HttpResponse {
+  match DigestSubscription::select(&auth.user.email).await {
+    Ok(subscription) => HttpResponse::Ok().json(subscription),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[put("/subscription")]
+async fn update_subscription(
+  request: web::Json<UpdateDigestSubscription>,
+  req: HttpRequest,
+  auth: Auth,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  // Digests only go to verified addresses, unsubscribing is always allowed
+  if !auth.user.is_email_verified() && request.enabled != Some(false) {
+    return ResponseError::error_response(&Error::new(
+      403,
+      "Verify your email address before subscribing to digests".to_string(),
+    ));
+  }
+  match DigestSubscription::upsert(&auth.user.email, &request).await {
+    Ok(subscription) => {
+      log::info!(
+        "Updated digest subscription [Email: {}] [IP Address: {}]",
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::Ok().json(subscription)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PreviewFormat {
+  Json,
+  Html,
+  Text,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PreviewParameters {
+  format: Option<PreviewFormat>,
+}
+
+#[derive(Debug, Serialize)]
+struct DigestPreview {
+  #[serde(flatten)]
+  rendered: RenderedDigest,
+  digest: Digest,
+}
+
+/// Render the next digest without sending it
+#[get("/preview")]
+async fn preview(data: web::Data<AppState>, req: HttpRequest, auth: Auth) -> HttpResponse {
+  let parameters = match web::Query::<PreviewParameters>::from_query(req.query_string()) {
+    Ok(p) => p.into_inner(),
+    Err(err) => return ResponseError::error_response(&err),
+  };
+  let digest = match Digest::preview(&data.client, &auth.user.email).await {
+    Ok(digest) => digest,
+    Err(err) => {
+      log::error!("{}", err);
+      return ResponseError::error_response(&err);
+    }
+  };
+  let rendered = digest.render();
+  match parameters.format.unwrap_or(PreviewFormat::Json) {
+    PreviewFormat::Json => HttpResponse::Ok().json(DigestPreview { rendered, digest }),
+    PreviewFormat::Html => HttpResponse::Ok()
+      .content_type("text/html; charset=utf-8")
+      .body(rendered.html),
+    PreviewFormat::Text => HttpResponse::Ok()
+      .content_type("text/plain; charset=utf-8")
+      .body(rendered.text),
+  }
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+  config.service(
+    web::scope("digests")
+      .service(get_subscription)
+      .service(update_subscription)
+      .service(preview),
+  );
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+use crate::db;
+use crate::error::ApiResult;
+use crate::mail::{self, Mail};
+use super::model::NEXT_SEND_AT_SQL;
+use super::{Digest, DigestSubscription};
+
+const TABLE_NAME: &str = "digest_subscriptions";
+const USERS_TABLE_NAME: &str = "users";
+const POLL_INTERVAL_SECONDS: u64 = 60;
+const BATCH_SIZE: i64 = 50;
+/// Claimed digests are hidden from other replicas for this long, and retried once it passes
+/// when sending failed
+const LEASE_SECONDS: i64 = 600;
+
+/// Take the subscriptions of verified, enabled users that are due, leasing each so that only
+/// one replica sends a given digest
+async fn claim_due() -> ApiResult<Vec<String>> {
+  let pool = db::pool();
+  let emails: Vec<String> = sqlx::query_scalar(&format!(
+    r#"
+    UPDATE {0} SET next_send_at = NOW() + make_interval(secs => $2)
+    WHERE email IN (
+      SELECT email FROM {0}
+      WHERE enabled AND next_send_at <= NOW()
+        AND email IN (
+          SELECT email FROM {1} WHERE disabled_at IS NULL AND email_verified_at IS NOT NULL
+        )
+      ORDER BY next_send_at
+      LIMIT $1
+      FOR UPDATE SKIP LOCKED
+    )
+    RETURNING email
+    "#,
+    TABLE_NAME, USERS_TABLE_NAME
+  ))
+  .bind(BATCH_SIZE)
+  .bind(LEASE_SECONDS as f64)
+  .fetch_all(pool)
+  .await?;
+  Ok(emails)
+}
+
+/// Move a handled subscription on to its next send time, ending its lease
+async fn schedule_next(email: &str) -> ApiResult<()> {
+  let pool = db::pool();
+  sqlx::query(&format!(
+    r#"
+    UPDATE {} SET next_send_at = {} WHERE email = $1
+    "#,
+    TABLE_NAME, NEXT_SEND_AT_SQL
+  ))
+  .bind(email)
+  .execute(pool)
+  .await?;
+  Ok(())
+}
+
+/// Send the digest to one user, skipped while they have no favorites
+pub async fn send_digest(client: &reqwest::Client, email: &str) -> ApiResult<bool> {
+  let previous = DigestSubscription::select_snapshot(email).await?;
+  let digest = Digest::build(client, email, previous).await?;
+  if digest.airports.is_empty() {
+    return Ok(false);
+  }
+  let rendered = digest.render();
+  mail::send(&Mail {
+    to: email.to_string(),
+    subject: rendered.subject,
+    body: rendered.text,
+    html: Some(rendered.html),
+  })
+  .await?;
+  DigestSubscription::record_sent(email, &digest.snapshot()).await?;
+  Ok(true)
+}
+
+/// Send due digests until the process exits. A failed digest keeps its send time and is
+/// retried when its lease runs out.
+pub fn spawn_scheduler(client: reqwest::Client) {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(POLL_INTERVAL_SECONDS));
+    loop {
+      interval.tick().await;
+      let emails = match claim_due().await {
+        Ok(emails) => emails,
+        Err(err) => {
+          log::error!("Unable to claim due digests: {}", err);
+          continue;
+        }
+      };
+      for email in emails {
+        match send_digest(&client, &email).await {
+          Ok(true) => log::info!("Sent weather digest [Email: {}]", email),
+          Ok(false) => log::debug!("Skipped empty weather digest [Email: {}]", email),
+          Err(err) => {
+            log::error!("Unable to send weather digest [Email: {}]: {}", email, err);
+            continue;
+          }
+        }
+        if let Err(err) = schedule_next(&email).await {
+          log::error!(
+            "Unable to schedule next weather digest [Email: {}]: {}",
+            email,
+            err
+          );
+        }
+      }
+    }
+  });
+}
@@ -0,0 +1,58 @@
+/// Replace each `{{key}}` in the template with its value, unknown keys are left in place.
+/// Values are inserted as given, escape them first for HTML templates.
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+  let mut output = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find("{{") {
+    output.push_str(&rest[..start]);
+    let tag = &rest[start..];
+    let Some(end) = tag.find("}}") else {
+      output.push_str(tag);
+      return output;
+    };
+    let key = tag[2..end].trim();
+    match values.iter().find(|(name, _)| *name == key) {
+      Some((_, value)) => output.push_str(value),
+      None => output.push_str(&tag[..end + 2]),
+    }
+    rest = &tag[end + 2..];
+  }
+  output.push_str(rest);
+  output
+}
+
+pub fn escape_html(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&#39;"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() {
+    let values = [("icao", "KBOS"), ("wind", "{{icao}}")];
+    assert_eq!(render("{{icao}}: {{ wind }}", &values), "KBOS: {{icao}}");
+    assert_eq!(render("{{unknown}} {{icao}}", &values), "{{unknown}} KBOS");
+    assert_eq!(render("{{icao", &values), "{{icao");
+  }
+
+  #[test]
+  fn test_escape_html() {
+    assert_eq!(
+      escape_html(r#"<a href="x">R&D's</a>"#),
+      "&lt;a href=&quot;x&quot;&gt;R&amp;D&#39;s&lt;/a&gt;"
+    );
+  }
+}
@@ -184,6 +184,8 @@ impl From<sqlx::Error> for Error {
           match code.trim() {
             // Unique violation
             "23505" => return Error::new(409, err.to_string()),
+            // Foreign key violation
+            "23503" => return Error::new(409, err.to_string()),
             _ => (),
           }
         }
@@ -204,3 +206,27 @@ impl From<sqlx::migrate::MigrateError> for Error {
     Error::new(500, error.to_string())
   }
 }
+
+impl From<lettre::address::AddressError> for Error {
+  fn from(error: lettre::address::AddressError) -> Self {
+    Self::new(400, format!("Invalid email address: {}", error))
+  }
+}
+
+impl From<lettre::error::Error> for Error {
+  fn from(error: lettre::error::Error) -> Self {
+    Self::new(500, format!("Unknown mail error: {}", error))
+  }
+}
+
+impl From<lettre::transport::smtp::Error> for Error {
+  fn from(error: lettre::transport::smtp::Error) -> Self {
+    Self::new(500, format!("Unknown smtp error: {}", error))
+  }
+}
+
+impl From<lettre::transport::file::Error> for Error {
+  fn from(error: lettre::transport::file::Error) -> Self {
+    Self::new(500, format!("Unknown mail file error: {}", error))
+  }
+}
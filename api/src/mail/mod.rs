@@ -0,0 +1,173 @@
+use std::sync::OnceLock;
+use lettre::{
+  message::{header::ContentType, Mailbox, MultiPart},
+  AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use crate::error::{ApiResult, Error};
+
+static MAILER: OnceLock<Mailer> = OnceLock::new();
+
+/// Plain text email sent to a single recipient, with an optional HTML alternative
+#[derive(Debug, Clone)]
+pub struct Mail {
+  pub to: String,
+  pub subject: String,
+  pub body: String,
+  pub html: Option<String>,
+}
+
+impl Mail {
+  pub fn password_reset(to: &str, token: &str, ttl_seconds: i64) -> Self {
+    Self {
+      to: to.to_string(),
+      subject: "Reset your password".to_string(),
+      body: format!(
+        "A password reset was requested for your account.\n\n\
+        Follow this link within {} to choose a new password:\n{}\n\n\
+        If you did not request a reset you can ignore this email.",
+        describe_ttl(ttl_seconds),
+        app_link(&format!("/reset-password?token={}", token))
+      ),
+      html: None,
+    }
+  }
+
+  pub fn email_verification(to: &str, token: &str, ttl_seconds: i64) -> Self {
+    Self {
+      to: to.to_string(),
+      subject: "Verify your email address".to_string(),
+      body: format!(
+        "Welcome to Aviation Weather!\n\n\
+        Follow this link within {} to verify your email address:\n{}\n\n\
+        If you did not create an account you can ignore this email.",
+        describe_ttl(ttl_seconds),
+        app_link(&format!("/verify?token={}", token))
+      ),
+      html: None,
+    }
+  }
+}
+
+/// Link lifetime in minutes below an hour and in whole hours above
+fn describe_ttl(seconds: i64) -> String {
+  let plural = |count: i64, unit: &str| match count {
+    1 => format!("1 {}", unit),
+    count => format!("{} {}s", count, unit),
+  };
+  if seconds < 3600 {
+    plural((seconds / 60).max(1), "minute")
+  } else {
+    plural(seconds / 3600, "hour")
+  }
+}
+
+/// Outgoing mail transport, selected with `MAIL_TRANSPORT`
+enum Transport {
+  /// Delivers through the SMTP server at `SMTP_URL`
+  Smtp(AsyncSmtpTransport<Tokio1Executor>),
+  /// Writes each message as an `.eml` file into `MAIL_DIRECTORY`, for local testing
+  File(AsyncFileTransport<Tokio1Executor>),
+  /// Only logs the message, for local testing
+  Log,
+}
+
+pub struct Mailer {
+  from: Mailbox,
+  transport: Transport,
+}
+
+pub fn initialize() -> ApiResult<()> {
+  let from = std::env::var("MAIL_FROM")
+    .unwrap_or("Aviation Weather <no-reply@localhost>".to_string())
+    .parse::<Mailbox>()?;
+  let transport = match std::env::var("MAIL_TRANSPORT")
+    .unwrap_or("log".to_string())
+    .as_str()
+  {
+    "smtp" => {
+      let url = std::env::var("SMTP_URL").expect("SMTP_URL must be set for the smtp transport");
+      Transport::Smtp(AsyncSmtpTransport::<Tokio1Executor>::from_url(&url)?.build())
+    }
+    "file" => {
+      let directory = std::env::var("MAIL_DIRECTORY").unwrap_or("mail".to_string());
+      std::fs::create_dir_all(&directory)?;
+      log::info!("Writing outgoing mail to {}", &directory);
+      Transport::File(AsyncFileTransport::<Tokio1Executor>::new(directory))
+    }
+    "log" => {
+      log::warn!("Outgoing mail is only logged, set MAIL_TRANSPORT to deliver it.");
+      Transport::Log
+    }
+    transport => {
+      return Err(Error::new(
+        500,
+        format!("Unknown mail transport '{}'", transport),
+      ))
+    }
+  };
+  if MAILER.set(Mailer { from, transport }).is_err() {
+    log::warn!("Mailer already initialized");
+  }
+  Ok(())
+}
+
+pub async fn send(mail: &Mail) -> ApiResult<()> {
+  let mailer = MAILER.get().expect("Mailer not initialized");
+  let builder = Message::builder()
+    .from(mailer.from.clone())
+    .to(mail.to.parse::<Mailbox>()?)
+    .subject(&mail.subject);
+  let message = match &mail.html {
+    Some(html) => builder.multipart(MultiPart::alternative_plain_html(
+      mail.body.clone(),
+      html.clone(),
+    ))?,
+    None => builder
+      .header(ContentType::TEXT_PLAIN)
+      .body(mail.body.clone())?,
+  };
+
+  match &mailer.transport {
+    Transport::Smtp(transport) => {
+      transport.send(message).await?;
+    }
+    Transport::File(transport) => {
+      transport.send(message).await?;
+    }
+    Transport::Log => log::info!(
+      "Outgoing mail [To: {}] [Subject: {}]\n{}",
+      mail.to,
+      mail.subject,
+      mail.body
+    ),
+  }
+  Ok(())
+}
+
+/// Send in the background so response times don't reveal whether mail was sent
+pub fn spawn_send(mail: Mail) {
+  tokio::spawn(async move {
+    if let Err(err) = send(&mail).await {
+      log::error!("Unable to send mail [To: {}]: {}", mail.to, err);
+    }
+  });
+}
+
+/// Absolute link into the UI, based on `APP_URL`
+pub fn app_link(path: &str) -> String {
+  let app_url = std::env::var("APP_URL").unwrap_or("https://localhost:8443".to_string());
+  format!("{}{}", app_url.trim_end_matches('/'), path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_describe_ttl() {
+    assert_eq!(describe_ttl(30), "1 minute");
+    assert_eq!(describe_ttl(1800), "30 minutes");
+    assert_eq!(describe_ttl(3600), "1 hour");
+    assert_eq!(describe_ttl(86400), "24 hours");
+  }
+}
@@ -1,19 +1,30 @@
 use std::env;
+use std::sync::Arc;
 use std::time::Duration;
 use actix_cors::Cors;
 use actix_web::{App, HttpServer, middleware::Logger, web};
+use chrono::Utc;
 use dotenv::from_filename;
 use reqwest::Certificate;
-use crate::auth::hash;
+use crate::auth::{hash, OidcConfig, Totp};
 use crate::users::{User, ADMIN_ROLE};
 
 mod airports;
+mod alerts;
+mod audit;
 mod auth;
 mod db;
+mod digests;
 mod error;
+mod mail;
 mod metars;
+mod organizations;
+mod roles;
 mod scheduler;
+mod settings;
 mod users;
+mod watchlists;
+mod webhooks;
 
 #[derive(Debug, Clone)]
 struct AppState {
@@ -24,6 +35,7 @@ struct AppState {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
   initialize_environment()?;
   db::initialize().await?;
+  mail::initialize()?;
   // scheduler::update_airports();
 
   // Initialize admin user
@@ -41,11 +53,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         );
       }
       let admin_user = User {
-        email,
+        email: email.clone(),
         password_hash,
         role: ADMIN_ROLE.to_string(),
         first_name: "Admin".to_string(),
         last_name: "".to_string(),
+        email_verified_at: Some(Utc::now()),
+        totp_enabled_at: None,
+        totp_required: false,
+        disabled_at: None,
+        picture_key: None,
         updated_at: Default::default(),
         created_at: Default::default(),
       };
@@ -56,6 +73,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
       };
     }
+
+    // The administrator must enrol in 2FA before any permission is granted, setting it to
+    // anything else lifts the requirement again
+    if let Ok(required) = env::var("ADMIN_REQUIRE_2FA") {
+      let required = required == "true";
+      match Totp::set_required(&email, required).await {
+        Ok(_) => log::debug!(
+          "Two-factor authentication is {} for the default administrator",
+          if required { "required" } else { "optional" }
+        ),
+        Err(err) => log::warn!("{}", err),
+      }
+    }
+  }
+
+  // A mapping to a missing role would reject every single sign-on from that group
+  if let Some(oidc) = OidcConfig::current() {
+    oidc.validate().await?;
   }
 
   let certificate_path = env::var("SSL_CA_PATH")?;
@@ -64,11 +99,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
   let client = reqwest::Client::builder()
     .timeout(Duration::from_secs(10))
-    .add_root_certificate(certificate)
+    .add_root_certificate(certificate.clone())
     .tls_built_in_root_certs(true)
     .build()
     .expect("Failed to create reqwest client");
 
+  let webhook_client = reqwest::Client::builder()
+    .timeout(Duration::from_secs(10))
+    .redirect(reqwest::redirect::Policy::none())
+    .dns_resolver(Arc::new(webhooks::PublicResolver))
+    .add_root_certificate(certificate)
+    .tls_built_in_root_certs(true)
+    .build()
+    .expect("Failed to create webhook client");
+  webhooks::spawn_worker(webhook_client);
+  metars::MetarStream::spawn_relay();
+
+  digests::spawn_scheduler(client.clone());
+
   let state = AppState { client };
   let host = env::var("API_HOST").unwrap_or("localhost".to_string());
   let port = env::var("API_PORT").unwrap_or("5000".to_string());
@@ -87,9 +135,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
       .service(
         web::scope("api")
           .configure(airports::init_routes)
+          .configure(alerts::init_routes)
+          .configure(audit::init_routes)
+          .configure(digests::init_routes)
           .configure(metars::init_routes)
+          .configure(organizations::init_routes)
           .configure(auth::init_routes)
-          .configure(users::init_routes),
+          .configure(roles::init_routes)
+          .configure(settings::init_routes)
+          .configure(users::init_routes)
+          .configure(watchlists::init_routes)
+          .configure(webhooks::init_routes),
       )
   })
   .bind(format!("{}:{}", host, port))
@@ -0,0 +1,203 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use chrono::Utc;
+use moka::future::Cache;
+use moka::Expiry;
+use serde::{Deserialize, Serialize};
+use super::{Metar, METAR_OUTDATED_SECONDS};
+
+const DEFAULT_CAPACITY: u64 = 20000;
+// Entries are re-read from Postgres at least this often, other replicas may have inserted newer data
+const MAX_TTL_SECONDS: i64 = 900;
+// Outdated observations are still cached briefly so they don't hit Postgres on every request
+const MIN_TTL_SECONDS: i64 = 60;
+// Stations without a stored observation are remembered this long, most airports never report
+const MISSING_TTL_SECONDS: u64 = 120;
+
+static CACHE: OnceLock<Cache<String, Option<Metar>>> = OnceLock::new();
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetarCacheStats {
+  pub hits: u64,
+  pub misses: u64,
+  pub entries: u64,
+  pub capacity: u64,
+}
+
+/// Keeps a cached METAR until its observation becomes outdated, bounded by the min and max TTL.
+/// A station known to have no METAR is kept for `MISSING_TTL_SECONDS`.
+struct ObservationExpiry;
+
+impl ObservationExpiry {
+  fn ttl(metar: &Option<Metar>) -> Duration {
+    let Some(metar) = metar else {
+      return Duration::from_secs(MISSING_TTL_SECONDS);
+    };
+    let outdated_at = metar.observation_time.timestamp() + METAR_OUTDATED_SECONDS;
+    let ttl = (outdated_at - Utc::now().timestamp()).clamp(MIN_TTL_SECONDS, MAX_TTL_SECONDS);
+    Duration::from_secs(ttl as u64)
+  }
+}
+
+impl Expiry<String, Option<Metar>> for ObservationExpiry {
+  fn expire_after_create(
+    &self,
+    _key: &String,
+    value: &Option<Metar>,
+    _: Instant,
+  ) -> Option<Duration> {
+    Some(Self::ttl(value))
+  }
+
+  fn expire_after_update(
+    &self,
+    _key: &String,
+    value: &Option<Metar>,
+    _: Instant,
+    _: Option<Duration>,
+  ) -> Option<Duration> {
+    Some(Self::ttl(value))
+  }
+}
+
+/// In-process cache of the latest decoded METAR per station
+pub struct MetarCache;
+
+impl MetarCache {
+  fn cache() -> &'static Cache<String, Option<Metar>> {
+    CACHE.get_or_init(|| {
+      let capacity = std::env::var("METAR_CACHE_CAPACITY")
+        .ok()
+        .and_then(|c| c.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_CAPACITY);
+      Cache::builder()
+        .max_capacity(capacity)
+        .expire_after(ObservationExpiry)
+        .build()
+    })
+  }
+
+  /// Returns the cached METARs and the stations that were not cached. Stations cached as
+  /// having no METAR are in neither.
+  pub async fn get_all(icaos: &[String]) -> (Vec<Metar>, Vec<String>) {
+    let cache = Self::cache();
+    let mut metars: Vec<Metar> = vec![];
+    let mut missing: Vec<String> = vec![];
+    for icao in icaos {
+      match cache.get(icao).await {
+        Some(Some(metar)) => metars.push(metar),
+        Some(None) => {}
+        None => missing.push(icao.clone()),
+      }
+    }
+    HITS.fetch_add((icaos.len() - missing.len()) as u64, Ordering::Relaxed);
+    MISSES.fetch_add(missing.len() as u64, Ordering::Relaxed);
+    (metars, missing)
+  }
+
+  /// Cache a METAR unless a newer observation for the station is already cached, replacing
+  /// any entry saying the station has none
+  pub async fn insert(metar: &Metar) {
+    let cache = Self::cache();
+    if let Some(Some(cached)) = cache.get(&metar.station_id).await {
+      if cached.observation_time > metar.observation_time {
+        return;
+      }
+    }
+    cache
+      .insert(metar.station_id.clone(), Some(metar.clone()))
+      .await;
+  }
+
+  /// Remember that the stations have no stored METAR, leaving any observation cached meanwhile
+  pub async fn insert_missing(icaos: &[String]) {
+    let cache = Self::cache();
+    for icao in icaos {
+      cache.entry(icao.clone()).or_insert(None).await;
+    }
+  }
+
+  pub fn stats() -> MetarCacheStats {
+    let cache = Self::cache();
+    MetarCacheStats {
+      hits: HITS.load(Ordering::Relaxed),
+      misses: MISSES.load(Ordering::Relaxed),
+      entries: cache.entry_count(),
+      capacity: cache.policy().max_capacity().unwrap_or_default(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_newer_observation_replaces_cached() {
+    let now = Utc::now();
+    let older = Metar {
+      station_id: "KTST".to_string(),
+      observation_time: now - chrono::Duration::minutes(30),
+      ..Default::default()
+    };
+    let newer = Metar {
+      station_id: "KTST".to_string(),
+      observation_time: now - chrono::Duration::minutes(5),
+      ..Default::default()
+    };
+
+    MetarCache::insert(&newer).await;
+    MetarCache::insert(&older).await;
+    let (cached, missing) = MetarCache::get_all(&["KTST".to_string(), "KNONE".to_string()]).await;
+    assert_eq!(cached.len(), 1);
+    assert_eq!(cached[0].observation_time, newer.observation_time);
+    assert_eq!(missing, vec!["KNONE".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn test_missing_station_until_inserted() {
+    let icaos = vec!["KMIS".to_string()];
+    MetarCache::insert_missing(&icaos).await;
+    let (cached, missing) = MetarCache::get_all(&icaos).await;
+    assert!(cached.is_empty());
+    assert!(missing.is_empty());
+
+    let metar = Metar {
+      station_id: "KMIS".to_string(),
+      observation_time: Utc::now(),
+      ..Default::default()
+    };
+    MetarCache::insert(&metar).await;
+    MetarCache::insert_missing(&icaos).await;
+    let (cached, _) = MetarCache::get_all(&icaos).await;
+    assert_eq!(cached.len(), 1);
+  }
+
+  #[test]
+  fn test_ttl_bounds() {
+    let now = Utc::now();
+    let fresh = Metar {
+      observation_time: now,
+      ..Default::default()
+    };
+    let outdated = Metar {
+      observation_time: now - chrono::Duration::hours(3),
+      ..Default::default()
+    };
+    assert_eq!(
+      ObservationExpiry::ttl(&Some(fresh)),
+      Duration::from_secs(MAX_TTL_SECONDS as u64)
+    );
+    assert_eq!(
+      ObservationExpiry::ttl(&Some(outdated)),
+      Duration::from_secs(MIN_TTL_SECONDS as u64)
+    );
+    assert_eq!(
+      ObservationExpiry::ttl(&None),
+      Duration::from_secs(MISSING_TTL_SECONDS)
+    );
+  }
+}
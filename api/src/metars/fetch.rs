@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use chrono::{DateTime, Duration, Utc};
+use redis::{AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use crate::db::redis_async_connection;
+use crate::error::ApiResult;
+
+const KEY_PREFIX: &str = "metar:state:";
+const STATE_TTL: u64 = 604800; // (In seconds) 7 days, long enough to keep the backoff history
+pub const METAR_OUTDATED_SECONDS: i64 = 3600;
+const STALE_RETRY_SECONDS: i64 = 600;
+const UNAVAILABLE_BACKOFF_BASE_SECONDS: i64 = 900;
+const UNAVAILABLE_BACKOFF_MAX_SECONDS: i64 = 86400;
+const UPSTREAM_ERROR_BACKOFF_BASE_SECONDS: i64 = 60;
+const UPSTREAM_ERROR_BACKOFF_MAX_SECONDS: i64 = 1800;
+
+static IN_FLIGHT: OnceLock<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchStatus {
+  /// The latest observation is less than an hour old
+  Fresh,
+  /// The station reports, but upstream has nothing newer than an hour old
+  Stale,
+  /// Upstream returned no METAR for the station
+  Unavailable,
+  /// The upstream request itself failed
+  UpstreamError,
+}
+
+/// Result of the last upstream METAR fetch for a station, stored in Redis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetarFetchState {
+  pub icao: String,
+  pub status: FetchStatus,
+  /// Consecutive unavailable or failed attempts
+  pub attempts: u32,
+  pub last_attempt_at: DateTime<Utc>,
+  pub next_attempt_at: DateTime<Utc>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub observation_time: Option<DateTime<Utc>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub last_error: Option<String>,
+}
+
+impl MetarFetchState {
+  fn key(icao: &str) -> String {
+    format!("{}{}", KEY_PREFIX, icao)
+  }
+
+  /// A METAR was received; fresh observations are not refetched until they become outdated
+  pub fn observed(icao: &str, observation_time: DateTime<Utc>, now: DateTime<Utc>) -> Self {
+    let outdated_at = observation_time + Duration::seconds(METAR_OUTDATED_SECONDS);
+    let (status, next_attempt_at) = if now < outdated_at {
+      (FetchStatus::Fresh, outdated_at)
+    } else {
+      (
+        FetchStatus::Stale,
+        now + Duration::seconds(STALE_RETRY_SECONDS),
+      )
+    };
+    Self {
+      icao: icao.to_string(),
+      status,
+      attempts: 0,
+      last_attempt_at: now,
+      next_attempt_at,
+      observation_time: Some(observation_time),
+      last_error: None,
+    }
+  }
+
+  /// Upstream had no METAR for the station; retries back off exponentially
+  pub fn unavailable(previous: Option<&Self>, icao: &str, now: DateTime<Utc>) -> Self {
+    let attempts = Self::next_attempts(previous, FetchStatus::Unavailable);
+    Self {
+      icao: icao.to_string(),
+      status: FetchStatus::Unavailable,
+      attempts,
+      last_attempt_at: now,
+      next_attempt_at: now
+        + Duration::seconds(backoff(
+          UNAVAILABLE_BACKOFF_BASE_SECONDS,
+          UNAVAILABLE_BACKOFF_MAX_SECONDS,
+          attempts,
+        )),
+      observation_time: previous.and_then(|p| p.observation_time),
+      last_error: None,
+    }
+  }
+
+  /// The upstream request failed; retries back off exponentially with a shorter ceiling
+  pub fn upstream_error(
+    previous: Option<&Self>,
+    icao: &str,
+    error: &str,
+    now: DateTime<Utc>,
+  ) -> Self {
+    let attempts = Self::next_attempts(previous, FetchStatus::UpstreamError);
+    Self {
+      icao: icao.to_string(),
+      status: FetchStatus::UpstreamError,
+      attempts,
+      last_attempt_at: now,
+      next_attempt_at: now
+        + Duration::seconds(backoff(
+          UPSTREAM_ERROR_BACKOFF_BASE_SECONDS,
+          UPSTREAM_ERROR_BACKOFF_MAX_SECONDS,
+          attempts,
+        )),
+      observation_time: previous.and_then(|p| p.observation_time),
+      last_error: Some(error.to_string()),
+    }
+  }
+
+  fn next_attempts(previous: Option<&Self>, status: FetchStatus) -> u32 {
+    match previous {
+      Some(previous) if previous.status == status => previous.attempts.saturating_add(1),
+      _ => 1,
+    }
+  }
+
+  pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+    now >= self.next_attempt_at
+  }
+
+  pub async fn get_all(icaos: &[String]) -> ApiResult<HashMap<String, Self>> {
+    if icaos.is_empty() {
+      return Ok(HashMap::new());
+    }
+    let mut conn = redis_async_connection().await?;
+    let keys: Vec<String> = icaos.iter().map(|icao| Self::key(icao)).collect();
+    let values: Vec<Option<String>> = conn.mget(keys).await?;
+    Ok(
+      values
+        .into_iter()
+        .flatten()
+        .filter_map(|value| serde_json::from_str::<Self>(&value).ok())
+        .map(|state| (state.icao.clone(), state))
+        .collect(),
+    )
+  }
+
+  /// Every stored state, used by the admin endpoint
+  pub async fn scan() -> ApiResult<Vec<Self>> {
+    let mut conn = redis_async_connection().await?;
+    let mut keys: Vec<String> = vec![];
+    {
+      let mut iter = conn
+        .scan_match::<_, String>(format!("{}*", KEY_PREFIX))
+        .await?;
+      while let Some(key) = iter.next_item().await {
+        keys.push(key);
+      }
+    }
+    let icaos: Vec<String> = keys
+      .into_iter()
+      .map(|key| key.trim_start_matches(KEY_PREFIX).to_string())
+      .collect();
+    let mut states: Vec<Self> = Self::get_all(&icaos).await?.into_values().collect();
+    states.sort_by(|a, b| a.icao.cmp(&b.icao));
+    Ok(states)
+  }
+
+  pub async fn store(&self) -> ApiResult<()> {
+    let mut conn = redis_async_connection().await?;
+    let value = serde_json::to_string(self)?;
+    let result: RedisResult<()> = conn.set_ex(Self::key(&self.icao), value, STATE_TTL).await;
+    Ok(result?)
+  }
+
+  pub async fn delete(icao: &str) -> ApiResult<()> {
+    let mut conn = redis_async_connection().await?;
+    let result: RedisResult<()> = conn.del(Self::key(icao)).await;
+    Ok(result?)
+  }
+}
+
+fn backoff(base: i64, max: i64, attempts: u32) -> i64 {
+  let exponent = attempts.saturating_sub(1).min(20);
+  base.saturating_mul(1 << exponent).min(max)
+}
+
+/// Outcome of trying to become the single upstream fetcher for a station
+pub enum InFlight {
+  /// This request fetches the station; other requests wait until the guard is dropped
+  Acquired(InFlightGuard),
+  /// Another request is already fetching the station
+  Busy(InFlightWaiter),
+}
+
+pub struct InFlightGuard {
+  pub icao: String,
+  lock: Arc<AsyncMutex<()>>,
+  guard: Option<OwnedMutexGuard<()>>,
+}
+
+pub struct InFlightWaiter {
+  pub icao: String,
+  lock: Arc<AsyncMutex<()>>,
+}
+
+impl InFlight {
+  fn registry() -> &'static Mutex<HashMap<String, Arc<AsyncMutex<()>>>> {
+    IN_FLIGHT.get_or_init(|| Mutex::new(HashMap::new()))
+  }
+
+  pub fn acquire(icao: &str) -> Self {
+    let lock = {
+      let mut registry = Self::registry().lock().unwrap();
+      registry
+        .entry(icao.to_string())
+        .or_insert_with(|| Arc::new(AsyncMutex::new(())))
+        .clone()
+    };
+    match lock.clone().try_lock_owned() {
+      Ok(guard) => InFlight::Acquired(InFlightGuard {
+        icao: icao.to_string(),
+        lock,
+        guard: Some(guard),
+      }),
+      Err(_) => InFlight::Busy(InFlightWaiter {
+        icao: icao.to_string(),
+        lock,
+      }),
+    }
+  }
+
+  /// Remove the entry of a finished fetch, unless a later fetch has already replaced it
+  fn release(icao: &str, lock: &Arc<AsyncMutex<()>>) {
+    let mut registry = Self::registry().lock().unwrap();
+    if registry
+      .get(icao)
+      .is_some_and(|current| Arc::ptr_eq(current, lock))
+    {
+      registry.remove(icao);
+    }
+  }
+}
+
+impl Drop for InFlightGuard {
+  fn drop(&mut self) {
+    // Unregister before unlocking so no new caller can join a fetch that is finishing
+    InFlight::release(&self.icao, &self.lock);
+    drop(self.guard.take());
+  }
+}
+
+impl InFlightWaiter {
+  /// Wait for the in-flight fetch of this station to finish
+  pub async fn wait(self) {
+    let _ = self.lock.lock().await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_backoff() {
+    assert_eq!(backoff(900, 86400, 1), 900);
+    assert_eq!(backoff(900, 86400, 2), 1800);
+    assert_eq!(backoff(900, 86400, 4), 7200);
+    assert_eq!(backoff(900, 86400, 8), 86400);
+    assert_eq!(backoff(900, 86400, u32::MAX), 86400);
+  }
+
+  #[test]
+  fn test_transitions() {
+    let now = Utc::now();
+    let fresh = MetarFetchState::observed("KABC", now - Duration::minutes(10), now);
+    assert_eq!(fresh.status, FetchStatus::Fresh);
+    assert!(!fresh.is_due(now));
+    assert!(fresh.is_due(now + Duration::minutes(50)));
+
+    let stale = MetarFetchState::observed("KABC", now - Duration::hours(2), now);
+    assert_eq!(stale.status, FetchStatus::Stale);
+
+    let first = MetarFetchState::unavailable(Some(&stale), "KABC", now);
+    assert_eq!(first.attempts, 1);
+    let second = MetarFetchState::unavailable(Some(&first), "KABC", now);
+    assert_eq!(second.attempts, 2);
+    assert_eq!(
+      second.next_attempt_at - now,
+      Duration::seconds(UNAVAILABLE_BACKOFF_BASE_SECONDS * 2)
+    );
+
+    let error = MetarFetchState::upstream_error(Some(&second), "KABC", "timeout", now);
+    assert_eq!(error.attempts, 1);
+    assert_eq!(error.last_error.as_deref(), Some("timeout"));
+  }
+
+  #[tokio::test]
+  async fn test_single_flight() {
+    let first = InFlight::acquire("KSFL");
+    assert!(matches!(first, InFlight::Acquired(_)));
+    let second = InFlight::acquire("KSFL");
+    let waiter = match second {
+      InFlight::Busy(waiter) => waiter,
+      InFlight::Acquired(_) => panic!("Expected the station to be busy"),
+    };
+    drop(first);
+    assert!(!InFlight::registry().lock().unwrap().contains_key("KSFL"));
+    waiter.wait().await;
+
+    // A fetch started after the first one finished is not released by the old entry
+    let third = InFlight::acquire("KSFL");
+    assert!(matches!(third, InFlight::Acquired(_)));
+    let stale = Arc::new(AsyncMutex::new(()));
+    InFlight::release("KSFL", &stale);
+    assert!(matches!(InFlight::acquire("KSFL"), InFlight::Busy(_)));
+    drop(third);
+    assert!(!InFlight::registry().lock().unwrap().contains_key("KSFL"));
+  }
+}
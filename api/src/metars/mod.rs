@@ -1,5 +1,11 @@
+mod cache;
+mod fetch;
 mod model;
 mod routes;
+mod stream;
 
+pub use cache::*;
+pub use fetch::*;
 pub use model::*;
 pub use routes::init_routes;
+pub use stream::*;
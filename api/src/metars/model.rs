@@ -1,18 +1,26 @@
+use crate::alerts::AlertRule;
 use crate::error::Error;
+use crate::webhooks::Webhook;
 use crate::{error::ApiResult, db};
-use chrono::{DateTime, Datelike, Utc};
-use std::collections::HashSet;
-use redis::{AsyncCommands, RedisResult};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use std::collections::{HashMap, HashSet};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use crate::db::redis_async_connection;
+use uuid::Uuid;
+use super::{
+  InFlight, InFlightGuard, InFlightWaiter, MetarCache, MetarFetchState, MetarStream,
+  METAR_OUTDATED_SECONDS,
+};
 
 const TABLE_NAME: &str = "metars";
+const OBSERVATION_TIME_TOLERANCE_MINUTES: i64 = 60;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Metar {
   pub station_id: String, // icao
   pub raw_text: String,
+  #[serde(default)]
+  pub report_type: ReportType,
   pub observation_time: DateTime<Utc>,
   #[serde(skip_serializing_if = "Option::is_none")]
   pub temp_c: Option<f64>,
@@ -51,7 +59,7 @@ pub struct Metar {
   pub density_altitude: Option<f64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RunwayVisualRange {
   pub runway: String,
   #[serde(skip_serializing_if = "Option::is_none")]
@@ -73,7 +81,7 @@ impl Default for RunwayVisualRange {
   }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Remarks {
   #[serde(skip_serializing_if = "Option::is_none")]
   pub peak_wind: Option<PeakWind>,
@@ -107,7 +115,7 @@ pub struct Remarks {
   pub sky_condition_at_secondary_location_not_available: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PeakWind {
   pub degrees: i32,
   pub speed: i32,
@@ -137,7 +145,7 @@ impl Default for Remarks {
   }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SkyCondition {
   pub sky_cover: String,
   #[serde(skip_serializing_if = "Option::is_none")]
@@ -156,7 +164,36 @@ impl Default for SkyCondition {
   }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Routine (METAR) or special (SPECI) report
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReportType {
+  #[default]
+  #[serde(rename = "METAR")]
+  Metar,
+  #[serde(rename = "SPECI")]
+  Speci,
+}
+
+impl std::fmt::Display for ReportType {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ReportType::Metar => write!(f, "METAR"),
+      ReportType::Speci => write!(f, "SPECI"),
+    }
+  }
+}
+
+/// Outcome of storing an observation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetarInsert {
+  Inserted,
+  /// A COR report replaced the previously stored text for the same observation
+  Corrected,
+  /// The observation was already stored
+  Duplicate,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FlightCategory {
   VFR,
   MVFR,
@@ -170,6 +207,7 @@ impl Default for Metar {
     Self {
       raw_text: "".to_string(),
       station_id: "".to_string(),
+      report_type: ReportType::Metar,
       observation_time: chrono::DateTime::parse_from_rfc3339("1970-01-01T00:00:00Z")
         .unwrap()
         .with_timezone(&Utc),
@@ -201,33 +239,116 @@ impl Default for Metar {
 struct MetarRow {
   icao: String,
   observation_time: DateTime<Utc>,
+  report_type: String,
+  corrected: bool,
+  raw_text: String,
+  data: serde_json::Value,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct StoredMetarText {
   raw_text: String,
   data: serde_json::Value,
 }
 
 impl MetarRow {
-  async fn insert(&self) -> ApiResult<()> {
+  /// Insert the observation once; a corrected report replaces the stored text and the
+  /// replaced text is kept in the corrections table
+  async fn upsert(&self) -> ApiResult<MetarInsert> {
     let pool = db::pool();
-    sqlx::query(&format!(
+    let mut tx = pool.begin().await?;
+
+    let existing: Option<StoredMetarText> = sqlx::query_as(&format!(
       r#"
-      INSERT INTO {} (
-        icao,
-        observation_time,
-        raw_text,
-        data
-      )
-      VALUES ($1, $2, $3, $4)
+      SELECT raw_text, data FROM {}
+      WHERE icao = $1 AND observation_time = $2 AND report_type = $3
+      FOR UPDATE
       "#,
       TABLE_NAME,
     ))
-    .bind(self.icao.clone())
-    .bind(self.observation_time.clone())
-    .bind(self.raw_text.clone())
-    .bind(self.data.clone())
-    .execute(pool)
+    .bind(&self.icao)
+    .bind(self.observation_time)
+    .bind(&self.report_type)
+    .fetch_optional(&mut *tx)
     .await?;
 
-    Ok(())
+    let outcome = match existing {
+      None => {
+        let result = sqlx::query(&format!(
+          r#"
+          INSERT INTO {} (
+            icao,
+            observation_time,
+            report_type,
+            corrected,
+            raw_text,
+            data
+          )
+          VALUES ($1, $2, $3, $4, $5, $6)
+          ON CONFLICT (icao, observation_time, report_type) DO NOTHING
+          "#,
+          TABLE_NAME,
+        ))
+        .bind(&self.icao)
+        .bind(self.observation_time)
+        .bind(&self.report_type)
+        .bind(self.corrected)
+        .bind(&self.raw_text)
+        .bind(&self.data)
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+          MetarInsert::Duplicate
+        } else {
+          MetarInsert::Inserted
+        }
+      }
+      Some(stored) if self.corrected && stored.raw_text != self.raw_text => {
+        sqlx::query(
+          r#"
+          INSERT INTO metar_corrections (
+            id,
+            icao,
+            observation_time,
+            report_type,
+            previous_raw_text,
+            previous_data,
+            raw_text
+          )
+          VALUES ($1, $2, $3, $4, $5, $6, $7)
+          "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(&self.icao)
+        .bind(self.observation_time)
+        .bind(&self.report_type)
+        .bind(&stored.raw_text)
+        .bind(&stored.data)
+        .bind(&self.raw_text)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(&format!(
+          r#"
+          UPDATE {} SET raw_text = $4, data = $5, corrected = true, updated_at = NOW()
+          WHERE icao = $1 AND observation_time = $2 AND report_type = $3
+          "#,
+          TABLE_NAME,
+        ))
+        .bind(&self.icao)
+        .bind(self.observation_time)
+        .bind(&self.report_type)
+        .bind(&self.raw_text)
+        .bind(&self.data)
+        .execute(&mut *tx)
+        .await?;
+        MetarInsert::Corrected
+      }
+      Some(_) => MetarInsert::Duplicate,
+    };
+
+    tx.commit().await?;
+    Ok(outcome)
   }
 }
 
@@ -248,6 +369,12 @@ impl Metar {
   }
 
   fn parse(metar_string: &str) -> ApiResult<Self> {
+    Self::parse_with_reference(metar_string, Utc::now())
+  }
+
+  /// Parse a METAR whose `DDhhmmZ` observation time is resolved relative to `reference`,
+  /// the time the report was received or the bulletin was issued
+  pub fn parse_with_reference(metar_string: &str, reference: DateTime<Utc>) -> ApiResult<Self> {
     if metar_string.is_empty() {
       return Err(Error::new(
         404,
@@ -269,8 +396,16 @@ impl Metar {
       ));
     }
 
-    // Remove METAR at start of text
-    if metar_parts[0].to_string() == "METAR".to_string() {
+    // Remove METAR or SPECI at start of text
+    if metar_parts[0] == "METAR" {
+      metar_parts.remove(0);
+    } else if metar_parts[0] == "SPECI" {
+      metar.report_type = ReportType::Speci;
+      metar_parts.remove(0);
+    }
+    // Corrections may be marked before the station identifier
+    if metar_parts[0] == "COR" {
+      metar.remarks.corrected = Some(true);
       metar_parts.remove(0);
     }
 
@@ -302,32 +437,12 @@ impl Metar {
       Ok(minute) => minute,
       Err(err) => return Err(err.into()),
     };
-    let current_time = Utc::now().naive_utc();
-
-    // Check if the observation time is from the previous month
-    let observation_time_month = if current_time.day() > observation_time_day {
-      current_time.month() - 1
-    } else {
-      current_time.month()
-    };
-    // Check if the observation time is from the previous year
-    let observation_time_year = if current_time.month() > observation_time_month {
-      current_time.year() - 1
-    } else {
-      current_time.year()
-    };
-    let observation_time = format!(
-      "{:04}-{:02}-{:02}T{:02}:{:02}:00Z",
-      observation_time_year,
-      observation_time_month,
+    metar.observation_time = Self::resolve_observation_time(
       observation_time_day,
       observation_time_hour,
-      observation_time_minute
-    );
-    metar.observation_time = match chrono::DateTime::parse_from_rfc3339(&observation_time) {
-      Ok(datetime) => datetime.with_timezone(&Utc),
-      Err(err) => return Err(err.into()),
-    };
+      observation_time_minute,
+      reference,
+    )?;
 
     loop {
       if metar_parts.is_empty() {
@@ -823,26 +938,69 @@ impl Metar {
     Ok(metar)
   }
 
-  async fn get_missing_metar_icaos(
-    db_metars: &Vec<Self>,
-    station_icaos: &Vec<String>,
-  ) -> Vec<String> {
-    let mut missing_metar_icaos: Vec<String> = vec![];
-    let current_time = chrono::Local::now().naive_local().and_utc().timestamp();
-    let db_metars_set: HashSet<&str> = db_metars
-      .iter()
-      .map(|icao| icao.station_id.as_str())
-      .collect();
-    let station_icaos_set: HashSet<&str> = station_icaos.iter().map(|s| s.as_str()).collect();
-    for difference in db_metars_set.symmetric_difference(&station_icaos_set) {
-      missing_metar_icaos.push(difference.to_string());
+  /// The most recent date with the given day of month and time that is not after the
+  /// reference, walking back over months that are too short for the day
+  fn resolve_observation_time(
+    day: u32,
+    hour: u32,
+    minute: u32,
+    reference: DateTime<Utc>,
+  ) -> ApiResult<DateTime<Utc>> {
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+      return Err(Error::new(
+        500,
+        format!(
+          "Invalid observation time {:02}{:02}{:02}Z",
+          day, hour, minute
+        ),
+      ));
     }
-    for metar in db_metars {
-      if current_time > (metar.observation_time.timestamp() + 3600) {
-        log::trace!("{} METAR data is outdated", metar.station_id);
-        missing_metar_icaos.push(metar.station_id.to_string());
+
+    // Reports may be stamped slightly ahead of the reference clock
+    let latest = reference + chrono::Duration::minutes(OBSERVATION_TIME_TOLERANCE_MINUTES);
+    let reference_month = reference.year() * 12 + reference.month0() as i32;
+    for month_offset in [1, 0, -1, -2, -3] {
+      let month_index = reference_month + month_offset;
+      let year = month_index.div_euclid(12);
+      let month = month_index.rem_euclid(12) as u32 + 1;
+      let candidate = NaiveDate::from_ymd_opt(year, month, day)
+        .and_then(|date| date.and_hms_opt(hour, minute, 0))
+        .map(|datetime| datetime.and_utc());
+      if let Some(candidate) = candidate {
+        if candidate <= latest {
+          return Ok(candidate);
+        }
       }
     }
+    Err(Error::new(
+      500,
+      format!(
+        "Unable to resolve observation time {:02}{:02}{:02}Z before {}",
+        day, hour, minute, reference
+      ),
+    ))
+  }
+
+  fn get_missing_metar_icaos(db_metars: &[Self], station_icaos: &[String]) -> Vec<String> {
+    let current_time = Utc::now().timestamp();
+    let current_icaos: HashSet<&str> = db_metars
+      .iter()
+      .filter(|metar| {
+        let outdated = current_time > metar.observation_time.timestamp() + METAR_OUTDATED_SECONDS;
+        if outdated {
+          log::trace!("{} METAR data is outdated", metar.station_id);
+        }
+        !outdated
+      })
+      .map(|metar| metar.station_id.as_str())
+      .collect();
+    let mut missing_metar_icaos: Vec<String> = station_icaos
+      .iter()
+      .filter(|icao| !current_icaos.contains(icao.as_str()))
+      .cloned()
+      .collect();
+    missing_metar_icaos.sort();
+    missing_metar_icaos.dedup();
     missing_metar_icaos
   }
 
@@ -897,103 +1055,193 @@ impl Metar {
     Ok(MetarRow {
       icao: self.station_id.clone(),
       observation_time: self.observation_time,
+      report_type: self.report_type.to_string(),
+      corrected: self.is_correction(),
       raw_text: self.raw_text.clone(),
       data,
     })
   }
 
-  pub async fn find_all(
-    client: &Client,
-    icao_list: &Vec<String>,
-    force: &bool,
-  ) -> ApiResult<Vec<Self>> {
-    if icao_list.is_empty() {
-      return Ok(Vec::new());
+  /// Latest METAR per station, served from the in-process cache before Postgres
+  async fn select_latest(icao_list: &[String]) -> ApiResult<Vec<Self>> {
+    let (mut metars, uncached) = MetarCache::get_all(icao_list).await;
+    if uncached.is_empty() {
+      return Ok(metars);
     }
 
     let pool = db::pool();
     let metar_rows: Vec<MetarRow> = sqlx::query_as::<_, MetarRow>(&format!(
       r#"
-      SELECT DISTINCT ON (icao) * FROM {} WHERE icao = ANY($1) ORDER BY icao, observation_time DESC
+      SELECT DISTINCT ON (icao) * FROM {} WHERE icao = ANY($1)
+      ORDER BY icao, observation_time DESC, updated_at DESC
       "#,
       TABLE_NAME
     ))
-    .bind(icao_list)
+    .bind(&uncached)
     .fetch_all(pool)
     .await?;
-    let mut metars: Vec<Metar> = metar_rows
+    let stored: HashSet<String> = metar_rows.iter().map(|row| row.icao.clone()).collect();
+    for metar in metar_rows
       .into_iter()
       .filter_map(|metar_db| Metar::from_db(metar_db).ok())
+    {
+      MetarCache::insert(&metar).await;
+      metars.push(metar);
+    }
+    let unreported: Vec<String> = uncached
+      .into_iter()
+      .filter(|icao| !stored.contains(icao))
       .collect();
+    MetarCache::insert_missing(&unreported).await;
+    Ok(metars)
+  }
+
+  /// The latest stored observation of the station before this one
+  pub async fn select_previous(&self) -> ApiResult<Option<Self>> {
+    let pool = db::pool();
+    let metar_row: Option<MetarRow> = sqlx::query_as(&format!(
+      r#"
+      SELECT * FROM {} WHERE icao = $1 AND observation_time < $2
+      ORDER BY observation_time DESC, updated_at DESC
+      LIMIT 1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(&self.station_id)
+    .bind(self.observation_time)
+    .fetch_optional(pool)
+    .await?;
+    metar_row.map(Metar::from_db).transpose()
+  }
+
+  /// Replace any METARs for the same stations with the given ones
+  fn merge(metars: &mut Vec<Self>, updated: Vec<Self>) {
+    for metar in updated {
+      metars.retain(|m| m.station_id != metar.station_id);
+      metars.push(metar);
+    }
+  }
+
+  pub async fn find_all(
+    client: &Client,
+    icao_list: &[String],
+    force: &bool,
+  ) -> ApiResult<Vec<Self>> {
+    if icao_list.is_empty() {
+      return Ok(Vec::new());
+    }
+
+    let mut metars = Self::select_latest(icao_list).await?;
 
-    let mut conn = redis_async_connection().await?;
     // Check for missing metars
-    let missing_icao_list = Self::get_missing_metar_icaos(&metars, icao_list).await;
-    if !missing_icao_list.is_empty() {
-      let mut updated_missing_icao_list: Vec<&str> = Vec::new();
-      for icao in &missing_icao_list {
-        if *force {
-          updated_missing_icao_list.push(icao);
-        } else {
-          let result: RedisResult<Option<bool>> = conn.get(icao).await;
-          match result {
-            Ok(Some(value)) => {
-              if value {
-                updated_missing_icao_list.push(icao);
-              }
-            }
-            Ok(None) => {
-              updated_missing_icao_list.push(icao);
+    let missing_icao_list = Self::get_missing_metar_icaos(&metars, icao_list);
+    if missing_icao_list.is_empty() {
+      return Ok(metars);
+    }
+
+    // Skip stations that are backing off, unless forced
+    let now = Utc::now();
+    let states = MetarFetchState::get_all(&missing_icao_list).await?;
+    let mut fetch_guards: Vec<InFlightGuard> = vec![];
+    let mut waiters: Vec<InFlightWaiter> = vec![];
+    for icao in &missing_icao_list {
+      let due = *force || states.get(icao).is_none_or(|state| state.is_due(now));
+      if !due {
+        continue;
+      }
+      // Only one request fetches a given station at a time
+      match InFlight::acquire(icao) {
+        InFlight::Acquired(guard) => fetch_guards.push(guard),
+        InFlight::Busy(waiter) => waiters.push(waiter),
+      }
+    }
+
+    if !fetch_guards.is_empty() {
+      let fetch_icaos: Vec<String> = fetch_guards.iter().map(|g| g.icao.clone()).collect();
+      log::trace!("Retrieving missing METAR data for {:?}", fetch_icaos);
+      let fetched = Self::fetch_remote(client, &fetch_icaos, &states).await?;
+      Self::merge(&mut metars, fetched);
+      drop(fetch_guards);
+    }
+
+    if !waiters.is_empty() {
+      let waiting_icaos: Vec<String> = waiters.iter().map(|w| w.icao.clone()).collect();
+      futures::future::join_all(waiters.into_iter().map(|w| w.wait())).await;
+      let updated = Self::select_latest(&waiting_icaos).await?;
+      Self::merge(&mut metars, updated);
+    }
+
+    Ok(metars)
+  }
+
+  /// Fetch stations from upstream, store the results and record each station's fetch state
+  async fn fetch_remote(
+    client: &Client,
+    icaos: &[String],
+    states: &HashMap<String, MetarFetchState>,
+  ) -> ApiResult<Vec<Self>> {
+    let mut metars: Vec<Metar> = vec![];
+    for chunk in icaos.chunks(10) {
+      let chunk_icaos: Vec<&str> = chunk.iter().map(|icao| icao.as_str()).collect();
+      let now = Utc::now();
+      let remote_metars = match Self::get_remote_metars(client, &chunk_icaos).await {
+        Ok(m) => m,
+        Err(err) => {
+          log::warn!("Unable to get remote METAR data; {}", err);
+          for icao in chunk {
+            let state =
+              MetarFetchState::upstream_error(states.get(icao), icao, &err.to_string(), now);
+            if let Err(err) = state.store().await {
+              log::warn!("Unable to store METAR fetch state for {}: {}", icao, err);
             }
-            Err(err) => return Err(err.into()),
           }
+          continue;
         }
-      }
-      if !updated_missing_icao_list.is_empty() {
-        log::trace!(
-          "Retrieving missing METAR data for {:?}",
-          updated_missing_icao_list
-        );
-        let mut missing_icao_list = Self::get_remote_metars(client, &updated_missing_icao_list)
-          .await
-          .unwrap_or_else(|err| {
-            log::warn!("Unable to get remote METAR data; {}", err);
-            vec![]
-          });
-
-        if missing_icao_list.len() > 0 {
-          // Insert missing METARs
-          for missing_metar in &missing_icao_list {
-            let _: RedisResult<()> = conn.set(&missing_metar.station_id, true).await;
-            missing_metar.insert().await?;
-          }
-          metars.append(&mut missing_icao_list)
+      };
+
+      for icao in chunk {
+        let state = match remote_metars.iter().find(|m| &m.station_id == icao) {
+          Some(metar) => MetarFetchState::observed(icao, metar.observation_time, now),
+          None => MetarFetchState::unavailable(states.get(icao), icao, now),
+        };
+        if let Err(err) = state.store().await {
+          log::warn!("Unable to store METAR fetch state for {}: {}", icao, err);
         }
+      }
 
-        // Invalidate the still missing icaos
-        let still_missing_icao_list =
-          Self::get_missing_metar_icaos(&missing_icao_list, icao_list).await;
-        if !still_missing_icao_list.is_empty() {
-          for icao in still_missing_icao_list {
-            let _: RedisResult<()> = conn.set_ex(&icao, false, 3600).await;
-          }
+      for metar in remote_metars {
+        // Repeated observations are already in the results from Postgres
+        match metar.insert().await {
+          Ok(MetarInsert::Duplicate) => {}
+          Ok(_) => metars.push(metar),
+          Err(err) => log::warn!("Unable to store METAR for {}: {}", metar.station_id, err),
         }
       }
     }
-
     Ok(metars)
   }
 
-  pub async fn insert(&self) -> ApiResult<()> {
+  pub fn is_correction(&self) -> bool {
+    self.remarks.corrected.unwrap_or(false)
+  }
+
+  pub async fn insert(&self) -> ApiResult<MetarInsert> {
     let metar: MetarRow = self.to_db()?;
-    metar.insert().await?;
-    Ok(())
+    let outcome = metar.upsert().await?;
+    if outcome != MetarInsert::Duplicate {
+      MetarCache::insert(self).await;
+      MetarStream::publish(self).await;
+      AlertRule::spawn_evaluation(self.clone());
+      Webhook::spawn_dispatch_metar(self.clone());
+    }
+    Ok(outcome)
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use chrono::Timelike;
 
   #[test]
   fn test_metar() {
@@ -1013,6 +1261,91 @@ SLP125 P0003 60009 T00640036 10066 21012 58033 TSNO $".to_string();
     let metar = Metar::parse(&metar_string).unwrap();
     // dbg!(&metar);
 
+    metar_string = "SPECI KJYO 081812Z COR 27012KT 3SM BR OVC008 08/07 A2992".to_string();
+    let metar = Metar::parse(&metar_string).unwrap();
+    assert_eq!(metar.report_type, ReportType::Speci);
+    assert!(metar.is_correction());
+    assert_eq!(metar.to_db().unwrap().report_type, "SPECI");
+
+    metar_string = "METAR COR KJYO 081815Z 27012KT 10SM CLR 08/07 A2992".to_string();
+    let metar = Metar::parse(&metar_string).unwrap();
+    assert_eq!(metar.station_id, "KJYO");
+    assert_eq!(metar.report_type, ReportType::Metar);
+    assert!(metar.is_correction());
+
     // metar_string = "KHEF 092356Z 13009KT 10SM CLR 08/M03 A3022 RMK AO2 SLP239 6//// T00831033 10133 20078 53002 PNO $".to_string();
   }
+
+  fn reference(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+    NaiveDate::from_ymd_opt(year, month, day)
+      .unwrap()
+      .and_hms_opt(hour, minute, 0)
+      .unwrap()
+      .and_utc()
+  }
+
+  #[test]
+  fn test_observation_time_rollover() {
+    // January report day from December
+    let observation = Metar::resolve_observation_time(31, 23, 55, reference(2025, 1, 1, 0, 10));
+    assert_eq!(observation.unwrap(), reference(2024, 12, 31, 23, 55));
+
+    // Day 31 in a 30-day month falls back to the previous 31-day month
+    let observation = Metar::resolve_observation_time(31, 12, 0, reference(2025, 4, 15, 0, 0));
+    assert_eq!(observation.unwrap(), reference(2025, 3, 31, 12, 0));
+
+    // Day 30 in March skips February
+    let observation = Metar::resolve_observation_time(30, 6, 0, reference(2025, 3, 1, 0, 0));
+    assert_eq!(observation.unwrap(), reference(2025, 1, 30, 6, 0));
+
+    // Leap day only exists in leap years
+    let observation = Metar::resolve_observation_time(29, 18, 0, reference(2024, 3, 1, 0, 0));
+    assert_eq!(observation.unwrap(), reference(2024, 2, 29, 18, 0));
+    let observation = Metar::resolve_observation_time(29, 18, 0, reference(2025, 3, 1, 0, 0));
+    assert_eq!(observation.unwrap(), reference(2025, 1, 29, 18, 0));
+
+    // Reports stamped slightly ahead of the reference roll forward into the next month
+    let observation = Metar::resolve_observation_time(1, 0, 5, reference(2025, 12, 31, 23, 50));
+    assert_eq!(observation.unwrap(), reference(2026, 1, 1, 0, 5));
+
+    assert!(Metar::resolve_observation_time(0, 0, 0, reference(2025, 1, 1, 0, 0)).is_err());
+    assert!(Metar::resolve_observation_time(32, 0, 0, reference(2025, 1, 1, 0, 0)).is_err());
+    assert!(Metar::resolve_observation_time(1, 24, 0, reference(2025, 1, 1, 0, 0)).is_err());
+    assert!(Metar::resolve_observation_time(1, 0, 60, reference(2025, 1, 1, 0, 0)).is_err());
+  }
+
+  #[test]
+  fn test_observation_time_properties() {
+    // Every observation up to 27 days before the reference resolves to itself, for every
+    // reference day across a leap year and the years around it
+    let start = reference(2023, 1, 1, 0, 0);
+    for reference_day in 0..(3 * 366) {
+      for reference_minutes in [0, 754, 1439] {
+        let reference_time = start
+          + chrono::Duration::days(reference_day)
+          + chrono::Duration::minutes(reference_minutes);
+        for age_hours in (0..(27 * 24)).step_by(5) {
+          let observation = reference_time - chrono::Duration::hours(age_hours);
+          let resolved = Metar::resolve_observation_time(
+            observation.day(),
+            observation.hour(),
+            observation.minute(),
+            reference_time,
+          )
+          .unwrap();
+          assert_eq!(resolved, observation, "reference {}", reference_time);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_parse_with_reference() {
+    let metar = Metar::parse_with_reference(
+      "KMIA 312353Z 33004KT 10SM FEW015 25/22 A2990",
+      reference(2024, 1, 1, 0, 5),
+    )
+    .unwrap();
+    assert_eq!(metar.observation_time, reference(2023, 12, 31, 23, 53));
+  }
 }
@@ -1,24 +1,43 @@
-use crate::metars::Metar;
-use actix_web::{get, web, HttpResponse, HttpRequest};
+use std::collections::HashSet;
+use crate::metars::{Metar, MetarCache, MetarFetchState, MetarStream};
+use actix_web::{delete, get, http::header, web, HttpResponse, HttpRequest, ResponseError};
 use log::error;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::airports::{Airport, AirportVisibility};
+use crate::auth::{require, Auth, Permitted};
+use crate::error::{ApiResult, Error};
+use crate::watchlists::Watchlist;
 use crate::AppState;
 
+/// Most stations a single stream may follow
+const MAX_STREAM_STATIONS: usize = 5000;
+
 #[derive(Debug, Serialize, Deserialize)]
 struct FindAllParameters {
   icaos: Option<String>,
   force: Option<bool>,
+  /// Only stations in this watchlist
+  list: Option<Uuid>,
 }
 
 #[get("metars")]
-async fn find_all(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
-  let parameters = web::Query::<FindAllParameters>::from_query(req.query_string()).unwrap();
+async fn find_all(data: web::Data<AppState>, req: HttpRequest, auth: Option<Auth>) -> HttpResponse {
+  let parameters = match web::Query::<FindAllParameters>::from_query(req.query_string()) {
+    Ok(p) => p.into_inner(),
+    Err(err) => return ResponseError::error_response(&err),
+  };
   let icao_option = &parameters.icaos;
-  let icao_string = match icao_option {
-    Some(i) => i,
-    None => return HttpResponse::UnprocessableEntity().body("Missing icaos parameter"),
+  let icaos: Vec<String> = match (&parameters.list, icao_option) {
+    (Some(list), _) => {
+      match Watchlist::filter_icaos(list, icao_option.as_deref(), auth.as_ref()).await {
+        Ok(icaos) => icaos,
+        Err(err) => return ResponseError::error_response(&err),
+      }
+    }
+    (None, Some(icao_string)) => icao_string.split(',').map(|s| s.to_string()).collect(),
+    (None, None) => return HttpResponse::UnprocessableEntity().body("Missing icaos parameter"),
   };
-  let icaos: Vec<String> = icao_string.split(',').map(|s| s.to_string()).collect();
   let force = &parameters.force.unwrap_or(false);
 
   let client = &data.client;
@@ -32,6 +51,128 @@ async fn find_all(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
   HttpResponse::Ok().json(metars)
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct StreamParameters {
+  icaos: Option<String>,
+  /// Also every visible airport inside `north_east_lat,north_east_lon,south_west_lat,south_west_lon`
+  bounds: Option<String>,
+  /// Only stations in this watchlist
+  list: Option<Uuid>,
+}
+
+async fn stream_stations(
+  parameters: &StreamParameters,
+  auth: Option<&Auth>,
+) -> ApiResult<HashSet<String>> {
+  let icao_option = &parameters.icaos;
+  let mut stations: HashSet<String> = match (&parameters.list, icao_option) {
+    (Some(list), _) => Watchlist::filter_icaos(list, icao_option.as_deref(), auth)
+      .await?
+      .into_iter()
+      .collect(),
+    (None, Some(icao_string)) => icao_string
+      .split(',')
+      .map(|icao| icao.trim().to_uppercase())
+      .filter(|icao| !icao.is_empty())
+      .collect(),
+    (None, None) => HashSet::new(),
+  };
+  if let Some(bounds) = &parameters.bounds {
+    let visibility = AirportVisibility::for_auth(auth).await?;
+    let limit = (MAX_STREAM_STATIONS + 1) as i64;
+    stations.extend(Airport::select_icaos_in_bounds(bounds, &visibility, limit).await?);
+  }
+  if stations.is_empty() {
+    return Err(Error::new(
+      422,
+      "Missing icaos, bounds or list parameter".to_string(),
+    ));
+  }
+  if stations.len() > MAX_STREAM_STATIONS {
+    return Err(Error::new(
+      400,
+      format!("Streams are limited to {} stations", MAX_STREAM_STATIONS),
+    ));
+  }
+  Ok(stations)
+}
+
+/// Server-sent events with every new observation of the requested stations
+#[get("metars/stream")]
+async fn stream(req: HttpRequest, auth: Option<Auth>) -> HttpResponse {
+  let parameters = match web::Query::<StreamParameters>::from_query(req.query_string()) {
+    Ok(p) => p.into_inner(),
+    Err(err) => return ResponseError::error_response(&err),
+  };
+  let stations = match stream_stations(&parameters, auth.as_ref()).await {
+    Ok(stations) => stations,
+    Err(err) => return ResponseError::error_response(&err),
+  };
+  HttpResponse::Ok()
+    .content_type("text/event-stream")
+    .insert_header((header::CACHE_CONTROL, "no-cache"))
+    // Keep nginx from buffering the events
+    .insert_header(("X-Accel-Buffering", "no"))
+    .streaming(MetarStream::subscribe(stations))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FetchStateParameters {
+  icaos: Option<String>,
+}
+
+#[get("metars/state")]
+async fn get_fetch_states(_: Permitted<require::SystemRead>, req: HttpRequest) -> HttpResponse {
+  let parameters = match web::Query::<FetchStateParameters>::from_query(req.query_string()) {
+    Ok(p) => p.into_inner(),
+    Err(err) => return ResponseError::error_response(&err),
+  };
+
+  let states = match parameters.icaos {
+    Some(icao_string) => {
+      let icaos: Vec<String> = icao_string.split(',').map(|s| s.to_string()).collect();
+      MetarFetchState::get_all(&icaos).await.map(|states| {
+        icaos
+          .iter()
+          .filter_map(|icao| states.get(icao).cloned())
+          .collect::<Vec<MetarFetchState>>()
+      })
+    }
+    None => MetarFetchState::scan().await,
+  };
+  match states {
+    Ok(states) => HttpResponse::Ok().json(states),
+    Err(err) => {
+      error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[delete("metars/state/{icao}")]
+async fn reset_fetch_state(
+  icao: web::Path<String>,
+  _: Permitted<require::WeatherRefresh>,
+) -> HttpResponse {
+  match MetarFetchState::delete(&icao.into_inner()).await {
+    Ok(_) => HttpResponse::NoContent().finish(),
+    Err(err) => {
+      error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[get("metars/cache")]
+async fn get_cache_stats(_: Permitted<require::SystemRead>) -> HttpResponse {
+  HttpResponse::Ok().json(MetarCache::stats())
+}
+
 pub fn init_routes(config: &mut web::ServiceConfig) {
-  config.service(find_all);
+  config
+    .service(find_all)
+    .service(stream)
+    .service(get_fetch_states)
+    .service(reset_fetch_state)
+    .service(get_cache_stats);
 }
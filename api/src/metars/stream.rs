@@ -0,0 +1,131 @@
+use std::collections::HashSet;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt};
+use redis::AsyncCommands;
+use tokio::sync::broadcast::{self, error::RecvError};
+use crate::db::{redis_async_connection, redis_pubsub};
+use crate::error::ApiResult;
+use super::{Metar, MetarCache};
+
+/// Redis channel every replica publishes its newly stored observations to
+const CHANNEL: &str = "metars:updates";
+/// Updates a slow client may fall behind before it misses some
+const CHANNEL_CAPACITY: usize = 1024;
+const RECONNECT_SECONDS: u64 = 5;
+/// Comment sent to idle streams so proxies do not close them
+const HEARTBEAT_SECONDS: u64 = 15;
+
+static SENDER: OnceLock<broadcast::Sender<MetarUpdate>> = OnceLock::new();
+
+/// A stored observation as relayed to the streams of this replica, serialized once
+#[derive(Debug, Clone)]
+pub struct MetarUpdate {
+  pub station_id: String,
+  pub payload: Arc<str>,
+}
+
+/// Live METAR updates, fanned out across replicas through Redis pub/sub
+pub struct MetarStream;
+
+impl MetarStream {
+  fn sender() -> &'static broadcast::Sender<MetarUpdate> {
+    SENDER.get_or_init(|| broadcast::channel(CHANNEL_CAPACITY).0)
+  }
+
+  /// Announce a newly stored observation to every replica, streams are best effort so
+  /// failures are only logged
+  pub async fn publish(metar: &Metar) {
+    let payload = match serde_json::to_string(metar) {
+      Ok(payload) => payload,
+      Err(err) => {
+        log::error!(
+          "Unable to serialize METAR for {}: {}",
+          metar.station_id,
+          err
+        );
+        return;
+      }
+    };
+    let result = match redis_async_connection().await {
+      Ok(mut conn) => conn.publish::<_, _, ()>(CHANNEL, payload).await,
+      Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+      log::warn!("Unable to publish METAR for {}: {}", metar.station_id, err);
+    }
+  }
+
+  /// Forward published observations to the local streams until the process exits,
+  /// reconnecting whenever Redis goes away
+  pub fn spawn_relay() {
+    tokio::spawn(async move {
+      loop {
+        match Self::relay().await {
+          Ok(_) => log::warn!("METAR stream subscription closed, reconnecting"),
+          Err(err) => log::warn!("METAR stream subscription failed, reconnecting: {}", err),
+        }
+        tokio::time::sleep(Duration::from_secs(RECONNECT_SECONDS)).await;
+      }
+    });
+  }
+
+  async fn relay() -> ApiResult<()> {
+    let mut pubsub = redis_pubsub().await?;
+    pubsub.subscribe(CHANNEL).await?;
+    let mut messages = pubsub.into_on_message();
+    while let Some(message) = messages.next().await {
+      let payload: String = match message.get_payload() {
+        Ok(payload) => payload,
+        Err(err) => {
+          log::warn!("Ignoring unreadable METAR update: {}", err);
+          continue;
+        }
+      };
+      let metar: Metar = match serde_json::from_str(&payload) {
+        Ok(metar) => metar,
+        Err(err) => {
+          log::warn!("Ignoring invalid METAR update: {}", err);
+          continue;
+        }
+      };
+      // Observations stored by other replicas also refresh the local cache
+      MetarCache::insert(&metar).await;
+      // Sending only fails while no stream is listening
+      let _ = Self::sender().send(MetarUpdate {
+        station_id: metar.station_id,
+        payload: payload.into(),
+      });
+    }
+    Ok(())
+  }
+
+  /// Server-sent events carrying each new observation of `stations` as a `metar` event.
+  /// A client that falls too far behind receives a `lagged` event and should refetch.
+  pub fn subscribe(
+    stations: HashSet<String>,
+  ) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let receiver = Self::sender().subscribe();
+    let heartbeat = tokio::time::interval(Duration::from_secs(HEARTBEAT_SECONDS));
+    futures_util::stream::unfold(
+      (receiver, heartbeat, stations),
+      |(mut receiver, mut heartbeat, stations)| async move {
+        loop {
+          let event = tokio::select! {
+            _ = heartbeat.tick() => ": heartbeat\n\n".to_string(),
+            update = receiver.recv() => match update {
+              Ok(update) if stations.contains(&update.station_id) => {
+                format!("event: metar\ndata: {}\n\n", update.payload)
+              }
+              Ok(_) => continue,
+              Err(RecvError::Lagged(skipped)) => format!("event: lagged\ndata: {}\n\n", skipped),
+              Err(RecvError::Closed) => return None,
+            },
+          };
+          return Some((Ok(Bytes::from(event)), (receiver, heartbeat, stations)));
+        }
+      },
+    )
+  }
+}
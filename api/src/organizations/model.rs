@@ -0,0 +1,479 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::auth::Auth;
+use crate::db;
+use crate::error::{ApiResult, Error};
+
+const TABLE_NAME: &str = "organizations";
+const MEMBERS_TABLE_NAME: &str = "organization_members";
+const USERS_TABLE_NAME: &str = "users";
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_SLUG_LENGTH: usize = 32;
+
+/// Role of a member within an organization, ordered from least to most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrganizationRole {
+  /// Reads the organization's airports and edits its watchlists
+  Member,
+  /// Manages members and the organization's airports
+  Admin,
+  /// Manages admins and may delete the organization
+  Owner,
+}
+
+impl OrganizationRole {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      OrganizationRole::Member => "member",
+      OrganizationRole::Admin => "admin",
+      OrganizationRole::Owner => "owner",
+    }
+  }
+}
+
+impl TryFrom<String> for OrganizationRole {
+  type Error = String;
+
+  fn try_from(role: String) -> Result<Self, Self::Error> {
+    match role.as_str() {
+      "member" => Ok(OrganizationRole::Member),
+      "admin" => Ok(OrganizationRole::Admin),
+      "owner" => Ok(OrganizationRole::Owner),
+      _ => Err(format!("Invalid organization role '{}'", role)),
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Organization {
+  pub id: Uuid,
+  pub name: String,
+  pub slug: String,
+  /// Role of the requesting user
+  #[sqlx(try_from = "String")]
+  pub role: OrganizationRole,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct OrganizationMember {
+  pub email: String,
+  pub first_name: String,
+  pub last_name: String,
+  #[sqlx(try_from = "String")]
+  pub role: OrganizationRole,
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateOrganization {
+  pub name: String,
+  /// Derived from the name when omitted
+  pub slug: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateOrganization {
+  pub name: Option<String>,
+  pub slug: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetMemberRole {
+  pub role: OrganizationRole,
+}
+
+fn validate_name(name: &str) -> ApiResult<String> {
+  let name = name.trim();
+  if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+    return Err(Error::new(
+      400,
+      format!("Names must be 1 to {} characters", MAX_NAME_LENGTH),
+    ));
+  }
+  Ok(name.to_string())
+}
+
+/// Lowercase ASCII letters and digits separated by single dashes
+fn slugify(name: &str) -> String {
+  let mut slug = String::new();
+  for c in name.trim().chars() {
+    if c.is_ascii_alphanumeric() {
+      slug.push(c.to_ascii_lowercase());
+    } else if !slug.is_empty() && !slug.ends_with('-') {
+      slug.push('-');
+    }
+  }
+  let slug: String = slug.chars().take(MAX_SLUG_LENGTH).collect();
+  slug.trim_end_matches('-').to_string()
+}
+
+fn validate_slug(slug: &str) -> ApiResult<String> {
+  if slug.is_empty() || slug.len() > MAX_SLUG_LENGTH || slugify(slug) != slug {
+    return Err(Error::new(
+      400,
+      format!(
+        "Slugs must be 1 to {} lowercase letters, digits and single dashes",
+        MAX_SLUG_LENGTH
+      ),
+    ));
+  }
+  Ok(slug.to_string())
+}
+
+impl Organization {
+  /// Columns of an organization with the role `$1` holds in it
+  fn select_sql() -> String {
+    format!(
+      r#"
+      SELECT o.id, o.name, o.slug, m.role, o.created_at, o.updated_at
+      FROM {} o
+      JOIN {} m ON m.organization_id = o.id AND m.email = $1
+      "#,
+      TABLE_NAME, MEMBERS_TABLE_NAME
+    )
+  }
+
+  /// Organizations the user belongs to
+  pub async fn select_all(email: &str) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let organizations: Vec<Self> =
+      sqlx::query_as(&format!("{} ORDER BY o.name", Self::select_sql()))
+        .bind(email)
+        .fetch_all(pool)
+        .await?;
+    Ok(organizations)
+  }
+
+  /// The organization as seen by a member, other users cannot tell whether it exists
+  pub async fn select(id: &Uuid, email: &str) -> ApiResult<Self> {
+    let pool = db::pool();
+    let organization: Option<Self> =
+      sqlx::query_as(&format!("{} WHERE o.id = $2", Self::select_sql()))
+        .bind(email)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    organization.ok_or_else(|| Error::new(404, format!("Organization {} not found", id)))
+  }
+
+  /// Select the organization, failing unless the user holds at least the required role
+  pub async fn authorize(id: &Uuid, email: &str, required: OrganizationRole) -> ApiResult<Self> {
+    let organization = Self::select(id, email).await?;
+    if organization.role < required {
+      return Err(Error::new(
+        403,
+        format!(
+          "Organization {} requires the {} role",
+          id,
+          required.as_str()
+        ),
+      ));
+    }
+    Ok(organization)
+  }
+
+  /// IDs of the organizations whose private data the request may see, none when signed out
+  pub async fn visible_ids(auth: Option<&Auth>) -> ApiResult<Vec<Uuid>> {
+    match auth {
+      Some(auth) => Self::member_ids(&auth.user.email).await,
+      None => Ok(vec![]),
+    }
+  }
+
+  /// IDs of the organizations the user belongs to
+  pub async fn member_ids(email: &str) -> ApiResult<Vec<Uuid>> {
+    let pool = db::pool();
+    let ids: Vec<Uuid> = sqlx::query_scalar(&format!(
+      r#"
+      SELECT organization_id FROM {} WHERE email = $1
+      "#,
+      MEMBERS_TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_all(pool)
+    .await?;
+    Ok(ids)
+  }
+
+  /// Create an organization owned by the user
+  pub async fn insert(email: &str, request: &CreateOrganization) -> ApiResult<Self> {
+    let pool = db::pool();
+    let name = validate_name(&request.name)?;
+    let slug = match &request.slug {
+      Some(slug) => validate_slug(slug)?,
+      None => validate_slug(&slugify(&name))?,
+    };
+    let id = Uuid::new_v4();
+
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (id, name, slug) VALUES ($1, $2, $3)
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(&name)
+    .bind(&slug)
+    .execute(&mut *tx)
+    .await;
+    if let Err(err) = result {
+      return Err(Self::slug_conflict(err, &slug));
+    }
+    sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (organization_id, email, role) VALUES ($1, $2, $3)
+      "#,
+      MEMBERS_TABLE_NAME
+    ))
+    .bind(id)
+    .bind(email)
+    .bind(OrganizationRole::Owner.as_str())
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Self::select(&id, email).await
+  }
+
+  fn slug_conflict(err: sqlx::Error, slug: &str) -> Error {
+    match Error::from(err) {
+      err if err.status == 409 => Error::new(409, format!("Slug '{}' is already taken", slug)),
+      err => err,
+    }
+  }
+
+  pub async fn update(id: &Uuid, email: &str, request: &UpdateOrganization) -> ApiResult<Self> {
+    let pool = db::pool();
+    Self::authorize(id, email, OrganizationRole::Admin).await?;
+    let name = match &request.name {
+      Some(name) => Some(validate_name(name)?),
+      None => None,
+    };
+    let slug = match &request.slug {
+      Some(slug) => Some(validate_slug(slug)?),
+      None => None,
+    };
+    let result = sqlx::query(&format!(
+      r#"
+      UPDATE {} SET
+        name = COALESCE($2, name),
+        slug = COALESCE($3, slug),
+        updated_at = NOW()
+      WHERE id = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(name)
+    .bind(&slug)
+    .execute(pool)
+    .await;
+    if let Err(err) = result {
+      return Err(Self::slug_conflict(
+        err,
+        slug.as_deref().unwrap_or_default(),
+      ));
+    }
+    Self::select(id, email).await
+  }
+
+  /// Delete the organization along with its private airports and watchlists
+  pub async fn delete(id: &Uuid, email: &str) -> ApiResult<()> {
+    let pool = db::pool();
+    Self::authorize(id, email, OrganizationRole::Owner).await?;
+
+    // Airports go with the organization, and their runways and frequencies with them
+    sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE id = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn select_members(id: &Uuid, email: &str) -> ApiResult<Vec<OrganizationMember>> {
+    let pool = db::pool();
+    Self::authorize(id, email, OrganizationRole::Member).await?;
+    let members: Vec<OrganizationMember> = sqlx::query_as(&format!(
+      r#"
+      SELECT m.email, u.first_name, u.last_name, m.role, m.created_at
+      FROM {} m
+      JOIN {} u ON u.email = m.email
+      WHERE m.organization_id = $1
+      ORDER BY m.email
+      "#,
+      MEMBERS_TABLE_NAME, USERS_TABLE_NAME
+    ))
+    .bind(id)
+    .fetch_all(pool)
+    .await?;
+    Ok(members)
+  }
+
+  async fn member_role(id: &Uuid, member: &str) -> ApiResult<Option<OrganizationRole>> {
+    let pool = db::pool();
+    let role: Option<String> = sqlx::query_scalar(&format!(
+      r#"
+      SELECT role FROM {} WHERE organization_id = $1 AND email = $2
+      "#,
+      MEMBERS_TABLE_NAME
+    ))
+    .bind(id)
+    .bind(member)
+    .fetch_optional(pool)
+    .await?;
+    role
+      .map(|role| OrganizationRole::try_from(role).map_err(|err| Error::new(500, err)))
+      .transpose()
+  }
+
+  /// Fail when the member is the organization's only owner
+  async fn verify_not_last_owner(id: &Uuid, member: &str) -> ApiResult<()> {
+    let pool = db::pool();
+    let owners: i64 = sqlx::query_scalar(&format!(
+      r#"
+      SELECT COUNT(*) FROM {} WHERE organization_id = $1 AND role = $2 AND email <> $3
+      "#,
+      MEMBERS_TABLE_NAME
+    ))
+    .bind(id)
+    .bind(OrganizationRole::Owner.as_str())
+    .bind(member)
+    .fetch_one(pool)
+    .await?;
+    if owners == 0 {
+      return Err(Error::new(
+        400,
+        "Organizations need another owner before their last owner steps down".to_string(),
+      ));
+    }
+    Ok(())
+  }
+
+  /// Add a member or change their role, only owners may grant or take away ownership
+  pub async fn set_member(
+    id: &Uuid,
+    email: &str,
+    member: &str,
+    role: OrganizationRole,
+  ) -> ApiResult<Vec<OrganizationMember>> {
+    let pool = db::pool();
+    let organization = Self::authorize(id, email, OrganizationRole::Admin).await?;
+    let member = member.to_lowercase();
+    let current = Self::member_role(id, &member).await?;
+    if (role == OrganizationRole::Owner || current == Some(OrganizationRole::Owner))
+      && organization.role < OrganizationRole::Owner
+    {
+      return Err(Error::new(
+        403,
+        "Only owners may change ownership of an organization".to_string(),
+      ));
+    }
+    if current == Some(OrganizationRole::Owner) && role < OrganizationRole::Owner {
+      Self::verify_not_last_owner(id, &member).await?;
+    }
+
+    let result = sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (organization_id, email, role) VALUES ($1, $2, $3)
+      ON CONFLICT (organization_id, email) DO UPDATE SET role = EXCLUDED.role
+      "#,
+      MEMBERS_TABLE_NAME
+    ))
+    .bind(id)
+    .bind(&member)
+    .bind(role.as_str())
+    .execute(pool)
+    .await;
+    if let Err(err) = result {
+      return match Error::from(err) {
+        err if err.status == 409 => Err(Error::new(404, format!("User {} not found", member))),
+        err => Err(err),
+      };
+    }
+    Self::select_members(id, email).await
+  }
+
+  /// Remove a member, admins may remove members and admins while anyone may leave
+  pub async fn remove_member(id: &Uuid, email: &str, member: &str) -> ApiResult<()> {
+    let pool = db::pool();
+    let member = member.to_lowercase();
+    let organization = Self::select(id, email).await?;
+    let current = Self::member_role(id, &member).await?.ok_or_else(|| {
+      Error::new(
+        404,
+        format!("User {} is not a member of organization {}", member, id),
+      )
+    })?;
+    if member != email {
+      let required = match current {
+        OrganizationRole::Owner => OrganizationRole::Owner,
+        _ => OrganizationRole::Admin,
+      };
+      if organization.role < required {
+        return Err(Error::new(
+          403,
+          format!(
+            "Organization {} requires the {} role",
+            id,
+            required.as_str()
+          ),
+        ));
+      }
+    }
+    if current == OrganizationRole::Owner {
+      Self::verify_not_last_owner(id, &member).await?;
+    }
+
+    sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE organization_id = $1 AND email = $2
+      "#,
+      MEMBERS_TABLE_NAME
+    ))
+    .bind(id)
+    .bind(&member)
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_role_order() {
+    assert!(OrganizationRole::Member < OrganizationRole::Admin);
+    assert!(OrganizationRole::Admin < OrganizationRole::Owner);
+    assert_eq!(
+      OrganizationRole::try_from("admin".to_string()),
+      Ok(OrganizationRole::Admin)
+    );
+    assert!(OrganizationRole::try_from("ADMIN".to_string()).is_err());
+  }
+
+  #[test]
+  fn test_slugs() {
+    assert_eq!(
+      slugify("  Blue Ridge Flying Club "),
+      "blue-ridge-flying-club"
+    );
+    assert_eq!(slugify("Café & Co."), "caf-co");
+    assert_eq!(slugify("---"), "");
+    assert!(validate_slug("blue-ridge").is_ok());
+    assert!(validate_slug("Blue-Ridge").is_err());
+    assert!(validate_slug("blue--ridge").is_err());
+    assert!(validate_slug("").is_err());
+    assert!(validate_slug(&"a".repeat(MAX_SLUG_LENGTH + 1)).is_err());
+  }
+}
@@ -0,0 +1,143 @@
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, ResponseError};
+use uuid::Uuid;
+use crate::auth::{client_ip, Auth};
+use crate::organizations::{CreateOrganization, Organization, SetMemberRole, UpdateOrganization};
+
+#[get("")]
+async fn get_organizations(auth: Auth) -> HttpResponse {
+  match Organization::select_all(&auth.user.email).await {
+    Ok(organizations) => HttpResponse::Ok().json(organizations),
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[post("")]
+async fn create_organization(
+  request: web::Json<CreateOrganization>,
+  req: HttpRequest,
+  auth: Auth,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  match Organization::insert(&auth.user.email, &request).await {
+    Ok(organization) => {
+      log::info!(
+        "Created organization {} [Email: {}] [IP Address: {}]",
+        organization.id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::Created().json(organization)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[get("/{id}")]
+async fn get_organization(id: web::Path<Uuid>, auth: Auth) -> HttpResponse {
+  match Organization::select(&id, &auth.user.email).await {
+    Ok(organization) => HttpResponse::Ok().json(organization),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[put("/{id}")]
+async fn update_organization(
+  id: web::Path<Uuid>,
+  request: web::Json<UpdateOrganization>,
+  auth: Auth,
+) -> HttpResponse {
+  match Organization::update(&id, &auth.user.email, &request).await {
+    Ok(organization) => HttpResponse::Ok().json(organization),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/{id}")]
+async fn delete_organization(id: web::Path<Uuid>, req: HttpRequest, auth: Auth) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  match Organization::delete(&id, &auth.user.email).await {
+    Ok(_) => {
+      log::info!(
+        "Deleted organization {} [Email: {}] [IP Address: {}]",
+        id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[get("/{id}/members")]
+async fn get_members(id: web::Path<Uuid>, auth: Auth) -> HttpResponse {
+  match Organization::select_members(&id, &auth.user.email).await {
+    Ok(members) => HttpResponse::Ok().json(members),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[put("/{id}/members/{email}")]
+async fn set_member(
+  path: web::Path<(Uuid, String)>,
+  request: web::Json<SetMemberRole>,
+  req: HttpRequest,
+  auth: Auth,
+) -> HttpResponse {
+  let (id, member) = path.into_inner();
+  let ip_address = client_ip(&req);
+  match Organization::set_member(&id, &auth.user.email, &member, request.role).await {
+    Ok(members) => {
+      log::info!(
+        "Set {} as {} of organization {} [Email: {}] [IP Address: {}]",
+        member,
+        request.role.as_str(),
+        id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::Ok().json(members)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/{id}/members/{email}")]
+async fn remove_member(
+  path: web::Path<(Uuid, String)>,
+  req: HttpRequest,
+  auth: Auth,
+) -> HttpResponse {
+  let (id, member) = path.into_inner();
+  let ip_address = client_ip(&req);
+  match Organization::remove_member(&id, &auth.user.email, &member).await {
+    Ok(_) => {
+      log::info!(
+        "Removed {} from organization {} [Email: {}] [IP Address: {}]",
+        member,
+        id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+  config.service(
+    web::scope("organizations")
+      .service(get_organizations)
+      .service(create_organization)
+      .service(get_organization)
+      .service(update_organization)
+      .service(delete_organization)
+      .service(get_members)
+      .service(set_member)
+      .service(remove_member),
+  );
+}
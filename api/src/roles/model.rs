@@ -0,0 +1,287 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use crate::auth::Permission;
+use crate::db;
+use crate::error::{ApiResult, Error};
+use crate::users::{ADMIN_ROLE, USER_ROLE};
+
+const TABLE_NAME: &str = "roles";
+const PERMISSIONS_TABLE_NAME: &str = "role_permissions";
+// Guards against a cycle introduced directly in the database
+const MAX_HIERARCHY_DEPTH: i32 = 16;
+// Other replicas only see role changes once their cached entries expire
+const PERMISSIONS_CACHE_TTL: u64 = 60;
+
+static PERMISSIONS_CACHE: OnceLock<Cache<String, Vec<Permission>>> = OnceLock::new();
+
+#[derive(Debug, sqlx::FromRow)]
+struct RoleRow {
+  name: String,
+  parent: Option<String>,
+  description: Option<String>,
+  permissions: Vec<String>,
+  created_at: DateTime<Utc>,
+  updated_at: DateTime<Utc>,
+}
+
+/// Named set of permissions, inheriting every permission of its parent role
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Role {
+  pub name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub parent: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub description: Option<String>,
+  /// Permissions granted directly to this role
+  pub permissions: Vec<Permission>,
+  /// Permissions granted to this role and its ancestors
+  pub effective_permissions: Vec<Permission>,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateRole {
+  pub parent: Option<String>,
+  pub description: Option<String>,
+  pub permissions: Vec<Permission>,
+}
+
+fn parse_permissions(permissions: &[String]) -> Vec<Permission> {
+  permissions
+    .iter()
+    .filter_map(|permission| match Permission::from_str(permission) {
+      Ok(permission) => Some(permission),
+      Err(_) => {
+        log::warn!("Ignoring unknown permission '{}'", permission);
+        None
+      }
+    })
+    .collect()
+}
+
+impl Role {
+  fn cache() -> &'static Cache<String, Vec<Permission>> {
+    PERMISSIONS_CACHE.get_or_init(|| {
+      Cache::builder()
+        .time_to_live(Duration::from_secs(PERMISSIONS_CACHE_TTL))
+        .build()
+    })
+  }
+
+  /// Permissions of a role including those inherited from its ancestors
+  pub async fn permissions(name: &str) -> ApiResult<Vec<Permission>> {
+    if let Some(permissions) = Self::cache().get(name).await {
+      return Ok(permissions);
+    }
+    let pool = db::pool();
+    let permissions: Vec<String> = sqlx::query_scalar(&format!(
+      r#"
+      WITH RECURSIVE ancestry AS (
+        SELECT name, parent, 1 AS depth FROM {0} WHERE name = $1
+        UNION ALL
+        SELECT r.name, r.parent, a.depth + 1 FROM {0} r
+        JOIN ancestry a ON r.name = a.parent
+        WHERE a.depth < $2
+      )
+      SELECT DISTINCT p.permission FROM {1} p
+      JOIN ancestry a ON p.role = a.name
+      ORDER BY p.permission
+      "#,
+      TABLE_NAME, PERMISSIONS_TABLE_NAME
+    ))
+    .bind(name)
+    .bind(MAX_HIERARCHY_DEPTH)
+    .fetch_all(pool)
+    .await?;
+
+    let permissions = parse_permissions(&permissions);
+    Self::cache()
+      .insert(name.to_string(), permissions.clone())
+      .await;
+    Ok(permissions)
+  }
+
+  async fn from_row(row: RoleRow) -> ApiResult<Self> {
+    let effective_permissions = Self::permissions(&row.name).await?;
+    Ok(Self {
+      permissions: parse_permissions(&row.permissions),
+      effective_permissions,
+      name: row.name,
+      parent: row.parent,
+      description: row.description,
+      created_at: row.created_at,
+      updated_at: row.updated_at,
+    })
+  }
+
+  fn select_query(filter: &str) -> String {
+    format!(
+      r#"
+      SELECT r.*, COALESCE(ARRAY_AGG(p.permission ORDER BY p.permission)
+        FILTER (WHERE p.permission IS NOT NULL), '{{}}') AS permissions
+      FROM {} r
+      LEFT JOIN {} p ON p.role = r.name
+      {}
+      GROUP BY r.name
+      ORDER BY r.name
+      "#,
+      TABLE_NAME, PERMISSIONS_TABLE_NAME, filter
+    )
+  }
+
+  pub async fn select_all() -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let rows: Vec<RoleRow> = sqlx::query_as(&Self::select_query(""))
+      .fetch_all(pool)
+      .await?;
+    let mut roles: Vec<Self> = vec![];
+    for row in rows {
+      roles.push(Self::from_row(row).await?);
+    }
+    Ok(roles)
+  }
+
+  pub async fn select(name: &str) -> ApiResult<Self> {
+    let pool = db::pool();
+    let row: RoleRow = sqlx::query_as(&Self::select_query("WHERE r.name = $1"))
+      .bind(name)
+      .fetch_optional(pool)
+      .await?
+      .ok_or_else(|| Error::new(404, format!("Role {} not found", name)))?;
+    Self::from_row(row).await
+  }
+
+  /// Create or replace a role, rejecting parents that would form a cycle
+  pub async fn upsert(name: &str, role: &UpdateRole) -> ApiResult<Self> {
+    let pool = db::pool();
+    let name = name.trim().to_uppercase();
+    if name.is_empty() {
+      return Err(Error::new(400, "Role name is required".to_string()));
+    }
+    // Administrators must always be able to repair the role configuration
+    if name == ADMIN_ROLE && !role.permissions.contains(&Permission::RolesManage) {
+      return Err(Error::new(
+        400,
+        format!(
+          "Role {} must keep the '{}' permission",
+          name,
+          Permission::RolesManage
+        ),
+      ));
+    }
+    let parent = role.parent.as_deref().map(|p| p.trim().to_uppercase());
+
+    let mut tx = pool.begin().await?;
+    if let Some(parent) = &parent {
+      let ancestors: Vec<String> = sqlx::query_scalar(&format!(
+        r#"
+        WITH RECURSIVE ancestry AS (
+          SELECT name, parent, 1 AS depth FROM {0} WHERE name = $1
+          UNION ALL
+          SELECT r.name, r.parent, a.depth + 1 FROM {0} r
+          JOIN ancestry a ON r.name = a.parent
+          WHERE a.depth < $2
+        )
+        SELECT name FROM ancestry
+        "#,
+        TABLE_NAME
+      ))
+      .bind(parent)
+      .bind(MAX_HIERARCHY_DEPTH)
+      .fetch_all(&mut *tx)
+      .await?;
+      if ancestors.is_empty() {
+        return Err(Error::new(400, format!("Parent role {} not found", parent)));
+      }
+      if ancestors.contains(&name) {
+        return Err(Error::new(
+          400,
+          format!(
+            "Role {} cannot inherit from its own descendant {}",
+            name, parent
+          ),
+        ));
+      }
+    }
+
+    sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (name, parent, description)
+      VALUES ($1, $2, $3)
+      ON CONFLICT (name) DO UPDATE
+      SET parent = EXCLUDED.parent, description = EXCLUDED.description, updated_at = NOW()
+      "#,
+      TABLE_NAME
+    ))
+    .bind(&name)
+    .bind(&parent)
+    .bind(&role.description)
+    .execute(&mut *tx)
+    .await?;
+
+    let permissions: Vec<&str> = role.permissions.iter().map(|p| p.as_str()).collect();
+    sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE role = $1
+      "#,
+      PERMISSIONS_TABLE_NAME
+    ))
+    .bind(&name)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (role, permission)
+      SELECT $1, UNNEST($2::TEXT[])
+      ON CONFLICT DO NOTHING
+      "#,
+      PERMISSIONS_TABLE_NAME
+    ))
+    .bind(&name)
+    .bind(&permissions)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    // Descendants inherit the change as well
+    Self::cache().invalidate_all();
+    Self::select(&name).await
+  }
+
+  /// Delete a role that is neither built in nor assigned to users or child roles
+  pub async fn delete(name: &str) -> ApiResult<()> {
+    if name == ADMIN_ROLE || name == USER_ROLE {
+      return Err(Error::new(
+        400,
+        format!("Built-in role {} cannot be deleted", name),
+      ));
+    }
+    let pool = db::pool();
+    let result = sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE name = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(name)
+    .execute(pool)
+    .await
+    .map_err(|err| match Error::from(err) {
+      err if err.status == 409 => Error::new(
+        409,
+        format!("Role {} is still assigned to users or child roles", name),
+      ),
+      err => err,
+    })?;
+    if result.rows_affected() == 0 {
+      return Err(Error::new(404, format!("Role {} not found", name)));
+    }
+    Self::cache().invalidate_all();
+    Ok(())
+  }
+}
@@ -0,0 +1,105 @@
+use actix_web::{delete, get, put, web, HttpRequest, HttpResponse, ResponseError};
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::auth::{client_ip, require, Permission, Permitted};
+use crate::roles::{Role, UpdateRole};
+
+#[get("")]
+async fn get_roles(_: Permitted<require::RolesManage>) -> HttpResponse {
+  match Role::select_all().await {
+    Ok(roles) => HttpResponse::Ok().json(roles),
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[get("/permissions")]
+async fn get_permissions(_: Permitted<require::RolesManage>) -> HttpResponse {
+  HttpResponse::Ok().json(Permission::ALL)
+}
+
+#[get("/{name}")]
+async fn get_role(name: web::Path<String>, _: Permitted<require::RolesManage>) -> HttpResponse {
+  match Role::select(&name.into_inner()).await {
+    Ok(role) => HttpResponse::Ok().json(role),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[put("/{name}")]
+async fn upsert_role(
+  name: web::Path<String>,
+  role: web::Json<UpdateRole>,
+  req: HttpRequest,
+  auth: Permitted<require::RolesManage>,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  let name = name.into_inner();
+  // Names are stored in upper case
+  let before = Role::select(&name.trim().to_uppercase()).await.ok();
+  match Role::upsert(&name, &role.into_inner()).await {
+    Ok(role) => {
+      log::info!(
+        "Successful role update [Email: {}] [IP Address: {}] [Role: {}]",
+        auth.user.email,
+        ip_address,
+        role.name
+      );
+      AuditEvent::new(
+        AuditAction::RoleUpdated,
+        Some(&auth.user.email),
+        &ip_address,
+      )
+      .target(&role.name)
+      .changes(audit::diff(&before, &role))
+      .record()
+      .await;
+      HttpResponse::Ok().json(role)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/{name}")]
+async fn delete_role(
+  name: web::Path<String>,
+  req: HttpRequest,
+  auth: Permitted<require::RolesManage>,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  let name = name.into_inner();
+  let before = Role::select(&name).await.ok();
+  match Role::delete(&name).await {
+    Ok(_) => {
+      log::info!(
+        "Successful role deletion [Email: {}] [IP Address: {}] [Role: {}]",
+        auth.user.email,
+        ip_address,
+        name
+      );
+      AuditEvent::new(
+        AuditAction::RoleDeleted,
+        Some(&auth.user.email),
+        &ip_address,
+      )
+      .target(&name)
+      .changes((audit::fields(&before), None))
+      .record()
+      .await;
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+  config.service(
+    web::scope("roles")
+      .service(get_roles)
+      .service(get_permissions)
+      .service(get_role)
+      .service(upsert_role)
+      .service(delete_role),
+  );
+}
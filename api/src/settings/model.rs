@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use crate::db;
+use crate::error::ApiResult;
+
+const TABLE_NAME: &str = "settings";
+
+/// Instance-wide settings managed by administrators
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Settings {
+  /// Anyone may register an account
+  pub open_registration: bool,
+  /// Unverified users cannot log in or create API keys
+  pub require_email_verification: bool,
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Self {
+      open_registration: true,
+      require_email_verification: false,
+    }
+  }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UpdateSettings {
+  pub open_registration: Option<bool>,
+  pub require_email_verification: Option<bool>,
+}
+
+impl Settings {
+  pub async fn get() -> ApiResult<Self> {
+    let pool = db::pool();
+    let rows: Vec<(String, serde_json::Value)> = sqlx::query_as(&format!(
+      r#"
+      SELECT key, value FROM {}
+      "#,
+      TABLE_NAME
+    ))
+    .fetch_all(pool)
+    .await?;
+
+    let mut settings = Self::default();
+    for (key, value) in rows {
+      match (key.as_str(), value.as_bool()) {
+        ("open_registration", Some(value)) => settings.open_registration = value,
+        ("require_email_verification", Some(value)) => settings.require_email_verification = value,
+        _ => log::warn!("Ignoring unknown setting '{}': {}", key, value),
+      }
+    }
+    Ok(settings)
+  }
+
+  pub async fn update(update: &UpdateSettings) -> ApiResult<Self> {
+    let pool = db::pool();
+    let values = [
+      ("open_registration", update.open_registration),
+      (
+        "require_email_verification",
+        update.require_email_verification,
+      ),
+    ];
+
+    let mut tx = pool.begin().await?;
+    for (key, value) in values {
+      let Some(value) = value else { continue };
+      sqlx::query(&format!(
+        r#"
+        INSERT INTO {} (key, value) VALUES ($1, $2)
+        ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value, updated_at = NOW()
+        "#,
+        TABLE_NAME
+      ))
+      .bind(key)
+      .bind(serde_json::Value::Bool(value))
+      .execute(&mut *tx)
+      .await?;
+    }
+    tx.commit().await?;
+    Self::get().await
+  }
+}
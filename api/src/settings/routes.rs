@@ -0,0 +1,55 @@
+use actix_web::{get, put, web, HttpRequest, HttpResponse, ResponseError};
+use crate::audit::{self, AuditAction, AuditEvent};
+use crate::auth::{client_ip, require, Permitted};
+use crate::settings::{Settings, UpdateSettings};
+
+#[get("")]
+async fn get_settings(_: Permitted<require::SettingsManage>) -> HttpResponse {
+  match Settings::get().await {
+    Ok(settings) => HttpResponse::Ok().json(settings),
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[put("")]
+async fn update_settings(
+  settings: web::Json<UpdateSettings>,
+  req: HttpRequest,
+  auth: Permitted<require::SettingsManage>,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  let before = Settings::get().await.ok();
+  match Settings::update(&settings.into_inner()).await {
+    Ok(settings) => {
+      log::info!(
+        "Successful settings update [Email: {}] [IP Address: {}]",
+        auth.user.email,
+        ip_address
+      );
+      AuditEvent::new(
+        AuditAction::SettingsUpdated,
+        Some(&auth.user.email),
+        &ip_address,
+      )
+      .changes(audit::diff(&before, &settings))
+      .record()
+      .await;
+      HttpResponse::Ok().json(settings)
+    }
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+  config.service(
+    web::scope("admin/settings")
+      .service(get_settings)
+      .service(update_settings),
+  );
+}
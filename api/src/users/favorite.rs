@@ -0,0 +1,273 @@
+use std::collections::HashSet;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::db;
+use crate::error::{ApiResult, Error};
+use crate::metars::Metar;
+
+const TABLE_NAME: &str = "user_favorites";
+const AIRPORTS_TABLE_NAME: &str = "airports";
+const ORGANIZATION_MEMBERS_TABLE_NAME: &str = "organization_members";
+const MAX_FAVORITES: i64 = 100;
+const MAX_LABEL_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Favorite {
+  pub icao: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub label: Option<String>,
+  pub position: i32,
+  pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FavoriteRequest {
+  pub label: Option<String>,
+  /// Zero-based position in the list, appended to the end when missing
+  pub position: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReorderFavoritesRequest {
+  /// Every favorite ICAO in the new order
+  pub icaos: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FavoriteWeather {
+  #[serde(flatten)]
+  pub favorite: Favorite,
+  pub metar: Option<Metar>,
+}
+
+impl Favorite {
+  pub async fn select_all(email: &str) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let favorites: Vec<Self> = sqlx::query_as(&format!(
+      r#"
+      SELECT icao, label, position, created_at FROM {}
+      WHERE email = $1
+      ORDER BY position, created_at
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_all(pool)
+    .await?;
+    Ok(favorites)
+  }
+
+  pub async fn insert(email: &str, icao: &str, request: &FavoriteRequest) -> ApiResult<Self> {
+    let pool = db::pool();
+    let icao = icao.to_uppercase();
+    let label = request
+      .label
+      .as_ref()
+      .map(|label| label.trim().to_string())
+      .filter(|label| !label.is_empty());
+    if label
+      .as_ref()
+      .is_some_and(|label| label.chars().count() > MAX_LABEL_LENGTH)
+    {
+      return Err(Error::new(
+        400,
+        format!("Labels are limited to {} characters", MAX_LABEL_LENGTH),
+      ));
+    }
+
+    let mut tx = pool.begin().await?;
+    let exists: bool = sqlx::query_scalar(&format!(
+      r#"
+      SELECT EXISTS (
+        SELECT 1 FROM {} WHERE icao = $1
+          AND ((organization_id IS NULL AND (owner IS NULL OR owner = $2))
+            OR organization_id IN (SELECT organization_id FROM {} WHERE email = $2))
+      )
+      "#,
+      AIRPORTS_TABLE_NAME, ORGANIZATION_MEMBERS_TABLE_NAME
+    ))
+    .bind(&icao)
+    .bind(email)
+    .fetch_one(&mut *tx)
+    .await?;
+    if !exists {
+      return Err(Error::new(404, format!("Airport {} not found", icao)));
+    }
+
+    // Lock the user's list so concurrent inserts agree on positions
+    let positions: Vec<i32> = sqlx::query_scalar(&format!(
+      r#"
+      SELECT position FROM {} WHERE email = $1 FOR UPDATE
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_all(&mut *tx)
+    .await?;
+    let count = positions.len() as i64;
+    if count >= MAX_FAVORITES {
+      return Err(Error::new(
+        400,
+        format!("Users are limited to {} favorites", MAX_FAVORITES),
+      ));
+    }
+    let position = request
+      .position
+      .map(|position| position.clamp(0, count as i32))
+      .unwrap_or(count as i32);
+
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET position = position + 1 WHERE email = $1 AND position >= $2
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(position)
+    .execute(&mut *tx)
+    .await?;
+    let favorite: Self = sqlx::query_as(&format!(
+      r#"
+      INSERT INTO {} (email, icao, label, position) VALUES ($1, $2, $3, $4)
+      RETURNING icao, label, position, created_at
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(&icao)
+    .bind(&label)
+    .bind(position)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(favorite)
+  }
+
+  pub async fn delete(email: &str, icao: &str) -> ApiResult<()> {
+    let pool = db::pool();
+    let icao = icao.to_uppercase();
+
+    let mut tx = pool.begin().await?;
+    let position: Option<i32> = sqlx::query_scalar(&format!(
+      r#"
+      DELETE FROM {} WHERE email = $1 AND icao = $2 RETURNING position
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(&icao)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(position) = position else {
+      return Err(Error::new(
+        404,
+        format!("Airport {} is not a favorite", icao),
+      ));
+    };
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET position = position - 1 WHERE email = $1 AND position > $2
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(position)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+  }
+
+  /// Replace the order of the user's favorites, the list must contain each of them once
+  pub async fn reorder(email: &str, icaos: &[String]) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let icaos: Vec<String> = icaos.iter().map(|icao| icao.to_uppercase()).collect();
+
+    let mut tx = pool.begin().await?;
+    let current: Vec<String> = sqlx::query_scalar(&format!(
+      r#"
+      SELECT icao FROM {} WHERE email = $1 FOR UPDATE
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_all(&mut *tx)
+    .await?;
+    if !is_permutation(&current, &icaos) {
+      return Err(Error::new(
+        400,
+        "The new order must list every favorite exactly once".to_string(),
+      ));
+    }
+
+    let positions: Vec<i32> = (0..icaos.len() as i32).collect();
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} AS f SET position = o.position
+      FROM UNNEST($2::TEXT[], $3::INTEGER[]) AS o (icao, position)
+      WHERE f.email = $1 AND f.icao = o.icao
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(&icaos)
+    .bind(&positions)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Self::select_all(email).await
+  }
+
+  /// Favorites in order together with their latest METAR
+  pub async fn select_weather(
+    client: &reqwest::Client,
+    email: &str,
+  ) -> ApiResult<Vec<FavoriteWeather>> {
+    let favorites = Self::select_all(email).await?;
+    let icaos: Vec<String> = favorites
+      .iter()
+      .map(|favorite| favorite.icao.clone())
+      .collect();
+    let mut metars = Metar::find_all(client, &icaos, &false).await?;
+
+    Ok(
+      favorites
+        .into_iter()
+        .map(|favorite| {
+          let metar = metars
+            .iter()
+            .position(|metar| metar.station_id == favorite.icao)
+            .map(|index| metars.swap_remove(index));
+          FavoriteWeather { favorite, metar }
+        })
+        .collect(),
+    )
+  }
+}
+
+fn is_permutation(current: &[String], order: &[String]) -> bool {
+  let order_set: HashSet<&String> = order.iter().collect();
+  order.len() == current.len()
+    && order_set.len() == order.len()
+    && current.iter().all(|icao| order_set.contains(icao))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_is_permutation() {
+    let current = vec!["KJFK".to_string(), "KBOS".to_string()];
+    let order = |icaos: &[&str]| {
+      icaos
+        .iter()
+        .map(|icao| icao.to_string())
+        .collect::<Vec<_>>()
+    };
+    assert!(is_permutation(&current, &order(&["KBOS", "KJFK"])));
+    assert!(!is_permutation(&current, &order(&["KBOS"])));
+    assert!(!is_permutation(&current, &order(&["KBOS", "KBOS"])));
+    assert!(!is_permutation(&current, &order(&["KBOS", "KJFK", "KLAX"])));
+  }
+}
@@ -1,5 +1,9 @@
+mod favorite;
 mod model;
+mod picture;
 mod routes;
 
+pub use favorite::*;
 pub use model::*;
+pub use picture::*;
 pub use routes::init_routes;
@@ -1,12 +1,51 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::{Postgres, QueryBuilder};
-use crate::{auth::hash, error::ApiResult};
+use crate::{
+  auth::hash,
+  error::{ApiResult, Error},
+};
 use crate::db;
 
 pub const ADMIN_ROLE: &str = "ADMIN";
 pub const USER_ROLE: &str = "USER";
 const TABLE_NAME: &str = "users";
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_PASSWORD_LENGTH: usize = 128;
+const PASSWORD_SPECIAL_CHARACTERS: &str = "!@#$%^&*";
+
+/// Password rules shared by registration, password changes and resets, matching the UI
+pub fn validate_password(password: &str) -> ApiResult<()> {
+  let length = password.trim().chars().count();
+  let problem = if length < MIN_PASSWORD_LENGTH {
+    Some(format!(
+      "Password must be at least {} characters",
+      MIN_PASSWORD_LENGTH
+    ))
+  } else if length >= MAX_PASSWORD_LENGTH {
+    Some(format!(
+      "Password must be at most {} characters",
+      MAX_PASSWORD_LENGTH
+    ))
+  } else if !password.chars().any(|c| c.is_ascii_digit()) {
+    Some("Password must contain at least one number".to_string())
+  } else if !password.chars().any(|c| c.is_ascii_lowercase()) {
+    Some("Password must contain at least one lowercase letter".to_string())
+  } else if !password.chars().any(|c| c.is_ascii_uppercase()) {
+    Some("Password must contain at least one uppercase letter".to_string())
+  } else if !password
+    .chars()
+    .any(|c| PASSWORD_SPECIAL_CHARACTERS.contains(c))
+  {
+    Some("Password must contain at least one special character".to_string())
+  } else {
+    None
+  };
+  match problem {
+    Some(problem) => Err(Error::new(400, problem)),
+    None => Ok(()),
+  }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterRequest {
@@ -18,6 +57,7 @@ pub struct RegisterRequest {
 
 impl RegisterRequest {
   pub fn to_user(self) -> ApiResult<User> {
+    validate_password(&self.password)?;
     let password_hash = hash(&self.password)?;
     Ok(User {
       email: self.email.to_lowercase(),
@@ -25,6 +65,11 @@ impl RegisterRequest {
       role: USER_ROLE.to_string(),
       first_name: self.first_name,
       last_name: self.last_name,
+      email_verified_at: None,
+      totp_enabled_at: None,
+      totp_required: false,
+      disabled_at: None,
+      picture_key: None,
       updated_at: Utc::now(),
       created_at: Utc::now(),
     })
@@ -37,12 +82,39 @@ pub struct LoginRequest {
   pub password: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordResetRequest {
+  pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordResetConfirmRequest {
+  pub token: String,
+  pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResendVerificationRequest {
+  pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyEmailRequest {
+  pub token: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserResponse {
   pub email: String,
   pub role: String,
   pub first_name: String,
   pub last_name: String,
+  pub email_verified: bool,
+  pub two_factor_enabled: bool,
+  pub two_factor_required: bool,
+  pub disabled: bool,
+  pub has_picture: bool,
+  pub created_at: DateTime<Utc>,
 }
 
 impl From<User> for UserResponse {
@@ -52,10 +124,47 @@ impl From<User> for UserResponse {
       role: user.role,
       first_name: user.first_name,
       last_name: user.last_name,
+      email_verified: user.email_verified_at.is_some(),
+      two_factor_enabled: user.totp_enabled_at.is_some(),
+      two_factor_required: user.totp_required,
+      disabled: user.disabled_at.is_some(),
+      has_picture: user.picture_key.is_some(),
+      created_at: user.created_at,
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserQuery {
+  pub page: Option<u32>,
+  pub limit: Option<u32>,
+  /// Matched against the email and name
+  pub search: Option<String>,
+  pub role: Option<String>,
+  pub disabled: Option<bool>,
+}
+
+impl Default for UserQuery {
+  fn default() -> Self {
+    Self {
+      page: Some(1),
+      limit: Some(100),
+      search: None,
+      role: None,
+      disabled: None,
     }
   }
 }
 
+/// Changes an administrator can make to another user
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminUpdateUser {
+  pub role: Option<String>,
+  pub first_name: Option<String>,
+  pub last_name: Option<String>,
+  pub two_factor_required: Option<bool>,
+}
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct UpdateUser {
   pub email: Option<String>,
@@ -132,6 +241,13 @@ pub struct User {
   pub role: String,
   pub first_name: String,
   pub last_name: String,
+  pub email_verified_at: Option<DateTime<Utc>>,
+  pub totp_enabled_at: Option<DateTime<Utc>>,
+  /// The user must enrol in 2FA before any permission is granted
+  pub totp_required: bool,
+  pub disabled_at: Option<DateTime<Utc>>,
+  /// Bucket key of the profile picture, see `Picture`
+  pub picture_key: Option<String>,
   pub updated_at: DateTime<Utc>,
   pub created_at: DateTime<Utc>,
 }
@@ -156,18 +272,66 @@ impl User {
     user
   }
 
-  pub async fn count() -> i64 {
+  fn push_conditions(builder: &mut QueryBuilder<Postgres>, query: &UserQuery) {
+    builder.push(" WHERE TRUE");
+    if let Some(search) = query.search.as_ref().filter(|search| !search.is_empty()) {
+      let pattern = format!("%{}%", search);
+      builder
+        .push(" AND (email ILIKE ")
+        .push_bind(pattern.clone())
+        .push(" OR first_name ILIKE ")
+        .push_bind(pattern.clone())
+        .push(" OR last_name ILIKE ")
+        .push_bind(pattern)
+        .push(")");
+    }
+    if let Some(role) = &query.role {
+      builder.push(" AND role = ").push_bind(role.to_uppercase());
+    }
+    match query.disabled {
+      Some(true) => {
+        builder.push(" AND disabled_at IS NOT NULL");
+      }
+      Some(false) => {
+        builder.push(" AND disabled_at IS NULL");
+      }
+      None => {}
+    }
+  }
+
+  pub async fn select_all(query: &UserQuery) -> ApiResult<Vec<Self>> {
     let pool = db::pool();
 
-    sqlx::query_scalar(&format!(
-      r#"
-      SELECT COUNT(*) FROM {}
-      "#,
-      TABLE_NAME
-    ))
-    .fetch_one(pool)
-    .await
-    .unwrap_or_else(|_| 0)
+    let mut builder = QueryBuilder::<Postgres>::new("SELECT * FROM ");
+    builder.push(TABLE_NAME);
+    Self::push_conditions(&mut builder, query);
+    builder.push(" ORDER BY email");
+
+    // Apply pagination.
+    if let Some(limit) = query.limit {
+      builder.push(" LIMIT ").push_bind(limit as i64);
+      let offset = match query.page {
+        Some(page) => (page.saturating_sub(1) * limit) as i64,
+        None => 0,
+      };
+      builder.push(" OFFSET ").push_bind(offset);
+    }
+
+    let users: Vec<Self> = builder.build_query_as::<Self>().fetch_all(pool).await?;
+    Ok(users)
+  }
+
+  pub async fn count(query: &UserQuery) -> i64 {
+    let pool = db::pool();
+
+    let mut builder = QueryBuilder::<Postgres>::new("SELECT COUNT(*) FROM ");
+    builder.push(TABLE_NAME);
+    Self::push_conditions(&mut builder, query);
+    builder
+      .build_query_scalar()
+      .fetch_one(pool)
+      .await
+      .unwrap_or(0)
   }
 
   pub async fn insert(&self) -> ApiResult<User> {
@@ -180,10 +344,11 @@ impl User {
         role,
         first_name,
         last_name,
+        email_verified_at,
         created_at,
         updated_at
       )
-      VALUES ($1, $2, $3, $4, $5, $6, $7)
+      VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
       RETURNING *
       "#,
       TABLE_NAME,
@@ -193,6 +358,7 @@ impl User {
     .bind(&self.role)
     .bind(&self.first_name)
     .bind(&self.last_name)
+    .bind(self.email_verified_at)
     .bind(self.created_at)
     .bind(self.updated_at)
     .fetch_one(pool)
@@ -200,4 +366,91 @@ impl User {
 
     Ok(user)
   }
+
+  pub async fn verify_email(email: &str) -> ApiResult<User> {
+    let pool = db::pool();
+    let user: User = sqlx::query_as::<_, Self>(&format!(
+      r#"
+      UPDATE {} SET email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW()
+      WHERE email = $1
+      RETURNING *
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(user)
+  }
+
+  /// Disabling an account keeps its data but blocks every way of signing in
+  pub async fn set_disabled(email: &str, disabled: bool) -> ApiResult<User> {
+    let pool = db::pool();
+    let user: User = sqlx::query_as::<_, Self>(&format!(
+      r#"
+      UPDATE {} SET
+        disabled_at = CASE WHEN $2 THEN COALESCE(disabled_at, NOW()) ELSE NULL END,
+        updated_at = NOW()
+      WHERE email = $1
+      RETURNING *
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(disabled)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| Error::new(404, format!("User {} not found", email)))?;
+
+    Ok(user)
+  }
+
+  /// Delete the user, dependent rows are removed by their foreign keys
+  pub async fn delete(email: &str) -> ApiResult<()> {
+    let pool = db::pool();
+    let result = sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE email = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+      return Err(Error::new(404, format!("User {} not found", email)));
+    }
+    Ok(())
+  }
+
+  pub fn is_disabled(&self) -> bool {
+    self.disabled_at.is_some()
+  }
+
+  pub fn is_email_verified(&self) -> bool {
+    self.email_verified_at.is_some()
+  }
+
+  /// 2FA is required but the user has not enrolled yet
+  pub fn is_pending_two_factor(&self) -> bool {
+    self.totp_required && self.totp_enabled_at.is_none()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_validate_password() {
+    assert!(validate_password("Sup3r$ecret").is_ok());
+    assert!(validate_password("Sh0rt!").is_err());
+    assert!(validate_password("NoNumbers!").is_err());
+    assert!(validate_password("n0uppercase!").is_err());
+    assert!(validate_password("N0LOWERCASE!").is_err());
+    assert!(validate_password("N0Special").is_err());
+    assert!(validate_password(&format!("Aa1!{}", "a".repeat(124))).is_err());
+  }
 }
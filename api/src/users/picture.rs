@@ -0,0 +1,212 @@
+use std::io::Cursor;
+use image::{
+  codecs::webp::WebPEncoder, imageops::FilterType, DynamicImage, ImageFormat, ImageReader, Limits,
+};
+use serde::{Deserialize, Serialize};
+use crate::auth::csprng;
+use crate::db::{self, delete_file, get_file, upload_file};
+use crate::error::{ApiResult, Error};
+
+const TABLE_NAME: &str = "users";
+const PICTURE_PREFIX: &str = "avatars";
+pub const MAX_PICTURE_SIZE: usize = 5 * 1024 * 1024; // (In bytes) 5 MiB
+const MAX_PICTURE_DIMENSION: u32 = 8192; // (In pixels)
+const SUPPORTED_FORMATS: [ImageFormat; 4] = [
+  ImageFormat::Png,
+  ImageFormat::Jpeg,
+  ImageFormat::Gif,
+  ImageFormat::WebP,
+];
+pub const PICTURE_CONTENT_TYPE: &str = "image/webp";
+
+/// Square sizes every uploaded picture is stored at
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PictureSize {
+  Small,
+  #[default]
+  Medium,
+  Large,
+}
+
+impl PictureSize {
+  pub const ALL: [PictureSize; 3] = [PictureSize::Small, PictureSize::Medium, PictureSize::Large];
+
+  pub fn pixels(&self) -> u32 {
+    match self {
+      PictureSize::Small => 64,
+      PictureSize::Medium => 128,
+      PictureSize::Large => 256,
+    }
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      PictureSize::Small => "small",
+      PictureSize::Medium => "medium",
+      PictureSize::Large => "large",
+    }
+  }
+}
+
+/// Profile pictures, re-encoded as WebP and stored in the bucket under a key unique to each upload
+pub struct Picture;
+
+impl Picture {
+  pub fn path(key: &str, size: PictureSize) -> String {
+    format!("{}/{}/{}.webp", PICTURE_PREFIX, key, size.as_str())
+  }
+
+  /// Decode an upload by its content rather than its name, only raster formats are accepted
+  pub fn decode(bytes: &[u8]) -> ApiResult<DynamicImage> {
+    let format = image::guess_format(bytes)
+      .ok()
+      .filter(|format| SUPPORTED_FORMATS.contains(format))
+      .ok_or_else(|| {
+        Error::new(
+          415,
+          "Pictures must be PNG, JPEG, GIF or WebP images".to_string(),
+        )
+      })?;
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_PICTURE_DIMENSION);
+    limits.max_image_height = Some(MAX_PICTURE_DIMENSION);
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    reader
+      .decode()
+      .map_err(|err| Error::new(400, format!("Invalid image: {}", err)))
+  }
+
+  /// Crop to a centred square and scale to the given size
+  pub fn encode(image: &DynamicImage, size: PictureSize) -> ApiResult<Vec<u8>> {
+    let pixels = size.pixels();
+    let resized = image.resize_to_fill(pixels, pixels, FilterType::Lanczos3);
+    let mut bytes = Vec::new();
+    resized
+      .to_rgba8()
+      .write_with_encoder(WebPEncoder::new_lossless(&mut bytes))
+      .map_err(|err| Error::new(500, format!("Unable to encode picture: {}", err)))?;
+    Ok(bytes)
+  }
+
+  /// Store a new picture for the user and remove the previous one
+  pub async fn store(email: &str, bytes: Vec<u8>) -> ApiResult<String> {
+    // Decoding and resizing are CPU bound, keep them off the async workers
+    let encoded = tokio::task::spawn_blocking(move || {
+      let image = Self::decode(&bytes)?;
+      PictureSize::ALL
+        .iter()
+        .map(|size| Self::encode(&image, *size).map(|bytes| (*size, bytes)))
+        .collect::<ApiResult<Vec<_>>>()
+    })
+    .await
+    .map_err(|err| Error::new(500, err.to_string()))??;
+
+    let key = csprng(24);
+    for (size, bytes) in &encoded {
+      upload_file(&Self::path(&key, *size), bytes).await?;
+    }
+
+    let pool = db::pool();
+    let previous: Option<Option<String>> = sqlx::query_scalar(&format!(
+      r#"
+      UPDATE {0} AS u SET picture_key = $2, updated_at = NOW()
+      FROM (SELECT email, picture_key FROM {0} WHERE email = $1 FOR UPDATE) AS previous
+      WHERE u.email = previous.email
+      RETURNING previous.picture_key
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .bind(&key)
+    .fetch_optional(pool)
+    .await?;
+
+    match previous {
+      Some(Some(previous_key)) => Self::delete_files(&previous_key).await,
+      Some(None) => {}
+      None => {
+        Self::delete_files(&key).await;
+        return Err(Error::new(404, format!("User {} not found", email)));
+      }
+    }
+    Ok(key)
+  }
+
+  pub async fn get(key: &str, size: PictureSize) -> ApiResult<Vec<u8>> {
+    get_file(&Self::path(key, size)).await
+  }
+
+  /// Remove the user's picture, returning false when there was none
+  pub async fn delete(email: &str) -> ApiResult<bool> {
+    let pool = db::pool();
+    let previous: Option<String> = sqlx::query_scalar(&format!(
+      r#"
+      UPDATE {0} AS u SET picture_key = NULL, updated_at = NOW()
+      FROM (SELECT email, picture_key FROM {0} WHERE email = $1 FOR UPDATE) AS previous
+      WHERE u.email = previous.email AND previous.picture_key IS NOT NULL
+      RETURNING previous.picture_key
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_optional(pool)
+    .await?;
+
+    match previous {
+      Some(key) => {
+        Self::delete_files(&key).await;
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+
+  /// Best effort, an orphaned file only costs storage
+  pub async fn delete_files(key: &str) {
+    for size in PictureSize::ALL {
+      if let Err(err) = delete_file(&Self::path(key, size)).await {
+        log::warn!(
+          "Unable to delete picture {}: {}",
+          Self::path(key, size),
+          err
+        );
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_decode_sniffs_content() {
+    let mut png = Vec::new();
+    DynamicImage::new_rgba8(3, 2)
+      .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+      .unwrap();
+    assert_eq!(Picture::decode(&png).unwrap().width(), 3);
+
+    let err = Picture::decode(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>").unwrap_err();
+    assert_eq!(err.status, 415);
+    let err = Picture::decode(&png[..16]).unwrap_err();
+    assert_eq!(err.status, 400);
+  }
+
+  #[test]
+  fn test_encode_square() {
+    let image = DynamicImage::new_rgba8(300, 200);
+    for size in PictureSize::ALL {
+      let bytes = Picture::encode(&image, size).unwrap();
+      assert_eq!(image::guess_format(&bytes).unwrap(), ImageFormat::WebP);
+      let decoded = Picture::decode(&bytes).unwrap();
+      assert_eq!(
+        (decoded.width(), decoded.height()),
+        (size.pixels(), size.pixels())
+      );
+    }
+  }
+}
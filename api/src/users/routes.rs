@@ -1,165 +1,600 @@
-// use actix_multipart::Multipart;
-// use actix_web::{get, post, delete, web, HttpResponse, ResponseError};
-// use futures_util::StreamExt;
-
-// use crate::{
-//   auth::Auth,
-//   db::{delete_file, get_file, upload_file},
-//   error::ServiceError,
-//   users::User,
-// };
-
-// #[get("/favorites")]
-// async fn get_favorites(auth: Auth) -> HttpResponse {
-//   match User::get_by_email(&auth.user.email) {
-//     Ok(user) => return HttpResponse::Ok().json(user.favorites),
-//     Err(err) => return ResponseError::error_response(&err),
-//   }
-// }
-
-// #[post("/favorites/{icao}")]
-// async fn add_favorite(icao: web::Path<String>, auth: Auth) -> HttpResponse {
-//   match User::get_by_email(&auth.user.email) {
-//     Ok(user) => {
-//       if user.favorites.contains(&icao) {
-//         // Check if the airport ICAO is already in the user's favorites
-//         return HttpResponse::Conflict().finish();
-//       } else {
-//         // Add the airport ICAO to the user's favorites
-//         let mut favorites = user.favorites;
-//         favorites.push(icao.into_inner());
-//         match User::update_favorites(&user.email, favorites) {
-//           Ok(_) => return HttpResponse::Ok().finish(),
-//           Err(err) => return ResponseError::error_response(&err),
-//         }
-//       }
-//     }
-//     Err(err) => return ResponseError::error_response(&err),
-//   }
-// }
-
-// #[delete("/favorites/{icao}")]
-// async fn delete_favorite(icao: web::Path<String>, auth: Auth) -> HttpResponse {
-//   let icao: String = icao.into_inner();
-//   match User::get_by_email(&auth.user.email) {
-//     Ok(user) => {
-//       if user.favorites.contains(&icao) {
-//         // Check if the airport ICAO is already in the user's favorites
-//         let mut favorites = user.favorites;
-//         favorites.retain(|x| x != &icao);
-//         match User::update_favorites(&user.email, favorites) {
-//           Ok(_) => return HttpResponse::Ok().finish(),
-//           Err(err) => return ResponseError::error_response(&err),
-//         }
-//       } else {
-//         // Remove the airport ICAO from the user's favorites
-//         return HttpResponse::Conflict().finish();
-//       }
-//     }
-//     Err(err) => return ResponseError::error_response(&err),
-//   }
-// }
-
-// #[post("/picture")]
-// async fn set_picture(mut payload: Multipart, auth: Auth) -> HttpResponse {
-//   while let Some(item) = payload.next().await {
-//     let mut bytes = web::BytesMut::new();
-//     let mut field = match item {
-//       Ok(field) => field,
-//       Err(err) => return ResponseError::error_response(&err),
-//     };
-//     let content_type = field.content_disposition();
-//     let filename = match content_type.unwrap().get_filename() {
-//       Some(name) => match name.split(".").last() {
-//         Some(ext) => match ext {
-//           "apng" | "avif" | "gif" | "jpg" | "jpeg" | "jfif" | "pjpeg" | "pjp" | "png" | "svg"
-//           | "webp" => name,
-//           _ => {
-//             return ResponseError::error_response(&ServiceError::new(
-//               400,
-//               "File extension is not supported".to_string(),
-//             ))
-//           }
-//         },
-//         None => {
-//           return ResponseError::error_response(&ServiceError::new(
-//             400,
-//             "Unknown file extension".to_string(),
-//           ))
-//         }
-//       },
-//       None => {
-//         return ResponseError::error_response(&ServiceError::new(
-//           400,
-//           "File name is not provided".to_string(),
-//         ))
-//       }
-//     };
-//     let path = format!("users/{}/{}", auth.user.email, filename);
-
-//     while let Some(chunk) = field.next().await {
-//       let data = match chunk {
-//         Ok(data) => data,
-//         Err(err) => return ResponseError::error_response(&err),
-//       };
-//       bytes.extend_from_slice(&data);
-//     }
-//     match upload_file(&path, &bytes).await {
-//       Ok(_) => {
-//         match User::update_profile_picture(&auth.user.email, Some(&path)) {
-//           Ok(_) => {}
-//           Err(err) => return ResponseError::error_response(&err),
-//         };
-//       }
-//       Err(err) => return ResponseError::error_response(&err),
-//     };
-//   }
-//   HttpResponse::Ok().finish()
-// }
-
-// #[get("/picture")]
-// async fn get_picture(auth: Auth) -> HttpResponse {
-//   let user = match User::get_by_email(&auth.user.email) {
-//     Ok(user) => user,
-//     Err(err) => return ResponseError::error_response(&err),
-//   };
-//   if let Some(path) = user.profile_picture {
-//     match get_file(&path).await {
-//       Ok(bytes) => HttpResponse::Ok().body(bytes),
-//       Err(err) => ResponseError::error_response(&err),
-//     }
-//   } else {
-//     HttpResponse::NotFound().finish()
-//   }
-// }
-
-// #[delete("/picture")]
-// async fn delete_picture(auth: Auth) -> HttpResponse {
-//   let user = match User::get_by_email(&auth.user.email) {
-//     Ok(user) => user,
-//     Err(err) => return ResponseError::error_response(&err),
-//   };
-//   if let Some(path) = user.profile_picture {
-//     match delete_file(&path).await {
-//       Ok(_) => match User::update_profile_picture(&auth.user.email, None) {
-//         Ok(_) => HttpResponse::Ok().finish(),
-//         Err(err) => ResponseError::error_response(&err),
-//       },
-//       Err(err) => ResponseError::error_response(&err),
-//     }
-//   } else {
-//     HttpResponse::NotFound().finish()
-//   }
-// }
-
-pub fn init_routes(_config: &mut actix_web::web::ServiceConfig) {
-  // config.service(
-  //   web::scope("users")
-  //     .service(get_favorites)
-  //     .service(add_favorite)
-  //     .service(delete_favorite)
-  //     .service(set_picture)
-  //     .service(get_picture)
-  //     .service(delete_picture),
-  // );
+use actix_multipart::Multipart;
+use actix_web::{delete, get, http, post, put, web, HttpRequest, HttpResponse, ResponseError};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use crate::{
+  audit::{self, AuditAction, AuditEvent},
+  auth::{client_ip, csprng, require, AccountToken, Auth, LoginThrottle, Permitted, Session, Totp},
+  db::Paged,
+  error::{ApiResult, Error},
+  roles::Role,
+  users::{
+    AdminUpdateUser, Favorite, FavoriteRequest, Picture, PictureSize, MAX_PICTURE_SIZE,
+    PICTURE_CONTENT_TYPE, ReorderFavoritesRequest, UpdateUser, User, UserQuery, UserResponse,
+  },
+  AppState,
+};
+
+const MAX_LIMIT: u32 = 1000;
+
+#[get("/favorites")]
+async fn get_favorites(auth: Auth) -> HttpResponse {
+  match Favorite::select_all(&auth.user.email).await {
+    Ok(favorites) => HttpResponse::Ok().json(favorites),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[get("/favorites/weather")]
+async fn get_favorites_weather(data: web::Data<AppState>, auth: Auth) -> HttpResponse {
+  match Favorite::select_weather(&data.client, &auth.user.email).await {
+    Ok(favorites) => HttpResponse::Ok().json(favorites),
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[post("/favorites/{icao}")]
+async fn add_favorite(
+  icao: web::Path<String>,
+  request: Option<web::Json<FavoriteRequest>>,
+  auth: Auth,
+) -> HttpResponse {
+  let request = request
+    .map(|request| request.into_inner())
+    .unwrap_or_default();
+  match Favorite::insert(&auth.user.email, &icao, &request).await {
+    Ok(favorite) => HttpResponse::Created().json(favorite),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[put("/favorites")]
+async fn reorder_favorites(
+  request: web::Json<ReorderFavoritesRequest>,
+  auth: Auth,
+) -> HttpResponse {
+  match Favorite::reorder(&auth.user.email, &request.icaos).await {
+    Ok(favorites) => HttpResponse::Ok().json(favorites),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/favorites/{icao}")]
+async fn delete_favorite(icao: web::Path<String>, auth: Auth) -> HttpResponse {
+  match Favorite::delete(&auth.user.email, &icao).await {
+    Ok(_) => HttpResponse::NoContent().finish(),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[derive(Debug, Deserialize)]
+struct PictureQuery {
+  size: Option<PictureSize>,
+}
+
+/// Read the first file in the form, stopping once it exceeds the size limit
+async fn read_picture(mut payload: Multipart) -> ApiResult<Vec<u8>> {
+  while let Some(field) = payload.next().await {
+    let mut field = field.map_err(|err| Error::new(400, err.to_string()))?;
+    let is_file = field
+      .content_disposition()
+      .is_some_and(|disposition| disposition.get_filename().is_some());
+    if !is_file {
+      continue;
+    }
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.next().await {
+      let data = chunk.map_err(|err| Error::new(400, err.to_string()))?;
+      if bytes.len() + data.len() > MAX_PICTURE_SIZE {
+        return Err(Error::new(
+          413,
+          format!(
+            "Pictures are limited to {} MiB",
+            MAX_PICTURE_SIZE / 1024 / 1024
+          ),
+        ));
+      }
+      bytes.extend_from_slice(&data);
+    }
+    return Ok(bytes);
+  }
+  Err(Error::new(400, "No picture was uploaded".to_string()))
+}
+
+#[post("/picture")]
+async fn set_picture(payload: Multipart, req: HttpRequest, auth: Auth) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  let bytes = match read_picture(payload).await {
+    Ok(bytes) => bytes,
+    Err(err) => return ResponseError::error_response(&err),
+  };
+  match Picture::store(&auth.user.email, bytes).await {
+    Ok(_) => {
+      log::info!(
+        "Successful picture upload [Email: {}] [IP Address: {}]",
+        auth.user.email,
+        ip_address
+      );
+      match User::select(&auth.user.email).await {
+        Some(user) => HttpResponse::Ok().json(UserResponse::from(user)),
+        None => HttpResponse::NotFound().finish(),
+      }
+    }
+    Err(err) => {
+      log::error!(
+        "Invalid picture upload [Email: {}] [IP Address: {}]: {}",
+        auth.user.email,
+        ip_address,
+        err
+      );
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[get("/picture")]
+async fn get_picture(
+  query: web::Query<PictureQuery>,
+  req: HttpRequest,
+  auth: Auth,
+) -> HttpResponse {
+  let Some(key) = &auth.user.picture_key else {
+    return HttpResponse::NotFound().finish();
+  };
+  let size = query.size.unwrap_or_default();
+  // Every upload gets a new key, so the key and size identify the content
+  let etag = format!("\"{}-{}\"", key, size.as_str());
+  let cache_control = (http::header::CACHE_CONTROL, "private, max-age=86400");
+
+  let not_modified = req
+    .headers()
+    .get(http::header::IF_NONE_MATCH)
+    .and_then(|value| value.to_str().ok())
+    .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+  if not_modified {
+    return HttpResponse::NotModified()
+      .insert_header((http::header::ETAG, etag))
+      .insert_header(cache_control)
+      .finish();
+  }
+
+  match Picture::get(key, size).await {
+    Ok(bytes) => HttpResponse::Ok()
+      .content_type(PICTURE_CONTENT_TYPE)
+      .insert_header((http::header::ETAG, etag))
+      .insert_header(cache_control)
+      .body(bytes),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/picture")]
+async fn delete_picture(auth: Auth) -> HttpResponse {
+  match Picture::delete(&auth.user.email).await {
+    Ok(true) => HttpResponse::NoContent().finish(),
+    Ok(false) => HttpResponse::NotFound().finish(),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+/// Emails in admin paths are matched the way they are stored
+fn path_email(email: web::Path<String>) -> String {
+  email.into_inner().trim().to_lowercase()
+}
+
+async fn select_user(email: &str) -> ApiResult<User> {
+  User::select(email)
+    .await
+    .ok_or_else(|| Error::new(404, format!("User {} not found", email)))
+}
+
+/// Administrators cannot lock themselves out by changing their own account
+fn verify_not_self(email: &str, auth: &Permitted<require::UsersManage>) -> ApiResult<()> {
+  if email.eq_ignore_ascii_case(&auth.user.email) {
+    return Err(Error::new(
+      400,
+      "Administrators cannot change their own account here".to_string(),
+    ));
+  }
+  Ok(())
+}
+
+#[get("")]
+async fn get_users(req: HttpRequest, _: Permitted<require::UsersManage>) -> HttpResponse {
+  let mut query = match web::Query::<UserQuery>::from_query(req.query_string()) {
+    Ok(q) => q.into_inner(),
+    Err(err) => {
+      log::error!("{}", err);
+      UserQuery::default()
+    }
+  };
+
+  let total = User::count(&query).await;
+  let page = query.page.unwrap_or(1);
+  let limit = query.limit.unwrap_or(100).min(MAX_LIMIT);
+  query.limit = Some(limit);
+  query.page = Some(page);
+
+  match User::select_all(&query).await {
+    Ok(users) => HttpResponse::Ok().json(Paged {
+      data: users
+        .into_iter()
+        .map(UserResponse::from)
+        .collect::<Vec<UserResponse>>(),
+      page,
+      limit,
+      total,
+    }),
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[get("/{email}")]
+async fn get_user(email: web::Path<String>, _: Permitted<require::UsersManage>) -> HttpResponse {
+  let email = path_email(email);
+  match select_user(&email).await {
+    Ok(user) => HttpResponse::Ok().json(UserResponse::from(user)),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+/// Update the user, returning them as they were before and after
+async fn apply_update(email: &str, request: AdminUpdateUser) -> ApiResult<(User, User)> {
+  let before = select_user(email).await?;
+  let role = match request.role {
+    Some(role) => Some(Role::select(&role.to_uppercase()).await?.name),
+    None => None,
+  };
+  let update_user = UpdateUser {
+    email: None,
+    password: None,
+    role,
+    first_name: request.first_name,
+    last_name: request.last_name,
+  };
+  update_user.update(email).await?;
+  if let Some(required) = request.two_factor_required {
+    Totp::set_required(email, required).await?;
+  }
+  Ok((before, select_user(email).await?))
+}
+
+#[put("/{email}")]
+async fn update_user_details(
+  email: web::Path<String>,
+  request: web::Json<AdminUpdateUser>,
+  req: HttpRequest,
+  auth: Permitted<require::UsersManage>,
+) -> HttpResponse {
+  let email = path_email(email);
+  let ip_address = client_ip(&req);
+  let request = request.into_inner();
+  if request.role.is_some() {
+    if let Err(err) = verify_not_self(&email, &auth) {
+      return ResponseError::error_response(&err);
+    }
+  }
+
+  match apply_update(&email, request).await {
+    Ok((before, user)) => {
+      log::info!(
+        "Updated user {} [Role: {}] [Email: {}] [IP Address: {}]",
+        email,
+        user.role,
+        auth.user.email,
+        ip_address
+      );
+      let action = if before.role != user.role {
+        AuditAction::UserRoleChanged
+      } else {
+        AuditAction::UserUpdated
+      };
+      let user = UserResponse::from(user);
+      AuditEvent::new(action, Some(&auth.user.email), &ip_address)
+        .target(&email)
+        .changes(audit::diff(&UserResponse::from(before), &user))
+        .record()
+        .await;
+      HttpResponse::Ok().json(user)
+    }
+    Err(err) => {
+      log::error!("Unable to update user {}: {}", email, err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+async fn set_disabled(
+  email: &str,
+  disabled: bool,
+  ip_address: &str,
+  auth: &Permitted<require::UsersManage>,
+) -> HttpResponse {
+  if let Err(err) = verify_not_self(email, auth) {
+    return ResponseError::error_response(&err);
+  }
+  let user = match User::set_disabled(email, disabled).await {
+    Ok(user) => user,
+    Err(err) => return ResponseError::error_response(&err),
+  };
+  if disabled {
+    if let Err(err) = Session::delete_all(email).await {
+      log::error!(
+        "Unable to revoke sessions of disabled user {}: {}",
+        email,
+        err
+      );
+      return ResponseError::error_response(&err);
+    }
+  }
+  log::info!(
+    "User {} {} [Email: {}] [IP Address: {}]",
+    email,
+    if disabled { "disabled" } else { "enabled" },
+    auth.user.email,
+    ip_address
+  );
+  let action = if disabled {
+    AuditAction::UserDisabled
+  } else {
+    AuditAction::UserEnabled
+  };
+  AuditEvent::new(action, Some(&auth.user.email), ip_address)
+    .target(email)
+    .record()
+    .await;
+  HttpResponse::Ok().json(UserResponse::from(user))
+}
+
+#[post("/{email}/disable")]
+async fn disable_user(
+  email: web::Path<String>,
+  req: HttpRequest,
+  auth: Permitted<require::UsersManage>,
+) -> HttpResponse {
+  set_disabled(&path_email(email), true, &client_ip(&req), &auth).await
+}
+
+#[post("/{email}/enable")]
+async fn enable_user(
+  email: web::Path<String>,
+  req: HttpRequest,
+  auth: Permitted<require::UsersManage>,
+) -> HttpResponse {
+  set_disabled(&path_email(email), false, &client_ip(&req), &auth).await
+}
+
+async fn force_password_reset(email: &str) -> ApiResult<()> {
+  select_user(email).await?;
+  // Replace the password so the old one stops working before the user picks a new one
+  let update_user = UpdateUser {
+    email: None,
+    password: Some(csprng(64)),
+    role: None,
+    first_name: None,
+    last_name: None,
+  };
+  update_user.update(email).await?;
+  Session::delete_all(email).await?;
+  AccountToken::send_password_reset(email).await
+}
+
+#[post("/{email}/password-reset")]
+async fn reset_password(
+  email: web::Path<String>,
+  req: HttpRequest,
+  auth: Permitted<require::UsersManage>,
+) -> HttpResponse {
+  let email = path_email(email);
+  let ip_address = client_ip(&req);
+  match force_password_reset(&email).await {
+    Ok(_) => {
+      log::info!(
+        "Forced password reset for {} [Email: {}] [IP Address: {}]",
+        email,
+        auth.user.email,
+        ip_address
+      );
+      AuditEvent::new(
+        AuditAction::UserPasswordResetForced,
+        Some(&auth.user.email),
+        &ip_address,
+      )
+      .target(&email)
+      .record()
+      .await;
+      HttpResponse::Accepted().finish()
+    }
+    Err(err) => {
+      log::error!("Unable to force password reset for {}: {}", email, err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[delete("/{email}/sessions")]
+async fn revoke_sessions(
+  email: web::Path<String>,
+  req: HttpRequest,
+  auth: Permitted<require::UsersManage>,
+) -> HttpResponse {
+  let email = path_email(email);
+  let ip_address = client_ip(&req);
+  if let Err(err) = select_user(&email).await {
+    return ResponseError::error_response(&err);
+  }
+  match Session::delete_others(&email, None).await {
+    Ok(revoked) => {
+      log::info!(
+        "Revoked {} sessions of {} [Email: {}] [IP Address: {}]",
+        revoked,
+        email,
+        auth.user.email,
+        ip_address
+      );
+      AuditEvent::new(
+        AuditAction::UserSessionsRevoked,
+        Some(&auth.user.email),
+        &ip_address,
+      )
+      .target(&email)
+      .detail(format!("{} sessions", revoked))
+      .record()
+      .await;
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => {
+      log::error!("Unable to revoke sessions of {}: {}", email, err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+async fn delete_account(email: &str) -> ApiResult<User> {
+  let user = select_user(email).await?;
+  Session::delete_all(email).await?;
+  LoginThrottle::unlock(email).await?;
+  User::delete(email).await?;
+  if let Some(key) = &user.picture_key {
+    Picture::delete_files(key).await;
+  }
+  Ok(user)
+}
+
+#[delete("/{email}")]
+async fn delete_user(
+  email: web::Path<String>,
+  req: HttpRequest,
+  auth: Permitted<require::UsersManage>,
+) -> HttpResponse {
+  let email = path_email(email);
+  let ip_address = client_ip(&req);
+  if let Err(err) = verify_not_self(&email, &auth) {
+    return ResponseError::error_response(&err);
+  }
+  match delete_account(&email).await {
+    Ok(user) => {
+      log::info!(
+        "Deleted user {} [Email: {}] [IP Address: {}]",
+        email,
+        auth.user.email,
+        ip_address
+      );
+      AuditEvent::new(
+        AuditAction::UserDeleted,
+        Some(&auth.user.email),
+        &ip_address,
+      )
+      .target(&email)
+      .changes((audit::fields(&UserResponse::from(user)), None))
+      .record()
+      .await;
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => {
+      log::error!("Unable to delete user {}: {}", email, err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[get("/{email}/lockout")]
+async fn get_lockout(email: web::Path<String>, _: Permitted<require::UsersManage>) -> HttpResponse {
+  let email = path_email(email);
+  match LoginThrottle::status(&email).await {
+    Ok(status) => HttpResponse::Ok().json(status),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/{email}/lockout")]
+async fn unlock(
+  email: web::Path<String>,
+  req: HttpRequest,
+  auth: Permitted<require::UsersManage>,
+) -> HttpResponse {
+  let email = path_email(email);
+  let ip_address = client_ip(&req);
+  match LoginThrottle::unlock(&email).await {
+    Ok(unlocked) => {
+      log::info!(
+        "Account {} {} [Email: {}] [IP Address: {}]",
+        email,
+        if unlocked {
+          "unlocked"
+        } else {
+          "failures cleared"
+        },
+        auth.user.email,
+        ip_address
+      );
+      AuditEvent::new(
+        AuditAction::UserUnlocked,
+        Some(&auth.user.email),
+        &ip_address,
+      )
+      .target(&email)
+      .record()
+      .await;
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+  config.service(
+    web::scope("admin/users")
+      .service(get_users)
+      .service(get_user)
+      .service(update_user_details)
+      .service(disable_user)
+      .service(enable_user)
+      .service(reset_password)
+      .service(revoke_sessions)
+      .service(delete_user)
+      .service(get_lockout)
+      .service(unlock),
+  );
+  config.service(
+    web::scope("users")
+      .service(get_favorites)
+      .service(get_favorites_weather)
+      .service(add_favorite)
+      .service(reorder_favorites)
+      .service(delete_favorite)
+      .service(set_picture)
+      .service(get_picture)
+      .service(delete_picture),
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use actix_web::{test, App};
+  use super::*;
+
+  #[actix_web::test]
+  async fn test_revoke_sessions_requires_authentication() {
+    let app = test::init_service(App::new().configure(init_routes)).await;
+    let req = test::TestRequest::delete()
+      .uri("/admin/users/user@example.com/sessions")
+      .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::UNAUTHORIZED);
+
+    // Only DELETE is routed on the path
+    let req = test::TestRequest::get()
+      .uri("/admin/users/user@example.com/sessions")
+      .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), http::StatusCode::NOT_FOUND);
+  }
 }
@@ -0,0 +1,633 @@
+use std::collections::{HashMap, HashSet};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::auth::Auth;
+use crate::db;
+use crate::error::{ApiResult, Error};
+use crate::organizations::{Organization, OrganizationRole};
+
+const TABLE_NAME: &str = "watchlists";
+const AIRPORTS_TABLE_NAME: &str = "watchlist_airports";
+const MEMBERS_TABLE_NAME: &str = "watchlist_members";
+const ORGANIZATION_MEMBERS_TABLE_NAME: &str = "organization_members";
+const AIRPORT_DATASET_TABLE_NAME: &str = "airports";
+const MAX_AIRPORTS: usize = 500;
+const MAX_NAME_LENGTH: usize = 64;
+
+/// What a user may do with a watchlist, ordered from least to most
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchlistAccess {
+  Read,
+  Edit,
+  Owner,
+}
+
+impl WatchlistAccess {
+  fn as_str(&self) -> &'static str {
+    match self {
+      WatchlistAccess::Read => "read",
+      WatchlistAccess::Edit => "edit",
+      WatchlistAccess::Owner => "owner",
+    }
+  }
+
+  fn parse(access: &str) -> Option<Self> {
+    match access {
+      "read" => Some(WatchlistAccess::Read),
+      "edit" => Some(WatchlistAccess::Edit),
+      "owner" => Some(WatchlistAccess::Owner),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct WatchlistRow {
+  id: Uuid,
+  owner: String,
+  organization_id: Option<Uuid>,
+  name: String,
+  description: Option<String>,
+  access: String,
+  created_at: DateTime<Utc>,
+  updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Watchlist {
+  pub id: Uuid,
+  pub owner: String,
+  /// Organization whose members all share the watchlist
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub organization_id: Option<Uuid>,
+  pub name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub description: Option<String>,
+  /// Access of the requesting user
+  pub access: WatchlistAccess,
+  pub icaos: Vec<String>,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct WatchlistMember {
+  pub email: String,
+  #[sqlx(try_from = "String")]
+  pub access: MemberAccess,
+  pub created_at: DateTime<Utc>,
+}
+
+/// Access that can be granted to a member, ownership cannot be shared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberAccess {
+  Read,
+  Edit,
+}
+
+impl MemberAccess {
+  fn as_str(&self) -> &'static str {
+    WatchlistAccess::from(*self).as_str()
+  }
+}
+
+impl From<MemberAccess> for WatchlistAccess {
+  fn from(access: MemberAccess) -> Self {
+    match access {
+      MemberAccess::Read => WatchlistAccess::Read,
+      MemberAccess::Edit => WatchlistAccess::Edit,
+    }
+  }
+}
+
+impl TryFrom<String> for MemberAccess {
+  type Error = String;
+
+  fn try_from(access: String) -> Result<Self, Self::Error> {
+    match access.as_str() {
+      "read" => Ok(MemberAccess::Read),
+      "edit" => Ok(MemberAccess::Edit),
+      _ => Err(format!("Invalid watchlist access '{}'", access)),
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateWatchlist {
+  pub name: String,
+  pub description: Option<String>,
+  /// Share with every member of an organization the creator belongs to
+  pub organization_id: Option<Uuid>,
+  #[serde(default)]
+  pub icaos: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateWatchlist {
+  pub name: Option<String>,
+  pub description: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WatchlistAirports {
+  pub icaos: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareWatchlist {
+  pub access: MemberAccess,
+}
+
+fn validate_name(name: &str) -> ApiResult<String> {
+  let name = name.trim();
+  if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+    return Err(Error::new(
+      400,
+      format!("Names must be 1 to {} characters", MAX_NAME_LENGTH),
+    ));
+  }
+  Ok(name.to_string())
+}
+
+/// Uppercase and de-duplicate ICAOs, keeping the first occurrence
+fn normalize_icaos(icaos: &[String]) -> ApiResult<Vec<String>> {
+  let mut seen = HashSet::new();
+  let icaos: Vec<String> = icaos
+    .iter()
+    .map(|icao| icao.trim().to_uppercase())
+    .filter(|icao| !icao.is_empty() && seen.insert(icao.clone()))
+    .collect();
+  if icaos.len() > MAX_AIRPORTS {
+    return Err(Error::new(
+      400,
+      format!("Watchlists are limited to {} airports", MAX_AIRPORTS),
+    ));
+  }
+  Ok(icaos)
+}
+
+fn intersect_icaos(icaos: Vec<String>, requested: Option<&str>) -> Vec<String> {
+  let Some(requested) = requested else {
+    return icaos;
+  };
+  let requested: HashSet<String> = requested
+    .split(',')
+    .map(|icao| icao.trim().to_uppercase())
+    .collect();
+  icaos
+    .into_iter()
+    .filter(|icao| requested.contains(icao))
+    .collect()
+}
+
+impl Watchlist {
+  /// Columns of a watchlist with the access `$1` has to it, owned, shared or through an
+  /// organization where members may edit and admins act as owners
+  fn select_sql() -> String {
+    format!(
+      r#"
+      SELECT w.id, w.owner, w.organization_id, w.name, w.description, w.created_at, w.updated_at,
+        CASE
+          WHEN w.owner = $1 OR o.role IN ('admin', 'owner') THEN 'owner'
+          WHEN o.role IS NOT NULL THEN 'edit'
+          ELSE m.access
+        END AS access
+      FROM {} w
+      LEFT JOIN {} m ON m.watchlist_id = w.id AND m.email = $1
+      LEFT JOIN {} o ON o.organization_id = w.organization_id AND o.email = $1
+      WHERE (w.owner = $1 OR m.email IS NOT NULL OR o.email IS NOT NULL)
+      "#,
+      TABLE_NAME, MEMBERS_TABLE_NAME, ORGANIZATION_MEMBERS_TABLE_NAME
+    )
+  }
+
+  async fn from_rows(rows: Vec<WatchlistRow>) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let ids: Vec<Uuid> = rows.iter().map(|row| row.id).collect();
+    let airports: Vec<(Uuid, String)> = sqlx::query_as(&format!(
+      r#"
+      SELECT watchlist_id, icao FROM {} WHERE watchlist_id = ANY($1) ORDER BY position
+      "#,
+      AIRPORTS_TABLE_NAME
+    ))
+    .bind(&ids)
+    .fetch_all(pool)
+    .await?;
+
+    let mut icaos: HashMap<Uuid, Vec<String>> = HashMap::new();
+    for (id, icao) in airports {
+      icaos.entry(id).or_default().push(icao);
+    }
+    rows
+      .into_iter()
+      .map(|row| {
+        let access = WatchlistAccess::parse(&row.access)
+          .ok_or_else(|| Error::new(500, format!("Invalid watchlist access '{}'", row.access)))?;
+        Ok(Self {
+          icaos: icaos.remove(&row.id).unwrap_or_default(),
+          id: row.id,
+          owner: row.owner,
+          organization_id: row.organization_id,
+          name: row.name,
+          description: row.description,
+          access,
+          created_at: row.created_at,
+          updated_at: row.updated_at,
+        })
+      })
+      .collect()
+  }
+
+  /// Watchlists the user owns or that were shared with them
+  pub async fn select_all(email: &str) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let rows: Vec<WatchlistRow> =
+      sqlx::query_as(&format!("{} ORDER BY w.name", Self::select_sql()))
+        .bind(email)
+        .fetch_all(pool)
+        .await?;
+    Self::from_rows(rows).await
+  }
+
+  /// The watchlist as seen by the user, missing and inaccessible lists are both not found
+  pub async fn select(id: &Uuid, email: &str) -> ApiResult<Self> {
+    let pool = db::pool();
+    let row: Option<WatchlistRow> =
+      sqlx::query_as(&format!("{} AND w.id = $2", Self::select_sql()))
+        .bind(email)
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    let row = row.ok_or_else(|| Error::new(404, format!("Watchlist {} not found", id)))?;
+    Self::from_rows(vec![row])
+      .await?
+      .pop()
+      .ok_or_else(|| Error::new(404, format!("Watchlist {} not found", id)))
+  }
+
+  /// Select the watchlist, failing unless the user has at least the required access
+  pub async fn authorize(id: &Uuid, email: &str, required: WatchlistAccess) -> ApiResult<Self> {
+    let watchlist = Self::select(id, email).await?;
+    if watchlist.access < required {
+      return Err(Error::new(
+        403,
+        format!("Watchlist {} requires {} access", id, required.as_str()),
+      ));
+    }
+    Ok(watchlist)
+  }
+
+  /// ICAOs of a watchlist used as a request filter, narrowed to the requested comma separated
+  /// ICAOs when given. Filtering requires a signed in member of the list.
+  pub async fn filter_icaos(
+    id: &Uuid,
+    requested: Option<&str>,
+    auth: Option<&Auth>,
+  ) -> ApiResult<Vec<String>> {
+    let auth = auth.ok_or_else(|| {
+      Error::new(
+        401,
+        "Filtering by watchlist requires authentication".to_string(),
+      )
+    })?;
+    let watchlist = Self::authorize(id, &auth.user.email, WatchlistAccess::Read).await?;
+    Ok(intersect_icaos(watchlist.icaos, requested))
+  }
+
+  pub async fn insert(email: &str, request: &CreateWatchlist) -> ApiResult<Self> {
+    let pool = db::pool();
+    let name = validate_name(&request.name)?;
+    let icaos = normalize_icaos(&request.icaos)?;
+    if let Some(organization) = &request.organization_id {
+      Organization::authorize(organization, email, OrganizationRole::Member).await?;
+    }
+    let id = Uuid::new_v4();
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (id, owner, organization_id, name, description) VALUES ($1, $2, $3, $4, $5)
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(email)
+    .bind(request.organization_id)
+    .bind(&name)
+    .bind(&request.description)
+    .execute(&mut *tx)
+    .await?;
+    Self::replace_airports(&mut tx, &id, email, &[], &icaos).await?;
+    tx.commit().await?;
+    Self::select(&id, email).await
+  }
+
+  pub async fn update(id: &Uuid, email: &str, request: &UpdateWatchlist) -> ApiResult<Self> {
+    let pool = db::pool();
+    Self::authorize(id, email, WatchlistAccess::Edit).await?;
+    let name = match &request.name {
+      Some(name) => Some(validate_name(name)?),
+      None => None,
+    };
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET
+        name = COALESCE($2, name),
+        description = COALESCE($3, description),
+        updated_at = NOW()
+      WHERE id = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(name)
+    .bind(&request.description)
+    .execute(pool)
+    .await?;
+    Self::select(id, email).await
+  }
+
+  pub async fn delete(id: &Uuid, email: &str) -> ApiResult<()> {
+    let pool = db::pool();
+    Self::authorize(id, email, WatchlistAccess::Owner).await?;
+    sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE id = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .execute(pool)
+    .await?;
+    Ok(())
+  }
+
+  /// Replace the airports with those given. Airports already on the watchlist are kept as they
+  /// are, only newly added airports must be global or visible to the editor
+  async fn replace_airports(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    id: &Uuid,
+    email: &str,
+    existing: &[String],
+    icaos: &[String],
+  ) -> ApiResult<()> {
+    sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE watchlist_id = $1
+      "#,
+      AIRPORTS_TABLE_NAME
+    ))
+    .bind(id)
+    .execute(&mut **tx)
+    .await?;
+
+    let positions: Vec<i32> = (0..icaos.len() as i32).collect();
+    let result = sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (watchlist_id, icao, position)
+      SELECT $1, a.icao, a.position FROM UNNEST($2::TEXT[], $3::INTEGER[]) AS a (icao, position)
+      WHERE a.icao = ANY($5) OR EXISTS (
+        SELECT 1 FROM {} airport WHERE airport.icao = a.icao
+          AND ((airport.organization_id IS NULL
+              AND (airport.owner IS NULL OR airport.owner = $4))
+            OR airport.organization_id IN (SELECT organization_id FROM {} WHERE email = $4))
+      )
+      "#,
+      AIRPORTS_TABLE_NAME, AIRPORT_DATASET_TABLE_NAME, ORGANIZATION_MEMBERS_TABLE_NAME
+    ))
+    .bind(id)
+    .bind(icaos)
+    .bind(&positions)
+    .bind(email)
+    .bind(existing)
+    .execute(&mut **tx)
+    .await;
+    match result {
+      Ok(result) if result.rows_affected() < icaos.len() as u64 => Err(Error::new(
+        404,
+        "Watchlists may only contain known airports".to_string(),
+      )),
+      Ok(_) => Ok(()),
+      Err(err) => match Error::from(err) {
+        err if err.status == 409 => Err(Error::new(
+          404,
+          "Watchlists may only contain known airports".to_string(),
+        )),
+        err => Err(err),
+      },
+    }
+  }
+
+  /// Lock the watchlist until the transaction ends and read its airports
+  async fn lock_airports(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    id: &Uuid,
+  ) -> ApiResult<Vec<String>> {
+    sqlx::query(&format!(
+      r#"
+      SELECT id FROM {} WHERE id = $1 FOR UPDATE
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .execute(&mut **tx)
+    .await?;
+    let icaos: Vec<String> = sqlx::query_scalar(&format!(
+      r#"
+      SELECT icao FROM {} WHERE watchlist_id = $1 ORDER BY position
+      "#,
+      AIRPORTS_TABLE_NAME
+    ))
+    .bind(id)
+    .fetch_all(&mut **tx)
+    .await?;
+    Ok(icaos)
+  }
+
+  /// Change the airports while the watchlist is locked, so concurrent edits are applied one
+  /// after the other instead of overwriting each other
+  async fn update_airports(
+    id: &Uuid,
+    email: &str,
+    change: impl FnOnce(Vec<String>) -> ApiResult<Vec<String>>,
+  ) -> ApiResult<Self> {
+    let pool = db::pool();
+    Self::authorize(id, email, WatchlistAccess::Edit).await?;
+    let mut tx = pool.begin().await?;
+    let existing = Self::lock_airports(&mut tx, id).await?;
+    let icaos = change(existing.clone())?;
+    let icaos = normalize_icaos(&icaos)?;
+    Self::replace_airports(&mut tx, id, email, &existing, &icaos).await?;
+    Self::touch(&mut tx, id).await?;
+    tx.commit().await?;
+    Self::select(id, email).await
+  }
+
+  /// Replace the airports, keeping the given order
+  pub async fn set_airports(id: &Uuid, email: &str, icaos: &[String]) -> ApiResult<Self> {
+    Self::update_airports(id, email, |_| Ok(icaos.to_vec())).await
+  }
+
+  pub async fn add_airport(id: &Uuid, email: &str, icao: &str) -> ApiResult<Self> {
+    Self::update_airports(id, email, |mut icaos| {
+      icaos.push(icao.to_string());
+      Ok(icaos)
+    })
+    .await
+  }
+
+  pub async fn remove_airport(id: &Uuid, email: &str, icao: &str) -> ApiResult<Self> {
+    let icao = icao.to_uppercase();
+    Self::update_airports(id, email, |icaos| {
+      if !icaos.contains(&icao) {
+        return Err(Error::new(
+          404,
+          format!("Airport {} is not in watchlist {}", icao, id),
+        ));
+      }
+      Ok(icaos.into_iter().filter(|i| i != &icao).collect())
+    })
+    .await
+  }
+
+  async fn touch(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, id: &Uuid) -> ApiResult<()> {
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET updated_at = NOW() WHERE id = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn select_members(id: &Uuid, email: &str) -> ApiResult<Vec<WatchlistMember>> {
+    let pool = db::pool();
+    Self::authorize(id, email, WatchlistAccess::Read).await?;
+    let members: Vec<WatchlistMember> = sqlx::query_as(&format!(
+      r#"
+      SELECT email, access, created_at FROM {} WHERE watchlist_id = $1 ORDER BY email
+      "#,
+      MEMBERS_TABLE_NAME
+    ))
+    .bind(id)
+    .fetch_all(pool)
+    .await?;
+    Ok(members)
+  }
+
+  /// Share the watchlist with another user or change their access
+  pub async fn share(
+    id: &Uuid,
+    email: &str,
+    member: &str,
+    access: MemberAccess,
+  ) -> ApiResult<Vec<WatchlistMember>> {
+    let pool = db::pool();
+    let watchlist = Self::authorize(id, email, WatchlistAccess::Owner).await?;
+    let member = member.to_lowercase();
+    if member == watchlist.owner {
+      return Err(Error::new(
+        400,
+        "Watchlists cannot be shared with their owner".to_string(),
+      ));
+    }
+    let result = sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (watchlist_id, email, access) VALUES ($1, $2, $3)
+      ON CONFLICT (watchlist_id, email) DO UPDATE SET access = EXCLUDED.access
+      "#,
+      MEMBERS_TABLE_NAME
+    ))
+    .bind(id)
+    .bind(&member)
+    .bind(access.as_str())
+    .execute(pool)
+    .await;
+    if let Err(err) = result {
+      return match Error::from(err) {
+        err if err.status == 409 => Err(Error::new(404, format!("User {} not found", member))),
+        err => Err(err),
+      };
+    }
+    Self::select_members(id, email).await
+  }
+
+  /// Stop sharing with a member, the owner may remove anyone and members may leave
+  pub async fn unshare(id: &Uuid, email: &str, member: &str) -> ApiResult<()> {
+    let pool = db::pool();
+    let member = member.to_lowercase();
+    let required = if member == email {
+      WatchlistAccess::Read
+    } else {
+      WatchlistAccess::Owner
+    };
+    Self::authorize(id, email, required).await?;
+    let result = sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE watchlist_id = $1 AND email = $2
+      "#,
+      MEMBERS_TABLE_NAME
+    ))
+    .bind(id)
+    .bind(&member)
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+      return Err(Error::new(
+        404,
+        format!("User {} is not a member of watchlist {}", member, id),
+      ));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_access_order() {
+    assert!(WatchlistAccess::Read < WatchlistAccess::Edit);
+    assert!(WatchlistAccess::Edit < WatchlistAccess::Owner);
+    assert_eq!(
+      WatchlistAccess::from(MemberAccess::Edit),
+      WatchlistAccess::Edit
+    );
+    assert_eq!(
+      WatchlistAccess::parse("owner"),
+      Some(WatchlistAccess::Owner)
+    );
+    assert!(MemberAccess::try_from("owner".to_string()).is_err());
+  }
+
+  #[test]
+  fn test_intersect_icaos() {
+    let icaos = vec!["KJFK".to_string(), "KBOS".to_string()];
+    assert_eq!(intersect_icaos(icaos.clone(), None), icaos);
+    assert_eq!(
+      intersect_icaos(icaos.clone(), Some("kbos, KLAX")),
+      vec!["KBOS"]
+    );
+    assert!(intersect_icaos(icaos, Some("KLAX")).is_empty());
+  }
+
+  #[test]
+  fn test_normalize_icaos() {
+    let icaos = vec![
+      "kjfk".to_string(),
+      " KBOS ".to_string(),
+      "KJFK".to_string(),
+      "".to_string(),
+    ];
+    assert_eq!(normalize_icaos(&icaos).unwrap(), vec!["KJFK", "KBOS"]);
+    let too_many: Vec<String> = (0..=MAX_AIRPORTS).map(|i| format!("K{:03}", i)).collect();
+    assert_eq!(normalize_icaos(&too_many).unwrap_err().status, 400);
+  }
+}
@@ -0,0 +1,163 @@
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, ResponseError};
+use uuid::Uuid;
+use crate::auth::{client_ip, Auth};
+use crate::watchlists::{
+  CreateWatchlist, ShareWatchlist, UpdateWatchlist, Watchlist, WatchlistAirports,
+};
+
+#[get("")]
+async fn get_watchlists(auth: Auth) -> HttpResponse {
+  match Watchlist::select_all(&auth.user.email).await {
+    Ok(watchlists) => HttpResponse::Ok().json(watchlists),
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[post("")]
+async fn create_watchlist(
+  request: web::Json<CreateWatchlist>,
+  req: HttpRequest,
+  auth: Auth,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  match Watchlist::insert(&auth.user.email, &request).await {
+    Ok(watchlist) => {
+      log::info!(
+        "Created watchlist {} [Email: {}] [IP Address: {}]",
+        watchlist.id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::Created().json(watchlist)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[get("/{id}")]
+async fn get_watchlist(id: web::Path<Uuid>, auth: Auth) -> HttpResponse {
+  match Watchlist::select(&id, &auth.user.email).await {
+    Ok(watchlist) => HttpResponse::Ok().json(watchlist),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[put("/{id}")]
+async fn update_watchlist(
+  id: web::Path<Uuid>,
+  request: web::Json<UpdateWatchlist>,
+  auth: Auth,
+) -> HttpResponse {
+  match Watchlist::update(&id, &auth.user.email, &request).await {
+    Ok(watchlist) => HttpResponse::Ok().json(watchlist),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/{id}")]
+async fn delete_watchlist(id: web::Path<Uuid>, req: HttpRequest, auth: Auth) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  match Watchlist::delete(&id, &auth.user.email).await {
+    Ok(_) => {
+      log::info!(
+        "Deleted watchlist {} [Email: {}] [IP Address: {}]",
+        id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[put("/{id}/airports")]
+async fn set_airports(
+  id: web::Path<Uuid>,
+  request: web::Json<WatchlistAirports>,
+  auth: Auth,
+) -> HttpResponse {
+  match Watchlist::set_airports(&id, &auth.user.email, &request.icaos).await {
+    Ok(watchlist) => HttpResponse::Ok().json(watchlist),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[post("/{id}/airports/{icao}")]
+async fn add_airport(path: web::Path<(Uuid, String)>, auth: Auth) -> HttpResponse {
+  let (id, icao) = path.into_inner();
+  match Watchlist::add_airport(&id, &auth.user.email, &icao).await {
+    Ok(watchlist) => HttpResponse::Ok().json(watchlist),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/{id}/airports/{icao}")]
+async fn remove_airport(path: web::Path<(Uuid, String)>, auth: Auth) -> HttpResponse {
+  let (id, icao) = path.into_inner();
+  match Watchlist::remove_airport(&id, &auth.user.email, &icao).await {
+    Ok(watchlist) => HttpResponse::Ok().json(watchlist),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[get("/{id}/members")]
+async fn get_members(id: web::Path<Uuid>, auth: Auth) -> HttpResponse {
+  match Watchlist::select_members(&id, &auth.user.email).await {
+    Ok(members) => HttpResponse::Ok().json(members),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[put("/{id}/members/{email}")]
+async fn share_watchlist(
+  path: web::Path<(Uuid, String)>,
+  request: web::Json<ShareWatchlist>,
+  req: HttpRequest,
+  auth: Auth,
+) -> HttpResponse {
+  let (id, member) = path.into_inner();
+  let ip_address = client_ip(&req);
+  match Watchlist::share(&id, &auth.user.email, &member, request.access).await {
+    Ok(members) => {
+      log::info!(
+        "Shared watchlist {} with {} [Email: {}] [IP Address: {}]",
+        id,
+        member,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::Ok().json(members)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/{id}/members/{email}")]
+async fn unshare_watchlist(path: web::Path<(Uuid, String)>, auth: Auth) -> HttpResponse {
+  let (id, member) = path.into_inner();
+  match Watchlist::unshare(&id, &auth.user.email, &member).await {
+    Ok(_) => HttpResponse::NoContent().finish(),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+  config.service(
+    web::scope("watchlists")
+      .service(get_watchlists)
+      .service(create_watchlist)
+      .service(get_watchlist)
+      .service(update_watchlist)
+      .service(delete_watchlist)
+      .service(set_airports)
+      .service(add_airport)
+      .service(remove_airport)
+      .service(get_members)
+      .service(share_watchlist)
+      .service(unshare_watchlist),
+  );
+}
@@ -0,0 +1,381 @@
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::Duration;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Client, Url};
+use sha2::Sha256;
+use uuid::Uuid;
+use crate::auth::IpNetwork;
+use crate::db;
+use crate::error::{ApiResult, Error};
+use super::DeliveryStatus;
+
+const DELIVERIES_TABLE_NAME: &str = "webhook_deliveries";
+const TABLE_NAME: &str = "webhooks";
+const USER_AGENT: &str = "aviation-weather-webhooks";
+const POLL_INTERVAL_SECONDS: u64 = 5;
+const BATCH_SIZE: i64 = 50;
+/// Claimed deliveries are hidden from other workers for this long
+const LEASE_SECONDS: i64 = 60;
+const MAX_ATTEMPTS: i32 = 8;
+const RETRY_BASE_SECONDS: i64 = 30;
+const RETRY_MAX_SECONDS: i64 = 3600;
+const DELIVERY_RETENTION_DAYS: i64 = 30;
+const MAX_ERROR_LENGTH: usize = 512;
+
+/// Loopback, private, link-local and other ranges that are not reachable on the internet
+const RESERVED_NETWORKS: &[&str] = &[
+  "0.0.0.0/8",
+  "10.0.0.0/8",
+  "100.64.0.0/10",
+  "127.0.0.0/8",
+  "169.254.0.0/16",
+  "172.16.0.0/12",
+  "192.0.0.0/24",
+  "192.168.0.0/16",
+  "198.18.0.0/15",
+  "224.0.0.0/3",
+  "::/127",
+  "64:ff9b::/96",
+  "2002::/16",
+  "fc00::/7",
+  "fe80::/10",
+  "ff00::/8",
+];
+
+static ALLOW_PRIVATE: OnceLock<bool> = OnceLock::new();
+
+/// Whether receivers may resolve to private addresses, from `WEBHOOK_ALLOW_PRIVATE`. Only
+/// meant for development against a local receiver.
+fn allow_private() -> bool {
+  *ALLOW_PRIVATE
+    .get_or_init(|| env::var("WEBHOOK_ALLOW_PRIVATE").is_ok_and(|allow| allow == "true"))
+}
+
+fn is_public(ip: &IpAddr) -> bool {
+  !RESERVED_NETWORKS
+    .iter()
+    .filter_map(|network| IpNetwork::parse(network))
+    .any(|network| network.contains(ip))
+}
+
+/// Resolver for the webhook client that only hands out public addresses, so a receiver that
+/// passed `verify_destination` cannot rebind its name to a private address before connecting
+pub struct PublicResolver;
+
+impl Resolve for PublicResolver {
+  fn resolve(&self, name: Name) -> Resolving {
+    Box::pin(async move {
+      let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+        .await?
+        .filter(|address| allow_private() || is_public(&address.ip()))
+        .collect();
+      if addresses.is_empty() {
+        return Err(format!("{} does not resolve to a public address", name.as_str()).into());
+      }
+      let addresses: Addrs = Box::new(addresses.into_iter());
+      Ok(addresses)
+    })
+  }
+}
+
+/// Reject receivers that are not http(s) or that resolve to a private address, checked when
+/// the webhook is saved and again before every attempt
+pub async fn verify_destination(url: &str) -> ApiResult<Url> {
+  let invalid = |reason: &str| Error::new(400, format!("Invalid webhook URL: {}", reason));
+  let parsed = Url::parse(url).map_err(|err| invalid(&err.to_string()))?;
+  if !matches!(parsed.scheme(), "http" | "https") {
+    return Err(invalid("only http and https are supported"));
+  }
+  let host = parsed.host_str().ok_or_else(|| invalid("missing host"))?;
+  let port = parsed
+    .port_or_known_default()
+    .ok_or_else(|| invalid("missing port"))?;
+  if allow_private() {
+    return Ok(parsed);
+  }
+  let host = host.trim_start_matches('[').trim_end_matches(']');
+  let addresses: Vec<IpAddr> = tokio::net::lookup_host((host, port))
+    .await
+    .map_err(|_| invalid("host could not be resolved"))?
+    .map(|address| address.ip())
+    .collect();
+  if addresses.is_empty() || !addresses.iter().all(is_public) {
+    return Err(invalid("private addresses are not allowed"));
+  }
+  Ok(parsed)
+}
+
+/// Hex HMAC-SHA256 of `{timestamp}.{body}`, sent as `X-Webhook-Signature: sha256=<hex>`
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+  let mut mac =
+    Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+  mac.update(timestamp.to_string().as_bytes());
+  mac.update(b".");
+  mac.update(body);
+  hex::encode(mac.finalize().into_bytes())
+}
+
+/// Seconds to wait after a failed attempt, doubling from 30 seconds up to an hour
+pub fn retry_delay(attempts: i32) -> i64 {
+  let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+  (RETRY_BASE_SECONDS << exponent).min(RETRY_MAX_SECONDS)
+}
+
+#[derive(sqlx::FromRow)]
+struct ClaimedDelivery {
+  id: Uuid,
+  webhook_id: Uuid,
+  event: String,
+  payload: serde_json::Value,
+  attempts: i32,
+  url: String,
+  secret: String,
+}
+
+struct Attempt {
+  response_status: Option<i32>,
+  error: Option<String>,
+}
+
+impl Attempt {
+  fn succeeded(&self) -> bool {
+    self.error.is_none()
+  }
+}
+
+fn truncate(error: String) -> String {
+  match error.char_indices().nth(MAX_ERROR_LENGTH) {
+    Some((index, _)) => error[..index].to_string(),
+    None => error,
+  }
+}
+
+/// Take due deliveries of enabled webhooks, counting the attempt up front so a crash
+/// mid-delivery still moves towards the dead letter
+async fn claim() -> ApiResult<Vec<ClaimedDelivery>> {
+  let pool = db::pool();
+  let deliveries: Vec<ClaimedDelivery> = sqlx::query_as(&format!(
+    r#"
+    UPDATE {0} d SET
+      attempts = d.attempts + 1,
+      next_attempt_at = NOW() + make_interval(secs => $2)
+    FROM {1} w
+    WHERE w.id = d.webhook_id AND d.id IN (
+      SELECT id FROM {0}
+      WHERE status = $3 AND next_attempt_at <= NOW()
+        AND webhook_id IN (SELECT id FROM {1} WHERE enabled)
+      ORDER BY next_attempt_at
+      LIMIT $1
+      FOR UPDATE SKIP LOCKED
+    )
+    RETURNING d.id, d.webhook_id, d.event, d.payload, d.attempts, w.url, w.secret
+    "#,
+    DELIVERIES_TABLE_NAME, TABLE_NAME
+  ))
+  .bind(BATCH_SIZE)
+  .bind(LEASE_SECONDS as f64)
+  .bind(DeliveryStatus::Pending.as_str())
+  .fetch_all(pool)
+  .await?;
+  Ok(deliveries)
+}
+
+async fn attempt(client: &Client, delivery: &ClaimedDelivery) -> Attempt {
+  let url = match verify_destination(&delivery.url).await {
+    Ok(url) => url,
+    Err(err) => {
+      return Attempt {
+        response_status: None,
+        error: Some(err.details),
+      }
+    }
+  };
+  let body = match serde_json::to_vec(&delivery.payload) {
+    Ok(body) => body,
+    Err(err) => {
+      return Attempt {
+        response_status: None,
+        error: Some(err.to_string()),
+      }
+    }
+  };
+  let timestamp = Utc::now().timestamp();
+  let signature = sign(&delivery.secret, timestamp, &body);
+  let response = client
+    .post(url)
+    .header(reqwest::header::CONTENT_TYPE, "application/json")
+    .header(reqwest::header::USER_AGENT, USER_AGENT)
+    .header("X-Webhook-Id", delivery.id.to_string())
+    .header("X-Webhook-Event", &delivery.event)
+    .header("X-Webhook-Timestamp", timestamp.to_string())
+    .header("X-Webhook-Signature", format!("sha256={}", signature))
+    .body(body)
+    .send()
+    .await;
+  match response {
+    Ok(response) if response.status().is_success() => Attempt {
+      response_status: Some(response.status().as_u16() as i32),
+      error: None,
+    },
+    Ok(response) => Attempt {
+      response_status: Some(response.status().as_u16() as i32),
+      error: Some(format!("Receiver responded with {}", response.status())),
+    },
+    Err(err) => Attempt {
+      response_status: None,
+      error: Some(truncate(err.to_string())),
+    },
+  }
+}
+
+async fn complete(delivery: &ClaimedDelivery, attempt: &Attempt) -> ApiResult<()> {
+  let pool = db::pool();
+  let status = if attempt.succeeded() {
+    DeliveryStatus::Delivered
+  } else if delivery.attempts >= MAX_ATTEMPTS {
+    DeliveryStatus::Dead
+  } else {
+    DeliveryStatus::Pending
+  };
+  sqlx::query(&format!(
+    r#"
+    UPDATE {} SET
+      status = $2,
+      response_status = $3,
+      error = $4,
+      next_attempt_at = NOW() + make_interval(secs => $5),
+      delivered_at = CASE WHEN $6 THEN NOW() ELSE delivered_at END
+    WHERE id = $1
+    "#,
+    DELIVERIES_TABLE_NAME
+  ))
+  .bind(delivery.id)
+  .bind(status.as_str())
+  .bind(attempt.response_status)
+  .bind(&attempt.error)
+  .bind(retry_delay(delivery.attempts) as f64)
+  .bind(status == DeliveryStatus::Delivered)
+  .execute(pool)
+  .await?;
+  match status {
+    DeliveryStatus::Dead => log::warn!(
+      "Webhook {} delivery {} failed after {} attempts: {}",
+      delivery.webhook_id,
+      delivery.id,
+      delivery.attempts,
+      attempt.error.as_deref().unwrap_or_default()
+    ),
+    DeliveryStatus::Pending => log::debug!(
+      "Webhook {} delivery {} failed, retrying: {}",
+      delivery.webhook_id,
+      delivery.id,
+      attempt.error.as_deref().unwrap_or_default()
+    ),
+    DeliveryStatus::Delivered => {}
+  }
+  Ok(())
+}
+
+async fn prune() -> ApiResult<u64> {
+  let pool = db::pool();
+  let result = sqlx::query(&format!(
+    r#"
+    DELETE FROM {} WHERE status = $1 AND created_at < NOW() - make_interval(days => $2)
+    "#,
+    DELIVERIES_TABLE_NAME
+  ))
+  .bind(DeliveryStatus::Delivered.as_str())
+  .bind(DELIVERY_RETENTION_DAYS as i32)
+  .execute(pool)
+  .await?;
+  Ok(result.rows_affected())
+}
+
+/// Send queued deliveries until the process exits. Receivers are called without following
+/// redirects, so a public URL cannot bounce the request to a private address.
+pub fn spawn_worker(client: Client) {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(POLL_INTERVAL_SECONDS));
+    let mut last_pruned = None;
+    loop {
+      interval.tick().await;
+      let deliveries = match claim().await {
+        Ok(deliveries) => deliveries,
+        Err(err) => {
+          log::error!("Unable to claim webhook deliveries: {}", err);
+          continue;
+        }
+      };
+      let attempts = futures_util::future::join_all(
+        deliveries.iter().map(|delivery| attempt(&client, delivery)),
+      )
+      .await;
+      for (delivery, attempt) in deliveries.iter().zip(attempts) {
+        if let Err(err) = complete(delivery, &attempt).await {
+          log::error!("Unable to record webhook delivery {}: {}", delivery.id, err);
+        }
+      }
+
+      let today = Utc::now().date_naive();
+      if last_pruned != Some(today) {
+        match prune().await {
+          Ok(pruned) => {
+            log::debug!("Pruned {} delivered webhook deliveries", pruned);
+            last_pruned = Some(today);
+          }
+          Err(err) => log::error!("Unable to prune webhook deliveries: {}", err),
+        }
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_sign() {
+    let signature = sign("whsec_secret", 1700000000, br#"{"event":"ping"}"#);
+    // printf '1700000000.{"event":"ping"}' | openssl dgst -sha256 -hmac whsec_secret
+    assert_eq!(
+      signature,
+      "7ca78d295d0f2ccc02a2a7da8dce1acd7a4ac767ef641fbd32c37d82ea0b65b4"
+    );
+    assert_ne!(
+      signature,
+      sign("whsec_secret", 1700000001, br#"{"event":"ping"}"#)
+    );
+    assert_ne!(
+      signature,
+      sign("whsec_other", 1700000000, br#"{"event":"ping"}"#)
+    );
+  }
+
+  #[test]
+  fn test_retry_delay() {
+    assert_eq!(retry_delay(1), 30);
+    assert_eq!(retry_delay(2), 60);
+    assert_eq!(retry_delay(4), 240);
+    assert_eq!(retry_delay(MAX_ATTEMPTS), 3600);
+    assert_eq!(retry_delay(100), 3600);
+  }
+
+  #[test]
+  fn test_is_public() {
+    assert!(is_public(&"93.184.216.34".parse().unwrap()));
+    assert!(is_public(&"2606:2800:220:1::".parse().unwrap()));
+    assert!(!is_public(&"127.0.0.1".parse().unwrap()));
+    assert!(!is_public(&"10.1.2.3".parse().unwrap()));
+    assert!(!is_public(&"169.254.169.254".parse().unwrap()));
+    assert!(!is_public(&"::1".parse().unwrap()));
+    assert!(!is_public(&"::ffff:192.168.1.1".parse().unwrap()));
+    assert!(!is_public(&"fd00::1".parse().unwrap()));
+    assert!(!is_public(&"64:ff9b::a00:1".parse().unwrap()));
+    assert!(!is_public(&"2002:c0a8:101::1".parse().unwrap()));
+  }
+}
@@ -0,0 +1,7 @@
+mod delivery;
+mod model;
+mod routes;
+
+pub use delivery::*;
+pub use model::*;
+pub use routes::init_routes;
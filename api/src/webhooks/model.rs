@@ -0,0 +1,631 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, QueryBuilder};
+use uuid::Uuid;
+use crate::alerts::{AlertEvent, AlertRule};
+use crate::auth::csprng;
+use crate::db;
+use crate::error::{ApiResult, Error};
+use crate::metars::{FlightCategory, Metar};
+use super::delivery::verify_destination;
+
+const TABLE_NAME: &str = "webhooks";
+const DELIVERIES_TABLE_NAME: &str = "webhook_deliveries";
+const ORGANIZATION_MEMBERS_TABLE_NAME: &str = "organization_members";
+const MAX_WEBHOOKS: i64 = 20;
+const MAX_URL_LENGTH: usize = 2048;
+const MAX_ICAOS: usize = 500;
+const SECRET_PREFIX: &str = "whsec_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WebhookEvent {
+  /// Every newly stored observation of a station
+  #[serde(rename = "metar.updated")]
+  MetarUpdated,
+  /// An observation whose flight category differs from the one before it
+  #[serde(rename = "flight_category.changed")]
+  FlightCategoryChanged,
+  /// An alert rule visible to the subscriber was triggered
+  #[serde(rename = "alert.fired")]
+  AlertFired,
+  /// Sent on request to test a subscription, never subscribed to
+  #[serde(rename = "ping")]
+  Ping,
+}
+
+impl WebhookEvent {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      WebhookEvent::MetarUpdated => "metar.updated",
+      WebhookEvent::FlightCategoryChanged => "flight_category.changed",
+      WebhookEvent::AlertFired => "alert.fired",
+      WebhookEvent::Ping => "ping",
+    }
+  }
+}
+
+impl TryFrom<String> for WebhookEvent {
+  type Error = String;
+
+  fn try_from(event: String) -> Result<Self, Self::Error> {
+    match event.as_str() {
+      "metar.updated" => Ok(WebhookEvent::MetarUpdated),
+      "flight_category.changed" => Ok(WebhookEvent::FlightCategoryChanged),
+      "alert.fired" => Ok(WebhookEvent::AlertFired),
+      "ping" => Ok(WebhookEvent::Ping),
+      _ => Err(format!("Invalid webhook event '{}'", event)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Webhook {
+  pub id: Uuid,
+  pub owner: String,
+  pub url: String,
+  /// Only returned when the webhook is created or its secret rotated
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub secret: Option<String>,
+  pub events: Vec<String>,
+  /// Stations whose weather events are sent, every station when absent
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub icaos: Option<Vec<String>>,
+  pub enabled: bool,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateWebhook {
+  pub url: String,
+  pub events: Vec<WebhookEvent>,
+  pub icaos: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateWebhook {
+  pub url: Option<String>,
+  pub events: Option<Vec<WebhookEvent>>,
+  /// An empty list removes the station filter
+  pub icaos: Option<Vec<String>>,
+  pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+  Pending,
+  Delivered,
+  /// Ran out of attempts, kept until retried by hand
+  Dead,
+}
+
+impl DeliveryStatus {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      DeliveryStatus::Pending => "pending",
+      DeliveryStatus::Delivered => "delivered",
+      DeliveryStatus::Dead => "dead",
+    }
+  }
+}
+
+impl TryFrom<String> for DeliveryStatus {
+  type Error = String;
+
+  fn try_from(status: String) -> Result<Self, Self::Error> {
+    match status.as_str() {
+      "pending" => Ok(DeliveryStatus::Pending),
+      "delivered" => Ok(DeliveryStatus::Delivered),
+      "dead" => Ok(DeliveryStatus::Dead),
+      _ => Err(format!("Invalid delivery status '{}'", status)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct WebhookDelivery {
+  pub id: Uuid,
+  pub webhook_id: Uuid,
+  #[sqlx(try_from = "String")]
+  pub event: WebhookEvent,
+  pub payload: serde_json::Value,
+  #[sqlx(try_from = "String")]
+  pub status: DeliveryStatus,
+  pub attempts: i32,
+  pub next_attempt_at: DateTime<Utc>,
+  /// Status code of the last response, absent when the receiver could not be reached
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub response_status: Option<i32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+  pub created_at: DateTime<Utc>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub delivered_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeliveryQuery {
+  pub page: Option<u32>,
+  pub limit: Option<u32>,
+  pub status: Option<DeliveryStatus>,
+}
+
+impl Default for DeliveryQuery {
+  fn default() -> Self {
+    Self {
+      page: Some(1),
+      limit: Some(100),
+      status: None,
+    }
+  }
+}
+
+/// Body sent to the receiver, `id` is the delivery and stays the same across retries
+#[derive(Debug, Serialize)]
+struct Payload<'a, T: Serialize> {
+  id: Uuid,
+  event: WebhookEvent,
+  created_at: DateTime<Utc>,
+  data: &'a T,
+}
+
+#[derive(Debug, Serialize)]
+struct FlightCategoryChange<'a> {
+  icao: &'a str,
+  previous: FlightCategory,
+  current: FlightCategory,
+  metar: &'a Metar,
+}
+
+fn validate_events(events: &[WebhookEvent]) -> ApiResult<Vec<String>> {
+  let mut events: Vec<String> = events
+    .iter()
+    .filter(|event| **event != WebhookEvent::Ping)
+    .map(|event| event.as_str().to_string())
+    .collect();
+  events.sort();
+  events.dedup();
+  if events.is_empty() {
+    return Err(Error::new(
+      400,
+      "Webhooks must subscribe to at least one event".to_string(),
+    ));
+  }
+  Ok(events)
+}
+
+/// Normalized station filter, `None` for every station
+fn validate_icaos(icaos: &Option<Vec<String>>) -> ApiResult<Option<Vec<String>>> {
+  let Some(icaos) = icaos else {
+    return Ok(None);
+  };
+  let mut icaos: Vec<String> = icaos
+    .iter()
+    .map(|icao| icao.trim().to_uppercase())
+    .filter(|icao| !icao.is_empty())
+    .collect();
+  icaos.sort();
+  icaos.dedup();
+  if icaos.len() > MAX_ICAOS {
+    return Err(Error::new(
+      400,
+      format!("Webhooks are limited to {} stations", MAX_ICAOS),
+    ));
+  }
+  Ok((!icaos.is_empty()).then_some(icaos))
+}
+
+async fn validate_url(url: &str) -> ApiResult<String> {
+  let url = url.trim();
+  if url.len() > MAX_URL_LENGTH {
+    return Err(Error::new(
+      400,
+      format!("Webhook URLs are limited to {} characters", MAX_URL_LENGTH),
+    ));
+  }
+  verify_destination(url).await?;
+  Ok(url.to_string())
+}
+
+fn generate_secret() -> String {
+  format!("{}{}", SECRET_PREFIX, csprng(32))
+}
+
+impl Webhook {
+  const COLUMNS: &'static str =
+    "id, owner, url, NULL AS secret, events, icaos, enabled, created_at, updated_at";
+
+  pub async fn select_all(email: &str) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let webhooks: Vec<Self> = sqlx::query_as(&format!(
+      r#"
+      SELECT {} FROM {} WHERE owner = $1 ORDER BY created_at
+      "#,
+      Self::COLUMNS,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_all(pool)
+    .await?;
+    Ok(webhooks)
+  }
+
+  pub async fn select(id: &Uuid, email: &str) -> ApiResult<Self> {
+    let pool = db::pool();
+    let webhook: Option<Self> = sqlx::query_as(&format!(
+      r#"
+      SELECT {} FROM {} WHERE id = $1 AND owner = $2
+      "#,
+      Self::COLUMNS,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(email)
+    .fetch_optional(pool)
+    .await?;
+    webhook.ok_or_else(|| Error::new(404, format!("Webhook {} not found", id)))
+  }
+
+  pub async fn insert(email: &str, request: &CreateWebhook) -> ApiResult<Self> {
+    let pool = db::pool();
+    let url = validate_url(&request.url).await?;
+    let events = validate_events(&request.events)?;
+    let icaos = validate_icaos(&request.icaos)?;
+
+    let count: i64 = sqlx::query_scalar(&format!(
+      r#"
+      SELECT COUNT(*) FROM {} WHERE owner = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(email)
+    .fetch_one(pool)
+    .await?;
+    if count >= MAX_WEBHOOKS {
+      return Err(Error::new(
+        400,
+        format!("Users are limited to {} webhooks", MAX_WEBHOOKS),
+      ));
+    }
+
+    let id = Uuid::new_v4();
+    let secret = generate_secret();
+    sqlx::query(&format!(
+      r#"
+      INSERT INTO {} (id, owner, url, secret, events, icaos) VALUES ($1, $2, $3, $4, $5, $6)
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(email)
+    .bind(&url)
+    .bind(&secret)
+    .bind(&events)
+    .bind(&icaos)
+    .execute(pool)
+    .await?;
+    let mut webhook = Self::select(&id, email).await?;
+    webhook.secret = Some(secret);
+    Ok(webhook)
+  }
+
+  pub async fn update(id: &Uuid, email: &str, request: &UpdateWebhook) -> ApiResult<Self> {
+    let pool = db::pool();
+    Self::select(id, email).await?;
+    let url = match &request.url {
+      Some(url) => Some(validate_url(url).await?),
+      None => None,
+    };
+    let events = match &request.events {
+      Some(events) => Some(validate_events(events)?),
+      None => None,
+    };
+    let icaos = validate_icaos(&request.icaos)?;
+
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET
+        url = COALESCE($2, url),
+        events = COALESCE($3, events),
+        icaos = CASE WHEN $4 THEN $5 ELSE icaos END,
+        enabled = COALESCE($6, enabled),
+        updated_at = NOW()
+      WHERE id = $1
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(url)
+    .bind(events)
+    .bind(request.icaos.is_some())
+    .bind(icaos)
+    .bind(request.enabled)
+    .execute(pool)
+    .await?;
+    Self::select(id, email).await
+  }
+
+  pub async fn delete(id: &Uuid, email: &str) -> ApiResult<()> {
+    let pool = db::pool();
+    let result = sqlx::query(&format!(
+      r#"
+      DELETE FROM {} WHERE id = $1 AND owner = $2
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(email)
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+      return Err(Error::new(404, format!("Webhook {} not found", id)));
+    }
+    Ok(())
+  }
+
+  /// Replace the signing secret, deliveries still waiting to be sent use the new one
+  pub async fn rotate_secret(id: &Uuid, email: &str) -> ApiResult<Self> {
+    let pool = db::pool();
+    let secret = generate_secret();
+    let result = sqlx::query(&format!(
+      r#"
+      UPDATE {} SET secret = $3, updated_at = NOW() WHERE id = $1 AND owner = $2
+      "#,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(email)
+    .bind(&secret)
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+      return Err(Error::new(404, format!("Webhook {} not found", id)));
+    }
+    let mut webhook = Self::select(id, email).await?;
+    webhook.secret = Some(secret);
+    Ok(webhook)
+  }
+
+  /// Queue a test delivery regardless of the subscribed events
+  pub async fn ping(id: &Uuid, email: &str) -> ApiResult<WebhookDelivery> {
+    let webhook = Self::select(id, email).await?;
+    let data = serde_json::json!({ "webhook_id": webhook.id });
+    let ids = WebhookDelivery::enqueue(&[webhook.id], WebhookEvent::Ping, &data).await?;
+    WebhookDelivery::select(&webhook.id, &ids[0], email).await
+  }
+
+  /// Queue `event` for every enabled webhook subscribed to it. Station events only reach
+  /// webhooks without a station filter or with `icao` in it, alerts only reach the owner of
+  /// the rule and members of its organization.
+  async fn dispatch<T: Serialize>(
+    event: WebhookEvent,
+    icao: &str,
+    rule: Option<&AlertRule>,
+    data: &T,
+  ) -> ApiResult<()> {
+    let pool = db::pool();
+    let mut builder = QueryBuilder::<Postgres>::new("SELECT id FROM ");
+    builder
+      .push(TABLE_NAME)
+      .push(" WHERE enabled AND ")
+      .push_bind(event.as_str())
+      .push(" = ANY(events) AND (icaos IS NULL OR ")
+      .push_bind(icao.to_string())
+      .push(" = ANY(icaos))");
+    if let Some(rule) = rule {
+      builder
+        .push(" AND (owner = ")
+        .push_bind(rule.owner.clone())
+        .push(" OR owner IN (SELECT email FROM ")
+        .push(ORGANIZATION_MEMBERS_TABLE_NAME)
+        .push(" WHERE organization_id = ")
+        .push_bind(rule.organization_id)
+        .push("))");
+    }
+    let webhooks: Vec<Uuid> = builder.build_query_scalar().fetch_all(pool).await?;
+    if !webhooks.is_empty() {
+      WebhookDelivery::enqueue(&webhooks, event, data).await?;
+    }
+    Ok(())
+  }
+
+  /// Queue the weather events of a newly stored observation
+  pub async fn dispatch_metar(metar: &Metar) -> ApiResult<()> {
+    let icao = &metar.station_id;
+    Self::dispatch(WebhookEvent::MetarUpdated, icao, None, metar).await?;
+    if let Some(previous) = metar.select_previous().await? {
+      if previous.flight_category != metar.flight_category {
+        let change = FlightCategoryChange {
+          icao,
+          previous: previous.flight_category,
+          current: metar.flight_category,
+          metar,
+        };
+        Self::dispatch(WebhookEvent::FlightCategoryChanged, icao, None, &change).await?;
+      }
+    }
+    Ok(())
+  }
+
+  pub async fn dispatch_alert(rule: &AlertRule, event: &AlertEvent) -> ApiResult<()> {
+    Self::dispatch(WebhookEvent::AlertFired, &event.icao, Some(rule), event).await
+  }
+
+  /// Dispatch in the background, webhooks must never hold up storing an observation
+  pub fn spawn_dispatch_metar(metar: Metar) {
+    tokio::spawn(async move {
+      if let Err(err) = Self::dispatch_metar(&metar).await {
+        log::error!("Unable to queue webhooks for {}: {}", metar.station_id, err);
+      }
+    });
+  }
+}
+
+impl WebhookDelivery {
+  const COLUMNS: &'static str = "d.id, d.webhook_id, d.event, d.payload, d.status, d.attempts, \
+    d.next_attempt_at, d.response_status, d.error, d.created_at, d.delivered_at";
+
+  /// Insert a pending delivery per webhook, returning their ids
+  async fn enqueue<T: Serialize>(
+    webhooks: &[Uuid],
+    event: WebhookEvent,
+    data: &T,
+  ) -> ApiResult<Vec<Uuid>> {
+    let pool = db::pool();
+    let created_at = Utc::now();
+    let mut ids = vec![];
+    let mut builder = QueryBuilder::<Postgres>::new("INSERT INTO ");
+    builder
+      .push(DELIVERIES_TABLE_NAME)
+      .push(" (id, webhook_id, event, payload, status, created_at) ");
+    let mut deliveries = vec![];
+    for webhook in webhooks {
+      let id = Uuid::new_v4();
+      let payload = serde_json::to_value(Payload {
+        id,
+        event,
+        created_at,
+        data,
+      })?;
+      ids.push(id);
+      deliveries.push((id, *webhook, payload));
+    }
+    builder.push_values(deliveries, |mut row, (id, webhook, payload)| {
+      row
+        .push_bind(id)
+        .push_bind(webhook)
+        .push_bind(event.as_str())
+        .push_bind(payload)
+        .push_bind(DeliveryStatus::Pending.as_str())
+        .push_bind(created_at);
+    });
+    builder.build().execute(pool).await?;
+    Ok(ids)
+  }
+
+  fn push_conditions(
+    builder: &mut QueryBuilder<Postgres>,
+    webhook_id: &Uuid,
+    email: &str,
+    query: &DeliveryQuery,
+  ) {
+    builder
+      .push(" FROM ")
+      .push(DELIVERIES_TABLE_NAME)
+      .push(" d JOIN ")
+      .push(TABLE_NAME)
+      .push(" w ON w.id = d.webhook_id WHERE d.webhook_id = ")
+      .push_bind(*webhook_id)
+      .push(" AND w.owner = ")
+      .push_bind(email.to_string());
+    if let Some(status) = query.status {
+      builder.push(" AND d.status = ").push_bind(status.as_str());
+    }
+  }
+
+  /// Delivery log of a webhook, newest first
+  pub async fn select_all(
+    webhook_id: &Uuid,
+    email: &str,
+    query: &DeliveryQuery,
+  ) -> ApiResult<Vec<Self>> {
+    let pool = db::pool();
+    let mut builder = QueryBuilder::<Postgres>::new(format!("SELECT {}", Self::COLUMNS));
+    Self::push_conditions(&mut builder, webhook_id, email, query);
+    builder.push(" ORDER BY d.created_at DESC");
+    if let Some(limit) = query.limit {
+      builder.push(" LIMIT ").push_bind(limit as i64);
+      let offset = (query.page.unwrap_or(1).saturating_sub(1) * limit) as i64;
+      builder.push(" OFFSET ").push_bind(offset);
+    }
+    let deliveries: Vec<Self> = builder.build_query_as().fetch_all(pool).await?;
+    Ok(deliveries)
+  }
+
+  pub async fn count(webhook_id: &Uuid, email: &str, query: &DeliveryQuery) -> i64 {
+    let pool = db::pool();
+    let mut builder = QueryBuilder::<Postgres>::new("SELECT COUNT(*)");
+    Self::push_conditions(&mut builder, webhook_id, email, query);
+    builder
+      .build_query_scalar()
+      .fetch_one(pool)
+      .await
+      .unwrap_or(0)
+  }
+
+  pub async fn select(webhook_id: &Uuid, id: &Uuid, email: &str) -> ApiResult<Self> {
+    let pool = db::pool();
+    let delivery: Option<Self> = sqlx::query_as(&format!(
+      r#"
+      SELECT {} FROM {} d JOIN {} w ON w.id = d.webhook_id
+      WHERE d.id = $1 AND d.webhook_id = $2 AND w.owner = $3
+      "#,
+      Self::COLUMNS,
+      DELIVERIES_TABLE_NAME,
+      TABLE_NAME
+    ))
+    .bind(id)
+    .bind(webhook_id)
+    .bind(email)
+    .fetch_optional(pool)
+    .await?;
+    delivery.ok_or_else(|| Error::new(404, format!("Delivery {} not found", id)))
+  }
+
+  /// Send a dead letter again with a fresh set of attempts
+  pub async fn retry(webhook_id: &Uuid, id: &Uuid, email: &str) -> ApiResult<Self> {
+    let pool = db::pool();
+    let delivery = Self::select(webhook_id, id, email).await?;
+    if delivery.status != DeliveryStatus::Dead {
+      return Err(Error::new(
+        409,
+        format!("Delivery {} is {}", id, delivery.status.as_str()),
+      ));
+    }
+    sqlx::query(&format!(
+      r#"
+      UPDATE {} SET status = $2, attempts = 0, next_attempt_at = NOW()
+      WHERE id = $1 AND status = $3
+      "#,
+      DELIVERIES_TABLE_NAME
+    ))
+    .bind(id)
+    .bind(DeliveryStatus::Pending.as_str())
+    .bind(DeliveryStatus::Dead.as_str())
+    .execute(pool)
+    .await?;
+    Self::select(webhook_id, id, email).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_validate_events() {
+    let events = validate_events(&[
+      WebhookEvent::AlertFired,
+      WebhookEvent::MetarUpdated,
+      WebhookEvent::AlertFired,
+    ])
+    .unwrap();
+    assert_eq!(events, vec!["alert.fired", "metar.updated"]);
+    assert!(validate_events(&[WebhookEvent::Ping]).is_err());
+    assert!(validate_events(&[]).is_err());
+
+    let event: WebhookEvent = serde_json::from_str(r#""flight_category.changed""#).unwrap();
+    assert_eq!(event, WebhookEvent::FlightCategoryChanged);
+  }
+
+  #[test]
+  fn test_validate_icaos() {
+    assert_eq!(validate_icaos(&None).unwrap(), None);
+    assert_eq!(validate_icaos(&Some(vec![" ".to_string()])).unwrap(), None);
+    assert_eq!(
+      validate_icaos(&Some(vec!["kbos".to_string(), "KBOS ".to_string()])).unwrap(),
+      Some(vec!["KBOS".to_string()])
+    );
+  }
+}
@@ -0,0 +1,179 @@
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, ResponseError};
+use uuid::Uuid;
+use crate::auth::{client_ip, require, Permitted};
+use crate::db::Paged;
+use crate::webhooks::{CreateWebhook, DeliveryQuery, UpdateWebhook, Webhook, WebhookDelivery};
+
+const MAX_LIMIT: u32 = 500;
+
+#[get("")]
+async fn get_webhooks(auth: Permitted<require::WebhooksManage>) -> HttpResponse {
+  match Webhook::select_all(&auth.user.email).await {
+    Ok(webhooks) => HttpResponse::Ok().json(webhooks),
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[post("")]
+async fn create_webhook(
+  request: web::Json<CreateWebhook>,
+  req: HttpRequest,
+  auth: Permitted<require::WebhooksManage>,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  match Webhook::insert(&auth.user.email, &request).await {
+    Ok(webhook) => {
+      log::info!(
+        "Created webhook {} [Email: {}] [IP Address: {}]",
+        webhook.id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::Created().json(webhook)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[get("/{id}")]
+async fn get_webhook(
+  id: web::Path<Uuid>,
+  auth: Permitted<require::WebhooksManage>,
+) -> HttpResponse {
+  match Webhook::select(&id, &auth.user.email).await {
+    Ok(webhook) => HttpResponse::Ok().json(webhook),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[put("/{id}")]
+async fn update_webhook(
+  id: web::Path<Uuid>,
+  request: web::Json<UpdateWebhook>,
+  auth: Permitted<require::WebhooksManage>,
+) -> HttpResponse {
+  match Webhook::update(&id, &auth.user.email, &request).await {
+    Ok(webhook) => HttpResponse::Ok().json(webhook),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[delete("/{id}")]
+async fn delete_webhook(
+  id: web::Path<Uuid>,
+  req: HttpRequest,
+  auth: Permitted<require::WebhooksManage>,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  match Webhook::delete(&id, &auth.user.email).await {
+    Ok(_) => {
+      log::info!(
+        "Deleted webhook {} [Email: {}] [IP Address: {}]",
+        id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::NoContent().finish()
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[post("/{id}/secret")]
+async fn rotate_secret(
+  id: web::Path<Uuid>,
+  req: HttpRequest,
+  auth: Permitted<require::WebhooksManage>,
+) -> HttpResponse {
+  let ip_address = client_ip(&req);
+  match Webhook::rotate_secret(&id, &auth.user.email).await {
+    Ok(webhook) => {
+      log::info!(
+        "Rotated secret of webhook {} [Email: {}] [IP Address: {}]",
+        id,
+        auth.user.email,
+        ip_address
+      );
+      HttpResponse::Ok().json(webhook)
+    }
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[post("/{id}/ping")]
+async fn ping_webhook(
+  id: web::Path<Uuid>,
+  auth: Permitted<require::WebhooksManage>,
+) -> HttpResponse {
+  match Webhook::ping(&id, &auth.user.email).await {
+    Ok(delivery) => HttpResponse::Accepted().json(delivery),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+#[get("/{id}/deliveries")]
+async fn get_deliveries(
+  id: web::Path<Uuid>,
+  req: HttpRequest,
+  auth: Permitted<require::WebhooksManage>,
+) -> HttpResponse {
+  let mut query = match web::Query::<DeliveryQuery>::from_query(req.query_string()) {
+    Ok(q) => q.into_inner(),
+    Err(err) => {
+      log::error!("{}", err);
+      DeliveryQuery::default()
+    }
+  };
+  if let Err(err) = Webhook::select(&id, &auth.user.email).await {
+    return ResponseError::error_response(&err);
+  }
+
+  let total = WebhookDelivery::count(&id, &auth.user.email, &query).await;
+  let page = query.page.unwrap_or(1);
+  let limit = query.limit.unwrap_or(100).min(MAX_LIMIT);
+  query.limit = Some(limit);
+  query.page = Some(page);
+
+  match WebhookDelivery::select_all(&id, &auth.user.email, &query).await {
+    Ok(deliveries) => HttpResponse::Ok().json(Paged {
+      data: deliveries,
+      page,
+      limit,
+      total,
+    }),
+    Err(err) => {
+      log::error!("{}", err);
+      ResponseError::error_response(&err)
+    }
+  }
+}
+
+#[post("/{id}/deliveries/{delivery_id}/retry")]
+async fn retry_delivery(
+  path: web::Path<(Uuid, Uuid)>,
+  auth: Permitted<require::WebhooksManage>,
+) -> HttpResponse {
+  let (id, delivery_id) = path.into_inner();
+  match WebhookDelivery::retry(&id, &delivery_id, &auth.user.email).await {
+    Ok(delivery) => HttpResponse::Accepted().json(delivery),
+    Err(err) => ResponseError::error_response(&err),
+  }
+}
+
+pub fn init_routes(config: &mut web::ServiceConfig) {
+  config.service(
+    web::scope("webhooks")
+      .service(get_webhooks)
+      .service(create_webhook)
+      .service(get_webhook)
+      .service(update_webhook)
+      .service(delete_webhook)
+      .service(rotate_secret)
+      .service(ping_webhook)
+      .service(get_deliveries)
+      .service(retry_delivery),
+  );
+}